@@ -0,0 +1,145 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Actions requested by server in heartbeat response, e.g.
+//! `{"actions": [{"id": "1", "action": "wake", "params": {"macs": ["00:11:22:33:44:55"]}}]}`.
+//!
+//! Every handler is disabled unless explicitly enabled in `[action.*]`,
+//! results are reported back with `action_result`.
+
+use crate::configparser::config::Configure;
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerAction {
+    pub id: Option<String>,
+    pub action: String,
+    #[serde(default)]
+    pub params: Value,
+    /// Unix timestamp after which operator approvals are no longer valid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+    /// Operator signatures, see `session::authz`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub approvals: Vec<String>,
+}
+
+#[async_trait]
+pub trait ActionHandler: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value>;
+
+    /// Action used to report result back to server.
+    fn result_action(&self) -> &str {
+        "action_result"
+    }
+}
+
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<String, Box<dyn ActionHandler>>,
+}
+
+impl Dispatcher {
+    pub fn new(cfg: &Configure) -> anyhow::Result<Self> {
+        let mut dispatcher = Self::default();
+        if let Some(remote_access) = cfg.remote_access.as_ref().filter(|r| r.enabled) {
+            dispatcher.register(Box::new(crate::remote_access::RemoteAccessHandler::new(
+                remote_access,
+                &cfg.identification.as_ref().unwrap().token,
+            )));
+        }
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.packages.as_ref().is_some_and(|p| p.enabled))
+        {
+            dispatcher.register(Box::new(crate::packages::PackageListHandler));
+        }
+        let actions = match &cfg.action {
+            Some(actions) => actions,
+            None => return Ok(dispatcher),
+        };
+        if let Some(wake) = actions.wake.as_ref().filter(|w| w.enabled) {
+            dispatcher.register(Box::new(crate::wake::WakeHandler::new(wake)?));
+        }
+        if let Some(exec) = actions.exec.as_ref().filter(|e| e.enabled) {
+            dispatcher.register(Box::new(crate::exec::ExecHandler::new(exec)));
+        }
+        if let Some(fetch) = actions.fetch_file.as_ref().filter(|f| f.enabled) {
+            dispatcher.register(Box::new(crate::fetch::FetchFileHandler::new(fetch)?));
+        }
+        Ok(dispatcher)
+    }
+
+    pub fn register(&mut self, handler: Box<dyn ActionHandler>) {
+        info!("Enable server action: {}", handler.name());
+        self.handlers.insert(handler.name().to_string(), handler);
+    }
+
+    /// Result reported when action is refused before running.
+    pub fn refuse(request: &ServerAction, reason: &str) -> (String, Value) {
+        (
+            "action_result".to_string(),
+            serde_json::json!({
+                "id": request.id,
+                "action": request.action,
+                "ok": false,
+                "error": reason,
+            }),
+        )
+    }
+
+    /// Run requested action, return (result action, result body).
+    pub async fn dispatch(&self, request: &ServerAction) -> (String, Value) {
+        let handler = match self.handlers.get(&request.action) {
+            Some(handler) => handler,
+            None => {
+                warn!(
+                    "Refuse server action {} which is not enabled",
+                    request.action
+                );
+                return Self::refuse(request, "action not permitted");
+            }
+        };
+        info!("Run server action {} ({:?})", request.action, request.id);
+        let body = match handler.handle(&request.params).await {
+            Ok(result) => serde_json::json!({
+                "id": request.id,
+                "action": request.action,
+                "ok": true,
+                "result": result,
+            }),
+            Err(e) => serde_json::json!({
+                "id": request.id,
+                "action": request.action,
+                "ok": false,
+                "error": e.to_string(),
+                "code": crate::error::code(&e).number(),
+            }),
+        };
+        (handler.result_action().to_string(), body)
+    }
+}
@@ -0,0 +1,256 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Local threshold alerts in `[[alerting.rule]]`, posted directly to webhook when a rule
+//! starts or stops breaching, for deployments without alerting server.
+//!
+//! Messages are handlebars templates rendered with the heartbeat payload, where element
+//! matched by `*` is bound to the name of its collection (`{{ mount.mount_on }}` for
+//! `mount.*.mount_avail`), along with `state`, `hostname`, `rule`, `metric`, `value` and `condition`.
+
+use crate::configparser::config::{AlertRule, Alerting, Configure};
+use crate::error::Error;
+use crate::notify::Notifier;
+use crate::ratelimit::Limiter;
+use crate::template::Templates;
+use log::{debug, error};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_MESSAGE: &str =
+    "[{{ state }}] {{ hostname }}: {{ rule }} {{ metric }} = {{ value }} ({{ condition }})";
+
+struct Match {
+    metric: String,
+    value: f64,
+    /// Elements matched by `*`, keyed by name of their collection.
+    bindings: Vec<(String, Value)>,
+}
+
+/// Numeric values at dotted `path`, `*` matches every key or array element.
+fn lookup(
+    value: &Value,
+    path: &[&str],
+    prefix: &str,
+    bindings: &[(String, Value)],
+    found: &mut Vec<Match>,
+) {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => {
+            if let Some(number) = value.as_f64() {
+                found.push(Match {
+                    metric: prefix.to_string(),
+                    value: number,
+                    bindings: bindings.to_vec(),
+                });
+            }
+            return;
+        }
+    };
+    let join = |name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        }
+    };
+    let bind = |child: &Value| {
+        let name = prefix.rsplit('.').next().filter(|name| !name.is_empty());
+        let mut bindings = bindings.to_vec();
+        bindings.push((name.unwrap_or("item").to_string(), child.clone()));
+        bindings
+    };
+    match (value, *key) {
+        (Value::Object(map), "*") => {
+            for (name, child) in map {
+                lookup(child, rest, &join(name), &bind(child), found);
+            }
+        }
+        (Value::Array(items), "*") => {
+            for (index, child) in items.iter().enumerate() {
+                lookup(child, rest, &join(&index.to_string()), &bind(child), found);
+            }
+        }
+        (Value::Object(map), key) => {
+            if let Some(child) = map.get(key) {
+                lookup(child, rest, &join(key), bindings, found);
+            }
+        }
+        (Value::Array(items), key) => {
+            if let Some(child) = key.parse::<usize>().ok().and_then(|i| items.get(i)) {
+                lookup(child, rest, &join(key), bindings, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Integral values are rendered without fraction.
+fn number(value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+        Value::from(value as i64)
+    } else {
+        Value::from(value)
+    }
+}
+
+fn condition(rule: &AlertRule) -> String {
+    match (rule.above, rule.below) {
+        (Some(above), Some(below)) => format!("> {} or < {}", above, below),
+        (Some(above), None) => format!("> {}", above),
+        (None, Some(below)) => format!("< {}", below),
+        (None, None) => String::new(),
+    }
+}
+
+pub struct Alerter {
+    rules: Vec<AlertRule>,
+    templates: Templates,
+    notifier: Arc<Notifier>,
+    hostname: String,
+    /// `<rule>/<metric>` currently breaching.
+    firing: Mutex<HashSet<String>>,
+    /// Messages per `<rule>/<metric>`.
+    limiter: Limiter,
+}
+
+impl Alerter {
+    pub fn new(cfg: &Configure, alerting: &Alerting) -> anyhow::Result<Self> {
+        let rules = alerting.rule.clone().unwrap_or_default();
+        let mut templates = Templates::default();
+        for rule in &rules {
+            if rule.above.is_none() && rule.below.is_none() {
+                return Err(Error::Configure {
+                    field: "alerting.rule",
+                    message: format!("Alert rule {} has neither above nor below", rule.name),
+                }
+                .into());
+            }
+            templates.register(
+                "alerting.rule.message",
+                &rule.name,
+                rule.message.as_deref().unwrap_or(DEFAULT_MESSAGE),
+            )?;
+        }
+        Ok(Self {
+            rules,
+            templates,
+            notifier: Arc::new(Notifier::new(
+                cfg,
+                alerting.webhook.as_ref(),
+                None,
+                alerting.smtp.as_ref(),
+            )?),
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+            firing: Default::default(),
+            limiter: Limiter::new(
+                alerting.limit.as_ref().and_then(|limit| limit.burst),
+                alerting.limit.as_ref().and_then(|limit| limit.per_minute),
+            )?,
+        })
+    }
+
+    fn suppressed(&self, key: &str, count: u64) -> String {
+        format!(
+            "[SUPPRESSED] {}: {} similar alerts of {} suppressed",
+            self.hostname, count, key
+        )
+    }
+
+    /// Messages of rules started or stopped breaching since previous evaluation, and summaries
+    /// of messages suppressed by rate limit.
+    pub fn evaluate(&self, payload: &Value) -> Vec<String> {
+        let mut firing = self.firing.lock().unwrap();
+        let mut messages = self
+            .limiter
+            .flush()
+            .into_iter()
+            .map(|(key, count)| self.suppressed(&key, count))
+            .collect::<Vec<_>>();
+        for rule in &self.rules {
+            let mut found = Vec::new();
+            let path = rule.metric.split('.').collect::<Vec<_>>();
+            lookup(payload, &path, "", &[], &mut found);
+            for Match {
+                metric,
+                value,
+                bindings,
+            } in found
+            {
+                let breached = rule.above.is_some_and(|above| value > above)
+                    || rule.below.is_some_and(|below| value < below);
+                let key = format!("{}/{}", rule.name, metric);
+                let state = match (breached, firing.contains(&key)) {
+                    (true, false) => {
+                        firing.insert(key.clone());
+                        "FIRING"
+                    }
+                    (false, true) => {
+                        firing.remove(&key);
+                        "RESOLVED"
+                    }
+                    _ => continue,
+                };
+                match self.limiter.admit(&key) {
+                    Some(0) => {}
+                    Some(count) => messages.push(self.suppressed(&key, count)),
+                    None => {
+                        debug!("Alert {} {} suppressed by rate limit", key, state);
+                        continue;
+                    }
+                }
+                let mut context = payload.clone();
+                if let Some(map) = context.as_object_mut() {
+                    map.extend(bindings);
+                    map.extend(vec![
+                        ("state".to_string(), Value::from(state)),
+                        ("hostname".to_string(), Value::from(self.hostname.as_str())),
+                        ("rule".to_string(), Value::from(rule.name.as_str())),
+                        ("metric".to_string(), Value::from(metric)),
+                        ("value".to_string(), number(value)),
+                        ("condition".to_string(), Value::from(condition(rule))),
+                    ]);
+                }
+                match self.templates.render(&rule.name, &context) {
+                    Ok(message) => messages.push(message),
+                    Err(e) => error!("Unable render message of alert rule {}: {}", rule.name, e),
+                }
+            }
+        }
+        messages
+    }
+
+    /// Send messages in background, without delaying heartbeat.
+    pub fn notify(&self, messages: Vec<String>) {
+        if messages.is_empty() || self.notifier.is_empty() {
+            return;
+        }
+        let notifier = self.notifier.clone();
+        tokio::spawn(async move {
+            for message in messages {
+                if let Err(e) = notifier.send(&message).await {
+                    error!("Unable send alert: {:?}", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,252 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Refuse to send anything to hosts not matching configured allow-list of domains and CIDRs.
+//!
+//! Domain rules match host name exactly, `*.example.com` matches any subdomain.
+//! CIDR rules match hosts given as IP address.
+
+use crate::configparser::config::Configure;
+use crate::error::Error;
+use std::fmt::Formatter;
+use std::net::IpAddr;
+
+#[derive(Debug)]
+pub struct DeniedError {
+    url: String,
+}
+
+impl std::error::Error for DeniedError {}
+
+impl std::fmt::Display for DeniedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not in outbound allow-list", self.url)
+    }
+}
+
+#[derive(Clone)]
+pub struct AllowList {
+    domains: Vec<String>,
+    networks: Vec<(IpAddr, u8)>,
+}
+
+fn parse_network(rule: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let (address, prefix) = match rule.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+        None => (rule.parse::<IpAddr>()?, None),
+    };
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max);
+    if prefix > max {
+        return Err(Error::Configure {
+            field: "outbound.allow",
+            message: format!("Invalid prefix length in {}", rule),
+        }
+        .into());
+    }
+    Ok((address, prefix))
+}
+
+fn in_network(ip: &IpAddr, network: &(IpAddr, u8)) -> bool {
+    let prefix = network.1 as u32;
+    match (ip, &network.0) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(*ip) & mask == u32::from(*net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(*ip) & mask == u128::from(*net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl AllowList {
+    pub fn new(rules: &[String]) -> anyhow::Result<Self> {
+        let mut domains: Vec<String> = Default::default();
+        let mut networks: Vec<(IpAddr, u8)> = Default::default();
+        for rule in rules {
+            let rule = rule.trim();
+            if rule.starts_with(|c: char| c.is_ascii_digit()) || rule.contains(':') {
+                networks.push(parse_network(rule).map_err(|e| Error::Configure {
+                    field: "outbound.allow",
+                    message: format!("Invalid allow-list rule {}: {}", rule, e),
+                })?);
+            } else {
+                domains.push(rule.to_ascii_lowercase());
+            }
+        }
+        Ok(Self { domains, networks })
+    }
+
+    /// Allow-list from configure, `None` if not configured (everything allowed).
+    pub fn from_config(cfg: &Configure) -> anyhow::Result<Option<Self>> {
+        match &cfg.outbound {
+            Some(outbound) => Ok(Some(Self::new(&outbound.allow)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn allows(&self, url: &reqwest::Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return false,
+        };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.networks.iter().any(|network| in_network(&ip, network)),
+            Err(_) => {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                self.domains
+                    .iter()
+                    .any(|rule| match rule.strip_prefix("*.") {
+                        Some(parent) => host.ends_with(&format!(".{}", parent)),
+                        None => *rule == host,
+                    })
+            }
+        }
+    }
+
+    pub fn check(&self, url: &str) -> anyhow::Result<()> {
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if self.allows(&parsed) => Ok(()),
+            _ => Err(anyhow::Error::new(DeniedError {
+                url: url.to_string(),
+            })),
+        }
+    }
+
+    /// Check every server address and webhook in configure.
+    pub fn check_config(&self, cfg: &Configure) -> anyhow::Result<()> {
+        self.check(&cfg.server.server_address)?;
+        for server in cfg.server.backup_servers.iter().flatten() {
+            self.check(server)?;
+        }
+        for alternate in cfg
+            .server
+            .alternates
+            .iter()
+            .flat_map(|a| a.values().flatten())
+        {
+            self.check(alternate)?;
+        }
+        let webhooks = [
+            cfg.fallback.as_ref().and_then(|f| f.webhook.as_ref()),
+            cfg.alerting.as_ref().and_then(|a| a.webhook.as_ref()),
+        ];
+        for webhook in webhooks.iter().flatten() {
+            self.check(&webhook.url)?;
+        }
+        Ok(())
+    }
+
+    /// Redirect policy which refuses to follow redirect out of allow-list.
+    pub fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        let allow_list = self.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if !allow_list.allows(attempt.url()) {
+                let url = attempt.url().to_string();
+                attempt.error(DeniedError { url })
+            } else if attempt.previous().len() > 10 {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow_list(rules: &[&str]) -> AllowList {
+        AllowList::new(
+            &rules
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    fn allows(list: &AllowList, url: &str) -> bool {
+        list.check(url).is_ok()
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let list = allow_list(&["10.0.0.0/8", "192.168.1.7/32", "172.16.0.1"]);
+        assert!(allows(&list, "http://10.255.0.1/"));
+        assert!(!allows(&list, "http://11.0.0.1/"));
+        assert!(allows(&list, "http://192.168.1.7:8080/"));
+        assert!(!allows(&list, "http://192.168.1.8/"));
+        assert!(allows(&list, "http://172.16.0.1/"));
+        assert!(!allows(&list, "http://172.16.0.2/"));
+        // Host given as name is only matched by domain rules
+        assert!(!allows(&list, "http://localhost/"));
+
+        let any = allow_list(&["0.0.0.0/0"]);
+        assert!(allows(&any, "http://8.8.8.8/"));
+        assert!(!allows(&any, "http://[::1]/"));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let list = allow_list(&["2001:db8::/32", "::1/128"]);
+        assert!(allows(&list, "http://[2001:db8:ffff::1]/"));
+        assert!(!allows(&list, "http://[2001:db9::1]/"));
+        assert!(allows(&list, "http://[::1]:9000/"));
+        assert!(!allows(&list, "http://[::2]/"));
+        assert!(!allows(&list, "http://127.0.0.1/"));
+
+        let any = allow_list(&["::/0"]);
+        assert!(allows(&any, "http://[fe80::1]/"));
+    }
+
+    #[test]
+    fn domains_exact_and_suffix() {
+        let list = allow_list(&["Example.com", "*.probe.example.org"]);
+        assert!(allows(&list, "https://example.com/"));
+        assert!(allows(&list, "https://EXAMPLE.com./path"));
+        assert!(!allows(&list, "https://www.example.com/"));
+        assert!(!allows(&list, "https://evil-example.com/"));
+        assert!(!allows(&list, "https://example.com.evil.net/"));
+
+        assert!(allows(&list, "https://a.probe.example.org/"));
+        assert!(allows(&list, "https://a.b.probe.example.org/"));
+        assert!(!allows(&list, "https://probe.example.org/"));
+        assert!(!allows(&list, "https://evilprobe.example.org/"));
+        assert!(!allows(&list, "not a url"));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for rule in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x"].iter() {
+            let e = AllowList::new(&[rule.to_string()]).err().unwrap();
+            assert_eq!(
+                crate::error::code(&e),
+                crate::error::Code::Configure,
+                "{}",
+                rule
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `annotate` subcommand: attach a note to the next heartbeat through control socket,
+//! e.g. `probe-client annotate "deploying v1.2.3"`, so deploy markers show up in the timeline
+//! of server alongside metrics.
+//!
+//! Notes are sent once, as `annotations` of heartbeat with the time they were made.
+
+use serde_json::Value;
+use std::sync::Mutex;
+
+pub const SUBCOMMAND_NAME: &str = "annotate";
+pub const CONTROL_COMMAND: &str = "annotate";
+pub const PAYLOAD_KEY: &str = "annotations";
+/// Oldest notes are dropped beyond this while server is unreachable.
+const MAX_PENDING: usize = 32;
+
+static PENDING: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Attach a note to the next heartbeat of running client, e.g. a deploy marker")
+        .arg(
+            clap::Arg::with_name("text")
+                .help("Note, e.g. `deploying v1.2.3`")
+                .required(true)
+                .multiple(true),
+        )
+        .arg(crate::output::arg())
+}
+
+pub async fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    let text = matches
+        .values_of("text")
+        .unwrap()
+        .collect::<Vec<_>>()
+        .join(" ");
+    crate::control::send(matches, &format!("{} {}", CONTROL_COMMAND, text)).await
+}
+
+/// Queue note for next heartbeat.
+pub fn add(text: &str) {
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() >= MAX_PENDING {
+        pending.remove(0);
+    }
+    pending.push(serde_json::json!({
+        "text": text,
+        "timestamp": chrono::Utc::now().timestamp(),
+    }));
+}
+
+/// Notes queued since last heartbeat.
+pub fn take() -> Vec<Value> {
+    std::mem::take(&mut *PENDING.lock().unwrap())
+}
+
+/// Queue notes of heartbeat which was not sent again, ahead of newer ones.
+pub fn restore(mut annotations: Vec<Value>) {
+    let mut pending = PENDING.lock().unwrap();
+    annotations.append(&mut pending);
+    let excess = annotations.len().saturating_sub(MAX_PENDING);
+    annotations.drain(..excess);
+    *pending = annotations;
+}
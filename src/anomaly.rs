@@ -0,0 +1,162 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Flag statistically unusual CPU, load and network rate values against rolling (EWMA) baselines.
+
+use crate::configparser::config::Anomaly as AnomalyConfig;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const DEFAULT_ALPHA: f64 = 0.1;
+pub const DEFAULT_THRESHOLD: f64 = 3.0;
+pub const DEFAULT_WARMUP: u64 = 10;
+
+const GAUGES: &[&str] = &["cpu.user", "cpu.system", "loadavg.last1"];
+const COUNTERS: &[&str] = &["rx_bytes", "tx_bytes"];
+
+#[derive(Default)]
+struct Baseline {
+    mean: f64,
+    variance: f64,
+    count: u64,
+}
+
+impl Baseline {
+    fn update(&mut self, value: f64, alpha: f64) {
+        if self.count == 0 {
+            self.mean = value;
+        } else {
+            let diff = value - self.mean;
+            let increment = alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - alpha) * (self.variance + diff * increment);
+        }
+        self.count += 1;
+    }
+
+    fn stddev(&self) -> f64 {
+        // Floor at 1% of mean, so tiny jitter of a flat series is not flagged
+        self.variance
+            .sqrt()
+            .max(self.mean.abs() * 0.01)
+            .max(f64::EPSILON)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Finding {
+    metric: String,
+    value: f64,
+    mean: f64,
+    stddev: f64,
+    score: f64,
+}
+
+#[derive(Default)]
+struct State {
+    baselines: HashMap<String, Baseline>,
+    counters: HashMap<String, (f64, Instant)>,
+}
+
+pub struct Detector {
+    alpha: f64,
+    threshold: f64,
+    warmup: u64,
+    state: Mutex<State>,
+}
+
+fn lookup(value: &Value, path: &str) -> Option<f64> {
+    path.split('.')
+        .try_fold(value, |current, key| current.get(key))
+        .and_then(Value::as_f64)
+}
+
+impl Detector {
+    pub fn new(cfg: &AnomalyConfig) -> Self {
+        Self {
+            alpha: cfg.alpha.unwrap_or(DEFAULT_ALPHA).clamp(0.001, 1.0),
+            threshold: cfg.threshold.unwrap_or(DEFAULT_THRESHOLD),
+            warmup: cfg.warmup.unwrap_or(DEFAULT_WARMUP),
+            state: Default::default(),
+        }
+    }
+
+    /// Per-second rates of network counters since the previous call.
+    fn rates(state: &mut State, payload: &Value, now: Instant) -> Vec<(String, f64)> {
+        let mut result: Vec<(String, f64)> = Default::default();
+        let interfaces = match payload
+            .pointer("/network_statistics/interfaces")
+            .and_then(Value::as_object)
+        {
+            Some(interfaces) => interfaces,
+            None => return result,
+        };
+        for (name, stats) in interfaces {
+            for counter in COUNTERS {
+                let value = match stats.get(counter).and_then(Value::as_f64) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let key = format!("network.{}.{}", name, counter);
+                if let Some((last, at)) = state.counters.insert(key.clone(), (value, now)) {
+                    let elapsed = now.duration_since(at).as_secs_f64();
+                    // Skip counter reset
+                    if elapsed > 0.0 && value >= last {
+                        result.push((format!("{}_rate", key), (value - last) / elapsed));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Update baselines with values in normalized payload, return unusual ones.
+    pub fn evaluate(&self, payload: &Value) -> Vec<Finding> {
+        let mut state = self.state.lock().unwrap();
+        let mut metrics: Vec<(String, f64)> = GAUGES
+            .iter()
+            .filter_map(|path| lookup(payload, path).map(|v| (path.to_string(), v)))
+            .collect();
+        metrics.extend(Self::rates(&mut state, payload, Instant::now()));
+
+        let mut findings: Vec<Finding> = Default::default();
+        for (metric, value) in metrics {
+            let baseline = state.baselines.entry(metric.clone()).or_default();
+            if baseline.count >= self.warmup {
+                let stddev = baseline.stddev();
+                let score = (value - baseline.mean) / stddev;
+                if score.abs() > self.threshold {
+                    findings.push(Finding {
+                        metric,
+                        value,
+                        mean: baseline.mean,
+                        stddev,
+                        score,
+                    });
+                }
+            }
+            baseline.update(value, self.alpha);
+        }
+        findings
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Append-only audit log of every action requested by server, accepted or rejected, with its result.
+//!
+//! Each line is a JSON entry carrying `prev` (hash of previous entry) and `hash`, which is
+//! SHA-256 of `prev` followed by the entry (without `hash`) serialized as compact JSON with sorted keys,
+//! so removed or modified entries break the chain. In read-only mode entries are logged instead.
+
+use crate::action::ServerAction;
+use crate::signing::sha256_hex;
+use log::{info, warn};
+use serde_json::Value;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const DEFAULT_AUDIT_FILE: &str = "probe_audit.log";
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub struct AuditLog {
+    path: PathBuf,
+    last_hash: Mutex<String>,
+}
+
+impl AuditLog {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let last_hash = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| {
+                contents
+                    .lines()
+                    .last()
+                    .and_then(|line| serde_json::from_str::<Value>(line).ok())
+                    .and_then(|entry| {
+                        entry
+                            .get("hash")
+                            .and_then(Value::as_str)
+                            .map(str::to_string)
+                    })
+            })
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+        Self {
+            path,
+            last_hash: Mutex::new(last_hash),
+        }
+    }
+
+    fn append(&self, line: &str) -> std::io::Result<()> {
+        if crate::state::is_read_only() {
+            info!("Audit: {}", line);
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()
+    }
+
+    /// Record requested action, its authorization status and result body.
+    pub fn record(&self, request: &ServerAction, authorization: &str, result: &Value) {
+        let mut last_hash = self.last_hash.lock().unwrap();
+        let mut entry = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "request": request,
+            "authorization": authorization,
+            "result": result,
+            "prev": *last_hash,
+        });
+        let mut data = last_hash.as_bytes().to_vec();
+        data.extend(serde_json::to_vec(&entry).unwrap());
+        let hash = sha256_hex(&data);
+        entry["hash"] = Value::String(hash.clone());
+        match self.append(&entry.to_string()) {
+            Ok(()) => *last_hash = hash,
+            Err(e) => warn!("Unable write audit log to {}: {:?}", self.path.display(), e),
+        }
+    }
+}
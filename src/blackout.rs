@@ -0,0 +1,441 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Blackout windows in `[[blackout]]`: recurring periods, starting at times matched by a cron
+//! expression or RFC 5545 RRULE, during which heartbeats are tagged `maintenance` or not sent
+//! at all, so planned load such as backups saturating links doesn't raise latency alerts.
+//!
+//! Cron expressions have five fields `minute hour day-of-month month day-of-week` with `*`,
+//! lists, ranges and steps (day of week 0 or 7 is Sunday). RRULEs support `FREQ` (`HOURLY` to
+//! `YEARLY`), `BYMONTH`, `BYMONTHDAY`, `BYDAY` (without ordinals), `BYHOUR` and `BYMINUTE`.
+//! Start times are local time unless `utc = true`.
+
+use crate::configparser::config::{Blackout, Configure};
+use crate::error::Error;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use log::info;
+use std::sync::Mutex;
+
+/// Longest window, bounds the backward search for its start.
+pub const MAX_DURATION: u64 = 7 * 24 * 3600;
+pub const DEFAULT_MODE: &str = "tag";
+pub const DEFAULT_UTC: bool = false;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Send heartbeat with `maintenance` tag
+    Tag,
+    /// Send no heartbeat
+    Suppress,
+}
+
+/// Start times a window recurs at, to the minute.
+struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    /// Day of month, index 0 unused
+    days: Vec<bool>,
+    /// Index 0 unused
+    months: Vec<bool>,
+    /// 0 is Sunday
+    weekdays: Vec<bool>,
+    /// Cron: when both day of month and day of week are restricted, either one matches
+    day_or: bool,
+}
+
+/// One cron field or RRULE list within `min..=max` as membership flags.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut flags = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|e| format!("{:?} in {:?}: {}", value, part, e))
+        };
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, number(step)?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Step of {:?} is zero", part));
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                None if part.contains('/') => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{:?} out of range {}-{}", part, min, max));
+        }
+        (start..=end)
+            .step_by(step as usize)
+            .for_each(|value| flags[value as usize] = true);
+    }
+    Ok(flags)
+}
+
+/// Whether `flags` allow every value from `min`, as `*` (or `*/1`, `1-31`) does.
+fn unrestricted(flags: &[bool], min: usize) -> bool {
+    flags[min..].iter().all(|allowed| *allowed)
+}
+
+fn only(max: usize, value: usize) -> Vec<bool> {
+    let mut flags = vec![false; max + 1];
+    flags[value] = true;
+    flags
+}
+
+impl Schedule {
+    fn cron(expression: &str) -> Result<Self, String> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Cron expression {:?} requires 5 fields",
+                expression
+            ));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays.pop() == Some(true) {
+            weekdays[0] = true;
+        }
+        let days = parse_field(fields[2], 1, 31)?;
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            day_or: !unrestricted(&days, 1) && !unrestricted(&weekdays, 0),
+            days,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+        })
+    }
+
+    fn rrule(rule: &str) -> Result<Self, String> {
+        let rule = rule.trim().trim_start_matches("RRULE:");
+        let mut freq = None;
+        let mut schedule = Self {
+            minutes: only(59, 0),
+            hours: only(23, 0),
+            days: vec![true; 32],
+            months: vec![true; 13],
+            weekdays: vec![true; 7],
+            day_or: false,
+        };
+        let (mut by_hour, mut by_day, mut by_month_day, mut by_month) =
+            (false, false, false, false);
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid RRULE part {:?}", part))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => freq = Some(value.to_ascii_uppercase()),
+                "INTERVAL" if value == "1" => {}
+                "WKST" => {}
+                "BYMINUTE" => schedule.minutes = parse_field(value, 0, 59)?,
+                "BYHOUR" => {
+                    schedule.hours = parse_field(value, 0, 23)?;
+                    by_hour = true;
+                }
+                "BYMONTHDAY" => {
+                    schedule.days = parse_field(value, 1, 31)?;
+                    by_month_day = true;
+                }
+                "BYMONTH" => {
+                    schedule.months = parse_field(value, 1, 12)?;
+                    by_month = true;
+                }
+                "BYDAY" => {
+                    let mut weekdays = vec![false; 7];
+                    for day in value.split(',') {
+                        let index = ["SU", "MO", "TU", "WE", "TH", "FR", "SA"]
+                            .iter()
+                            .position(|name| day.eq_ignore_ascii_case(name))
+                            .ok_or_else(|| format!("Unsupported BYDAY {:?}", day))?;
+                        weekdays[index] = true;
+                    }
+                    schedule.weekdays = weekdays;
+                    by_day = true;
+                }
+                _ => return Err(format!("Unsupported RRULE part {:?}", part)),
+            }
+        }
+        match freq.as_deref() {
+            Some("HOURLY") => {
+                if !by_hour {
+                    schedule.hours = vec![true; 24];
+                }
+            }
+            Some("DAILY") => {}
+            Some("WEEKLY") if !by_day => return Err(format!("RRULE {:?} requires BYDAY", rule)),
+            Some("WEEKLY") => {}
+            Some("MONTHLY") | Some("YEARLY") => {
+                if !by_month_day && !by_day {
+                    schedule.days = only(31, 1);
+                }
+                if freq.as_deref() == Some("YEARLY") && !by_month {
+                    schedule.months = only(12, 1);
+                }
+            }
+            _ => return Err(format!("RRULE {:?} has unsupported FREQ", rule)),
+        }
+        Ok(schedule)
+    }
+
+    fn matches(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        self.minutes[time.minute() as usize]
+            && self.hours[time.hour() as usize]
+            && self.months[time.month() as usize]
+            && if self.day_or {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+}
+
+struct Window {
+    name: String,
+    schedule: Schedule,
+    duration: chrono::Duration,
+    mode: Mode,
+    utc: bool,
+}
+
+impl Window {
+    fn new(index: usize, cfg: &Blackout) -> anyhow::Result<Self> {
+        let name = cfg
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("blackout{}", index));
+        let invalid = |field, message: String| Error::Configure {
+            field,
+            message: format!("Blackout window {}: {}", name, message),
+        };
+        let schedule = match (&cfg.cron, &cfg.rrule) {
+            (Some(cron), None) => Schedule::cron(cron).map_err(|e| invalid("blackout.cron", e)),
+            (None, Some(rrule)) => Schedule::rrule(rrule).map_err(|e| invalid("blackout.rrule", e)),
+            _ => Err(invalid(
+                "blackout.cron",
+                "requires exactly one of cron and rrule".to_string(),
+            )),
+        }?;
+        if cfg.duration == 0 || cfg.duration > MAX_DURATION {
+            return Err(invalid(
+                "blackout.duration",
+                format!("duration must be between 1 and {} seconds", MAX_DURATION),
+            )
+            .into());
+        }
+        let mode = match cfg.mode.as_deref().unwrap_or(DEFAULT_MODE) {
+            "tag" => Mode::Tag,
+            "suppress" => Mode::Suppress,
+            mode => {
+                return Err(invalid(
+                    "blackout.mode",
+                    format!("unknown mode {:?}, expect tag or suppress", mode),
+                )
+                .into())
+            }
+        };
+        Ok(Self {
+            name,
+            schedule,
+            duration: chrono::Duration::seconds(cfg.duration as i64),
+            mode,
+            utc: cfg.utc.unwrap_or(DEFAULT_UTC),
+        })
+    }
+
+    /// Start of current occurrence if `now` falls into one.
+    fn started(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        (0..=self.duration.num_minutes())
+            .map(|back| minute - chrono::Duration::minutes(back))
+            .take_while(|start| *start + self.duration > now)
+            .find(|start| self.schedule.matches(start))
+    }
+}
+
+pub struct Blackouts {
+    windows: Vec<Window>,
+    /// Name of window active on last check, to log only transitions
+    active: Mutex<Option<String>>,
+}
+
+impl Blackouts {
+    pub fn new(cfg: &Configure) -> anyhow::Result<Option<Self>> {
+        let windows = match &cfg.blackout {
+            Some(windows) if !windows.is_empty() => windows,
+            _ => return Ok(None),
+        };
+        Ok(Some(Self {
+            windows: windows
+                .iter()
+                .enumerate()
+                .map(|(index, window)| Window::new(index, window))
+                .collect::<anyhow::Result<_>>()?,
+            active: Default::default(),
+        }))
+    }
+
+    /// Mode and `maintenance` tag of current window, windows suppressing heartbeats first.
+    pub fn check(&self) -> Option<(Mode, serde_json::Value)> {
+        let utc = chrono::Utc::now();
+        let mut current = None;
+        for window in &self.windows {
+            let now = if window.utc {
+                utc.naive_utc()
+            } else {
+                utc.with_timezone(&chrono::Local).naive_local()
+            };
+            if let Some(start) = window.started(now) {
+                let elapsed = (now - start).num_seconds();
+                let tag = serde_json::json!({
+                    "window": window.name,
+                    "started": utc.timestamp() - elapsed,
+                    "ends": utc.timestamp() - elapsed + window.duration.num_seconds(),
+                });
+                current = Some((window, tag));
+                if window.mode == Mode::Suppress {
+                    break;
+                }
+            }
+        }
+        let mut active = self.active.lock().unwrap();
+        let name = current.as_ref().map(|(window, _)| window.name.clone());
+        if *active != name {
+            match &name {
+                Some(name) => info!("Blackout window {} started", name),
+                None => info!(
+                    "Blackout window {} ended",
+                    active.as_deref().unwrap_or_default()
+                ),
+            }
+            *active = name;
+        }
+        current.map(|(window, tag)| (window.mode, tag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(flags: Vec<bool>) -> Vec<usize> {
+        flags
+            .iter()
+            .enumerate()
+            .filter(|(_, allowed)| **allowed)
+            .map(|(value, _)| value)
+            .collect()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // October 2026, the 1st is Thursday
+        chrono::NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn field_steps_ranges_and_lists() {
+        assert_eq!(values(parse_field("*/15", 0, 59).unwrap()), [0, 15, 30, 45]);
+        assert_eq!(values(parse_field("5/20", 0, 59).unwrap()), [5, 25, 45]);
+        assert_eq!(values(parse_field("1-10/3", 1, 31).unwrap()), [1, 4, 7, 10]);
+        assert_eq!(
+            values(parse_field("1,3-4,20", 1, 31).unwrap()),
+            [1, 3, 4, 20]
+        );
+        assert_eq!(values(parse_field("7", 0, 7).unwrap()), [7]);
+        for invalid in ["*/0", "60", "0", "5-3", "a", "1-", ""].iter() {
+            let (min, max) = if *invalid == "0" { (1, 31) } else { (0, 59) };
+            assert!(parse_field(invalid, min, max).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cron_day_of_month_or_week() {
+        // Both restricted: the 13th or any Friday
+        let schedule = Schedule::cron("0 0 13 * 5").unwrap();
+        assert!(schedule.matches(&at(13, 0, 0)));
+        assert!(schedule.matches(&at(16, 0, 0)));
+        assert!(!schedule.matches(&at(14, 0, 0)));
+
+        // Unrestricted day of month, however it is written, leaves day of week alone
+        for expression in ["0 0 * * 5", "0 0 */1 * 5", "0 0 1-31 * 5"].iter() {
+            let schedule = Schedule::cron(expression).unwrap();
+            assert!(schedule.matches(&at(16, 0, 0)), "{}", expression);
+            assert!(!schedule.matches(&at(13, 0, 0)), "{}", expression);
+        }
+        let schedule = Schedule::cron("0 0 13 * */1").unwrap();
+        assert!(schedule.matches(&at(13, 0, 0)));
+        assert!(!schedule.matches(&at(16, 0, 0)));
+
+        // Sunday is 0 or 7
+        let schedule = Schedule::cron("30 2 * * 7").unwrap();
+        assert!(schedule.matches(&at(18, 2, 30)));
+        assert!(!schedule.matches(&at(18, 2, 31)));
+
+        assert!(Schedule::cron("0 0 * *").is_err());
+        assert!(Schedule::cron("0 24 * * *").is_err());
+    }
+
+    #[test]
+    fn rrule_defaults_and_filters() {
+        let schedule =
+            Schedule::rrule("RRULE:FREQ=WEEKLY;BYDAY=MO,FR;BYHOUR=2;BYMINUTE=30").unwrap();
+        assert!(schedule.matches(&at(16, 2, 30)));
+        assert!(schedule.matches(&at(12, 2, 30)));
+        assert!(!schedule.matches(&at(13, 2, 30)));
+        assert!(!schedule.matches(&at(16, 2, 0)));
+
+        let schedule = Schedule::rrule("FREQ=HOURLY;BYMINUTE=0,30").unwrap();
+        assert!(schedule.matches(&at(13, 7, 30)));
+        assert!(!schedule.matches(&at(13, 7, 15)));
+
+        // Monthly on the 1st, yearly on January 1st unless told otherwise
+        let schedule = Schedule::rrule("FREQ=MONTHLY").unwrap();
+        assert!(schedule.matches(&at(1, 0, 0)));
+        assert!(!schedule.matches(&at(2, 0, 0)));
+        let schedule = Schedule::rrule("FREQ=YEARLY").unwrap();
+        assert!(!schedule.matches(&at(1, 0, 0)));
+
+        assert!(Schedule::rrule("FREQ=WEEKLY").is_err());
+        assert!(Schedule::rrule("FREQ=DAILY;INTERVAL=2").is_err());
+        assert!(Schedule::rrule("FREQ=DAILY;BYDAY=1MO").is_err());
+        assert!(Schedule::rrule("BYHOUR=1").is_err());
+    }
+
+    #[test]
+    fn window_covers_duration_after_start() {
+        let window = Window::new(
+            0,
+            &toml::from_str("cron = \"0 2 * * *\"\nduration = 3600").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(window.started(at(13, 2, 59)), Some(at(13, 2, 0)));
+        assert_eq!(window.started(at(13, 3, 0)), None);
+        assert_eq!(window.started(at(13, 1, 59)), None);
+    }
+}
@@ -0,0 +1,63 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Per-boot session ID, so server can partition data by boot of the host
+//! instead of inferring reboots from `boot_time` changes.
+//!
+//! Linux kernel boot ID is used if available, otherwise a random UUID is generated
+//! and kept in `probe_boot_id` of state directory as long as `boot_time` stays the same.
+
+use log::{info, warn};
+
+pub const BOOT_ID_FILE: &str = "probe_boot_id";
+const KERNEL_BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+/// Boot time derived from uptime may drift a little between reads.
+const BOOT_TIME_TOLERANCE: i64 = 5;
+
+fn load(boot_time: i64) -> Option<String> {
+    let contents = std::fs::read_to_string(crate::state::path(BOOT_ID_FILE)).ok()?;
+    let (stored_time, boot_id) = contents.trim().split_once(' ')?;
+    let stored_time: i64 = stored_time.parse().ok()?;
+    if (stored_time - boot_time).abs() <= BOOT_TIME_TOLERANCE {
+        Some(boot_id.to_string())
+    } else {
+        None
+    }
+}
+
+pub fn boot_id(boot_time: i64) -> String {
+    if let Ok(boot_id) = std::fs::read_to_string(KERNEL_BOOT_ID_PATH) {
+        return boot_id.trim().to_string();
+    }
+    if let Some(boot_id) = load(boot_time) {
+        return boot_id;
+    }
+    let boot_id = uuid::Uuid::new_v4().to_string();
+    info!("Generate new boot session ID: {}", boot_id);
+    let path = crate::state::path(BOOT_ID_FILE);
+    if let Err(e) = crate::state::write(&path, format!("{} {}", boot_time, boot_id)) {
+        warn!(
+            "Unable persist boot session ID to {}: {:?}",
+            path.display(),
+            e
+        );
+    }
+    boot_id
+}
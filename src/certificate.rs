@@ -0,0 +1,296 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Report days until expiry of local certificate files and TLS endpoints in `[[check.certificate]]`.
+
+use crate::collector::Collector;
+use crate::configparser::config::CertificateCheck;
+use crate::error::Error;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::Engine as _;
+use chrono::TimeZone;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub const COLLECTOR_NAME: &str = "certificates";
+const CONNECT_TIMEOUT: u64 = 10;
+
+/// Split DER TLV, return (tag, content, rest).
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, data) = data.split_first()?;
+    let (length, data) = if first < 0x80 {
+        (first as usize, data)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || data.len() < count {
+            return None;
+        }
+        let length = data[..count]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (length, &data[count..])
+    };
+    if data.len() < length {
+        return None;
+    }
+    Some((tag, &data[..length], &data[length..]))
+}
+
+fn parse_time(tag: u8, content: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(content).ok()?.trim_end_matches('Z');
+    let (year, rest) = match tag {
+        // UTCTime, two digits year
+        0x17 => {
+            let year: i32 = text.get(..2)?.parse().ok()?;
+            (
+                if year >= 50 { 1900 + year } else { 2000 + year },
+                &text[2..],
+            )
+        }
+        // GeneralizedTime
+        0x18 => (text.get(..4)?.parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<u32> { rest.get(i * 2..i * 2 + 2)?.parse().ok() };
+    let naive = chrono::NaiveDate::from_ymd_opt(year, field(0)?, field(1)?)?.and_hms_opt(
+        field(2)?,
+        field(3)?,
+        field(4).unwrap_or(0),
+    )?;
+    Some(chrono::Utc.from_utc_datetime(&naive).timestamp())
+}
+
+/// Extract `notAfter` of DER encoded X.509 certificate as unix timestamp.
+pub fn not_after(der: &[u8]) -> anyhow::Result<i64> {
+    let parse = || -> Option<i64> {
+        let (_, certificate, _) = read_tlv(der).filter(|(tag, _, _)| *tag == 0x30)?;
+        let (_, tbs, _) = read_tlv(certificate).filter(|(tag, _, _)| *tag == 0x30)?;
+        let mut rest = tbs;
+        // Optional explicit version
+        if rest.first() == Some(&0xa0) {
+            rest = read_tlv(rest)?.2;
+        }
+        // serialNumber, signature, issuer
+        for _ in 0..3 {
+            rest = read_tlv(rest)?.2;
+        }
+        let (_, validity, _) = read_tlv(rest).filter(|(tag, _, _)| *tag == 0x30)?;
+        let (_, _, validity) = read_tlv(validity)?;
+        let (tag, time, _) = read_tlv(validity)?;
+        parse_time(tag, time)
+    };
+    parse().ok_or_else(|| {
+        Error::Malformed {
+            what: "certificate".to_string(),
+            message: "validity not found".to_string(),
+        }
+        .into()
+    })
+}
+
+/// DER of first certificate in PEM file.
+pub(crate) fn read_pem(path: &str) -> anyhow::Result<Vec<u8>> {
+    let contents = std::fs::read_to_string(path).map_err(|source| Error::Io {
+        operation: "read",
+        path: path.to_string(),
+        source,
+    })?;
+    read_pem_str(&contents).map_err(|e| match crate::error::of(&e) {
+        Some(Error::Malformed { message, .. }) => Error::Malformed {
+            what: format!("certificate {}", path),
+            message: message.clone(),
+        }
+        .into(),
+        _ => e,
+    })
+}
+
+/// DER of first certificate in PEM text.
+pub(crate) fn read_pem_str(contents: &str) -> anyhow::Result<Vec<u8>> {
+    let body: String = contents
+        .lines()
+        .skip_while(|line| !line.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END CERTIFICATE-----"))
+        .collect();
+    let malformed = |message: String| Error::Malformed {
+        what: "certificate".to_string(),
+        message,
+    };
+    if body.is_empty() {
+        return Err(malformed("no PEM certificate found".to_string()).into());
+    }
+    Ok(base64::engine::general_purpose::STANDARD
+        .decode(body.trim())
+        .map_err(|e| malformed(e.to_string()))?)
+}
+
+/// Accept any certificate, only its validity is read.
+struct NoVerifier;
+
+impl rustls::client::ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Negotiated parameters of a TLS handshake.
+pub struct Handshake {
+    /// DER of leaf certificate
+    pub certificate: Vec<u8>,
+    pub version: String,
+    pub cipher: String,
+}
+
+/// DER of leaf certificate presented by TLS endpoint `host:port`.
+async fn fetch_endpoint(endpoint: &str) -> anyhow::Result<Vec<u8>> {
+    handshake(endpoint)
+        .await
+        .map(|handshake| handshake.certificate)
+}
+
+/// Handshake with TLS endpoint `host:port` (default port 443) without verifying it.
+pub async fn handshake(endpoint: &str) -> anyhow::Result<Handshake> {
+    let host = endpoint
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(endpoint)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let address = if endpoint.contains(':') && !endpoint.ends_with(']') {
+        endpoint.to_string()
+    } else {
+        format!("{}:443", endpoint)
+    };
+    handshake_with_sni(&address, host).await
+}
+
+/// Handshake with TLS endpoint at `address` presenting `sni` as server name.
+pub async fn handshake_with_sni(address: &str, sni: &str) -> anyhow::Result<Handshake> {
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoVerifier))
+        .with_no_client_auth();
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+    let server_name = rustls::ServerName::try_from(sni)?;
+    let stream = tokio::time::timeout(Duration::from_secs(CONNECT_TIMEOUT), async {
+        let tcp = tokio::net::TcpStream::connect(&address).await?;
+        connector.connect(server_name, tcp).await
+    })
+    .await
+    .map_err(|_| Error::Timeout {
+        error: anyhow!("Timeout connect to {}", address),
+    })??;
+    let (_, connection) = stream.get_ref();
+    let certificate = connection
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+        .map(|certificate| certificate.0.clone())
+        .ok_or_else(|| Error::Protocol {
+            protocol: "tls",
+            message: format!("No certificate presented by {}", address),
+        })?;
+    Ok(Handshake {
+        certificate,
+        version: connection
+            .protocol_version()
+            .map(|version| format!("{:?}", version))
+            .unwrap_or_default(),
+        cipher: connection
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_default(),
+    })
+}
+
+pub struct CertificateCollector {
+    checks: Vec<CertificateCheck>,
+}
+
+impl CertificateCollector {
+    pub fn new(checks: &[CertificateCheck]) -> Self {
+        Self {
+            checks: checks.to_vec(),
+        }
+    }
+
+    async fn inspect(check: &CertificateCheck) -> anyhow::Result<(String, Value)> {
+        let (target, der) = match (&check.file, &check.endpoint) {
+            (Some(file), None) => (file.clone(), read_pem(file)?),
+            (None, Some(endpoint)) => (endpoint.clone(), fetch_endpoint(endpoint).await?),
+            _ => {
+                return Err(Error::Configure {
+                    field: "check.certificate",
+                    message: "Exactly one of file or endpoint should be set".to_string(),
+                }
+                .into())
+            }
+        };
+        let not_after = not_after(&der)?;
+        let days = (not_after - chrono::Utc::now().timestamp()) as f64 / 86400.0;
+        let not_after = chrono::Utc
+            .timestamp_opt(not_after, 0)
+            .single()
+            .map(|t| t.to_rfc3339());
+        Ok((
+            target,
+            serde_json::json!({ "not_after": not_after, "days_until_expiry": days }),
+        ))
+    }
+}
+
+#[async_trait]
+impl Collector for CertificateCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let mut result = serde_json::Map::new();
+        for check in &self.checks {
+            let target = check
+                .file
+                .clone()
+                .or_else(|| check.endpoint.clone())
+                .unwrap_or_default();
+            match Self::inspect(check).await {
+                Ok((target, value)) => result.insert(target, value),
+                Err(e) => result.insert(target, serde_json::json!({ "error": e.to_string() })),
+            };
+        }
+        Ok(Value::Object(result))
+    }
+
+    fn source(&self) -> String {
+        "certificate expiry".to_string()
+    }
+}
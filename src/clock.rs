@@ -0,0 +1,165 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use async_trait::async_trait;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+/// Source of time for interval sleeps, timeouts and backoff.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    async fn sleep(&self, duration: Duration);
+
+    /// Monotonic time, advanced by `sleep` of fake clock.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time. Unlike monotonic time, it keeps counting while the host is suspended.
+    fn wall(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Clock which never really waits, every sleep only advance the virtual time.
+#[cfg(any(test, feature = "devtools"))]
+pub struct FakeClock {
+    start: Instant,
+    wall_start: SystemTime,
+    elapsed: std::sync::Mutex<Duration>,
+    /// Wall-clock time passed while suspended, and to pass during next sleep
+    suspended: std::sync::Mutex<(Duration, Duration)>,
+}
+
+#[cfg(any(test, feature = "devtools"))]
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            wall_start: SystemTime::now(),
+            elapsed: Default::default(),
+            suspended: Default::default(),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "devtools"))]
+impl FakeClock {
+    pub fn advance(&self, duration: Duration) -> Duration {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += duration;
+        *elapsed
+    }
+
+    /// Pretend host is suspended for `duration` during next sleep: only wall-clock time moves on.
+    #[cfg(test)]
+    pub fn suspend(&self, duration: Duration) {
+        self.suspended.lock().unwrap().1 += duration;
+    }
+}
+
+#[cfg(any(test, feature = "devtools"))]
+#[async_trait]
+impl Clock for FakeClock {
+    async fn sleep(&self, duration: Duration) {
+        let elapsed = self.advance(duration);
+        {
+            let (total, next) = &mut *self.suspended.lock().unwrap();
+            *total += std::mem::take(next);
+        }
+        log::debug!(
+            "Fake clock skip {:?}, total elapsed {:?}",
+            duration,
+            elapsed
+        );
+        tokio::task::yield_now().await
+    }
+
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.wall_start + *self.elapsed.lock().unwrap() + self.suspended.lock().unwrap().0
+    }
+}
+
+/// Sleep `duration` on `clock`, return true if `rx` received (or closed) before that.
+pub async fn sleep_or_recv(
+    clock: &dyn Clock,
+    duration: Duration,
+    rx: &mut mpsc::Receiver<()>,
+) -> bool {
+    tokio::select! {
+        biased;
+        _ = rx.recv() => true,
+        _ = clock.sleep(duration) => false,
+    }
+}
+
+/// Wall-clock time of `clock` elapsed since `start` beyond `expected`, see [`Clock::wall`].
+pub fn overrun(clock: &dyn Clock, start: SystemTime, expected: Duration) -> Duration {
+    clock
+        .wall()
+        .duration_since(start)
+        .unwrap_or_default()
+        .saturating_sub(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fake_clock_skips_sleep() {
+        let clock = FakeClock::default();
+        let start = clock.now();
+        let real = Instant::now();
+        clock.sleep(Duration::from_secs(3600)).await;
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+        assert!(real.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn sleep_or_recv_stops_before_sleeping() {
+        let clock = FakeClock::default();
+        let start = clock.now();
+        let (tx, mut rx) = mpsc::channel(1);
+        assert!(!sleep_or_recv(&clock, Duration::from_secs(5), &mut rx).await);
+        tx.send(()).await.unwrap();
+        assert!(sleep_or_recv(&clock, Duration::from_secs(5), &mut rx).await);
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+        drop(tx);
+        assert!(sleep_or_recv(&clock, Duration::from_secs(5), &mut rx).await);
+    }
+}
@@ -0,0 +1,455 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Cloud instance metadata from EC2 (IMDSv2), GCE and Azure metadata services.
+//!
+//! The `cloud` collector reports instance type, tags and lifecycle in heartbeat.
+//! Termination notices (EC2 spot interruption, GCE preemption, Azure scheduled
+//! `Preempt`/`Terminate` events) are polled separately: client then sends `terminating`
+//! with remaining seconds and unregisters, so server can tell planned disappearance from failure.
+
+use crate::collector::Collector;
+use crate::configparser::config::Cloud;
+use crate::error::Error;
+use crate::normalize::Unit;
+use async_trait::async_trait;
+use log::{debug, warn};
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::fmt::Formatter;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub const COLLECTOR_NAME: &str = "cloud";
+pub const TERMINATING_EVENT: &str = "terminating";
+pub const UNREGISTER_EVENT: &str = "unregister";
+pub const DEFAULT_POLL_INTERVAL: u64 = 5;
+const REQUEST_TIMEOUT: u64 = 2;
+const AWS_ENDPOINT: &str = "http://169.254.169.254";
+const GCP_ENDPOINT: &str = "http://metadata.google.internal";
+const AZURE_ENDPOINT: &str = "http://169.254.169.254";
+const AWS_TOKEN_TTL: &str = "21600";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Provider {
+    Aws,
+    Gcp,
+    Azure,
+}
+
+impl Provider {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "aws" | "ec2" => Ok(Provider::Aws),
+            "gcp" | "gce" => Ok(Provider::Gcp),
+            "azure" => Ok(Provider::Azure),
+            _ => Err(Error::Configure {
+                field: "collector.cloud.provider",
+                message: format!("Unsupported cloud provider {}", name),
+            }
+            .into()),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Provider::Aws => "aws",
+            Provider::Gcp => "gcp",
+            Provider::Azure => "azure",
+        }
+    }
+
+    fn default_endpoint(self) -> &'static str {
+        match self {
+            Provider::Aws => AWS_ENDPOINT,
+            Provider::Gcp => GCP_ENDPOINT,
+            Provider::Azure => AZURE_ENDPOINT,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Termination {
+    pub provider: String,
+    pub action: String,
+    /// Unix timestamp of termination, if announced.
+    pub time: Option<i64>,
+}
+
+impl Termination {
+    /// Body of `terminating` event.
+    pub fn event(&self) -> Value {
+        let mut body = serde_json::to_value(self).unwrap();
+        body["remaining"] = serde_json::json!(self
+            .time
+            .map(|time| (time - chrono::Utc::now().timestamp()).max(0)));
+        body
+    }
+}
+
+/// Returned after instance unregistered itself due to termination.
+#[derive(Debug)]
+pub struct TerminatingError;
+
+impl std::error::Error for TerminatingError {}
+
+impl std::fmt::Display for TerminatingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Instance is terminating")
+    }
+}
+
+pub struct Metadata {
+    client: reqwest::Client,
+    provider: Provider,
+    endpoint: String,
+}
+
+impl Metadata {
+    /// Return `None` if provider is neither configured nor detected.
+    pub fn new(cfg: &Cloud) -> anyhow::Result<Option<Self>> {
+        let provider = match &cfg.provider {
+            Some(name) => name.clone(),
+            None => match crate::virt::detect().and_then(|info| info.cloud) {
+                Some(name) => name,
+                None => return Ok(None),
+            },
+        };
+        let provider = Provider::parse(&provider)?;
+        // Metadata services are link-local, never go through proxy
+        let client = reqwest::ClientBuilder::new()
+            .no_proxy()
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+            .build()?;
+        Ok(Some(Self {
+            client,
+            provider,
+            endpoint: cfg
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| provider.default_endpoint().to_string())
+                .trim_end_matches('/')
+                .to_string(),
+        }))
+    }
+
+    /// IMDSv2 session token, `None` falls back to IMDSv1.
+    async fn aws_token(&self) -> Option<String> {
+        let resp = self
+            .client
+            .put(format!("{}/latest/api/token", self.endpoint))
+            .header("X-aws-ec2-metadata-token-ttl-seconds", AWS_TOKEN_TTL)
+            .send()
+            .await
+            .ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        resp.text().await.ok()
+    }
+
+    /// GET `path`, `None` if not found.
+    async fn get(&self, path: &str, token: Option<&str>) -> anyhow::Result<Option<String>> {
+        let mut request = self.client.get(format!("{}{}", self.endpoint, path));
+        request = match self.provider {
+            Provider::Aws => match token {
+                Some(token) => request.header("X-aws-ec2-metadata-token", token),
+                None => request,
+            },
+            Provider::Gcp => request.header("Metadata-Flavor", "Google"),
+            Provider::Azure => request.header("Metadata", "true"),
+        };
+        let resp = request.send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(Error::UnexpectedStatus {
+                url: resp.url().to_string(),
+                status: resp.status().as_u16(),
+            }
+            .into());
+        }
+        Ok(Some(resp.text().await?))
+    }
+
+    async fn aws_instance(&self) -> anyhow::Result<Value> {
+        let token = self.aws_token().await;
+        let token = token.as_deref();
+        let get = |path: &'static str| async move {
+            self.get(&format!("/latest/meta-data/{}", path), token)
+                .await
+                .map(|v| v.map(|s| s.trim().to_string()))
+        };
+        let mut tags = serde_json::Map::new();
+        // Only available if instance metadata tags are enabled
+        if let Some(keys) = get("tags/instance").await? {
+            for key in keys.lines().filter(|key| !key.is_empty()) {
+                if let Some(value) = self
+                    .get(&format!("/latest/meta-data/tags/instance/{}", key), token)
+                    .await?
+                {
+                    tags.insert(key.to_string(), Value::String(value));
+                }
+            }
+        }
+        Ok(serde_json::json!({
+            "instance_id": get("instance-id").await?,
+            "instance_type": get("instance-type").await?,
+            "zone": get("placement/availability-zone").await?,
+            "lifecycle": get("instance-life-cycle").await?,
+            "tags": tags,
+        }))
+    }
+
+    async fn gcp_instance(&self) -> anyhow::Result<Value> {
+        let get = |path: &'static str| async move {
+            self.get(&format!("/computeMetadata/v1/instance/{}", path), None)
+                .await
+        };
+        let last_segment = |v: Option<String>| v.map(|s| s.rsplit('/').next().unwrap().to_string());
+        let preemptible = get("scheduling/preemptible").await?;
+        let tags: Value = match get("tags?alt=json").await? {
+            Some(tags) => serde_json::from_str(&tags)?,
+            None => Value::Array(Vec::new()),
+        };
+        Ok(serde_json::json!({
+            "instance_id": get("id").await?,
+            "instance_type": last_segment(get("machine-type").await?),
+            "zone": last_segment(get("zone").await?),
+            "lifecycle": match preemptible.as_deref() {
+                Some("TRUE") => Some("preemptible"),
+                Some(_) => Some("normal"),
+                None => None,
+            },
+            "maintenance_event": get("maintenance-event").await?,
+            "tags": tags,
+        }))
+    }
+
+    async fn azure_instance(&self) -> anyhow::Result<Value> {
+        let compute: Value = match self
+            .get("/metadata/instance/compute?api-version=2021-02-01", None)
+            .await?
+        {
+            Some(compute) => serde_json::from_str(&compute)?,
+            None => {
+                return Err(Error::Unsupported {
+                    what: "Azure instance metadata".to_string(),
+                }
+                .into())
+            }
+        };
+        let field = |name: &str| {
+            compute
+                .get(name)
+                .and_then(Value::as_str)
+                .filter(|s| !s.is_empty())
+        };
+        let tags: serde_json::Map<String, Value> = compute
+            .get("tagsList")
+            .and_then(Value::as_array)
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| {
+                        Some((
+                            tag.get("name")?.as_str()?.to_string(),
+                            tag.get("value")?.clone(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(serde_json::json!({
+            "instance_id": field("vmId"),
+            "instance_type": field("vmSize"),
+            "region": field("location"),
+            "zone": field("zone"),
+            "lifecycle": field("priority").map(str::to_lowercase),
+            "tags": tags,
+        }))
+    }
+
+    pub async fn instance(&self) -> anyhow::Result<Value> {
+        let mut value = match self.provider {
+            Provider::Aws => self.aws_instance().await?,
+            Provider::Gcp => self.gcp_instance().await?,
+            Provider::Azure => self.azure_instance().await?,
+        };
+        value["provider"] = Value::String(self.provider.name().to_string());
+        Ok(value)
+    }
+
+    /// Pending termination of this instance, if any.
+    pub async fn termination(&self) -> anyhow::Result<Option<Termination>> {
+        let notice = |action: &str, time: Option<i64>| Termination {
+            provider: self.provider.name().to_string(),
+            action: action.to_string(),
+            time,
+        };
+        match self.provider {
+            Provider::Aws => {
+                let token = self.aws_token().await;
+                let action = match self
+                    .get("/latest/meta-data/spot/instance-action", token.as_deref())
+                    .await?
+                {
+                    Some(action) => action,
+                    None => return Ok(None),
+                };
+                let action: Value = serde_json::from_str(&action)?;
+                let time = action
+                    .get("time")
+                    .and_then(Value::as_str)
+                    .and_then(|time| chrono::DateTime::parse_from_rfc3339(time).ok())
+                    .map(|time| time.timestamp());
+                Ok(Some(notice(
+                    action
+                        .get("action")
+                        .and_then(Value::as_str)
+                        .unwrap_or("terminate"),
+                    time,
+                )))
+            }
+            Provider::Gcp => {
+                let preempted = self
+                    .get("/computeMetadata/v1/instance/preempted", None)
+                    .await?;
+                Ok(match preempted.as_deref().map(str::trim) {
+                    Some("TRUE") => Some(notice("preempt", None)),
+                    _ => None,
+                })
+            }
+            Provider::Azure => {
+                let events: Value = match self
+                    .get("/metadata/scheduledevents?api-version=2020-07-01", None)
+                    .await?
+                {
+                    Some(events) => serde_json::from_str(&events)?,
+                    None => return Ok(None),
+                };
+                let event = events
+                    .get("Events")
+                    .and_then(Value::as_array)
+                    .and_then(|events| {
+                        events.iter().find(|event| {
+                            matches!(
+                                event.get("EventType").and_then(Value::as_str),
+                                Some("Preempt") | Some("Terminate")
+                            )
+                        })
+                    });
+                Ok(event.map(|event| {
+                    let time = event
+                        .get("NotBefore")
+                        .and_then(Value::as_str)
+                        .and_then(|time| chrono::DateTime::parse_from_rfc2822(time).ok())
+                        .map(|time| time.timestamp());
+                    notice(&event["EventType"].as_str().unwrap().to_lowercase(), time)
+                }))
+            }
+        }
+    }
+}
+
+pub struct CloudCollector {
+    metadata: Metadata,
+}
+
+impl CloudCollector {
+    pub fn new(metadata: Metadata) -> Self {
+        Self { metadata }
+    }
+}
+
+#[async_trait]
+impl Collector for CloudCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let mut value = self.metadata.instance().await?;
+        value["termination"] = serde_json::to_value(self.metadata.termination().await?)?;
+        Ok(value)
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![("termination.time".to_string(), Unit::UnixTimestamp)]
+    }
+
+    fn source(&self) -> String {
+        format!("{} instance metadata", self.metadata.provider.name())
+    }
+}
+
+/// Termination notice observed by background poller.
+pub struct TerminationWatch {
+    notice: Mutex<Option<Termination>>,
+    notify: Notify,
+}
+
+impl TerminationWatch {
+    /// Wait until termination is announced, return the notice once.
+    pub async fn notified(&self) -> Termination {
+        loop {
+            if let Some(notice) = self.notice.lock().unwrap().take() {
+                return notice;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Poll termination notice in background if `[collector.cloud]` is enabled.
+pub fn spawn(cfg: &Cloud) -> anyhow::Result<Option<Arc<TerminationWatch>>> {
+    if !cfg.enabled {
+        return Ok(None);
+    }
+    let metadata = match Metadata::new(cfg)? {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    let poll_interval = cfg.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+    let watch = Arc::new(TerminationWatch {
+        notice: Default::default(),
+        notify: Notify::new(),
+    });
+    let watcher = watch.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(poll_interval));
+        loop {
+            interval.tick().await;
+            match metadata.termination().await {
+                Ok(Some(notice)) => {
+                    warn!("Instance termination announced: {:?}", notice);
+                    *watcher.notice.lock().unwrap() = Some(notice);
+                    watcher.notify.notify_one();
+                    // Notice stays until the instance is gone, report it only once
+                    break;
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Unable query termination notice: {}", e),
+            }
+        }
+    });
+    Ok(Some(watch))
+}
@@ -0,0 +1,327 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::configparser::config::{Configure, Script};
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use log::error;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of additional metrics, reported under `collectors` in heartbeat.
+#[async_trait]
+pub trait Collector: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn collect(&self) -> anyhow::Result<Value>;
+
+    /// Unit of fields in collected value, keyed by dotted path.
+    fn units(&self) -> Vec<(String, Unit)> {
+        Vec::new()
+    }
+
+    /// Where the value comes from, shown in data report.
+    fn source(&self) -> String {
+        "builtin".to_string()
+    }
+
+    /// Events noticed while collecting, sent to server on their own after next heartbeat.
+    fn events(&self) -> Vec<(String, Value)> {
+        Vec::new()
+    }
+}
+
+pub struct ScriptCollector {
+    name: String,
+    command: String,
+    args: Vec<String>,
+    limits: Limits,
+    units: Vec<(String, Unit)>,
+}
+
+impl From<&Script> for ScriptCollector {
+    fn from(script: &Script) -> Self {
+        Self {
+            name: script.name.clone(),
+            command: script.command.clone(),
+            args: script.args.clone().unwrap_or_default(),
+            limits: Limits {
+                timeout: script.timeout,
+                cpu_seconds: script.cpu_seconds,
+                memory_mb: script.memory_mb,
+                seccomp: script.seccomp.unwrap_or(crate::sandbox::DEFAULT_SECCOMP),
+                max_output: None,
+            },
+            units: script
+                .units
+                .as_ref()
+                .map(|units| units.iter().map(|(k, v)| (k.clone(), *v)).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Collector for ScriptCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let output = sandbox::run(&self.command, &self.args, &self.limits).await?;
+        let output = output.trim();
+        Ok(serde_json::from_str(output).unwrap_or_else(|_| Value::String(output.to_string())))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        self.units.clone()
+    }
+
+    fn source(&self) -> String {
+        format!("script: {}", self.command)
+    }
+}
+
+#[derive(Default)]
+pub struct Registry {
+    collectors: Vec<Box<dyn Collector>>,
+    /// Seconds to reuse value of expensive collectors, from `[collector.cache]`
+    ttls: HashMap<String, Duration>,
+    /// Last successful value of cached collectors and when it was collected
+    cache: Mutex<HashMap<String, (Instant, Value)>>,
+}
+
+impl Registry {
+    pub fn new(cfg: &Configure) -> Self {
+        let mut registry = Self::default();
+        if let Some(scripts) = cfg.collector.as_ref().and_then(|c| c.script.as_ref()) {
+            for script in scripts {
+                registry.register(Box::new(ScriptCollector::from(script)));
+            }
+        }
+        if let Some(cloud) = cfg
+            .collector
+            .as_ref()
+            .and_then(|c| c.cloud.as_ref())
+            .filter(|c| c.enabled)
+        {
+            match crate::cloud::Metadata::new(cloud) {
+                Ok(Some(metadata)) => {
+                    registry.register(Box::new(crate::cloud::CloudCollector::new(metadata)))
+                }
+                Ok(None) => log::warn!("No cloud provider detected, cloud collector ignored"),
+                Err(e) => error!("Unable initialize cloud collector: {}", e),
+            }
+        }
+        #[cfg(feature = "talkers")]
+        if let Some(talkers) = cfg
+            .collector
+            .as_ref()
+            .and_then(|c| c.talkers.as_ref())
+            .filter(|c| c.enabled)
+        {
+            match crate::talkers::TalkersCollector::new(talkers) {
+                Ok(collector) => registry.register(Box::new(collector)),
+                Err(e) => error!("Unable initialize talkers collector: {}", e),
+            }
+        }
+        #[cfg(not(feature = "talkers"))]
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.talkers.as_ref().is_some_and(|t| t.enabled))
+        {
+            log::warn!("Built without talkers feature, talkers collector ignored");
+        }
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.tcp.as_ref().is_some_and(|t| t.enabled))
+        {
+            #[cfg(all(feature = "ebpf", target_os = "linux"))]
+            match crate::ebpf::TcpCollector::new() {
+                Ok(collector) => registry.register(Box::new(collector)),
+                Err(e) => error!("Unable initialize tcp collector: {}", e),
+            }
+            #[cfg(not(all(feature = "ebpf", target_os = "linux")))]
+            log::warn!("tcp collector requires Linux and ebpf feature, ignored");
+        }
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.packages.as_ref().is_some_and(|p| p.enabled))
+        {
+            registry.register(Box::new(crate::packages::PackageCollector));
+            registry.ttls.insert(
+                crate::packages::COLLECTOR_NAME.to_string(),
+                crate::packages::DEFAULT_INTERVAL,
+            );
+        }
+        #[cfg(target_os = "linux")]
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.reboot.as_ref().is_some_and(|r| r.enabled))
+        {
+            registry.register(Box::new(crate::reboot::RebootCollector::default()));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.reboot.as_ref().is_some_and(|r| r.enabled))
+        {
+            log::warn!("Reboot collector is only supported on Linux, ignored");
+        }
+        #[cfg(any(target_os = "linux", windows))]
+        if let Some(firewall) = cfg
+            .collector
+            .as_ref()
+            .and_then(|c| c.firewall.as_ref())
+            .filter(|f| f.enabled)
+        {
+            registry.register(Box::new(crate::firewall::FirewallCollector::new(firewall)));
+        }
+        #[cfg(not(any(target_os = "linux", windows)))]
+        if cfg
+            .collector
+            .as_ref()
+            .is_some_and(|c| c.firewall.as_ref().is_some_and(|f| f.enabled))
+        {
+            log::warn!("Firewall collector is only supported on Linux and Windows, ignored");
+        }
+        if let Some(files) = cfg.watch.as_ref().and_then(|w| w.file.as_ref()) {
+            registry.register(Box::new(crate::integrity::IntegrityCollector::new(files)));
+        }
+        #[cfg(windows)]
+        if let Some(eventlog) = cfg.watch.as_ref().and_then(|w| w.eventlog.as_ref()) {
+            registry.register(Box::new(crate::eventlog::EventLogCollector::new(eventlog)));
+        }
+        #[cfg(not(windows))]
+        if cfg.watch.as_ref().is_some_and(|w| w.eventlog.is_some()) {
+            log::warn!("Event log collector is only supported on Windows, ignored");
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(kernel) = cfg.watch.as_ref().and_then(|w| w.kernel.as_ref()) {
+            registry.register(Box::new(crate::kmsg::KernelCollector::new(kernel)));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if cfg.watch.as_ref().is_some_and(|w| w.kernel.is_some()) {
+            log::warn!("Kernel event collector is only supported on Linux, ignored");
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mac) = cfg.watch.as_ref().and_then(|w| w.mac.as_ref()) {
+            registry.register(Box::new(crate::mac::MacCollector::new(mac)));
+        }
+        #[cfg(not(target_os = "linux"))]
+        if cfg.watch.as_ref().is_some_and(|w| w.mac.is_some()) {
+            log::warn!("Mandatory access control collector is only supported on Linux, ignored");
+        }
+        if let Some(checks) = cfg.check.as_ref().and_then(|c| c.certificate.as_ref()) {
+            registry.register(Box::new(crate::certificate::CertificateCollector::new(
+                checks,
+            )));
+        }
+        if let Some(checks) = cfg.check.as_ref().and_then(|c| c.dns.as_ref()) {
+            registry.register(Box::new(crate::dns::DnsCollector::new(checks)));
+        }
+        if let Some(checks) = cfg.check.as_ref().and_then(|c| c.fs_latency.as_ref()) {
+            registry.register(Box::new(crate::fslatency::FsLatencyCollector::new(checks)));
+        }
+        if let Some(checks) = cfg.check.as_ref().and_then(|c| c.peer.as_ref()) {
+            registry.register(Box::new(crate::peer::PeerCollector::new(checks)));
+        }
+        #[cfg(feature = "plugins")]
+        for plugin in crate::plugin::discover(crate::state::path(crate::plugin::DEFAULT_PLUGIN_DIR))
+        {
+            registry.register(Box::new(plugin));
+        }
+        if let Some(cache) = cfg.collector.as_ref().and_then(|c| c.cache.as_ref()) {
+            for (name, ttl) in cache {
+                if !registry.collectors.iter().any(|c| c.name() == name) {
+                    log::warn!("Cache of unknown collector {} ignored", name);
+                    continue;
+                }
+                registry
+                    .ttls
+                    .insert(name.clone(), Duration::from_secs(*ttl));
+            }
+        }
+        registry
+    }
+
+    pub fn register(&mut self, collector: Box<dyn Collector>) {
+        self.collectors.push(collector);
+    }
+
+    pub fn units(&self) -> Vec<(String, Vec<(String, Unit)>)> {
+        self.collectors
+            .iter()
+            .map(|c| (c.name().to_string(), c.units()))
+            .collect()
+    }
+
+    pub fn sources(&self) -> Vec<(String, String)> {
+        self.collectors
+            .iter()
+            .map(|c| (c.name().to_string(), c.source()))
+            .collect()
+    }
+
+    /// Drain pending events of every collector, as action and body.
+    pub fn events(&self) -> Vec<(String, Value)> {
+        self.collectors.iter().flat_map(|c| c.events()).collect()
+    }
+
+    pub async fn collect(&self) -> HashMap<String, Value> {
+        let mut result: HashMap<String, Value> = Default::default();
+        for collector in &self.collectors {
+            let ttl = self.ttls.get(collector.name());
+            if let Some(ttl) = ttl {
+                if let Some((collected, value)) = self.cache.lock().unwrap().get(collector.name()) {
+                    if collected.elapsed() < *ttl {
+                        result.insert(collector.name().to_string(), value.clone());
+                        continue;
+                    }
+                }
+            }
+            let value = match collector.collect().await {
+                Ok(value) => {
+                    if ttl.is_some() {
+                        self.cache.lock().unwrap().insert(
+                            collector.name().to_string(),
+                            (Instant::now(), value.clone()),
+                        );
+                    }
+                    value
+                }
+                Err(e) => {
+                    error!("Got error in collector {}: {}", collector.name(), e);
+                    serde_json::json!({ "error": e.to_string() })
+                }
+            };
+            result.insert(collector.name().to_string(), value);
+        }
+        result
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! A/B server comparison in `[compare]`, a debug mode validating a new server implementation
+//! against the current one before cutover.
+//!
+//! Every request body sent to current server is sent as is to candidate server as well, concurrently.
+//! Responses are compared on HTTP status, `status`, requested actions and rejected sections,
+//! divergences are logged. Only the response of current server is acted on.
+
+use crate::configparser::config::Configure;
+use log::{info, warn};
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+/// HTTP status and body of candidate response.
+type Outcome = anyhow::Result<(u16, Value)>;
+
+/// Pending candidate response of a request, kept in extensions of current server response.
+pub struct Pending {
+    action: String,
+    handle: JoinHandle<Outcome>,
+}
+
+pub struct Mirror {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Mirror {
+    pub fn new(cfg: &Configure) -> anyhow::Result<Option<Self>> {
+        let compare = match &cfg.compare {
+            Some(compare) => compare,
+            None => return Ok(None),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!(
+                "Bearer {}",
+                compare.token.as_ref().unwrap_or(&cfg.server.token)
+            )
+            .parse()?,
+        );
+        let client = crate::session::client_builder(cfg)?
+            .default_headers(headers)
+            .build()?;
+        warn!(
+            "A/B comparison enabled, requests are also sent to {}",
+            compare.server_address
+        );
+        Ok(Some(Self {
+            client,
+            url: compare.server_address.clone(),
+        }))
+    }
+
+    /// Send request body to candidate server in background.
+    pub fn send(&self, body: bytes::Bytes) -> Pending {
+        let action = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|envelope| envelope["action"].as_str().map(str::to_string))
+            .unwrap_or_default();
+        let request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        let handle = tokio::spawn(async move {
+            let response = request.send().await?;
+            let status = response.status().as_u16();
+            let bytes = response.bytes().await?;
+            Ok((
+                status,
+                serde_json::from_slice(&bytes)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).to_string())),
+            ))
+        });
+        Pending { action, handle }
+    }
+}
+
+/// Names of requested actions, in order.
+fn actions(body: &Value) -> Vec<&str> {
+    body["actions"]
+        .as_array()
+        .map(|actions| {
+            actions
+                .iter()
+                .filter_map(|a| a["action"].as_str())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Wait for candidate response and log how it differs from current server response.
+pub async fn compare(pending: Pending, status: u16, body: &Value) {
+    let (candidate_status, candidate) = match pending.handle.await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => {
+            warn!("A/B {}: candidate server failed: {}", pending.action, e);
+            return;
+        }
+        Err(e) => {
+            warn!("A/B {}: candidate request aborted: {}", pending.action, e);
+            return;
+        }
+    };
+    let mut divergences = Vec::new();
+    if status != candidate_status {
+        divergences.push(format!("HTTP status {} vs {}", status, candidate_status));
+    }
+    if body["status"] != candidate["status"] {
+        divergences.push(format!(
+            "status {} vs {}",
+            body["status"], candidate["status"]
+        ));
+    }
+    if actions(body) != actions(&candidate) {
+        divergences.push(format!(
+            "actions {:?} vs {:?}",
+            actions(body),
+            actions(&candidate)
+        ));
+    }
+    if body["rejected"] != candidate["rejected"] {
+        divergences.push(format!(
+            "rejected {} vs {}",
+            body["rejected"], candidate["rejected"]
+        ));
+    }
+    if divergences.is_empty() {
+        info!("A/B {}: responses match", pending.action);
+    } else {
+        warn!(
+            "A/B {}: responses diverge: {}",
+            pending.action,
+            divergences.join(", ")
+        );
+    }
+}
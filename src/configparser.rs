@@ -20,13 +20,42 @@
 
 pub(crate) mod config {
 
+    use crate::normalize::Unit;
+    use crate::privacy::Privacy;
     use serde_derive::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap};
 
     #[derive(Serialize, Deserialize)]
     pub struct Configure {
         pub server: RemoteServer,
         pub statistics: Statistics,
         pub identification: Option<Identification>,
+        pub collector: Option<Collectors>,
+        pub policy: Option<Policy>,
+        pub derived: Option<HashMap<String, String>>,
+        pub relay: Option<Relay>,
+        pub tunnel: Option<Tunnel>,
+        pub tor: Option<Tor>,
+        pub signing: Option<Signing>,
+        pub anomaly: Option<Anomaly>,
+        pub forecast: Option<Forecast>,
+        pub outbound: Option<Outbound>,
+        pub watch: Option<Watch>,
+        pub check: Option<Check>,
+        pub action: Option<Actions>,
+        pub remote_access: Option<RemoteAccess>,
+        pub authz: Option<Authz>,
+        pub audit: Option<Audit>,
+        pub privilege: Option<Privilege>,
+        pub hardening: Option<Hardening>,
+        pub fallback: Option<Fallback>,
+        pub alerting: Option<Alerting>,
+        pub dns_cache: Option<DnsCache>,
+        pub pressure: Option<Pressure>,
+        pub scheduling: Option<Scheduling>,
+        pub compare: Option<Compare>,
+        pub dashboard: Option<Dashboard>,
+        pub blackout: Option<Vec<Blackout>>,
     }
 
     #[derive(Serialize, Deserialize)]
@@ -35,12 +64,53 @@ pub(crate) mod config {
         pub token: String,
         pub backup_servers: Option<Vec<String>>,
         pub interval: Option<u32>,
+        pub ping_interval: Option<u64>,
         pub check_server_version: Option<bool>,
+        pub discovery_timeout: Option<u64>,
+        pub bind_address: Option<String>,
+        pub bind_interface: Option<String>,
+        pub sni_hostname: Option<String>,
+        pub host_header: Option<String>,
+        pub roaming: Option<bool>,
+        pub canary: Option<bool>,
+        pub degraded: Option<bool>,
+        pub degraded_interval: Option<u64>,
+        pub transport: Option<String>,
+        // Tables only below: toml can not serialize a plain value after a table
+        pub alternates: Option<HashMap<String, Vec<String>>>,
+        pub sections: Option<HashMap<String, Vec<String>>>,
+        pub pool: Option<Pool>,
+        pub mtls: Option<Mtls>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Mtls {
+        pub certificate: Option<String>,
+        pub key: Option<String>,
+        pub enroll: Option<bool>,
+        pub renew_before: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Pool {
+        pub max_idle_per_host: Option<usize>,
+        pub idle_timeout: Option<u64>,
     }
 
     #[derive(Serialize, Deserialize)]
     pub struct Statistics {
         pub enabled: bool,
+        pub backlog_size: Option<usize>,
+        pub backfill_window: Option<u64>,
+        pub privacy: Option<Privacy>,
+        pub spool: Option<Spool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Spool {
+        pub path: Option<String>,
+        pub max_size: Option<u64>,
+        pub max_age: Option<u64>,
     }
 
     #[derive(Clone, Serialize, Deserialize)]
@@ -48,9 +118,1276 @@ pub(crate) mod config {
         pub token: String,
     }
 
+    #[derive(Serialize, Deserialize)]
+    pub struct Collectors {
+        pub script: Option<Vec<Script>>,
+        pub cloud: Option<Cloud>,
+        pub talkers: Option<Talkers>,
+        pub tcp: Option<Tcp>,
+        pub firewall: Option<Firewall>,
+        pub reboot: Option<Reboot>,
+        pub packages: Option<Packages>,
+        pub cache: Option<HashMap<String, u64>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Tcp {
+        pub enabled: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Packages {
+        pub enabled: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Reboot {
+        pub enabled: bool,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Firewall {
+        pub enabled: bool,
+        pub expected: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Talkers {
+        pub enabled: bool,
+        pub interface: Option<String>,
+        pub window: Option<u64>,
+        pub top: Option<usize>,
+        pub asn_database: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Cloud {
+        pub enabled: bool,
+        pub provider: Option<String>,
+        pub endpoint: Option<String>,
+        pub poll_interval: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Script {
+        pub name: String,
+        pub command: String,
+        pub args: Option<Vec<String>>,
+        pub timeout: Option<u64>,
+        pub cpu_seconds: Option<u64>,
+        pub memory_mb: Option<u64>,
+        pub seccomp: Option<bool>,
+        pub units: Option<HashMap<String, Unit>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Policy {
+        pub module: String,
+        pub fuel: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Relay {
+        pub listen: String,
+        pub advertise: Option<bool>,
+        pub aggregate: Option<bool>,
+        pub flush_interval: Option<u64>,
+        pub max_batch: Option<usize>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct Tunnel {
+        pub host: String,
+        pub port: Option<u16>,
+        pub user: Option<String>,
+        pub identity_file: Option<String>,
+        pub known_hosts: Option<String>,
+        pub local_port: Option<u16>,
+        pub ssh_command: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Tor {
+        pub socks: Option<String>,
+        pub timeout: Option<u64>,
+        pub connect_timeout: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Signing {
+        pub key: Option<String>,
+        pub tpm: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Anomaly {
+        pub alpha: Option<f64>,
+        pub threshold: Option<f64>,
+        pub warmup: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Forecast {
+        pub history_file: Option<String>,
+        pub sample_interval: Option<i64>,
+        pub min_samples: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Outbound {
+        pub allow: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Watch {
+        pub file: Option<Vec<WatchFile>>,
+        pub eventlog: Option<WatchEventLog>,
+        pub kernel: Option<WatchKernel>,
+        pub mac: Option<WatchMac>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct WatchFile {
+        pub path: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct WatchEventLog {
+        pub logs: Option<Vec<String>>,
+        pub warnings: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct WatchKernel {
+        pub errors: Option<bool>,
+        pub max_events: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct WatchMac {
+        pub audit_log: Option<String>,
+        pub max_subjects: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Check {
+        pub certificate: Option<Vec<CertificateCheck>>,
+        pub dns: Option<Vec<DnsCheck>>,
+        pub fs_latency: Option<Vec<FsLatencyCheck>>,
+        pub peer: Option<Vec<PeerCheck>>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct CertificateCheck {
+        pub file: Option<String>,
+        pub endpoint: Option<String>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct DnsCheck {
+        pub name: String,
+        pub record_type: Option<String>,
+        pub expected: Option<Vec<String>>,
+        pub resolver: Option<String>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct FsLatencyCheck {
+        pub path: String,
+        pub size: Option<usize>,
+        pub timeout: Option<u64>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct PeerCheck {
+        pub name: Option<String>,
+        pub address: String,
+        pub count: Option<u32>,
+        pub timeout: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Actions {
+        pub wake: Option<WakeAction>,
+        pub exec: Option<ExecAction>,
+        pub fetch_file: Option<FetchFileAction>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct WakeAction {
+        pub enabled: bool,
+        pub broadcast: Option<String>,
+        pub allowed_macs: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct ExecAction {
+        pub enabled: bool,
+        pub command: Option<Vec<AllowedCommand>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct AllowedCommand {
+        pub alias: String,
+        pub command: String,
+        pub args: Option<Vec<String>>,
+        pub timeout: Option<u64>,
+        pub cpu_seconds: Option<u64>,
+        pub memory_mb: Option<u64>,
+        pub seccomp: Option<bool>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct FetchFileAction {
+        pub enabled: bool,
+        pub allow: Vec<String>,
+        pub max_size: Option<u64>,
+        pub redact: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RemoteAccess {
+        pub enabled: bool,
+        pub server_key: String,
+        pub host: String,
+        pub port: Option<u16>,
+        pub user: Option<String>,
+        pub identity_file: Option<String>,
+        pub known_hosts: Option<String>,
+        pub ssh_command: Option<String>,
+        pub forward_port: Option<u16>,
+        pub max_duration: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Authz {
+        pub operator_keys: Vec<String>,
+        pub required: Option<usize>,
+        pub actions: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Audit {
+        pub path: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Privilege {
+        pub user: String,
+        pub group: Option<String>,
+        pub keep_capabilities: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Hardening {
+        pub enabled: bool,
+        pub allow_read: Option<Vec<String>>,
+        pub allow_write: Option<Vec<String>>,
+        pub allow_execute: Option<Vec<String>>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Fallback {
+        pub after: Option<u64>,
+        pub recovery: Option<bool>,
+        pub message: Option<String>,
+        pub webhook: Option<Webhook>,
+        pub command: Option<NotifyCommand>,
+        pub smtp: Option<Smtp>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct Webhook {
+        pub url: String,
+        pub body: Option<String>,
+        pub headers: Option<HashMap<String, String>>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct Smtp {
+        pub host: String,
+        pub port: Option<u16>,
+        pub tls: Option<SmtpTls>,
+        pub username: Option<String>,
+        pub password: Option<String>,
+        pub from: String,
+        pub to: Vec<String>,
+        pub subject: Option<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SmtpTls {
+        /// Upgrade plain connection with STARTTLS (port 587)
+        #[default]
+        Starttls,
+        /// TLS from the start (port 465)
+        Tls,
+        /// Unencrypted, only for local relay
+        None,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct NotifyCommand {
+        pub command: String,
+        pub args: Option<Vec<String>>,
+        pub timeout: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Alerting {
+        pub webhook: Option<Webhook>,
+        pub smtp: Option<Smtp>,
+        pub rule: Option<Vec<AlertRule>>,
+        pub limit: Option<RateLimit>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RateLimit {
+        pub burst: Option<u32>,
+        pub per_minute: Option<f64>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct AlertRule {
+        pub name: String,
+        pub metric: String,
+        pub above: Option<f64>,
+        pub below: Option<f64>,
+        pub message: Option<String>,
+    }
+
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct DnsCache {
+        pub enabled: Option<bool>,
+        pub resolver: Option<String>,
+        pub min_ttl: Option<u64>,
+        pub max_ttl: Option<u64>,
+        pub negative_ttl: Option<u64>,
+        pub stale: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Pressure {
+        pub enabled: bool,
+        pub memory: Option<f64>,
+        pub cpu: Option<f64>,
+        pub load: Option<f64>,
+        pub timeout: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Scheduling {
+        pub nice: Option<i32>,
+        pub io_class: Option<String>,
+        pub io_priority: Option<i32>,
+        pub cpu_weight: Option<u32>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Compare {
+        pub server_address: String,
+        pub token: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Dashboard {
+        pub listen: Option<String>,
+        pub history: Option<usize>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct Blackout {
+        pub name: Option<String>,
+        pub cron: Option<String>,
+        pub rrule: Option<String>,
+        pub duration: u64,
+        pub mode: Option<String>,
+        pub utc: Option<bool>,
+    }
+
     #[derive(Serialize, Deserialize)]
     pub struct RegisterData {
         pub hostname: String,
         pub boot_time: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub address: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub public_key: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub public_key_algorithm: Option<String>,
+        pub units: BTreeMap<String, Unit>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub virtualization: Option<crate::virt::VirtInfo>,
+        pub capabilities: crate::session::capabilities::Capabilities,
+        /// Site-specific facts of `facts.toml` and `facts.d/`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub facts: Option<serde_json::Map<String, serde_json::Value>>,
+    }
+}
+
+/// One-line description and default of every configure option, keyed by dotted path (`*` for
+/// keys chosen by user). Defaults come from the constants the client falls back to, so
+/// `config init` shows what a missing option actually means.
+pub(crate) mod fields {
+
+    pub enum Default {
+        Value(toml::Value),
+        /// File (or directory) of this name in state directory
+        StateFile(&'static str),
+    }
+
+    pub struct Field {
+        pub path: &'static str,
+        pub description: &'static str,
+        pub default: Option<Default>,
+    }
+
+    fn field(path: &'static str, description: &'static str) -> Field {
+        Field {
+            path,
+            description,
+            default: None,
+        }
+    }
+
+    fn value(
+        path: &'static str,
+        description: &'static str,
+        default: impl Into<toml::Value>,
+    ) -> Field {
+        Field {
+            path,
+            description,
+            default: Some(Default::Value(default.into())),
+        }
+    }
+
+    fn state_file(path: &'static str, description: &'static str, file: &'static str) -> Field {
+        Field {
+            path,
+            description,
+            default: Some(Default::StateFile(file)),
+        }
+    }
+
+    fn serialized<T: serde::Serialize>(value: T) -> Option<Default> {
+        toml::Value::try_from(value).ok().map(Default::Value)
+    }
+
+    pub fn find(path: &str) -> Option<Field> {
+        all().into_iter().find(|field| field.path == path)
+    }
+
+    pub fn all() -> Vec<Field> {
+        use super::config::SmtpTls;
+        use crate::privacy::Privacy;
+        vec![
+            field(
+                "server.server_address",
+                "Probe server address, `auto` to discover via mDNS",
+            ),
+            field("server.token", "Authorization token"),
+            field(
+                "server.backup_servers",
+                "Servers tried in turn once the current one fails",
+            ),
+            value(
+                "server.interval",
+                "Seconds between heartbeats",
+                crate::session::DEFAULT_INTERVAL,
+            ),
+            field(
+                "server.ping_interval",
+                "Seconds between lightweight pings between heartbeats, disabled if unset",
+            ),
+            value(
+                "server.check_server_version",
+                "Record server version reported in registration",
+                crate::session::DEFAULT_CHECK_SERVER_VERSION,
+            ),
+            Field {
+                path: "server.discovery_timeout",
+                description: "Seconds to wait for mDNS discovery of `auto` server address",
+                #[cfg(feature = "mdns")]
+                default: Some(Default::Value(
+                    (crate::discovery::DEFAULT_DISCOVERY_TIMEOUT as i64).into(),
+                )),
+                #[cfg(not(feature = "mdns"))]
+                default: None,
+            },
+            field(
+                "server.bind_address",
+                "Source address of requests, not with bind_interface",
+            ),
+            field(
+                "server.bind_interface",
+                "Source interface of requests, not with bind_address",
+            ),
+            field(
+                "server.sni_hostname",
+                "Name presented in TLS handshake instead of server host",
+            ),
+            field("server.host_header", "Host header instead of server host"),
+            value(
+                "server.roaming",
+                "Register again once default route or primary address changed",
+                crate::session::DEFAULT_ROAMING,
+            ),
+            value(
+                "server.canary",
+                "Opt into experimental payload changes offered by server",
+                crate::session::DEFAULT_CANARY,
+            ),
+            value(
+                "server.degraded",
+                "Keep retrying registration once every server failed, instead of exiting",
+                crate::session::DEFAULT_DEGRADED,
+            ),
+            value(
+                "server.degraded_interval",
+                "Seconds between registration attempts in degraded mode",
+                crate::session::DEFAULT_DEGRADED_INTERVAL as i64,
+            ),
+            value(
+                "server.transport",
+                "https or websocket",
+                crate::websocket::DEFAULT_TRANSPORT,
+            ),
+            field(
+                "server.alternates.*",
+                "Alternate URLs of this server, tried before failing over",
+            ),
+            field(
+                "server.sections.*",
+                "Dotted payload sections this server receives",
+            ),
+            field(
+                "server.pool.max_idle_per_host",
+                "Idle connections kept per server, unlimited if unset",
+            ),
+            field(
+                "server.pool.idle_timeout",
+                "Seconds before an idle connection is closed",
+            ),
+            state_file(
+                "server.mtls.certificate",
+                "PEM client certificate chain",
+                crate::mtls::CERTIFICATE_FILE,
+            ),
+            state_file(
+                "server.mtls.key",
+                "PKCS#8 client key",
+                crate::mtls::KEY_FILE,
+            ),
+            value(
+                "server.mtls.enroll",
+                "Obtain and renew client certificate from server",
+                crate::mtls::DEFAULT_ENROLL,
+            ),
+            value(
+                "server.mtls.renew_before",
+                "Days before expiry to renew enrolled certificate",
+                crate::mtls::DEFAULT_RENEW_BEFORE as i64,
+            ),
+            field("statistics.enabled", "Report statistics in each heartbeat"),
+            value(
+                "statistics.backlog_size",
+                "Heartbeats kept during outage",
+                crate::downsample::DEFAULT_BACKLOG_SIZE as i64,
+            ),
+            value(
+                "statistics.backfill_window",
+                "Seconds summarized per backfill window",
+                crate::downsample::DEFAULT_WINDOW as i64,
+            ),
+            Field {
+                path: "statistics.privacy",
+                description: "Infrastructure details removed from payload",
+                default: serialized(Privacy::default()),
+            },
+            state_file(
+                "statistics.spool.path",
+                "Directory keeping missed heartbeats across restarts",
+                crate::spool::DEFAULT_SPOOL_DIR,
+            ),
+            value(
+                "statistics.spool.max_size",
+                "MiB of spool before oldest heartbeats are dropped",
+                crate::spool::DEFAULT_MAX_SIZE as i64,
+            ),
+            value(
+                "statistics.spool.max_age",
+                "Seconds a spooled heartbeat is kept",
+                crate::spool::DEFAULT_MAX_AGE as i64,
+            ),
+            field(
+                "identification.token",
+                "Client UUID, generated on first run",
+            ),
+            field("collector.script.name", "Name reported under `collectors`"),
+            field("collector.script.command", "Executable to run"),
+            field("collector.script.args", "Arguments of command"),
+            value(
+                "collector.script.timeout",
+                "Wall-clock seconds before script is killed",
+                crate::sandbox::DEFAULT_TIMEOUT as i64,
+            ),
+            field(
+                "collector.script.cpu_seconds",
+                "CPU time limit in seconds (Unix)",
+            ),
+            field("collector.script.memory_mb", "Memory limit in MiB (Unix)"),
+            value(
+                "collector.script.seccomp",
+                "Deny dangerous syscalls (Linux)",
+                crate::sandbox::DEFAULT_SECCOMP,
+            ),
+            field(
+                "collector.script.units.*",
+                "Unit of output field, normalized before sending",
+            ),
+            field(
+                "collector.cloud.enabled",
+                "Report cloud metadata and watch for termination notices",
+            ),
+            field(
+                "collector.cloud.provider",
+                "aws, gcp or azure, detected if unset",
+            ),
+            field(
+                "collector.cloud.endpoint",
+                "Metadata service address, default of provider if unset",
+            ),
+            value(
+                "collector.cloud.poll_interval",
+                "Seconds between termination notice checks",
+                crate::cloud::DEFAULT_POLL_INTERVAL as i64,
+            ),
+            field(
+                "collector.talkers.enabled",
+                "Report top outgoing destinations (Linux, CAP_NET_RAW)",
+            ),
+            field(
+                "collector.talkers.interface",
+                "Capture on this interface only, all if unset",
+            ),
+            Field {
+                path: "collector.talkers.window",
+                description: "Seconds of traffic sampled each heartbeat",
+                #[cfg(feature = "talkers")]
+                default: Some(Default::Value(
+                    (crate::talkers::DEFAULT_WINDOW as i64).into(),
+                )),
+                #[cfg(not(feature = "talkers"))]
+                default: None,
+            },
+            Field {
+                path: "collector.talkers.top",
+                description: "Destinations reported",
+                #[cfg(feature = "talkers")]
+                default: Some(Default::Value((crate::talkers::DEFAULT_TOP as i64).into())),
+                #[cfg(not(feature = "talkers"))]
+                default: None,
+            },
+            field(
+                "collector.talkers.asn_database",
+                "ip2asn TSV to group destinations by AS number",
+            ),
+            field(
+                "collector.tcp.enabled",
+                "Trace TCP retransmits and connect latency with eBPF (Linux)",
+            ),
+            field(
+                "collector.firewall.enabled",
+                "Report firewall rules and policies (Linux, Windows)",
+            ),
+            Field {
+                path: "collector.firewall.expected",
+                description: "Whether firewall is expected to be enabled on this host",
+                #[cfg(any(target_os = "linux", windows))]
+                default: Some(Default::Value(crate::firewall::DEFAULT_EXPECTED.into())),
+                #[cfg(not(any(target_os = "linux", windows)))]
+                default: None,
+            },
+            field(
+                "collector.reboot.enabled",
+                "Report whether a reboot is required (Linux)",
+            ),
+            field(
+                "collector.packages.enabled",
+                "Report package count and list hash (Unix)",
+            ),
+            field(
+                "collector.cache.*",
+                "Seconds to reuse last value of this collector",
+            ),
+            field("policy.module", "WebAssembly policy module"),
+            Field {
+                path: "policy.fuel",
+                description: "Instruction budget of each evaluation",
+                #[cfg(feature = "policy")]
+                default: Some(Default::Value((crate::policy::DEFAULT_FUEL as i64).into())),
+                #[cfg(not(feature = "policy"))]
+                default: None,
+            },
+            field("derived.*", "Expression of computed metric"),
+            field(
+                "relay.listen",
+                "Address to accept heartbeats of other probes on",
+            ),
+            Field {
+                path: "relay.advertise",
+                description: "Advertise relay as `_probe._tcp` via mDNS",
+                #[cfg(feature = "relay")]
+                default: Some(Default::Value(crate::relay::DEFAULT_ADVERTISE.into())),
+                #[cfg(not(feature = "relay"))]
+                default: None,
+            },
+            Field {
+                path: "relay.aggregate",
+                description: "Answer heartbeats locally and upload them in batches",
+                #[cfg(feature = "relay")]
+                default: Some(Default::Value(crate::relay::DEFAULT_AGGREGATE.into())),
+                #[cfg(not(feature = "relay"))]
+                default: None,
+            },
+            Field {
+                path: "relay.flush_interval",
+                description: "Seconds between aggregated uploads",
+                #[cfg(feature = "relay")]
+                default: Some(Default::Value(
+                    (crate::relay::DEFAULT_FLUSH_INTERVAL as i64).into(),
+                )),
+                #[cfg(not(feature = "relay"))]
+                default: None,
+            },
+            Field {
+                path: "relay.max_batch",
+                description: "Pending heartbeats kept for aggregated upload",
+                #[cfg(feature = "relay")]
+                default: Some(Default::Value(
+                    (crate::relay::DEFAULT_MAX_BATCH as i64).into(),
+                )),
+                #[cfg(not(feature = "relay"))]
+                default: None,
+            },
+            field("tunnel.host", "SSH jump host"),
+            value(
+                "tunnel.port",
+                "SSH port of jump host",
+                crate::tunnel::DEFAULT_PORT as i64,
+            ),
+            field("tunnel.user", "SSH user, default of ssh if unset"),
+            field("tunnel.identity_file", "SSH private key"),
+            field("tunnel.known_hosts", "SSH known hosts file"),
+            value(
+                "tunnel.local_port",
+                "Local SOCKS port",
+                crate::tunnel::DEFAULT_LOCAL_PORT as i64,
+            ),
+            value(
+                "tunnel.ssh_command",
+                "SSH executable",
+                crate::tunnel::DEFAULT_SSH_COMMAND,
+            ),
+            value(
+                "tor.socks",
+                "Tor SOCKS proxy address",
+                crate::tor::DEFAULT_SOCKS,
+            ),
+            value(
+                "tor.timeout",
+                "Request timeout in seconds",
+                crate::tor::DEFAULT_TIMEOUT as i64,
+            ),
+            value(
+                "tor.connect_timeout",
+                "Connect timeout in seconds",
+                crate::tor::DEFAULT_CONNECT_TIMEOUT as i64,
+            ),
+            state_file(
+                "signing.key",
+                "PKCS#8 signing key",
+                crate::signing::DEFAULT_KEY_FILE,
+            ),
+            value(
+                "signing.tpm",
+                "Keep signing key in TPM 2.0 when present",
+                crate::signing::DEFAULT_TPM,
+            ),
+            value(
+                "anomaly.alpha",
+                "EWMA smoothing factor",
+                crate::anomaly::DEFAULT_ALPHA,
+            ),
+            value(
+                "anomaly.threshold",
+                "Standard deviations from baseline flagged as anomaly",
+                crate::anomaly::DEFAULT_THRESHOLD,
+            ),
+            value(
+                "anomaly.warmup",
+                "Heartbeats to learn before flagging",
+                crate::anomaly::DEFAULT_WARMUP as i64,
+            ),
+            state_file(
+                "forecast.history_file",
+                "Disk usage history",
+                crate::forecast::DEFAULT_HISTORY_FILE,
+            ),
+            value(
+                "forecast.sample_interval",
+                "Seconds between recorded samples",
+                crate::forecast::DEFAULT_SAMPLE_INTERVAL,
+            ),
+            value(
+                "forecast.min_samples",
+                "Samples required before estimating",
+                crate::forecast::DEFAULT_MIN_SAMPLES as i64,
+            ),
+            field(
+                "outbound.allow",
+                "Hosts, wildcards and CIDR ranges the client may connect to",
+            ),
+            field("watch.file.path", "File hashed each heartbeat"),
+            Field {
+                path: "watch.eventlog.logs",
+                description: "Windows event logs counted (Windows)",
+                #[cfg(windows)]
+                default: Some(Default::Value(
+                    crate::eventlog::DEFAULT_LOGS.to_vec().into(),
+                )),
+                #[cfg(not(windows))]
+                default: None,
+            },
+            Field {
+                path: "watch.eventlog.warnings",
+                description: "Also count warnings",
+                #[cfg(windows)]
+                default: Some(Default::Value(crate::eventlog::DEFAULT_WARNINGS.into())),
+                #[cfg(not(windows))]
+                default: None,
+            },
+            Field {
+                path: "watch.kernel.errors",
+                description: "Also report any other kernel message at error level",
+                #[cfg(target_os = "linux")]
+                default: Some(Default::Value(crate::kmsg::DEFAULT_ERRORS.into())),
+                #[cfg(not(target_os = "linux"))]
+                default: None,
+            },
+            Field {
+                path: "watch.kernel.max_events",
+                description: "Distinct kernel events reported (Linux)",
+                #[cfg(target_os = "linux")]
+                default: Some(Default::Value(
+                    (crate::kmsg::DEFAULT_MAX_EVENTS as i64).into(),
+                )),
+                #[cfg(not(target_os = "linux"))]
+                default: None,
+            },
+            Field {
+                path: "watch.mac.audit_log",
+                description: "Audit log read for SELinux and AppArmor denials (Linux)",
+                #[cfg(target_os = "linux")]
+                default: Some(Default::Value(crate::mac::DEFAULT_AUDIT_LOG.into())),
+                #[cfg(not(target_os = "linux"))]
+                default: None,
+            },
+            Field {
+                path: "watch.mac.max_subjects",
+                description: "Denying subjects reported (Linux)",
+                #[cfg(target_os = "linux")]
+                default: Some(Default::Value(
+                    (crate::mac::DEFAULT_MAX_SUBJECTS as i64).into(),
+                )),
+                #[cfg(not(target_os = "linux"))]
+                default: None,
+            },
+            field(
+                "check.certificate.file",
+                "PEM certificate file to check expiry of",
+            ),
+            field(
+                "check.certificate.endpoint",
+                "TLS endpoint (host:port) to check expiry of",
+            ),
+            field("check.dns.name", "Name to resolve"),
+            value(
+                "check.dns.record_type",
+                "A, AAAA, CNAME, MX, NS or TXT",
+                crate::dns::DEFAULT_RECORD_TYPE,
+            ),
+            field("check.dns.expected", "Answers expected"),
+            field(
+                "check.dns.resolver",
+                "Resolver address, first nameserver in /etc/resolv.conf if unset",
+            ),
+            field("check.fs_latency.path", "Directory to write probe file in"),
+            value(
+                "check.fs_latency.size",
+                "Bytes written",
+                crate::fslatency::DEFAULT_SIZE as i64,
+            ),
+            value(
+                "check.fs_latency.timeout",
+                "Seconds before probe is reported as failed",
+                crate::fslatency::DEFAULT_TIMEOUT as i64,
+            ),
+            field(
+                "check.peer.name",
+                "Name reported under `collectors.peers`, address if unset",
+            ),
+            field("check.peer.address", "Host and port of peer"),
+            value(
+                "check.peer.count",
+                "Connection attempts per heartbeat",
+                crate::peer::DEFAULT_COUNT,
+            ),
+            value(
+                "check.peer.timeout",
+                "Seconds before an attempt is counted as lost",
+                crate::peer::DEFAULT_TIMEOUT as i64,
+            ),
+            field(
+                "action.wake.enabled",
+                "Allow server to send Wake-on-LAN packets",
+            ),
+            value(
+                "action.wake.broadcast",
+                "Broadcast address of magic packets",
+                crate::wake::DEFAULT_BROADCAST,
+            ),
+            field(
+                "action.wake.allowed_macs",
+                "Only these MACs can be woken, any if unset",
+            ),
+            field(
+                "action.exec.enabled",
+                "Allow server to run allow-listed commands",
+            ),
+            field(
+                "action.exec.command.alias",
+                "Name server refers to command by",
+            ),
+            field("action.exec.command.command", "Executable to run"),
+            field("action.exec.command.args", "Arguments of command"),
+            value(
+                "action.exec.command.timeout",
+                "Wall-clock seconds before command is killed",
+                crate::sandbox::DEFAULT_TIMEOUT as i64,
+            ),
+            field(
+                "action.exec.command.cpu_seconds",
+                "CPU time limit in seconds (Unix)",
+            ),
+            field(
+                "action.exec.command.memory_mb",
+                "Memory limit in MiB (Unix)",
+            ),
+            value(
+                "action.exec.command.seccomp",
+                "Deny dangerous syscalls (Linux)",
+                crate::sandbox::DEFAULT_SECCOMP,
+            ),
+            field(
+                "action.fetch_file.enabled",
+                "Allow server to fetch allow-listed files",
+            ),
+            field(
+                "action.fetch_file.allow",
+                "Globs matched against resolved path",
+            ),
+            value(
+                "action.fetch_file.max_size",
+                "Only the last N bytes are sent",
+                crate::fetch::DEFAULT_MAX_SIZE as i64,
+            ),
+            field(
+                "action.fetch_file.redact",
+                "Regular expressions replaced with `[REDACTED]`",
+            ),
+            field(
+                "remote_access.enabled",
+                "Allow operator to open a reverse SSH tunnel",
+            ),
+            field(
+                "remote_access.server_key",
+                "Base64 Ed25519 public key of operator",
+            ),
+            field("remote_access.host", "SSH jump host"),
+            value(
+                "remote_access.port",
+                "SSH port of jump host",
+                crate::tunnel::DEFAULT_PORT as i64,
+            ),
+            field("remote_access.user", "SSH user, default of ssh if unset"),
+            field("remote_access.identity_file", "SSH private key"),
+            field("remote_access.known_hosts", "SSH known hosts file"),
+            value(
+                "remote_access.ssh_command",
+                "SSH executable",
+                crate::tunnel::DEFAULT_SSH_COMMAND,
+            ),
+            value(
+                "remote_access.forward_port",
+                "Local port exposed through tunnel",
+                crate::remote_access::DEFAULT_FORWARD_PORT as i64,
+            ),
+            value(
+                "remote_access.max_duration",
+                "Seconds before tunnel is closed",
+                crate::remote_access::DEFAULT_MAX_DURATION as i64,
+            ),
+            field(
+                "authz.operator_keys",
+                "Base64 Ed25519 public keys of operators",
+            ),
+            value(
+                "authz.required",
+                "Operator approvals required",
+                crate::session::authz::DEFAULT_REQUIRED as i64,
+            ),
+            field(
+                "authz.actions",
+                "More actions requiring approvals, on top of dangerous ones",
+            ),
+            state_file(
+                "audit.path",
+                "Audit log of server actions",
+                crate::audit::DEFAULT_AUDIT_FILE,
+            ),
+            field("privilege.user", "User to run as after setup"),
+            field(
+                "privilege.group",
+                "Group to run as, primary group of user if unset",
+            ),
+            field(
+                "privilege.keep_capabilities",
+                "Capabilities kept after dropping privileges (Linux)",
+            ),
+            field(
+                "hardening.enabled",
+                "Restrict filesystem access with Landlock (Linux)",
+            ),
+            field("hardening.allow_read", "Extra paths readable"),
+            field("hardening.allow_write", "Extra paths writable"),
+            field("hardening.allow_execute", "Extra executables allowed"),
+            value(
+                "fallback.after",
+                "Minutes without successful report before notifying",
+                crate::fallback::DEFAULT_AFTER as i64,
+            ),
+            value(
+                "fallback.recovery",
+                "Notify when reporting works again",
+                crate::fallback::DEFAULT_RECOVERY,
+            ),
+            value(
+                "fallback.message",
+                "Template rendered with state, hostname, server and minutes",
+                crate::fallback::DEFAULT_MESSAGE,
+            ),
+            field("fallback.webhook.url", "Webhook URL"),
+            field(
+                "fallback.webhook.body",
+                "JSON body template, Slack and Discord compatible if unset",
+            ),
+            field("fallback.webhook.headers.*", "Extra HTTP header"),
+            field("fallback.command.command", "Executable to run with message"),
+            field(
+                "fallback.command.args",
+                "Arguments of command, before message",
+            ),
+            value(
+                "fallback.command.timeout",
+                "Seconds before command is killed",
+                crate::sandbox::DEFAULT_TIMEOUT as i64,
+            ),
+            field("fallback.smtp.host", "SMTP server"),
+            field(
+                "fallback.smtp.port",
+                "SMTP port, 587 with starttls, 465 with tls, 25 with none if unset",
+            ),
+            Field {
+                path: "fallback.smtp.tls",
+                description: "Transport security",
+                default: serialized(SmtpTls::default()),
+            },
+            field("fallback.smtp.username", "SMTP user"),
+            field("fallback.smtp.password", "SMTP password"),
+            field("fallback.smtp.from", "Sender address"),
+            field("fallback.smtp.to", "Recipient addresses"),
+            Field {
+                path: "fallback.smtp.subject",
+                description: "Subject template rendered with message and hostname",
+                #[cfg(feature = "smtp")]
+                default: Some(Default::Value(crate::notify::DEFAULT_SUBJECT.into())),
+                #[cfg(not(feature = "smtp"))]
+                default: None,
+            },
+            field("alerting.webhook.url", "Webhook URL"),
+            field(
+                "alerting.webhook.body",
+                "JSON body template, Slack and Discord compatible if unset",
+            ),
+            field("alerting.webhook.headers.*", "Extra HTTP header"),
+            field("alerting.smtp.host", "SMTP server"),
+            field(
+                "alerting.smtp.port",
+                "SMTP port, 587 with starttls, 465 with tls, 25 with none if unset",
+            ),
+            Field {
+                path: "alerting.smtp.tls",
+                description: "Transport security",
+                default: serialized(SmtpTls::default()),
+            },
+            field("alerting.smtp.username", "SMTP user"),
+            field("alerting.smtp.password", "SMTP password"),
+            field("alerting.smtp.from", "Sender address"),
+            field("alerting.smtp.to", "Recipient addresses"),
+            Field {
+                path: "alerting.smtp.subject",
+                description: "Subject template rendered with message and hostname",
+                #[cfg(feature = "smtp")]
+                default: Some(Default::Value(crate::notify::DEFAULT_SUBJECT.into())),
+                #[cfg(not(feature = "smtp"))]
+                default: None,
+            },
+            field("alerting.rule.name", "Rule name reported in message"),
+            field(
+                "alerting.rule.metric",
+                "Dotted metric path, `*` matches any key",
+            ),
+            field("alerting.rule.above", "Fire while value is above"),
+            field("alerting.rule.below", "Fire while value is below"),
+            value(
+                "alerting.rule.message",
+                "Template rendered with state, hostname, rule, metric, value, condition",
+                crate::alert::DEFAULT_MESSAGE,
+            ),
+            value(
+                "alerting.limit.burst",
+                "Messages sent at once",
+                crate::ratelimit::DEFAULT_BURST,
+            ),
+            value(
+                "alerting.limit.per_minute",
+                "Messages refilled per minute",
+                crate::ratelimit::DEFAULT_PER_MINUTE,
+            ),
+            value(
+                "dns_cache.enabled",
+                "Resolve server addresses through internal cache",
+                crate::resolver::DEFAULT_ENABLED,
+            ),
+            field(
+                "dns_cache.resolver",
+                "Resolver address, first nameserver in /etc/resolv.conf if unset",
+            ),
+            value(
+                "dns_cache.min_ttl",
+                "Lower bound of answer TTL in seconds",
+                crate::resolver::DEFAULT_MIN_TTL as i64,
+            ),
+            value(
+                "dns_cache.max_ttl",
+                "Upper bound of answer TTL in seconds",
+                crate::resolver::DEFAULT_MAX_TTL as i64,
+            ),
+            value(
+                "dns_cache.negative_ttl",
+                "Seconds to cache names without address",
+                crate::resolver::DEFAULT_NEGATIVE_TTL as i64,
+            ),
+            value(
+                "dns_cache.stale",
+                "Seconds an expired answer may be used when resolver fails",
+                crate::resolver::DEFAULT_STALE as i64,
+            ),
+            field(
+                "pressure.enabled",
+                "Back off heartbeats while host is under severe pressure",
+            ),
+            value(
+                "pressure.memory",
+                "Memory stall share threshold in percent (Linux)",
+                crate::pressure::DEFAULT_MEMORY,
+            ),
+            value(
+                "pressure.cpu",
+                "CPU stall share threshold in percent (Linux)",
+                crate::pressure::DEFAULT_CPU,
+            ),
+            value(
+                "pressure.load",
+                "1 minute load average per CPU threshold",
+                crate::pressure::DEFAULT_LOAD,
+            ),
+            value(
+                "pressure.timeout",
+                "Request timeout in seconds under pressure",
+                crate::pressure::DEFAULT_TIMEOUT as i64,
+            ),
+            field("scheduling.nice", "Nice level, -20..19 (Unix)"),
+            value(
+                "scheduling.io_class",
+                "I/O scheduling class, realtime, best-effort or idle (Linux)",
+                crate::scheduling::DEFAULT_IO_CLASS,
+            ),
+            value(
+                "scheduling.io_priority",
+                "I/O priority, 0..7 (Linux)",
+                crate::scheduling::DEFAULT_IO_PRIORITY,
+            ),
+            field(
+                "scheduling.cpu_weight",
+                "CPU weight of own cgroup v2, 1..10000 (Linux)",
+            ),
+            field(
+                "compare.server_address",
+                "Second server receiving copies of heartbeats",
+            ),
+            field(
+                "compare.token",
+                "Authorization token, token of [server] if unset",
+            ),
+            Field {
+                path: "dashboard.listen",
+                description: "Address of local dashboard",
+                #[cfg(feature = "dashboard")]
+                default: Some(Default::Value(crate::dashboard::DEFAULT_LISTEN.into())),
+                #[cfg(not(feature = "dashboard"))]
+                default: None,
+            },
+            Field {
+                path: "dashboard.history",
+                description: "Heartbeats kept in history",
+                #[cfg(feature = "dashboard")]
+                default: Some(Default::Value(
+                    (crate::dashboard::DEFAULT_HISTORY as i64).into(),
+                )),
+                #[cfg(not(feature = "dashboard"))]
+                default: None,
+            },
+            field(
+                "blackout.name",
+                "Name reported in `maintenance`, blackout0, blackout1, ... if unset",
+            ),
+            field("blackout.cron", "Cron expression of window starts"),
+            field("blackout.rrule", "RFC 5545 RRULE of window starts"),
+            field("blackout.duration", "Seconds each window lasts"),
+            value(
+                "blackout.mode",
+                "tag or suppress heartbeats within window",
+                crate::blackout::DEFAULT_MODE,
+            ),
+            value(
+                "blackout.utc",
+                "Start times are in UTC instead of local time",
+                crate::blackout::DEFAULT_UTC,
+            ),
+        ]
     }
 }
@@ -0,0 +1,103 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `test` subcommand: one connectivity check against configured server, registration followed
+//! by a keepalive, without starting heartbeats or running any requested action.
+//!
+//! With `--output json` it prints `{"server", "ok", "steps": [{"step", "ok", "elapsed_ms",
+//! "error"}]}`: `step` is `register` or `ping`, `error` as described in Error codes.
+//! Exits with error if any step failed.
+
+use crate::session::{Overrides, Session};
+use serde_json::Value;
+use std::time::Instant;
+
+pub const SUBCOMMAND_NAME: &str = "test";
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Check connectivity to configured server once (register and ping)")
+        .arg(crate::output::arg())
+}
+
+struct Step {
+    name: &'static str,
+    elapsed: std::time::Duration,
+    result: anyhow::Result<()>,
+}
+
+impl Step {
+    fn to_json(&self) -> Value {
+        serde_json::json!({
+            "step": self.name,
+            "ok": self.result.is_ok(),
+            "elapsed_ms": self.elapsed.as_millis() as u64,
+            "error": self.result.as_ref().err().map(crate::error::structured),
+        })
+    }
+}
+
+async fn step<F: std::future::Future<Output = anyhow::Result<()>>>(
+    name: &'static str,
+    future: F,
+) -> Step {
+    let start = Instant::now();
+    let result = future.await;
+    Step {
+        name,
+        elapsed: start.elapsed(),
+        result,
+    }
+}
+
+pub async fn run(
+    matches: &clap::ArgMatches<'_>,
+    cfg: &str,
+    overrides: &Overrides,
+) -> anyhow::Result<()> {
+    let mut session = Session::new(cfg, overrides).await?;
+    let server = session.call_next().cloned();
+    let mut steps = vec![step("register", async { session.register().await.map(|_| ()) }).await];
+    if steps[0].result.is_ok() {
+        steps.push(step("ping", session.send_ping()).await);
+    }
+    let failed = steps.iter().position(|step| step.result.is_err());
+    if crate::output::of(matches) == crate::output::Format::Json {
+        crate::output::print_json(&serde_json::json!({
+            "server": server,
+            "ok": failed.is_none(),
+            "steps": steps.iter().map(Step::to_json).collect::<Vec<_>>(),
+        }))?;
+    } else {
+        println!("server: {}", server.as_deref().unwrap_or_default());
+        for step in &steps {
+            println!(
+                "{}: {} ({} ms)",
+                step.name,
+                if step.result.is_ok() { "ok" } else { "failed" },
+                step.elapsed.as_millis()
+            );
+        }
+    }
+    match failed {
+        Some(index) => Err(steps.swap_remove(index).result.unwrap_err()),
+        None => Ok(()),
+    }
+}
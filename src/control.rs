@@ -0,0 +1,179 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Local control socket `probe_control.sock` in state directory, for operators on the host.
+//!
+//! One command per connection: write a line, read the reply until the connection is closed,
+//! e.g. `probe-client control dns flush`. Socket is only accessible by owner. Only supported on Unix.
+//!
+//! Commands:
+//! - `dns flush`: drop every entry of DNS cache (`[dns_cache]`)
+//! - `reload`: read `[server]` of configure again and register, same as `SIGHUP`
+//! - `annotate <text>`: attach note to next heartbeat (see [`crate::annotate`])
+//! - `status`: state of running client as JSON (see [`crate::status`])
+
+use log::{info, warn};
+
+pub const CONTROL_SOCKET: &str = "probe_control.sock";
+pub const SUBCOMMAND_NAME: &str = "control";
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Send command to running client over control socket")
+        .arg(
+            clap::Arg::with_name("command")
+                .help("Command and its arguments, e.g. `dns flush`")
+                .required(true)
+                .multiple(true),
+        )
+        .arg(crate::output::arg())
+}
+
+fn handle(line: &str) -> String {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        ["dns", "flush"] => {
+            let count = crate::resolver::flush();
+            info!("Flushed {} DNS cache entries by control command", count);
+            format!("ok: flushed {} entries", count)
+        }
+        [crate::annotate::CONTROL_COMMAND, _, ..] => {
+            let text = line[crate::annotate::CONTROL_COMMAND.len()..].trim();
+            info!("Annotate next heartbeat by control command: {}", text);
+            crate::annotate::add(text);
+            "ok: annotation queued for next heartbeat".to_string()
+        }
+        [crate::status::CONTROL_COMMAND] => crate::status::reply(),
+        ["reload"] => {
+            info!("Reload configure by control command");
+            crate::reload::request();
+            "ok: reload requested".to_string()
+        }
+        _ => format!("error: unknown command {:?}", line),
+    }
+}
+
+/// Listen on control socket in background, replace stale socket left by previous process.
+#[cfg(unix)]
+pub fn spawn() -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+    use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _};
+
+    let path = crate::state::path(CONTROL_SOCKET);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Unable accept control connection: {:?}", e);
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut line = String::new();
+                if let Err(e) = stream.read_line(&mut line).await {
+                    warn!("Unable read control command: {:?}", e);
+                    return;
+                }
+                let reply = handle(line.trim());
+                let stream = stream.get_mut();
+                if let Err(e) = stream.write_all(format!("{}\n", reply).as_bytes()).await {
+                    warn!("Unable send control reply: {:?}", e);
+                }
+                stream.shutdown().await.ok();
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn() -> anyhow::Result<()> {
+    Ok(())
+}
+
+pub async fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    let command = matches
+        .values_of("command")
+        .unwrap()
+        .collect::<Vec<_>>()
+        .join(" ");
+    send(matches, &command).await
+}
+
+/// Send `command` to running client and return its reply.
+#[cfg(unix)]
+pub async fn request(command: &str) -> anyhow::Result<String> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let path = crate::state::path(CONTROL_SOCKET);
+    let mut stream = tokio::net::UnixStream::connect(&path)
+        .await
+        .map_err(|source| crate::error::Error::Io {
+            operation: "connect",
+            path: path.display().to_string(),
+            source,
+        })?;
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+#[cfg(not(unix))]
+pub async fn request(_command: &str) -> anyhow::Result<String> {
+    Err(crate::error::Error::Unavailable {
+        feature: "control",
+        message: "Control socket is only supported on Unix".to_string(),
+    }
+    .into())
+}
+
+/// Send `command` to running client, print its reply in format of `matches`.
+pub async fn send(matches: &clap::ArgMatches<'_>, command: &str) -> anyhow::Result<()> {
+    let reply = request(command).await?;
+    match crate::output::of(matches) {
+        crate::output::Format::Text => print!("{}", reply),
+        crate::output::Format::Json => crate::output::print_json(&serde_json::json!({
+            "command": command,
+            "ok": !reply.starts_with("error"),
+            "reply": reply.trim_end(),
+        }))?,
+    }
+    if reply.starts_with("error") {
+        return Err(crate::error::Error::Command {
+            program: command.to_string(),
+            message: reply.trim_end().to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
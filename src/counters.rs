@@ -0,0 +1,178 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Cumulative client counters kept across restarts in `probe_counters.bin` of state directory.
+//!
+//! The file is memory-mapped and updated in place without fsync, the kernel writes pages back
+//! on its own, so counting on every heartbeat costs nothing more than an atomic add.
+//! Counters survive restarts and crashes (not power loss). In read-only mode and on
+//! non-Unix platforms they are kept in memory only.
+
+use log::warn;
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const COUNTERS_FILE: &str = "probe_counters.bin";
+
+const MAGIC: u64 = u64::from_le_bytes(*b"PRBCNT01");
+/// Magic header followed by counters.
+const SLOTS: usize = 6;
+
+#[derive(Clone, Copy)]
+pub enum Counter {
+    BytesSent = 1,
+    HeartbeatsSent = 2,
+    Failovers = 3,
+    ConnectionsNew = 4,
+    ConnectionsReused = 5,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub bytes_sent: u64,
+    pub heartbeats_sent: u64,
+    pub failovers: u64,
+    pub connections_new: u64,
+    pub connections_reused: u64,
+}
+
+enum Storage {
+    #[cfg(unix)]
+    Mapped(*mut libc::c_void),
+    Memory(Box<[AtomicU64; SLOTS]>),
+}
+
+pub struct Counters {
+    storage: Storage,
+}
+
+// Mapped memory is only accessed through atomics.
+unsafe impl Send for Counters {}
+unsafe impl Sync for Counters {}
+
+#[cfg(unix)]
+fn map(path: &std::path::Path) -> std::io::Result<*mut libc::c_void> {
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // Counters survive restart
+        .truncate(false)
+        .open(path)?;
+    let len = SLOTS * std::mem::size_of::<u64>();
+    let current = file.metadata()?.len();
+    if current != len as u64 {
+        // Counters added by newer version are appended, keep existing ones
+        if current > len as u64 || current < std::mem::size_of::<u64>() as u64 {
+            file.set_len(0)?;
+        }
+        file.set_len(len as u64)?;
+    }
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ptr)
+}
+
+impl Counters {
+    pub fn open() -> Self {
+        let counters = Self {
+            storage: Self::storage(),
+        };
+        let slots = counters.slots();
+        if slots[0].load(Ordering::Relaxed) != MAGIC {
+            for slot in slots.iter() {
+                slot.store(0, Ordering::Relaxed);
+            }
+            slots[0].store(MAGIC, Ordering::Relaxed);
+        }
+        counters
+    }
+
+    #[cfg(unix)]
+    fn storage() -> Storage {
+        if !crate::state::is_read_only() {
+            let path = crate::state::path(COUNTERS_FILE);
+            match map(&path) {
+                Ok(ptr) => return Storage::Mapped(ptr),
+                Err(e) => warn!(
+                    "Unable map counters file {}, keep counters in memory: {:?}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Storage::Memory(Default::default())
+    }
+
+    #[cfg(not(unix))]
+    fn storage() -> Storage {
+        Storage::Memory(Default::default())
+    }
+
+    fn slots(&self) -> &[AtomicU64; SLOTS] {
+        match &self.storage {
+            #[cfg(unix)]
+            Storage::Mapped(ptr) => unsafe { &*(*ptr as *const [AtomicU64; SLOTS]) },
+            Storage::Memory(slots) => slots,
+        }
+    }
+
+    pub fn add(&self, counter: Counter, value: u64) {
+        self.slots()[counter as usize].fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let slots = self.slots();
+        let get = |counter: Counter| slots[counter as usize].load(Ordering::Relaxed);
+        Snapshot {
+            bytes_sent: get(Counter::BytesSent),
+            heartbeats_sent: get(Counter::HeartbeatsSent),
+            failovers: get(Counter::Failovers),
+            connections_new: get(Counter::ConnectionsNew),
+            connections_reused: get(Counter::ConnectionsReused),
+        }
+    }
+}
+
+impl Drop for Counters {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Storage::Mapped(ptr) = self.storage {
+            unsafe {
+                libc::munmap(ptr, SLOTS * std::mem::size_of::<u64>());
+            }
+        }
+    }
+}
@@ -0,0 +1,343 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Optional local web dashboard in `[dashboard]` (requires `--features dashboard`): live collected
+//! metrics, last heartbeat status and recent history, so small deployments get value even
+//! without a central server.
+//!
+//! History of recent heartbeats is kept in `probe_dashboard_history.json` of state directory.
+//! There is no authentication, so it should only listen on loopback address. Requests naming
+//! any other host than listen address or `localhost` are refused, so a page of another site can
+//! not read it through DNS rebinding.
+
+use crate::configparser::config::Configure;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{error, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:9101";
+pub const DEFAULT_HISTORY: usize = 360;
+const HISTORY_FILE: &str = "probe_dashboard_history.json";
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>probe-client</title>
+<style>
+body { font-family: sans-serif; margin: 2em; }
+.ok { color: green; } .failed { color: red; }
+table { border-collapse: collapse; } td { padding: 2px 12px 2px 0; font-family: monospace; }
+svg { border: 1px solid #ccc; }
+</style>
+</head>
+<body>
+<h1>probe-client <span id="version"></span></h1>
+<p>Last heartbeat: <span id="last">none yet</span></p>
+<h2>History</h2>
+<p>CPU % (blue), memory % (orange), load per CPU &times; 100 (grey)</p>
+<svg id="history" width="720" height="120" viewBox="0 0 720 100" preserveAspectRatio="none"></svg>
+<h2>Metrics</h2>
+<table id="metrics"></table>
+<script>
+function flatten(prefix, value, rows) {
+  if (value !== null && typeof value === "object") {
+    for (const key of Object.keys(value)) flatten(prefix ? prefix + "." + key : key, value[key], rows);
+  } else {
+    rows.push([prefix, value]);
+  }
+  return rows;
+}
+function line(points, key, scale, color) {
+  const step = 720 / Math.max(points.length - 1, 1);
+  const coords = points.map((p, i) => typeof p[key] !== "number" ? null : (i * step) + "," + (100 - Math.min(p[key] * scale, 100)))
+    .filter(c => c !== null).join(" ");
+  return `<polyline fill="none" stroke="${color}" stroke-width="1.5" points="${coords}"/>`;
+}
+function cell(text) {
+  const td = document.createElement("td");
+  td.textContent = String(text);
+  return td;
+}
+async function refresh() {
+  const status = await (await fetch("api/status")).json();
+  document.getElementById("version").textContent = status.version;
+  const last = status.last;
+  const lastNode = document.getElementById("last");
+  if (last === null) {
+    lastNode.textContent = "none yet";
+  } else {
+    const state = document.createElement("span");
+    state.className = last.ok ? "ok" : "failed";
+    state.textContent = last.ok ? "ok" : "failed: " + last.error;
+    lastNode.replaceChildren(state, " at " + new Date(last.timestamp * 1000).toLocaleString());
+  }
+  document.getElementById("history").innerHTML = line(status.history, "cpu", 1, "steelblue") +
+    line(status.history, "memory", 1, "darkorange") + line(status.history, "load", 100, "grey");
+  document.getElementById("metrics").replaceChildren(...flatten("", status.metrics, []).map(([k, v]) => {
+    const tr = document.createElement("tr");
+    tr.append(cell(k), cell(v));
+    return tr;
+  }));
+}
+refresh();
+setInterval(refresh, 5000);
+</script>
+</body>
+</html>
+"#;
+
+/// Summary of one heartbeat kept in history.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Point {
+    pub timestamp: i64,
+    pub ok: bool,
+    pub cpu: Option<f64>,
+    pub memory: Option<f64>,
+    /// Load average per CPU
+    pub load: Option<f64>,
+}
+
+/// History saved by previous heartbeats, oldest first.
+pub fn load_history() -> VecDeque<Point> {
+    std::fs::read_to_string(crate::state::path(HISTORY_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Serialize)]
+struct Last {
+    timestamp: i64,
+    ok: bool,
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    metrics: Value,
+    last: Option<Last>,
+    history: VecDeque<Point>,
+}
+
+/// State shown by dashboard, updated by session on each heartbeat.
+pub struct Board {
+    inner: Mutex<Inner>,
+    size: usize,
+    path: PathBuf,
+}
+
+impl Board {
+    pub fn new(cfg: &Configure) -> Option<Arc<Self>> {
+        let dashboard = cfg.dashboard.as_ref()?;
+        let path = crate::state::path(HISTORY_FILE);
+        let size = dashboard.history.unwrap_or(DEFAULT_HISTORY);
+        Some(Arc::new(Self {
+            inner: Mutex::new(Inner {
+                history: load_history().into_iter().rev().take(size).rev().collect(),
+                ..Default::default()
+            }),
+            size,
+            path,
+        }))
+    }
+
+    /// Payload of heartbeat about to be sent.
+    pub fn update(&self, payload: &Value) {
+        self.inner.lock().unwrap().metrics = payload.clone();
+    }
+
+    /// Result of heartbeat, appended to history.
+    pub fn record(&self, result: &anyhow::Result<()>) {
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut inner = self.inner.lock().unwrap();
+        let metrics = &inner.metrics;
+        let memory = match (
+            metrics["memory"]["used"].as_f64(),
+            metrics["memory"]["total"].as_f64(),
+        ) {
+            (Some(used), Some(total)) if total > 0.0 => Some(used / total * 100.0),
+            _ => None,
+        };
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let point = Point {
+            timestamp,
+            ok: result.is_ok(),
+            cpu: metrics["cpu"]["idle"].as_f64().map(|idle| 100.0 - idle),
+            memory,
+            load: metrics["loadavg"]["last1"]
+                .as_f64()
+                .map(|load| load / cpus as f64),
+        };
+        inner.last = Some(Last {
+            timestamp,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        inner.history.push_back(point);
+        while inner.history.len() > self.size {
+            inner.history.pop_front();
+        }
+        if let Err(e) =
+            crate::state::write(&self.path, serde_json::to_string(&inner.history).unwrap())
+        {
+            warn!(
+                "Unable save dashboard history to {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn status(&self) -> Value {
+        let inner = self.inner.lock().unwrap();
+        serde_json::json!({
+            "version": crate::session::CLIENT_VERSION,
+            "last": inner.last,
+            "metrics": inner.metrics,
+            "history": inner.history,
+        })
+    }
+}
+
+/// Whether `host` header names the dashboard itself: listen address or `localhost`, with port
+/// of listen address if any. IP literals are accepted for wildcard listen address, those can not
+/// be rebound to another site.
+fn host_allowed(host: &str, listen: &SocketAddr) -> bool {
+    let (name, port) = if let Some(rest) = host.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((name, "")) => (name, None),
+            Some((name, port)) => match port.strip_prefix(':') {
+                Some(port) => (name, Some(port)),
+                None => return false,
+            },
+            None => return false,
+        }
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        }
+    };
+    if let Some(port) = port {
+        if port.parse::<u16>().ok() != Some(listen.port()) {
+            return false;
+        }
+    }
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip == listen.ip() || listen.ip().is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+fn handle(board: &Board, listen: &SocketAddr, req: Request<Body>) -> Response<Body> {
+    let allowed = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .is_some_and(|host| host_allowed(host, listen));
+    if !allowed {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        return resp;
+    }
+    let (content_type, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/") => ("text/html; charset=utf-8", PAGE.to_string()),
+        (&Method::GET, "/api/status") => ("application/json", board.status().to_string()),
+        _ => {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            return resp;
+        }
+    };
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn spawn(cfg: &Configure, board: Option<Arc<Board>>) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let (dashboard, board) = match (&cfg.dashboard, board) {
+        (Some(dashboard), Some(board)) => (dashboard, board),
+        _ => return Ok(None),
+    };
+    let listen: SocketAddr = dashboard
+        .listen
+        .as_deref()
+        .unwrap_or(DEFAULT_LISTEN)
+        .parse()?;
+    if !listen.ip().is_loopback() {
+        warn!(
+            "Dashboard listens on non-loopback address {} without authentication",
+            listen
+        );
+    }
+    // Bind before returning, so listener is ready before privileges are dropped
+    let builder = hyper::Server::try_bind(&listen)?;
+    info!("Dashboard listening on http://{}", listen);
+    let make_svc = make_service_fn(move |_conn| {
+        let board = board.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handle(&board, &listen, req);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    });
+    Ok(Some(tokio::spawn(async move {
+        if let Err(e) = builder.serve(make_svc).await {
+            error!("Got error in dashboard: {:?}", e);
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::host_allowed;
+
+    #[test]
+    fn only_own_host_allowed() {
+        let listen = "127.0.0.1:9101".parse().unwrap();
+        assert!(host_allowed("127.0.0.1:9101", &listen));
+        assert!(host_allowed("localhost:9101", &listen));
+        assert!(host_allowed("LOCALHOST", &listen));
+        assert!(!host_allowed("localhost:80", &listen));
+        assert!(!host_allowed("attacker.example:9101", &listen));
+        assert!(!host_allowed("127.0.0.2:9101", &listen));
+        assert!(!host_allowed("[::1]:9101", &listen));
+
+        let listen = "[::]:9101".parse().unwrap();
+        assert!(host_allowed("[::1]:9101", &listen));
+        assert!(host_allowed("192.0.2.1:9101", &listen));
+        assert!(!host_allowed("[::1]9101", &listen));
+        assert!(!host_allowed("rebind.example", &listen));
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Computed metrics from simple arithmetic expressions, e.g.
+//! `mem_pct = "memory.used / memory.total * 100"`.
+
+use crate::error::Error;
+use log::warn;
+use serde_json::Value;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Path(String),
+    Op(char),
+    LParen,
+    RParen,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Number(f64),
+    Path(Vec<String>),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Default::default();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' => {
+                chars.next();
+            }
+            '+' | '-' | '*' | '/' => {
+                tokens.push(Token::Op(c));
+                chars.next();
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                chars.next();
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                chars.next();
+            }
+            '0'..='9' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' {
+                        s.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Number(
+                    s.parse().map_err(|_| format!("Invalid number {}", s))?,
+                ));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '.' {
+                        s.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Path(s));
+            }
+            _ => return Err(format!("Unexpected character '{}' in expression", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.term()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.factor()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op != '*' && op != '/' {
+                break;
+            }
+            self.next();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.factor()?));
+        }
+        Ok(lhs)
+    }
+
+    // factor := number | path | '-' factor | '(' expr ')'
+    fn factor(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Path(p)) => Ok(Expr::Path(p.split('.').map(str::to_string).collect())),
+            Some(Token::Op('-')) => Ok(Expr::Neg(Box::new(self.factor()?))),
+            Some(Token::LParen) => {
+                let expr = self.expr()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err("Missing closing parenthesis".to_string()),
+                }
+            }
+            Some(t) => Err(format!("Unexpected token {:?}", t)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn lookup(value: &Value, path: &[String]) -> Option<f64> {
+    let mut current = value;
+    for segment in path {
+        current = match current {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(array) => array.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    match current {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+impl Expr {
+    fn parse(input: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            pos: 0,
+        };
+        let expr = parser.expr()?;
+        if parser.peek().is_some() {
+            return Err("Unexpected trailing token in expression".to_string());
+        }
+        Ok(expr)
+    }
+
+    fn eval(&self, value: &Value) -> Result<f64, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Path(path) => {
+                lookup(value, path).ok_or_else(|| format!("{} not found", path.join(".")))?
+            }
+            Expr::Neg(e) => -e.eval(value)?,
+            Expr::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.eval(value)?, rhs.eval(value)?);
+                match op {
+                    '+' => lhs + rhs,
+                    '-' => lhs - rhs,
+                    '*' => lhs * rhs,
+                    '/' => lhs / rhs,
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+pub struct Derived {
+    expressions: Vec<(String, Expr)>,
+}
+
+impl Derived {
+    pub fn new(definitions: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut expressions: Vec<(String, Expr)> = Default::default();
+        for (name, expression) in definitions {
+            let expr = Expr::parse(expression).map_err(|e| Error::Configure {
+                field: "derived",
+                message: format!("Unable parse derived metric {}: {}", name, e),
+            })?;
+            expressions.push((name.clone(), expr));
+        }
+        Ok(Self { expressions })
+    }
+
+    /// Evaluate all expressions against serialized payload, skip any failed one.
+    pub fn evaluate(&self, value: &Value) -> HashMap<String, f64> {
+        let mut result: HashMap<String, f64> = Default::default();
+        for (name, expr) in &self.expressions {
+            match expr.eval(value) {
+                Ok(v) if v.is_finite() => {
+                    result.insert(name.clone(), v);
+                }
+                Ok(v) => warn!("Derived metric {} is not finite ({})", name, v),
+                Err(e) => warn!("Unable evaluate derived metric {}: {}", name, e),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<f64, String> {
+        let payload = serde_json::json!({
+            "memory": { "used": 3, "total": 4 },
+            "disks": [{ "free": 10 }],
+            "online": true,
+        });
+        Expr::parse(expression)?.eval(&payload)
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(eval("10 - 2 - 3"), Ok(5.0));
+        assert_eq!(eval("8 / 4 / 2"), Ok(1.0));
+        assert_eq!(eval("-2 * -(1 + 2)"), Ok(6.0));
+        assert_eq!(eval("memory.used / memory.total * 100"), Ok(75.0));
+        assert_eq!(eval("disks.0.free + online"), Ok(11.0));
+    }
+
+    #[test]
+    fn unknown_metric() {
+        assert_eq!(
+            eval("memory.free * 2"),
+            Err("memory.free not found".to_string())
+        );
+        assert!(eval("memory * 2").is_err());
+    }
+
+    #[test]
+    fn divide_by_zero_skipped() {
+        assert_eq!(eval("1 / 0"), Ok(f64::INFINITY));
+        let definitions = [
+            (
+                "broken".to_string(),
+                "memory.used / (memory.total - 4)".to_string(),
+            ),
+            (
+                "pct".to_string(),
+                "memory.used / memory.total * 100".to_string(),
+            ),
+        ];
+        let derived = Derived::new(&definitions.iter().cloned().collect()).unwrap();
+        let result = derived.evaluate(&serde_json::json!({
+            "memory": { "used": 3, "total": 4 },
+        }));
+        assert_eq!(result.len(), 1);
+        assert_eq!(result["pct"], 75.0);
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(
+            Expr::parse("(1 + 2").unwrap_err(),
+            "Missing closing parenthesis"
+        );
+        assert_eq!(
+            Expr::parse("1 + 2)").unwrap_err(),
+            "Unexpected trailing token in expression"
+        );
+        assert_eq!(
+            Expr::parse("1 2").unwrap_err(),
+            "Unexpected trailing token in expression"
+        );
+        assert_eq!(
+            Expr::parse("1 +").unwrap_err(),
+            "Unexpected end of expression"
+        );
+        assert!(Expr::parse("1 % 2").is_err());
+        assert!(Expr::parse("1.2.3").is_err());
+
+        let definitions = [("bad".to_string(), "(1".to_string())];
+        assert!(Derived::new(&definitions.iter().cloned().collect()).is_err());
+    }
+}
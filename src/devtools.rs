@@ -0,0 +1,502 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+pub mod mock_server {
+    use crate::session::envelope::RequestEnvelope;
+    use crate::session::{STATUS_MAINTENANCE, STATUS_REREGISTER};
+    use crate::signing::sha256_hex;
+    use crate::websocket;
+    use hyper::header::{CONNECTION, UPGRADE};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use log::{info, warn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    pub const SUBCOMMAND_NAME: &str = "mock-server";
+
+    pub struct Options {
+        register_status: i64,
+        heartbeat_status: i64,
+        delay: u64,
+        fail_times: u32,
+        version: String,
+        actions: Option<serde_json::Value>,
+        reject: Vec<String>,
+        forget_after: Option<u32>,
+        maintenance: u32,
+        corrupt_digest: bool,
+        experiments: Vec<String>,
+        push: Option<serde_json::Value>,
+    }
+
+    impl Options {
+        fn from_matches(matches: &clap::ArgMatches) -> anyhow::Result<Self> {
+            Ok(Self {
+                register_status: matches.value_of("register_status").unwrap().parse()?,
+                heartbeat_status: matches.value_of("heartbeat_status").unwrap().parse()?,
+                delay: matches.value_of("delay").unwrap().parse()?,
+                fail_times: matches.value_of("fail_times").unwrap().parse()?,
+                version: matches
+                    .value_of("server_version")
+                    .unwrap_or(crate::session::CLIENT_VERSION)
+                    .to_string(),
+                actions: match matches.value_of("actions") {
+                    Some(actions) => Some(serde_json::from_str(actions)?),
+                    None => None,
+                },
+                reject: matches
+                    .values_of("reject")
+                    .map(|values| values.map(str::to_string).collect())
+                    .unwrap_or_default(),
+                forget_after: match matches.value_of("forget_after") {
+                    Some(heartbeats) => Some(heartbeats.parse()?),
+                    None => None,
+                },
+                maintenance: matches.value_of("maintenance").unwrap().parse()?,
+                corrupt_digest: matches.is_present("corrupt_digest"),
+                experiments: matches
+                    .values_of("experiments")
+                    .map(|values| values.map(str::to_string).collect())
+                    .unwrap_or_default(),
+                push: match matches.value_of("push") {
+                    Some(actions) => Some(serde_json::from_str(actions)?),
+                    None => None,
+                },
+            })
+        }
+    }
+
+    struct State {
+        options: Options,
+        requests: AtomicU32,
+        actions_sent: AtomicBool,
+        heartbeats: AtomicU32,
+        /// Client is forgotten and must register again
+        forgotten: AtomicBool,
+        /// Ed25519 public key of last registration, to verify request manifests
+        public_key: std::sync::Mutex<Option<String>>,
+        #[cfg(test)]
+        last_heartbeat: std::sync::Mutex<Option<serde_json::Value>>,
+    }
+
+    impl State {
+        fn new(options: Options) -> Self {
+            Self {
+                options,
+                requests: AtomicU32::new(0),
+                actions_sent: AtomicBool::new(false),
+                heartbeats: AtomicU32::new(0),
+                forgotten: AtomicBool::new(false),
+                public_key: Default::default(),
+                #[cfg(test)]
+                last_heartbeat: Default::default(),
+            }
+        }
+    }
+
+    pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+        clap::SubCommand::with_name(SUBCOMMAND_NAME)
+            .about("Run a simulated probe server for testing")
+            .arg(
+                clap::Arg::with_name("listen")
+                    .long("listen")
+                    .help("Address to listen on")
+                    .takes_value(true)
+                    .default_value("127.0.0.1:8888"),
+            )
+            .arg(
+                clap::Arg::with_name("register_status")
+                    .long("register-status")
+                    .help("Status code returned for register requests")
+                    .takes_value(true)
+                    .default_value("200"),
+            )
+            .arg(
+                clap::Arg::with_name("heartbeat_status")
+                    .long("heartbeat-status")
+                    .help("Status code returned for heartbeat requests")
+                    .takes_value(true)
+                    .default_value("200"),
+            )
+            .arg(
+                clap::Arg::with_name("delay")
+                    .long("delay")
+                    .help("Seconds to wait before each response")
+                    .takes_value(true)
+                    .default_value("0"),
+            )
+            .arg(
+                clap::Arg::with_name("fail_times")
+                    .long("fail-times")
+                    .help("Answer the first N requests with HTTP 503")
+                    .takes_value(true)
+                    .default_value("0"),
+            )
+            .arg(
+                clap::Arg::with_name("server_version")
+                    .long("server-version")
+                    .help("Version string reported in responses")
+                    .takes_value(true),
+            )
+            .arg(
+                clap::Arg::with_name("actions")
+                    .long("actions")
+                    .help("JSON array of actions requested in the first heartbeat response")
+                    .takes_value(true),
+            )
+            .arg(
+                clap::Arg::with_name("reject")
+                    .long("reject")
+                    .help("Refuse heartbeats containing these dotted payload sections with status 4022")
+                    .takes_value(true)
+                    .multiple(true)
+                    .use_delimiter(true),
+            )
+            .arg(
+                clap::Arg::with_name("forget_after")
+                    .long("forget-after")
+                    .help("Forget the client once after N heartbeats, answering status 4031 until it registers again")
+                    .takes_value(true),
+            )
+            .arg(
+                clap::Arg::with_name("maintenance")
+                    .long("maintenance")
+                    .help("Answer the first N heartbeats with status 5030, asking to retry after 1 second")
+                    .takes_value(true)
+                    .default_value("0"),
+            )
+            .arg(
+                clap::Arg::with_name("corrupt_digest")
+                    .long("corrupt-digest")
+                    .help("Echo a wrong request body digest, as if the body was altered in transit"),
+            )
+            .arg(
+                clap::Arg::with_name("experiments")
+                    .long("experiments")
+                    .help("Experiments offered to canary clients in registration response")
+                    .takes_value(true)
+                    .multiple(true)
+                    .use_delimiter(true),
+            )
+            .arg(
+                clap::Arg::with_name("push")
+                    .long("push")
+                    .help("JSON array of actions pushed to WebSocket clients a second after they connect")
+                    .takes_value(true),
+            )
+    }
+
+    fn build_response(
+        version: &str,
+        digest: &str,
+        status: i64,
+        actions: Option<&serde_json::Value>,
+        rejected: &[&String],
+        experiments: &[String],
+    ) -> Response<Body> {
+        let mut body = serde_json::json!({
+            "version": version,
+            "status": status,
+            "digest": digest,
+            "message": if status == 200 { None } else { Some(format!("mock status {}", status)) },
+        });
+        if let Some(actions) = actions {
+            body["actions"] = actions.clone();
+        }
+        if status == STATUS_MAINTENANCE {
+            body["retry_after"] = serde_json::json!(1);
+        }
+        if !rejected.is_empty() {
+            body["rejected"] = serde_json::json!(rejected);
+        }
+        if !experiments.is_empty() {
+            body["experiments"] = serde_json::json!(experiments);
+        }
+        Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    /// Answer every request message of WebSocket connection as if it was POSTed.
+    async fn serve_websocket(state: Arc<State>, upgraded: hyper::upgrade::Upgraded) {
+        let mut stream = websocket::Stream::new(upgraded, false);
+        let mut push = state.options.push.clone().map(|actions| {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                actions
+            })
+        });
+        loop {
+            let pushed = async {
+                match &mut push {
+                    Some(push) => push.await,
+                    None => std::future::pending().await,
+                }
+            };
+            let message = tokio::select! {
+                actions = pushed => {
+                    push = None;
+                    info!("Push actions to WebSocket client");
+                    let push = serde_json::json!({ "push": { "actions": actions } });
+                    match stream.send(websocket::OPCODE_TEXT, push.to_string().as_bytes()).await {
+                        Ok(()) => continue,
+                        Err(e) => Err(e),
+                    }
+                }
+                message = stream.receive() => message,
+            };
+            let result = match message {
+                Ok(websocket::Message::Data(body)) => {
+                    let req = Request::post("/").body(Body::from(body)).unwrap();
+                    let resp = handle(state.clone(), req).await.unwrap();
+                    let body = hyper::body::to_bytes(resp.into_body())
+                        .await
+                        .unwrap_or_default();
+                    stream.send(websocket::OPCODE_TEXT, &body).await
+                }
+                Ok(websocket::Message::Ping(payload)) => {
+                    stream.send(websocket::OPCODE_PONG, &payload).await
+                }
+                Ok(websocket::Message::Close) => {
+                    info!("WebSocket client closed connection");
+                    stream.close().await.ok();
+                    return;
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("WebSocket connection ended: {}", e);
+                return;
+            }
+        }
+    }
+
+    fn upgrade(state: Arc<State>, mut req: Request<Body>) -> Response<Body> {
+        let key = match req.headers().get("Sec-WebSocket-Key") {
+            Some(key) => websocket::accept_key(key.to_str().unwrap_or_default()),
+            None => {
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+                return resp;
+            }
+        };
+        tokio::spawn(async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => serve_websocket(state, upgraded).await,
+                Err(e) => warn!("Unable upgrade to WebSocket: {}", e),
+            }
+        });
+        info!("Accept WebSocket connection");
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header("Sec-WebSocket-Accept", key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        if req
+            .headers()
+            .get(UPGRADE)
+            .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"))
+        {
+            return Ok(upgrade(state, req));
+        }
+        let count = state.requests.fetch_add(1, Ordering::SeqCst);
+        if req.method() != Method::POST {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+            return Ok(resp);
+        }
+
+        let body = hyper::body::to_bytes(req.into_body())
+            .await
+            .unwrap_or_default();
+        let envelope: RequestEnvelope = match serde_json::from_slice(&body) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Unable parse request #{}: {}", count, e);
+                let mut resp = Response::new(Body::empty());
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+                return Ok(resp);
+            }
+        };
+        let action = envelope.action.as_str();
+        info!(
+            "Received #{} action: {} (seq: {}) from {}",
+            count, action, envelope.seq, envelope.uuid
+        );
+
+        if action == "register"
+            && envelope.body["public_key_algorithm"] == crate::signing::ALGORITHM
+        {
+            *state.public_key.lock().unwrap() =
+                envelope.body["public_key"].as_str().map(str::to_string);
+        }
+        let public_key = state.public_key.lock().unwrap().clone();
+        if let (Some(manifest), Some(public_key)) = (&envelope.manifest, public_key) {
+            if let Err(e) = manifest.verify(
+                &public_key,
+                action,
+                &envelope.uuid,
+                envelope.timestamp,
+                envelope.nonce,
+                &envelope.body,
+            ) {
+                warn!("Invalid manifest of request #{}: {}", count, e);
+            }
+        }
+
+        if state.options.delay > 0 {
+            tokio::time::sleep(Duration::from_secs(state.options.delay)).await;
+        }
+
+        if count < state.options.fail_times {
+            warn!("Simulate failure for request #{}", count);
+            let mut resp = Response::new(Body::from("Service Unavailable"));
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Ok(resp);
+        }
+
+        let rejected = state
+            .options
+            .reject
+            .iter()
+            .filter(|section| {
+                action == "heartbeat"
+                    && envelope
+                        .body
+                        .pointer(&format!("/{}", section.replace('.', "/")))
+                        .is_some()
+            })
+            .collect::<Vec<_>>();
+        let heartbeat = match action {
+            "heartbeat" => state.heartbeats.fetch_add(1, Ordering::SeqCst),
+            _ => u32::MAX,
+        };
+        #[cfg(test)]
+        if action == "heartbeat" {
+            *state.last_heartbeat.lock().unwrap() = Some(envelope.body.clone());
+        }
+        if action == "register" {
+            state.forgotten.store(false, Ordering::SeqCst);
+        } else if Some(heartbeat) == state.options.forget_after {
+            warn!("Forget client on request #{}", count);
+            state.forgotten.store(true, Ordering::SeqCst);
+        }
+        let status = match action {
+            "register" => state.options.register_status,
+            "heartbeat" if heartbeat < state.options.maintenance => STATUS_MAINTENANCE,
+            "heartbeat" if state.forgotten.load(Ordering::SeqCst) => STATUS_REREGISTER,
+            "heartbeat" if !rejected.is_empty() => {
+                warn!("Reject sections {:?} of request #{}", rejected, count);
+                4022
+            }
+            "heartbeat" => state.options.heartbeat_status,
+            _ => 200,
+        };
+        if action != "heartbeat" && action != "register" {
+            info!("Body of {}: {}", action, envelope.body);
+        }
+        let actions = if action == "heartbeat" && !state.actions_sent.swap(true, Ordering::SeqCst) {
+            state.options.actions.as_ref()
+        } else {
+            None
+        };
+        let mut digest = sha256_hex(&body);
+        if state.options.corrupt_digest {
+            digest = sha256_hex(digest.as_bytes());
+        }
+        Ok(build_response(
+            &state.options.version,
+            &digest,
+            status,
+            actions,
+            &rejected,
+            match action {
+                "register" if envelope.canary == Some(true) => &state.options.experiments,
+                _ => &[],
+            },
+        ))
+    }
+
+    pub async fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+        let addr: SocketAddr = matches.value_of("listen").unwrap().parse()?;
+        let state = Arc::new(State::new(Options::from_matches(matches)?));
+
+        let make_svc = make_service_fn(move |_conn| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+
+        info!("Mock server listening on http://{}", addr);
+        hyper::Server::try_bind(&addr)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async {
+                tokio::signal::ctrl_c().await.ok();
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Mock server running in background, for tests driving the client against it.
+    #[cfg(test)]
+    pub struct Handle {
+        pub address: String,
+        state: Arc<State>,
+    }
+
+    #[cfg(test)]
+    impl Handle {
+        pub fn heartbeats(&self) -> u32 {
+            self.state.heartbeats.load(Ordering::SeqCst)
+        }
+
+        /// Body of last heartbeat received.
+        pub fn last_heartbeat(&self) -> Option<serde_json::Value> {
+            self.state.last_heartbeat.lock().unwrap().clone()
+        }
+    }
+
+    /// Start mock server with command line `args` on a free loopback port.
+    #[cfg(test)]
+    pub fn spawn(args: &[&str]) -> Handle {
+        let matches = subcommand()
+            .get_matches_from_safe(std::iter::once(SUBCOMMAND_NAME).chain(args.iter().copied()))
+            .unwrap();
+        let state = Arc::new(State::new(Options::from_matches(&matches).unwrap()));
+        let handler = state.clone();
+        let make_svc = make_service_fn(move |_conn| {
+            let state = handler.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let address = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        Handle { address, state }
+    }
+}
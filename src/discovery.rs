@@ -0,0 +1,101 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! LAN discovery of relay or server via mDNS/DNS-SD (`_probe._tcp`).
+//!
+//! Set `server_address = "auto"` to use the first service found in local network.
+
+use crate::error::Error;
+use log::{debug, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub const SERVICE_TYPE: &str = "_probe._tcp.local.";
+pub const DEFAULT_DISCOVERY_TIMEOUT: u64 = 10;
+
+fn build_url(ip: &IpAddr, port: u16, path: Option<&str>) -> String {
+    let path = path.unwrap_or("/");
+    match ip {
+        IpAddr::V4(ip) => format!("http://{}:{}{}", ip, port, path),
+        IpAddr::V6(ip) => format!("http://[{}]:{}{}", ip, port, path),
+    }
+}
+
+/// Browse `_probe._tcp` and return url of the first resolved service.
+pub async fn discover(timeout: Duration) -> anyhow::Result<String> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    let result = tokio::time::timeout(timeout, async {
+        while let Ok(event) = receiver.recv_async().await {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                debug!("Resolved service: {}", info.get_fullname());
+                if let Some(ip) = info.get_addresses().iter().next() {
+                    return Some(build_url(
+                        ip,
+                        info.get_port(),
+                        info.get_property_val_str("path"),
+                    ));
+                }
+            }
+        }
+        None
+    })
+    .await;
+    daemon.stop_browse(SERVICE_TYPE).ok();
+    if let Ok(status) = daemon.shutdown() {
+        status.recv_async().await.ok();
+    }
+    match result {
+        Ok(Some(url)) => {
+            info!("Discovered server via mDNS: {}", url);
+            Ok(url)
+        }
+        _ => Err(Error::Timeout {
+            error: anyhow::anyhow!(
+                "No {} service found in {} seconds",
+                SERVICE_TYPE,
+                timeout.as_secs()
+            ),
+        }
+        .into()),
+    }
+}
+
+/// Advertise this host as `_probe._tcp` service, keep returned daemon alive while serving.
+pub fn advertise(port: u16) -> anyhow::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()?;
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let mut properties = HashMap::new();
+    properties.insert("path".to_string(), "/".to_string());
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &hostname,
+        &format!("{}.local.", hostname),
+        (),
+        port,
+        properties,
+    )?
+    .enable_addr_auto();
+    daemon.register(service)?;
+    info!("Advertise relay as {} on port {}", SERVICE_TYPE, port);
+    Ok(daemon)
+}
@@ -0,0 +1,341 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! DNS resolution checks in `[[check.dns]]`, report latency and whether answers match expected values.
+//!
+//! Queries are sent over UDP directly to the resolver (default: first nameserver in /etc/resolv.conf),
+//! so split-horizon and resolver outages are seen as the host sees them.
+
+use crate::collector::Collector;
+use crate::configparser::config::DnsCheck;
+use crate::error::Error;
+use crate::normalize::Unit;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ring::rand::SecureRandom;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+pub const COLLECTOR_NAME: &str = "dns";
+const QUERY_TIMEOUT: u64 = 5;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+pub const DEFAULT_RECORD_TYPE: &str = "A";
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+fn violation(message: String) -> Error {
+    Error::Protocol {
+        protocol: COLLECTOR_NAME,
+        message,
+    }
+}
+
+fn record_type(name: &str) -> anyhow::Result<u16> {
+    Ok(match name.to_ascii_uppercase().as_str() {
+        "A" => TYPE_A,
+        "NS" => 2,
+        "CNAME" => 5,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => TYPE_AAAA,
+        _ => {
+            return Err(Error::Configure {
+                field: "check.dns.record_type",
+                message: format!("Unsupported record type {}", name),
+            }
+            .into())
+        }
+    })
+}
+
+/// One answer record with its TTL in seconds.
+pub struct Answer {
+    pub value: String,
+    pub ttl: u32,
+}
+
+#[derive(Debug)]
+pub struct NxDomainError;
+
+impl std::error::Error for NxDomainError {}
+
+impl std::fmt::Display for NxDomainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NXDOMAIN")
+    }
+}
+
+pub fn default_resolver() -> anyhow::Result<SocketAddr> {
+    let contents = std::fs::read_to_string(RESOLV_CONF)?;
+    contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| {
+            Error::Unsupported {
+                what: format!("Nameserver in {}", RESOLV_CONF),
+            }
+            .into()
+        })
+}
+
+pub fn parse_resolver(resolver: &str) -> anyhow::Result<SocketAddr> {
+    match resolver.parse::<SocketAddr>() {
+        Ok(address) => Ok(address),
+        Err(_) => Ok(SocketAddr::new(resolver.parse()?, 53)),
+    }
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(violation(format!("Invalid domain name {}", name)).into());
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    Ok(packet)
+}
+
+/// Read (possibly compressed) name at `pos`, return name and position after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Default::default();
+    let mut end = None;
+    for _ in 0..128 {
+        let length = *packet.get(pos)? as usize;
+        if length == 0 {
+            return Some((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if length & 0xc0 == 0xc0 {
+            let pointer = ((length & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = pointer;
+            continue;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + length)?).to_string());
+        pos += 1 + length;
+    }
+    None
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *packet.get(pos)?,
+        *packet.get(pos + 1)?,
+    ]))
+}
+
+fn read_u32(packet: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes([
+        *packet.get(pos)?,
+        *packet.get(pos + 1)?,
+        *packet.get(pos + 2)?,
+        *packet.get(pos + 3)?,
+    ]))
+}
+
+fn parse_response(packet: &[u8], id: u16, qtype: u16) -> anyhow::Result<Vec<Answer>> {
+    let malformed = || violation("Malformed DNS response".to_string());
+    if read_u16(packet, 0) != Some(id) {
+        return Err(violation("DNS response id mismatch".to_string()).into());
+    }
+    let flags = read_u16(packet, 2).ok_or_else(malformed)?;
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(anyhow::Error::new(NxDomainError)),
+        rcode => return Err(violation(format!("DNS error rcode {}", rcode)).into()),
+    }
+    let questions = read_u16(packet, 4).ok_or_else(malformed)?;
+    let answers = read_u16(packet, 6).ok_or_else(malformed)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos).ok_or_else(malformed)?.1 + 4;
+    }
+    let mut values: Vec<Answer> = Default::default();
+    for _ in 0..answers {
+        pos = read_name(packet, pos).ok_or_else(malformed)?.1;
+        let rtype = read_u16(packet, pos).ok_or_else(malformed)?;
+        let ttl = read_u32(packet, pos + 4).ok_or_else(malformed)?;
+        let length = read_u16(packet, pos + 8).ok_or_else(malformed)? as usize;
+        let start = pos + 10;
+        let data = packet.get(start..start + length).ok_or_else(malformed)?;
+        pos = start + length;
+        if rtype != qtype {
+            continue;
+        }
+        let value = match rtype {
+            1 if length == 4 => Ipv4Addr::new(data[0], data[1], data[2], data[3]).to_string(),
+            28 if length == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                Ipv6Addr::from(octets).to_string()
+            }
+            2 | 5 => read_name(packet, start).ok_or_else(malformed)?.0,
+            15 => format!(
+                "{} {}",
+                read_u16(packet, start).ok_or_else(malformed)?,
+                read_name(packet, start + 2).ok_or_else(malformed)?.0
+            ),
+            16 => {
+                let mut text = String::new();
+                let mut i = 0;
+                while i < data.len() {
+                    let size = data[i] as usize;
+                    text.push_str(&String::from_utf8_lossy(
+                        data.get(i + 1..i + 1 + size).ok_or_else(malformed)?,
+                    ));
+                    i += 1 + size;
+                }
+                text
+            }
+            _ => continue,
+        };
+        values.push(Answer { value, ttl });
+    }
+    Ok(values)
+}
+
+pub async fn query(resolver: SocketAddr, name: &str, qtype: u16) -> anyhow::Result<Vec<Answer>> {
+    let mut id = [0u8; 2];
+    ring::rand::SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| Error::Crypto {
+            message: "Unable generate query id".to_string(),
+        })?;
+    let id = u16::from_be_bytes(id);
+    let packet = build_query(id, name, qtype)?;
+    let bind: SocketAddr = if resolver.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(resolver).await?;
+    socket.send(&packet).await?;
+    let mut buffer = vec![0u8; 4096];
+    let size = tokio::time::timeout(Duration::from_secs(QUERY_TIMEOUT), socket.recv(&mut buffer))
+        .await
+        .map_err(|_| Error::Timeout {
+            error: anyhow!("Timeout query {}", resolver),
+        })??;
+    parse_response(&buffer[..size], id, qtype)
+}
+
+fn canonical(value: &str) -> String {
+    value.trim_end_matches('.').to_ascii_lowercase()
+}
+
+pub struct DnsCollector {
+    checks: Vec<DnsCheck>,
+}
+
+impl DnsCollector {
+    pub fn new(checks: &[DnsCheck]) -> Self {
+        Self {
+            checks: checks.to_vec(),
+        }
+    }
+
+    async fn inspect(check: &DnsCheck) -> anyhow::Result<Value> {
+        let record_type_name = check.record_type.as_deref().unwrap_or(DEFAULT_RECORD_TYPE);
+        let qtype = record_type(record_type_name)?;
+        let resolver = match &check.resolver {
+            Some(resolver) => parse_resolver(resolver)?,
+            None => default_resolver()?,
+        };
+        let start = Instant::now();
+        let result = query(resolver, &check.name, qtype).await;
+        let latency = start.elapsed().as_secs_f64();
+        let values = match result {
+            Ok(answers) => answers
+                .into_iter()
+                .map(|answer| answer.value)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                return Ok(serde_json::json!({
+                    "resolver": resolver.to_string(),
+                    "latency": latency,
+                    "ok": false,
+                    "error": e.to_string(),
+                }))
+            }
+        };
+        let ok = match &check.expected {
+            Some(expected) => {
+                let expected: BTreeSet<String> = expected.iter().map(|v| canonical(v)).collect();
+                let actual: BTreeSet<String> = values.iter().map(|v| canonical(v)).collect();
+                expected == actual
+            }
+            None => !values.is_empty(),
+        };
+        Ok(serde_json::json!({
+            "resolver": resolver.to_string(),
+            "latency": latency,
+            "values": values,
+            "ok": ok,
+        }))
+    }
+}
+
+#[async_trait]
+impl Collector for DnsCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let mut result = serde_json::Map::new();
+        for check in &self.checks {
+            let key = format!(
+                "{}:{}",
+                check.name,
+                check
+                    .record_type
+                    .as_deref()
+                    .unwrap_or(DEFAULT_RECORD_TYPE)
+                    .to_ascii_uppercase()
+            );
+            let value = Self::inspect(check)
+                .await
+                .unwrap_or_else(|e| serde_json::json!({ "ok": false, "error": e.to_string() }));
+            result.insert(key, value);
+        }
+        Ok(Value::Object(result))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![("*.latency".to_string(), Unit::Seconds)]
+    }
+
+    fn source(&self) -> String {
+        "dns check".to_string()
+    }
+}
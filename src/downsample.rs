@@ -0,0 +1,135 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub const DEFAULT_WINDOW: u64 = 900;
+pub const DEFAULT_BACKLOG_SIZE: usize = 1440;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sample {
+    timestamp: i64,
+    payload: Value,
+}
+
+impl Sample {
+    pub fn new(payload: Value) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().timestamp(),
+            payload,
+        }
+    }
+
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn payload(&self) -> &Value {
+        &self.payload
+    }
+}
+
+#[derive(Serialize)]
+pub struct Window {
+    start: i64,
+    end: i64,
+    count: usize,
+    min: BTreeMap<String, f64>,
+    max: BTreeMap<String, f64>,
+    avg: BTreeMap<String, f64>,
+}
+
+fn flatten(prefix: &str, value: &Value, output: &mut BTreeMap<String, f64>) {
+    let join = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    match value {
+        Value::Number(n) => {
+            if let Some(n) = n.as_f64() {
+                output.insert(prefix.to_string(), n);
+            }
+        }
+        Value::Object(map) => map.iter().for_each(|(k, v)| flatten(&join(k), v, output)),
+        Value::Array(array) => array
+            .iter()
+            .enumerate()
+            .for_each(|(i, v)| flatten(&join(&i.to_string()), v, output)),
+        _ => {}
+    }
+}
+
+impl Window {
+    fn new(samples: &[&Sample]) -> Self {
+        let mut min: BTreeMap<String, f64> = Default::default();
+        let mut max: BTreeMap<String, f64> = Default::default();
+        let mut sum: BTreeMap<String, (f64, usize)> = Default::default();
+        for sample in samples {
+            let mut values = BTreeMap::new();
+            flatten("", &sample.payload, &mut values);
+            for (key, value) in values {
+                let m = min.entry(key.clone()).or_insert(value);
+                *m = m.min(value);
+                let m = max.entry(key.clone()).or_insert(value);
+                *m = m.max(value);
+                let s = sum.entry(key).or_insert((0.0, 0));
+                s.0 += value;
+                s.1 += 1;
+            }
+        }
+        Self {
+            start: samples.first().map(|s| s.timestamp).unwrap_or_default(),
+            end: samples.last().map(|s| s.timestamp).unwrap_or_default(),
+            count: samples.len(),
+            min,
+            max,
+            avg: sum
+                .into_iter()
+                .map(|(k, (s, n))| (k, s / n as f64))
+                .collect(),
+        }
+    }
+}
+
+/// Summarize samples (ordered by time) into windows of `window` seconds,
+/// keep only min/max/avg of numeric fields.
+pub fn downsample(samples: &[Sample], window: u64) -> Vec<Window> {
+    let window = window.max(1) as i64;
+    let mut result: Vec<Window> = Default::default();
+    let mut current: Vec<&Sample> = Default::default();
+    for sample in samples {
+        if let Some(first) = current.first() {
+            if sample.timestamp - first.timestamp >= window {
+                result.push(Window::new(&current));
+                current.clear();
+            }
+        }
+        current.push(sample);
+    }
+    if !current.is_empty() {
+        result.push(Window::new(&current));
+    }
+    result
+}
@@ -0,0 +1,606 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! TCP retransmissions and connect latency in `[collector.tcp]`, traced with eBPF.
+//!
+//! Two small programs are attached to tracepoints on every CPU: `tcp:tcp_retransmit_skb` counts
+//! retransmitted segments, `sock:inet_sock_set_state` measures SYN_SENT → ESTABLISHED time of
+//! outgoing connections into log2 histogram (and counts SYN_SENT → CLOSE as failures).
+//! Programs are assembled here, no toolchain or BTF is needed; tracepoint field offsets are read from tracefs.
+//! Linux only, requires root (or `CAP_BPF` and `CAP_PERFMON`).
+//!
+//! Values are reported as change since previous heartbeat.
+
+use crate::collector::Collector;
+use crate::error::Error;
+use crate::normalize::Unit;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const COLLECTOR_NAME: &str = "tcp";
+const TRACEFS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+/// Slots of statistics map: counters, then histogram of connect latency by log2 of microseconds.
+const RETRANSMITS: u32 = 0;
+const CONNECT_FAILURES: u32 = 1;
+const FIRST_BUCKET: u32 = 2;
+const BUCKETS: u32 = 32;
+const MAX_CONNECTING: u32 = 10240;
+
+const TCP_ESTABLISHED: i32 = 1;
+const TCP_SYN_SENT: i32 = 2;
+const IPPROTO_TCP: i32 = 6;
+
+mod asm {
+    //! Minimal eBPF assembler, jumps refer to labels resolved by `finish`.
+
+    use std::collections::HashMap;
+
+    pub const R0: u8 = 0;
+    pub const R1: u8 = 1;
+    pub const R2: u8 = 2;
+    pub const R3: u8 = 3;
+    pub const R4: u8 = 4;
+    pub const R6: u8 = 6;
+    pub const R7: u8 = 7;
+    pub const R8: u8 = 8;
+    pub const R9: u8 = 9;
+    pub const R10: u8 = 10;
+
+    pub const MOV_IMM: u8 = 0xb7;
+    pub const MOV_REG: u8 = 0xbf;
+    pub const ADD_IMM: u8 = 0x07;
+    pub const SUB_REG: u8 = 0x1f;
+    pub const DIV_IMM: u8 = 0x37;
+    pub const RSH_IMM: u8 = 0x77;
+    pub const LDX_W: u8 = 0x61;
+    pub const LDX_H: u8 = 0x69;
+    pub const LDX_DW: u8 = 0x79;
+    pub const ST_W: u8 = 0x62;
+    pub const STX_W: u8 = 0x63;
+    pub const STX_DW: u8 = 0x7b;
+    pub const XADD_DW: u8 = 0xdb;
+    pub const JA: u8 = 0x05;
+    pub const JEQ_IMM: u8 = 0x15;
+    pub const JNE_IMM: u8 = 0x55;
+    pub const JLE_IMM: u8 = 0xb5;
+    pub const CALL: u8 = 0x85;
+    pub const EXIT: u8 = 0x95;
+    const LD_IMM64: u8 = 0x18;
+    const PSEUDO_MAP_FD: u8 = 1;
+
+    pub const MAP_LOOKUP_ELEM: i32 = 1;
+    pub const MAP_UPDATE_ELEM: i32 = 2;
+    pub const MAP_DELETE_ELEM: i32 = 3;
+    pub const KTIME_GET_NS: i32 = 5;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct Insn {
+        code: u8,
+        regs: u8,
+        off: i16,
+        imm: i32,
+    }
+
+    #[derive(Default)]
+    pub struct Asm {
+        insns: Vec<Insn>,
+        labels: HashMap<&'static str, usize>,
+        fixups: Vec<(usize, &'static str)>,
+    }
+
+    impl Asm {
+        pub fn emit(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) -> &mut Self {
+            self.insns.push(Insn {
+                code,
+                regs: (src << 4) | dst,
+                off,
+                imm,
+            });
+            self
+        }
+
+        /// Conditional (or `JA`) jump comparing `dst` with `imm`.
+        pub fn jump(&mut self, code: u8, dst: u8, imm: i32, target: &'static str) -> &mut Self {
+            self.fixups.push((self.insns.len(), target));
+            self.emit(code, dst, 0, 0, imm)
+        }
+
+        pub fn label(&mut self, name: &'static str) -> &mut Self {
+            self.labels.insert(name, self.insns.len());
+            self
+        }
+
+        pub fn load_map(&mut self, dst: u8, fd: i32) -> &mut Self {
+            self.emit(LD_IMM64, dst, PSEUDO_MAP_FD, 0, fd)
+                .emit(0, 0, 0, 0, 0)
+        }
+
+        pub fn call(&mut self, helper: i32) -> &mut Self {
+            self.emit(CALL, 0, 0, 0, helper)
+        }
+
+        pub fn exit_ok(&mut self) -> &mut Self {
+            self.emit(MOV_IMM, R0, 0, 0, 0).emit(EXIT, 0, 0, 0, 0)
+        }
+
+        pub fn finish(&mut self) -> Vec<Insn> {
+            for (index, target) in &self.fixups {
+                let target = self.labels[target] as i64;
+                self.insns[*index].off = (target - *index as i64 - 1) as i16;
+            }
+            std::mem::take(&mut self.insns)
+        }
+    }
+}
+
+use asm::*;
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> std::io::Result<libc::c_long> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// Leading part of `perf_event_attr` (`PERF_ATTR_SIZE_VER0`).
+#[repr(C)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_ARRAY: u32 = 2;
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+fn create_map(map_type: u32, key_size: u32, max_entries: u32) -> std::io::Result<OwnedFd> {
+    let mut attr = MapCreateAttr {
+        map_type,
+        key_size,
+        value_size: 8,
+        max_entries,
+        map_flags: 0,
+    };
+    let fd = bpf(BPF_MAP_CREATE, &mut attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn load_program(insns: &[Insn]) -> anyhow::Result<OwnedFd> {
+    let license = b"GPL\0";
+    let mut log = vec![0u8; 65536];
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_TRACEPOINT,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        kern_version: 0,
+        prog_flags: 0,
+    };
+    match bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }),
+        Err(e) => {
+            let end = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+            let log = String::from_utf8_lossy(&log[..end]);
+            let tail = log.lines().rev().take(3).collect::<Vec<_>>();
+            Err(Error::Io {
+                operation: "load eBPF program",
+                path: tail.join(" / "),
+                source: e,
+            }
+            .into())
+        }
+    }
+}
+
+fn map_get(map: &OwnedFd, key: u32) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    let mut attr = MapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        _pad: 0,
+        key: &key as *const u32 as u64,
+        value: &mut value as *mut u64 as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr)?;
+    Ok(value)
+}
+
+fn event_dir(category: &str, name: &str) -> anyhow::Result<PathBuf> {
+    TRACEFS
+        .iter()
+        .map(|root| PathBuf::from(root).join("events").join(category).join(name))
+        .find(|dir| dir.join("id").exists())
+        .ok_or_else(|| {
+            Error::Unsupported {
+                what: format!("Tracepoint {}:{} (is tracefs mounted?)", category, name),
+            }
+            .into()
+        })
+}
+
+/// Offsets of fields in tracepoint record, from its `format` file.
+fn field_offsets(dir: &std::path::Path) -> anyhow::Result<HashMap<String, i16>> {
+    let format = std::fs::read_to_string(dir.join("format"))?;
+    Ok(format
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().strip_prefix("field:")?.split(';');
+            let name = parts
+                .next()?
+                .split_whitespace()
+                .last()?
+                .trim_start_matches('*')
+                .split('[')
+                .next()?
+                .to_string();
+            let offset = parts.next()?.trim().strip_prefix("offset:")?.parse().ok()?;
+            Some((name, offset))
+        })
+        .collect())
+}
+
+fn online_cpus() -> Vec<i32> {
+    let parsed = std::fs::read_to_string("/sys/devices/system/cpu/online")
+        .ok()
+        .map(|online| {
+            online
+                .trim()
+                .split(',')
+                .filter_map(|range| match range.split_once('-') {
+                    Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+                    None => {
+                        let cpu = range.parse().ok()?;
+                        Some(cpu..=cpu)
+                    }
+                })
+                .flatten()
+                .collect::<Vec<i32>>()
+        })
+        .unwrap_or_default();
+    if parsed.is_empty() {
+        let count = std::thread::available_parallelism().map_or(1, |n| n.get());
+        (0..count as i32).collect()
+    } else {
+        parsed
+    }
+}
+
+/// Attach program to tracepoint on every CPU, events stay attached while returned descriptors are open.
+fn attach(dir: &std::path::Path, program: &OwnedFd) -> anyhow::Result<Vec<OwnedFd>> {
+    let id: u64 = std::fs::read_to_string(dir.join("id"))?.trim().parse()?;
+    let mut events = Vec::new();
+    for cpu in online_cpus() {
+        let attr = PerfEventAttr {
+            kind: PERF_TYPE_TRACEPOINT,
+            size: std::mem::size_of::<PerfEventAttr>() as u32,
+            config: id,
+            sample_period: 1,
+            sample_type: 0,
+            read_format: 0,
+            flags: 0,
+            wakeup_events: 1,
+            bp_type: 0,
+            config1: 0,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                -1,
+                cpu,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::Io {
+                operation: "open tracepoint",
+                path: format!("{} on CPU {}", dir.display(), cpu),
+                source: std::io::Error::last_os_error(),
+            }
+            .into());
+        }
+        let event = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        unsafe {
+            if libc::ioctl(
+                event.as_raw_fd(),
+                PERF_EVENT_IOC_SET_BPF,
+                program.as_raw_fd(),
+            ) < 0
+                || libc::ioctl(event.as_raw_fd(), PERF_EVENT_IOC_ENABLE, 0) < 0
+            {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// Count retransmitted segments.
+fn retransmit_program(stats: &OwnedFd) -> Vec<Insn> {
+    let mut asm = Asm::default();
+    asm.emit(ST_W, R10, 0, -4, RETRANSMITS as i32)
+        .emit(MOV_REG, R2, R10, 0, 0)
+        .emit(ADD_IMM, R2, 0, 0, -4)
+        .load_map(R1, stats.as_raw_fd())
+        .call(MAP_LOOKUP_ELEM)
+        .jump(JEQ_IMM, R0, 0, "out")
+        .emit(MOV_IMM, R1, 0, 0, 1)
+        .emit(XADD_DW, R0, R1, 0, 0)
+        .label("out")
+        .exit_ok();
+    asm.finish()
+}
+
+/// Record SYN_SENT time by socket, on leaving SYN_SENT count latency bucket or failure.
+fn connect_program(
+    stats: &OwnedFd,
+    connecting: &OwnedFd,
+    fields: &HashMap<String, i16>,
+) -> anyhow::Result<Vec<Insn>> {
+    let offset = |name: &str| {
+        fields.get(name).copied().ok_or_else(|| Error::Unsupported {
+            what: format!("Field {} of inet_sock_set_state", name),
+        })
+    };
+    let mut asm = Asm::default();
+    asm.emit(MOV_REG, R6, R1, 0, 0)
+        .emit(LDX_H, R7, R6, offset("protocol")?, 0)
+        .jump(JNE_IMM, R7, IPPROTO_TCP, "out")
+        .emit(LDX_DW, R8, R6, offset("skaddr")?, 0)
+        .emit(STX_DW, R10, R8, -8, 0)
+        .emit(LDX_W, R7, R6, offset("newstate")?, 0)
+        .emit(LDX_W, R9, R6, offset("oldstate")?, 0)
+        .jump(JNE_IMM, R7, TCP_SYN_SENT, "leave")
+        // Connecting: remember start time
+        .call(KTIME_GET_NS)
+        .emit(STX_DW, R10, R0, -16, 0)
+        .load_map(R1, connecting.as_raw_fd())
+        .emit(MOV_REG, R2, R10, 0, 0)
+        .emit(ADD_IMM, R2, 0, 0, -8)
+        .emit(MOV_REG, R3, R10, 0, 0)
+        .emit(ADD_IMM, R3, 0, 0, -16)
+        .emit(MOV_IMM, R4, 0, 0, 0)
+        .call(MAP_UPDATE_ELEM)
+        .jump(JA, 0, 0, "out")
+        .label("leave")
+        .jump(JNE_IMM, R9, TCP_SYN_SENT, "out")
+        .jump(JEQ_IMM, R7, TCP_ESTABLISHED, "established")
+        .emit(ST_W, R10, 0, -20, CONNECT_FAILURES as i32)
+        .jump(JA, 0, 0, "count")
+        .label("established")
+        .load_map(R1, connecting.as_raw_fd())
+        .emit(MOV_REG, R2, R10, 0, 0)
+        .emit(ADD_IMM, R2, 0, 0, -8)
+        .call(MAP_LOOKUP_ELEM)
+        // Started before programs were loaded
+        .jump(JEQ_IMM, R0, 0, "out")
+        .emit(LDX_DW, R8, R0, 0, 0)
+        .call(KTIME_GET_NS)
+        .emit(SUB_REG, R0, R8, 0, 0)
+        .emit(DIV_IMM, R0, 0, 0, 1000)
+        // Bucket is log2 of microseconds
+        .emit(MOV_IMM, R7, 0, 0, 0);
+    for (shift, label) in [(16, "s16"), (8, "s8"), (4, "s4"), (2, "s2"), (1, "s1")] {
+        asm.jump(JLE_IMM, R0, (1 << shift) - 1, label)
+            .emit(RSH_IMM, R0, 0, 0, shift)
+            .emit(ADD_IMM, R7, 0, 0, shift)
+            .label(label);
+    }
+    asm.emit(ADD_IMM, R7, 0, 0, FIRST_BUCKET as i32)
+        .emit(STX_W, R10, R7, -20, 0)
+        .label("count")
+        .load_map(R1, stats.as_raw_fd())
+        .emit(MOV_REG, R2, R10, 0, 0)
+        .emit(ADD_IMM, R2, 0, 0, -20)
+        .call(MAP_LOOKUP_ELEM)
+        .jump(JEQ_IMM, R0, 0, "delete")
+        .emit(MOV_IMM, R1, 0, 0, 1)
+        .emit(XADD_DW, R0, R1, 0, 0)
+        .label("delete")
+        .load_map(R1, connecting.as_raw_fd())
+        .emit(MOV_REG, R2, R10, 0, 0)
+        .emit(ADD_IMM, R2, 0, 0, -8)
+        .call(MAP_DELETE_ELEM)
+        .label("out")
+        .exit_ok();
+    Ok(asm.finish())
+}
+
+/// Upper bound in seconds of latency histogram bucket.
+fn bucket_bound(bucket: u32) -> f64 {
+    (1u64 << (bucket + 1)) as f64 / 1_000_000.0
+}
+
+/// Upper bound of bucket containing `quantile` of all samples.
+fn percentile(buckets: &[u64], quantile: f64) -> Option<f64> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = (total as f64 * quantile).ceil() as u64;
+    let mut seen = 0;
+    for (bucket, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return Some(bucket_bound(bucket as u32));
+        }
+    }
+    None
+}
+
+pub struct TcpCollector {
+    stats: OwnedFd,
+    _connecting: OwnedFd,
+    _programs: Vec<OwnedFd>,
+    _events: Vec<OwnedFd>,
+    previous: Mutex<(Instant, Vec<u64>)>,
+}
+
+impl TcpCollector {
+    pub fn new() -> anyhow::Result<Self> {
+        let stats = create_map(BPF_MAP_TYPE_ARRAY, 4, FIRST_BUCKET + BUCKETS)?;
+        let connecting = create_map(BPF_MAP_TYPE_HASH, 8, MAX_CONNECTING)?;
+        let retransmit = event_dir("tcp", "tcp_retransmit_skb")?;
+        let state = event_dir("sock", "inet_sock_set_state")?;
+        let programs = vec![
+            load_program(&retransmit_program(&stats))?,
+            load_program(&connect_program(
+                &stats,
+                &connecting,
+                &field_offsets(&state)?,
+            )?)?,
+        ];
+        let mut events = attach(&retransmit, &programs[0])?;
+        events.extend(attach(&state, &programs[1])?);
+        Ok(Self {
+            stats,
+            _connecting: connecting,
+            _programs: programs,
+            _events: events,
+            previous: Mutex::new((Instant::now(), vec![0; (FIRST_BUCKET + BUCKETS) as usize])),
+        })
+    }
+}
+
+#[async_trait]
+impl Collector for TcpCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let current = (0..FIRST_BUCKET + BUCKETS)
+            .map(|slot| map_get(&self.stats, slot))
+            .collect::<std::io::Result<Vec<u64>>>()?;
+        let now = Instant::now();
+        let (since, delta) = {
+            let mut previous = self.previous.lock().unwrap();
+            let delta = current
+                .iter()
+                .zip(&previous.1)
+                .map(|(current, previous)| current.saturating_sub(*previous))
+                .collect::<Vec<u64>>();
+            let since = std::mem::replace(&mut *previous, (now, current)).0;
+            (since, delta)
+        };
+        let seconds = now.duration_since(since).as_secs_f64().max(f64::EPSILON);
+        let retransmits = delta[RETRANSMITS as usize];
+        let buckets = &delta[FIRST_BUCKET as usize..];
+        let histogram = buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| serde_json::json!({ "le": bucket_bound(bucket as u32), "count": count }))
+            .collect::<Vec<_>>();
+        Ok(serde_json::json!({
+            "retransmits": retransmits,
+            "retransmit_rate": retransmits as f64 / seconds,
+            "connect_failures": delta[CONNECT_FAILURES as usize],
+            "connect_latency": {
+                "count": buckets.iter().sum::<u64>(),
+                "p50": percentile(buckets, 0.5),
+                "p99": percentile(buckets, 0.99),
+                "buckets": histogram,
+            },
+        }))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("retransmits".to_string(), Unit::Count),
+            ("connect_failures".to_string(), Unit::Count),
+            ("connect_latency.count".to_string(), Unit::Count),
+            ("connect_latency.p50".to_string(), Unit::Seconds),
+            ("connect_latency.p99".to_string(), Unit::Seconds),
+            ("connect_latency.buckets.*.le".to_string(), Unit::Seconds),
+            ("connect_latency.buckets.*.count".to_string(), Unit::Count),
+        ]
+    }
+
+    fn source(&self) -> String {
+        "eBPF tracepoints".to_string()
+    }
+}
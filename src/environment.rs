@@ -0,0 +1,261 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Configure from environment variables, for containers where baking a configure file is awkward.
+//!
+//! Every option has a variable named after its path, upper case and joined by `_`, e.g.
+//! `PROBE_CLIENT_STATISTICS_ENABLED` or `PROBE_CLIENT_COLLECTOR_TCP_ENABLED`. Options of `[server]`
+//! are also named without section, e.g. `PROBE_CLIENT_SERVER_ADDRESS`, `PROBE_CLIENT_TOKEN` and
+//! `PROBE_CLIENT_INTERVAL`. Options are discovered like `config init` (see [`reflect`]).
+//!
+//! Variables are applied on top of configure file and below command line overrides, never written back.
+//!
+//! [`reflect`]: crate::configtool::reflect
+
+use crate::configparser::config::Configure;
+use crate::configtool::reflect::{shape_of, Shape};
+use crate::error::Error;
+use log::info;
+use std::collections::BTreeMap;
+
+pub const PREFIX: &str = "PROBE_CLIENT_";
+/// Section whose options are also named without it, full names take precedence.
+const SHORTHAND_SECTION: &str = "server";
+
+/// Path and shape of every option below `path`, tables are walked into.
+fn options(
+    path: &[&'static str],
+    fields: Vec<(&'static str, Shape)>,
+    output: &mut Vec<(Vec<&'static str>, Shape)>,
+) {
+    for (key, shape) in fields {
+        let mut path = path.to_vec();
+        path.push(key);
+        match shape {
+            Shape::Optional(inner) => match *inner {
+                Shape::Table(fields) => options(&path, fields, output),
+                shape => output.push((path, shape)),
+            },
+            Shape::Table(fields) => options(&path, fields, output),
+            shape => output.push((path, shape)),
+        }
+    }
+}
+
+fn name(path: &[&str]) -> String {
+    format!("{}{}", PREFIX, path.join("_").to_uppercase())
+}
+
+fn is_string_array(shape: &Shape) -> bool {
+    matches!(shape, Shape::Array(inner) if matches!(inner.as_ref(), Shape::String))
+}
+
+/// Variable as value of option: scalars as is, arrays and tables in TOML syntax.
+/// Arrays of strings may also be comma separated.
+fn parse(raw: &str, shape: &Shape) -> anyhow::Result<toml::Value> {
+    Ok(match shape {
+        Shape::String | Shape::Enum(_) => toml::Value::String(raw.to_string()),
+        Shape::Bool => toml::Value::Boolean(raw.parse()?),
+        Shape::Integer => toml::Value::Integer(raw.parse()?),
+        Shape::Float => toml::Value::Float(raw.parse()?),
+        shape => match toml::from_str::<toml::value::Table>(&format!("value = {}", raw)) {
+            Ok(mut table) => table.remove("value").unwrap(),
+            Err(_) if matches!(shape, Shape::Unknown) => toml::Value::String(raw.to_string()),
+            Err(_) if is_string_array(shape) => toml::Value::Array(
+                raw.split(',')
+                    .map(|item| toml::Value::String(item.trim().to_string()))
+                    .collect(),
+            ),
+            Err(e) => return Err(e.into()),
+        },
+    })
+}
+
+/// Set `value` at `path`, creating missing tables.
+fn set(root: &mut toml::value::Table, path: &[&str], value: toml::Value) -> anyhow::Result<()> {
+    let (key, parents) = path.split_last().unwrap();
+    let mut table = root;
+    for parent in parents {
+        table = match table
+            .entry(parent.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+        {
+            toml::Value::Table(table) => table,
+            _ => {
+                return Err(Error::Configure {
+                    field: "environment",
+                    message: format!("{} is not a table", parent),
+                }
+                .into())
+            }
+        };
+    }
+    table.insert(key.to_string(), value);
+    Ok(())
+}
+
+/// Apply variables set in environment to `config`, return their names.
+pub fn apply(config: &mut Configure) -> anyhow::Result<Vec<String>> {
+    apply_from(config, |name| std::env::var(name).ok())
+}
+
+/// Apply variables found by `lookup` to `config`, return their names.
+fn apply_from(
+    config: &mut Configure,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<String>> {
+    let mut all = Vec::new();
+    if let Shape::Table(fields) = shape_of::<Configure>() {
+        options(&[], fields, &mut all);
+    }
+    let mut names = BTreeMap::new();
+    for (index, (path, _)) in all.iter().enumerate() {
+        names.insert(name(path), (index, false));
+        if path.len() == 2 && path[0] == SHORTHAND_SECTION {
+            names.entry(name(&path[1..])).or_insert((index, true));
+        }
+    }
+    let mut values = Vec::new();
+    for (name, (index, shorthand)) in names {
+        if let Some(raw) = lookup(&name) {
+            let (path, shape) = &all[index];
+            let value = parse(&raw, shape).map_err(|e| Error::Configure {
+                field: "environment",
+                message: format!("Invalid {}: {}", name, e),
+            })?;
+            values.push((shorthand, name, path, value));
+        }
+    }
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Full names are applied last, so they win over shorthand of the same option
+    values.sort_by_key(|(shorthand, ..)| !*shorthand);
+    let mut table = match toml::Value::try_from(&*config)? {
+        toml::Value::Table(table) => table,
+        _ => unreachable!(),
+    };
+    let mut applied = Vec::new();
+    for (_, name, path, value) in values {
+        info!("Override {} from environment", path.join("."));
+        set(&mut table, path, value)?;
+        applied.push(name);
+    }
+    *config = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e| Error::Configure {
+            field: "environment",
+            message: format!("Invalid configure from environment: {}", e),
+        })?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const FILE: &str = "[server]\nserver_address = \"https://file.example.com/\"\ntoken = \"file\"\ninterval = 10\n[statistics]\nenabled = false\n";
+
+    fn configure(variables: &[(&str, &str)]) -> anyhow::Result<(Configure, Vec<String>)> {
+        let variables = variables
+            .iter()
+            .map(|(name, value)| (format!("{}{}", PREFIX, name), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        let mut config: Configure = toml::from_str(FILE).unwrap();
+        let applied = apply_from(&mut config, |name| variables.get(name).cloned())?;
+        Ok((config, applied))
+    }
+
+    #[test]
+    fn file_then_environment_then_command_line() {
+        let (config, applied) = configure(&[]).unwrap();
+        assert!(applied.is_empty());
+        assert_eq!(config.server.interval, Some(10));
+
+        let (mut config, applied) = configure(&[
+            ("INTERVAL", "20"),
+            ("SERVER_ADDRESS", "https://env.example.com/"),
+        ])
+        .unwrap();
+        assert_eq!(applied.len(), 2);
+        assert_eq!(config.server.interval, Some(20));
+        assert_eq!(config.server.token, "file");
+
+        crate::session::Overrides {
+            server: None,
+            interval: std::num::NonZeroU32::new(30),
+        }
+        .apply(&mut config);
+        assert_eq!(config.server.interval, Some(30));
+        assert_eq!(config.server.server_address, "https://env.example.com/");
+    }
+
+    #[test]
+    fn full_name_wins_over_shorthand() {
+        for variables in [
+            [("SERVER_TOKEN", "full"), ("TOKEN", "short")],
+            [("TOKEN", "short"), ("SERVER_TOKEN", "full")],
+        ]
+        .iter()
+        {
+            assert_eq!(configure(variables).unwrap().0.server.token, "full");
+        }
+    }
+
+    #[test]
+    fn values_parsed_by_option_type() {
+        let (config, _) = configure(&[
+            ("STATISTICS_ENABLED", "true"),
+            (
+                "BACKUP_SERVERS",
+                "https://a.example.com/, https://b.example.com/",
+            ),
+        ])
+        .unwrap();
+        assert!(config.statistics.enabled);
+        assert_eq!(
+            config.server.backup_servers.unwrap(),
+            ["https://a.example.com/", "https://b.example.com/"]
+        );
+        let (config, _) = configure(&[("BACKUP_SERVERS", "[\"https://c.example.com/\"]")]).unwrap();
+        assert_eq!(
+            config.server.backup_servers.unwrap(),
+            ["https://c.example.com/"]
+        );
+
+        // Not a number, out of range of option type, not a bool
+        for invalid in [
+            ("INTERVAL", "soon"),
+            ("INTERVAL", "-1"),
+            ("STATISTICS_ENABLED", "yes"),
+        ]
+        .iter()
+        {
+            let e = configure(&[*invalid]).err().unwrap();
+            assert_eq!(
+                crate::error::code(&e),
+                crate::error::Code::Configure,
+                "{}",
+                e
+            );
+        }
+    }
+}
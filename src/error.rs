@@ -0,0 +1,626 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Errors of the client with stable numeric codes, so server can group them across the fleet
+//! whatever the message says.
+//!
+//! Failures of this crate are variants of [`Error`], carrying their context as fields. They are
+//! still passed around as `anyhow::Error`: code and context are read back from the chain (see
+//! `classify`), errors of other crates get a code by their type, anything else is `Unknown`.
+
+use serde_json::Value;
+use std::fmt::Formatter;
+
+/// Stable numeric codes of client failures. Grouped by hundreds: 1xx transport, 2xx server
+/// response, 3xx configure, 4xx local system, 5xx requested actions. Codes are never renumbered
+/// or reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Code {
+    Unknown = 0,
+    Timeout = 101,
+    Connect = 102,
+    TooManyRetries = 103,
+    Transport = 104,
+    Protocol = 105,
+    Resolve = 106,
+    ResponseError = 201,
+    ClockSkew = 202,
+    PayloadRejected = 203,
+    Maintenance = 204,
+    ChecksumMismatch = 205,
+    ExitRequested = 206,
+    ReInitRequested = 207,
+    InvalidResponse = 208,
+    UnexpectedStatus = 209,
+    Configure = 301,
+    Io = 401,
+    Unsupported = 402,
+    Crypto = 403,
+    Command = 404,
+    Policy = 405,
+    MissingParameter = 501,
+    InvalidParameter = 502,
+    NotAllowed = 503,
+    Unapproved = 504,
+    Expired = 505,
+    Replayed = 506,
+    WrongTarget = 507,
+    Busy = 508,
+    InvalidSignature = 509,
+}
+
+impl Code {
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    pub fn kind(self) -> &'static str {
+        match self {
+            Code::Unknown => "unknown",
+            Code::Timeout => "timeout",
+            Code::Connect => "connect",
+            Code::TooManyRetries => "too_many_retries",
+            Code::Transport => "transport",
+            Code::Protocol => "protocol",
+            Code::Resolve => "resolve",
+            Code::ResponseError => "response_error",
+            Code::ClockSkew => "clock_skew",
+            Code::PayloadRejected => "payload_rejected",
+            Code::Maintenance => "maintenance",
+            Code::ChecksumMismatch => "checksum_mismatch",
+            Code::ExitRequested => "exit_requested",
+            Code::ReInitRequested => "reinit_requested",
+            Code::InvalidResponse => "invalid_response",
+            Code::UnexpectedStatus => "unexpected_status",
+            Code::Configure => "configure",
+            Code::Io => "io",
+            Code::Unsupported => "unsupported",
+            Code::Crypto => "crypto",
+            Code::Command => "command",
+            Code::Policy => "policy",
+            Code::MissingParameter => "missing_parameter",
+            Code::InvalidParameter => "invalid_parameter",
+            Code::NotAllowed => "not_allowed",
+            Code::Unapproved => "unapproved",
+            Code::Expired => "expired",
+            Code::Replayed => "replayed",
+            Code::WrongTarget => "wrong_target",
+            Code::Busy => "busy",
+            Code::InvalidSignature => "invalid_signature",
+        }
+    }
+}
+
+impl std::fmt::Display for Code {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "E{:03}", self.number())
+    }
+}
+
+/// ` (message)` if any.
+fn note(message: &Option<String>) -> String {
+    message
+        .as_ref()
+        .map(|message| format!(" ({})", message))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Max retry times exceed! last error: {last:?}")]
+    TooManyRetries { last: anyhow::Error },
+    #[error("Timeout: {error:?}")]
+    Timeout { error: anyhow::Error },
+    /// Wire `protocol` (WebSocket, DNS) violated by peer, or connection ended by it.
+    #[error("{message}")]
+    Protocol {
+        protocol: &'static str,
+        message: String,
+    },
+    #[error("No address found for {name}")]
+    Resolve { name: String },
+    /// HTTP status other than success from a service that is not the server (webhook, metadata).
+    #[error("{url} returned {status}")]
+    UnexpectedStatus { url: String, status: u16 },
+    /// Output of a program or contents of a file is not in the format expected.
+    #[error("Unable parse {what}: {message}")]
+    Malformed { what: String, message: String },
+    #[error("Clock skew rejected by server, offset now {offset}s")]
+    ClockSkew { offset: i64 },
+    #[error("Payload rejected by server because of {}", .sections.join(", "))]
+    PayloadRejected { sections: Vec<String> },
+    #[error("Server under maintenance{}, retry in {retry_after} seconds", note(.message))]
+    Maintenance {
+        retry_after: u64,
+        message: Option<String>,
+    },
+    /// Request body digest echoed by server differs from the one sent, body was altered in
+    /// transit (mangling proxy, truncation) even though the request itself succeeded.
+    #[error("Receipt checksum mismatch from {url}: sent {length} bytes with SHA-256 {sent}, server received {received}")]
+    ChecksumMismatch {
+        url: String,
+        length: usize,
+        sent: String,
+        received: String,
+    },
+    /// `field` is dotted path in configure file.
+    #[error("{message}")]
+    Configure {
+        field: &'static str,
+        message: String,
+    },
+    #[error("Unable {operation} {path}: {source}")]
+    Io {
+        operation: &'static str,
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{what} not found on this host")]
+    Unsupported { what: String },
+    /// `feature` is not built in, or not supported on this platform.
+    #[error("{message}")]
+    Unavailable {
+        feature: &'static str,
+        message: String,
+    },
+    /// Key generation, loading or signing failed.
+    #[error("{message}")]
+    Crypto { message: String },
+    /// Helper program failed to start or exited with failure.
+    #[error("{program} failed: {message}")]
+    Command { program: String, message: String },
+    /// Policy module failed to load or evaluate.
+    #[cfg_attr(not(feature = "policy"), allow(dead_code))]
+    #[error("Policy module: {message}")]
+    Policy { message: String },
+    #[error("Missing {name} of {action}")]
+    MissingParameter { action: String, name: &'static str },
+    #[error("Invalid {name} of {action}: {value}")]
+    InvalidParameter {
+        action: String,
+        name: &'static str,
+        value: String,
+    },
+    /// `value` requested by server is not in allow-list `list` of configure.
+    #[error("{value} is not in {list}")]
+    NotAllowed {
+        action: String,
+        list: &'static str,
+        value: String,
+    },
+    /// Operator approvals of `[authz]` missing or not enough, `configured` is false without it.
+    #[error("{action} has {approved} of {required} required operator approvals{}", if *.configured { "" } else { " but [authz] is not configured" })]
+    Unapproved {
+        action: String,
+        approved: usize,
+        required: usize,
+        configured: bool,
+    },
+    #[error("{action} request expired at {expires}")]
+    Expired { action: String, expires: i64 },
+    #[error("{action} request {id} already used")]
+    Replayed { action: String, id: String },
+    #[error("{action} request is not for this probe")]
+    WrongTarget { action: String },
+    #[error("{action} is already running")]
+    Busy { action: String },
+    /// Signature of server, operator or manifest does not verify.
+    #[error("{reason}")]
+    InvalidSignature { reason: &'static str },
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match self {
+            Error::TooManyRetries { .. } => Code::TooManyRetries,
+            Error::Timeout { .. } => Code::Timeout,
+            Error::Protocol { .. } => Code::Protocol,
+            Error::Resolve { .. } => Code::Resolve,
+            Error::UnexpectedStatus { .. } => Code::UnexpectedStatus,
+            Error::Malformed { .. } => Code::InvalidResponse,
+            Error::ClockSkew { .. } => Code::ClockSkew,
+            Error::PayloadRejected { .. } => Code::PayloadRejected,
+            Error::Maintenance { .. } => Code::Maintenance,
+            Error::ChecksumMismatch { .. } => Code::ChecksumMismatch,
+            Error::Configure { .. } => Code::Configure,
+            Error::Io { .. } => Code::Io,
+            Error::Unsupported { .. } | Error::Unavailable { .. } => Code::Unsupported,
+            Error::Crypto { .. } => Code::Crypto,
+            Error::Command { .. } => Code::Command,
+            Error::Policy { .. } => Code::Policy,
+            Error::MissingParameter { .. } => Code::MissingParameter,
+            Error::InvalidParameter { .. } => Code::InvalidParameter,
+            Error::NotAllowed { .. } => Code::NotAllowed,
+            Error::Unapproved { .. } => Code::Unapproved,
+            Error::Expired { .. } => Code::Expired,
+            Error::Replayed { .. } => Code::Replayed,
+            Error::WrongTarget { .. } => Code::WrongTarget,
+            Error::Busy { .. } => Code::Busy,
+            Error::InvalidSignature { .. } => Code::InvalidSignature,
+        }
+    }
+
+    /// Machine-readable context sent along with code.
+    pub fn context(&self) -> Value {
+        match self {
+            Error::TooManyRetries { last } => serde_json::json!({ "last": code(last).number() }),
+            Error::Timeout { error } => classify(error).1,
+            Error::Protocol { protocol, .. } => serde_json::json!({ "protocol": protocol }),
+            Error::Resolve { name } => serde_json::json!({ "name": name }),
+            Error::UnexpectedStatus { url, status } => {
+                serde_json::json!({ "url": url, "status": status })
+            }
+            Error::Malformed { what, .. } => serde_json::json!({ "what": what }),
+            Error::ClockSkew { offset } => serde_json::json!({ "offset": offset }),
+            Error::PayloadRejected { sections } => serde_json::json!({ "sections": sections }),
+            Error::Maintenance {
+                retry_after,
+                message,
+            } => serde_json::json!({ "retry_after": retry_after, "message": message }),
+            Error::ChecksumMismatch {
+                url,
+                length,
+                sent,
+                received,
+            } => serde_json::json!({
+                "url": url,
+                "length": length,
+                "sent": sent,
+                "received": received,
+            }),
+            Error::Configure { field, .. } => serde_json::json!({ "field": field }),
+            Error::Io {
+                operation,
+                path,
+                source,
+            } => serde_json::json!({
+                "operation": operation,
+                "path": path,
+                "kind": format!("{:?}", source.kind()),
+            }),
+            Error::Unsupported { what } => serde_json::json!({ "what": what }),
+            Error::Unavailable { feature, .. } => serde_json::json!({ "feature": feature }),
+            Error::Crypto { .. } | Error::Policy { .. } => Value::Null,
+            Error::Command { program, .. } => serde_json::json!({ "program": program }),
+            Error::MissingParameter { action, name } => {
+                serde_json::json!({ "action": action, "name": name })
+            }
+            Error::InvalidParameter {
+                action,
+                name,
+                value,
+            } => serde_json::json!({ "action": action, "name": name, "value": value }),
+            Error::NotAllowed {
+                action,
+                list,
+                value,
+            } => serde_json::json!({ "action": action, "list": list, "value": value }),
+            Error::Unapproved {
+                action,
+                approved,
+                required,
+                configured,
+            } => serde_json::json!({
+                "action": action,
+                "approved": approved,
+                "required": required,
+                "configured": configured,
+            }),
+            Error::Expired { action, expires } => {
+                serde_json::json!({ "action": action, "expires": expires })
+            }
+            Error::Replayed { action, id } => serde_json::json!({ "action": action, "id": id }),
+            Error::WrongTarget { action } | Error::Busy { action } => {
+                serde_json::json!({ "action": action })
+            }
+            Error::InvalidSignature { reason } => serde_json::json!({ "reason": reason }),
+        }
+    }
+
+    /// Wait requested by server, if this is maintenance.
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::Maintenance { retry_after, .. } => {
+                Some(std::time::Duration::from_secs(*retry_after))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Error of this crate at top of chain of `e`, if any.
+pub fn of(e: &anyhow::Error) -> Option<&Error> {
+    e.downcast_ref()
+}
+
+/// Code and machine-readable context of the first known error in chain of `e`.
+fn classify(e: &anyhow::Error) -> (Code, Value) {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            return (e.code(), e.context());
+        }
+        if let Some(e) = cause.downcast_ref::<crate::session::response::Error>() {
+            return (Code::ResponseError, e.context());
+        }
+        if let Some(e) = cause.downcast_ref::<crate::session::ExitProcessRequest>() {
+            return (Code::ExitRequested, e.context());
+        }
+        if cause.is::<crate::session::ReInitRequest>() {
+            return (Code::ReInitRequested, Value::Null);
+        }
+        if let Some(violation) = cause.downcast_ref::<crate::sandbox::Violation>() {
+            let code = match violation {
+                crate::sandbox::Violation::Timeout(_) => Code::Timeout,
+                _ => Code::Command,
+            };
+            return (code, Value::Null);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            let code = if e.is_timeout() {
+                Code::Timeout
+            } else if e.is_connect() {
+                Code::Connect
+            } else if e.is_decode() {
+                Code::InvalidResponse
+            } else {
+                Code::Transport
+            };
+            return (
+                code,
+                serde_json::json!({
+                    "url": e.url().map(|url| url.as_str()),
+                    "status": e.status().map(|status| status.as_u16()),
+                }),
+            );
+        }
+        if cause.is::<serde_json::Error>() {
+            return (Code::InvalidResponse, Value::Null);
+        }
+        if cause.is::<toml::de::Error>() {
+            return (Code::Configure, Value::Null);
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return (
+                Code::Io,
+                serde_json::json!({ "kind": format!("{:?}", e.kind()) }),
+            );
+        }
+    }
+    (Code::Unknown, Value::Null)
+}
+
+pub fn code(e: &anyhow::Error) -> Code {
+    classify(e).0
+}
+
+/// `{code, kind, message, context}` of `e`, for reports sent to server.
+pub fn structured(e: &anyhow::Error) -> Value {
+    let (code, context) = classify(e);
+    serde_json::json!({
+        "code": code.number(),
+        "kind": code.kind(),
+        "message": e.to_string(),
+        "context": context,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_and_context_survive_chain() {
+        let e = anyhow::Error::from(Error::NotAllowed {
+            action: "exec".to_string(),
+            list: "command allow-list",
+            value: "rm".to_string(),
+        })
+        .context("Unable run action");
+        let value = structured(&e);
+        assert_eq!(value["code"], 503);
+        assert_eq!(value["kind"], "not_allowed");
+        assert_eq!(value["context"]["value"], "rm");
+
+        let e = anyhow::Error::from(Error::TooManyRetries {
+            last: Error::Timeout {
+                error: anyhow::anyhow!("slow"),
+            }
+            .into(),
+        });
+        assert_eq!(code(&e), Code::TooManyRetries);
+        assert_eq!(structured(&e)["context"]["last"], 101);
+    }
+
+    #[test]
+    fn every_variant_has_its_code() {
+        let action = || "exec".to_string();
+        let cases = vec![
+            (
+                Error::TooManyRetries {
+                    last: anyhow::anyhow!("last"),
+                },
+                103,
+            ),
+            (
+                Error::Timeout {
+                    error: anyhow::anyhow!("slow"),
+                },
+                101,
+            ),
+            (
+                Error::Protocol {
+                    protocol: "WebSocket",
+                    message: "closed".to_string(),
+                },
+                105,
+            ),
+            (
+                Error::Resolve {
+                    name: "example.com".to_string(),
+                },
+                106,
+            ),
+            (
+                Error::UnexpectedStatus {
+                    url: "https://example.com".to_string(),
+                    status: 500,
+                },
+                209,
+            ),
+            (
+                Error::Malformed {
+                    what: "output".to_string(),
+                    message: "eof".to_string(),
+                },
+                208,
+            ),
+            (Error::ClockSkew { offset: 10 }, 202),
+            (
+                Error::PayloadRejected {
+                    sections: vec!["disk".to_string()],
+                },
+                203,
+            ),
+            (
+                Error::Maintenance {
+                    retry_after: 60,
+                    message: None,
+                },
+                204,
+            ),
+            (
+                Error::ChecksumMismatch {
+                    url: "https://example.com".to_string(),
+                    length: 1,
+                    sent: "a".to_string(),
+                    received: "b".to_string(),
+                },
+                205,
+            ),
+            (
+                Error::Configure {
+                    field: "server",
+                    message: "invalid".to_string(),
+                },
+                301,
+            ),
+            (
+                Error::Io {
+                    operation: "open",
+                    path: "/nonexistent".to_string(),
+                    source: std::io::ErrorKind::NotFound.into(),
+                },
+                401,
+            ),
+            (
+                Error::Unsupported {
+                    what: "apt".to_string(),
+                },
+                402,
+            ),
+            (
+                Error::Unavailable {
+                    feature: "smtp",
+                    message: "disabled".to_string(),
+                },
+                402,
+            ),
+            (
+                Error::Crypto {
+                    message: "bad key".to_string(),
+                },
+                403,
+            ),
+            (
+                Error::Command {
+                    program: "true".to_string(),
+                    message: "killed".to_string(),
+                },
+                404,
+            ),
+            (
+                Error::Policy {
+                    message: "trap".to_string(),
+                },
+                405,
+            ),
+            (
+                Error::MissingParameter {
+                    action: action(),
+                    name: "command",
+                },
+                501,
+            ),
+            (
+                Error::InvalidParameter {
+                    action: action(),
+                    name: "command",
+                    value: "".to_string(),
+                },
+                502,
+            ),
+            (
+                Error::NotAllowed {
+                    action: action(),
+                    list: "command allow-list",
+                    value: "rm".to_string(),
+                },
+                503,
+            ),
+            (
+                Error::Unapproved {
+                    action: action(),
+                    approved: 0,
+                    required: 1,
+                    configured: true,
+                },
+                504,
+            ),
+            (
+                Error::Expired {
+                    action: action(),
+                    expires: 0,
+                },
+                505,
+            ),
+            (
+                Error::Replayed {
+                    action: action(),
+                    id: "1".to_string(),
+                },
+                506,
+            ),
+            (Error::WrongTarget { action: action() }, 507),
+            (Error::Busy { action: action() }, 508),
+            (
+                Error::InvalidSignature {
+                    reason: "bad signature",
+                },
+                509,
+            ),
+        ];
+        for (error, number) in cases {
+            let message = error.to_string();
+            let e = anyhow::Error::from(error).context("Unable do something");
+            assert_eq!(code(&e).number(), number, "{}", message);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Windows event log collector: count critical, error (and optionally warning) events
+//! of `System` and `Application` logs since previous heartbeat, by log, level and source.
+//!
+//! Events are queried with `Get-WinEvent` through PowerShell.
+
+use crate::collector::Collector;
+use crate::configparser::config::WatchEventLog;
+use crate::error::Error;
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "windows_events";
+pub const DEFAULT_LOGS: [&str; 2] = ["System", "Application"];
+pub const DEFAULT_WARNINGS: bool = false;
+const QUERY_TIMEOUT: u64 = 30;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Event {
+    log_name: String,
+    provider_name: Option<String>,
+    level: u8,
+}
+
+fn level_name(level: u8) -> &'static str {
+    match level {
+        1 => "critical",
+        2 => "error",
+        3 => "warning",
+        _ => "other",
+    }
+}
+
+pub struct EventLogCollector {
+    logs: Vec<String>,
+    levels: Vec<u8>,
+    since: Mutex<DateTime<Utc>>,
+}
+
+impl EventLogCollector {
+    pub fn new(cfg: &WatchEventLog) -> Self {
+        Self {
+            logs: cfg
+                .logs
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LOGS.iter().map(|s| s.to_string()).collect()),
+            levels: if cfg.warnings.unwrap_or(DEFAULT_WARNINGS) {
+                vec![1, 2, 3]
+            } else {
+                vec![1, 2]
+            },
+            since: Mutex::new(Utc::now()),
+        }
+    }
+
+    fn script(&self, since: &DateTime<Utc>) -> String {
+        let logs = self
+            .logs
+            .iter()
+            .map(|log| format!("'{}'", log.replace('\'', "''")))
+            .collect::<Vec<_>>()
+            .join(",");
+        let levels = self
+            .levels
+            .iter()
+            .map(u8::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "$ErrorActionPreference = 'SilentlyContinue'; \
+             ConvertTo-Json -Compress -InputObject @(Get-WinEvent -FilterHashtable \
+             @{{LogName=@({}); Level=@({}); StartTime=[datetime]::Parse('{}').ToLocalTime()}} \
+             | Select-Object LogName, ProviderName, Level)",
+            logs,
+            levels,
+            since.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )
+    }
+}
+
+#[async_trait]
+impl Collector for EventLogCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let since = *self.since.lock().unwrap();
+        let now = Utc::now();
+        let args = vec![
+            "-NoProfile".to_string(),
+            "-NonInteractive".to_string(),
+            "-Command".to_string(),
+            self.script(&since),
+        ];
+        let limits = Limits {
+            timeout: Some(QUERY_TIMEOUT),
+            ..Default::default()
+        };
+        let output = sandbox::run("powershell.exe", &args, &limits).await?;
+        let events: Vec<Event> = match output.trim() {
+            "" => Vec::new(),
+            output => serde_json::from_str(output).map_err(|e| Error::Malformed {
+                what: "Get-WinEvent output".to_string(),
+                message: e.to_string(),
+            })?,
+        };
+        *self.since.lock().unwrap() = now;
+
+        let mut logs: BTreeMap<String, Value> = self
+            .logs
+            .iter()
+            .map(|log| (log.clone(), serde_json::json!({ "sources": {} })))
+            .collect();
+        for level in &self.levels {
+            for summary in logs.values_mut() {
+                summary[level_name(*level)] = serde_json::json!(0);
+            }
+        }
+        for event in &events {
+            let summary = logs
+                .entry(event.log_name.clone())
+                .or_insert_with(|| serde_json::json!({ "sources": {} }));
+            let level = level_name(event.level);
+            summary[level] = serde_json::json!(summary[level].as_u64().unwrap_or(0) + 1);
+            let source = event.provider_name.as_deref().unwrap_or("unknown");
+            let count = &mut summary["sources"][source];
+            *count = serde_json::json!(count.as_u64().unwrap_or(0) + 1);
+        }
+        Ok(serde_json::json!({
+            "since": since.timestamp(),
+            "total": events.len(),
+            "logs": logs,
+        }))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("since".to_string(), Unit::UnixTimestamp),
+            ("total".to_string(), Unit::Count),
+        ]
+    }
+
+    fn source(&self) -> String {
+        "windows event log".to_string()
+    }
+}
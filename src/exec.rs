@@ -0,0 +1,98 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `exec` action: run a command from local allow-list by alias, for fleet diagnostics.
+//!
+//! Server only names an alias, program and arguments are fixed in configure (no shell).
+//! Output is returned with `command_result`.
+
+use crate::action::ActionHandler;
+use crate::configparser::config::ExecAction;
+use crate::error::Error;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+
+struct AllowedCommand {
+    command: String,
+    args: Vec<String>,
+    limits: Limits,
+}
+
+pub struct ExecHandler {
+    commands: HashMap<String, AllowedCommand>,
+}
+
+impl ExecHandler {
+    pub fn new(cfg: &ExecAction) -> Self {
+        let commands = cfg
+            .command
+            .iter()
+            .flatten()
+            .map(|c| {
+                (
+                    c.alias.clone(),
+                    AllowedCommand {
+                        command: c.command.clone(),
+                        args: c.args.clone().unwrap_or_default(),
+                        limits: Limits {
+                            timeout: c.timeout,
+                            cpu_seconds: c.cpu_seconds,
+                            memory_mb: c.memory_mb,
+                            seccomp: c.seccomp.unwrap_or(crate::sandbox::DEFAULT_SECCOMP),
+                            max_output: None,
+                        },
+                    },
+                )
+            })
+            .collect();
+        Self { commands }
+    }
+}
+
+#[async_trait]
+impl ActionHandler for ExecHandler {
+    fn name(&self) -> &str {
+        "exec"
+    }
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value> {
+        let alias =
+            params
+                .get("alias")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::MissingParameter {
+                    action: self.name().to_string(),
+                    name: "alias",
+                })?;
+        let command = self.commands.get(alias).ok_or_else(|| Error::NotAllowed {
+            action: self.name().to_string(),
+            list: "command allow-list",
+            value: alias.to_string(),
+        })?;
+        let output = sandbox::run(&command.command, &command.args, &command.limits).await?;
+        Ok(serde_json::json!({ "alias": alias, "output": output }))
+    }
+
+    fn result_action(&self) -> &str {
+        "command_result"
+    }
+}
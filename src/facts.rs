@@ -0,0 +1,76 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Site-specific facts maintained by external tooling (rack, owner, ticket links),
+//! sent as `facts` of every registration without changing configure or code.
+//!
+//! Read on every registration from `facts.toml` in state directory, then every `*.toml`
+//! of `facts.d/` in name order. Keys of later files replace those of earlier ones.
+
+use log::warn;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+pub const FACTS_FILE: &str = "facts.toml";
+pub const FACTS_DIR: &str = "facts.d";
+
+fn to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::from(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::from(b),
+        toml::Value::Datetime(datetime) => Value::from(datetime.to_string()),
+        toml::Value::Array(array) => array.into_iter().map(to_json).collect(),
+        toml::Value::Table(table) => table.into_iter().map(|(k, v)| (k, to_json(v))).collect(),
+    }
+}
+
+/// Merge keys of file into `facts`, skip it if missing or invalid.
+fn read(path: &Path, facts: &mut Map<String, Value>) {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Unable read facts {}: {}", path.display(), e);
+            return;
+        }
+    };
+    match toml::from_str::<toml::value::Table>(&contents) {
+        Ok(table) => facts.extend(table.into_iter().map(|(k, v)| (k, to_json(v)))),
+        Err(e) => warn!("Invalid facts {}, skipped: {}", path.display(), e),
+    }
+}
+
+/// Facts of every file, `None` if there is none.
+pub fn load() -> Option<Map<String, Value>> {
+    let mut facts = Map::new();
+    read(&crate::state::path(FACTS_FILE), &mut facts);
+    if let Ok(entries) = std::fs::read_dir(crate::state::path(FACTS_DIR)) {
+        let mut paths = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.iter().for_each(|path| read(path, &mut facts));
+    }
+    (!facts.is_empty()).then_some(facts)
+}
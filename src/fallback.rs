@@ -0,0 +1,126 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Out-of-band notification when the probe server has been unreachable for too long,
+//! so a broken monitoring chain is noticed even without the server.
+
+use crate::configparser::config::Configure;
+use crate::notify::Notifier;
+use crate::template::Templates;
+use log::error;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Minutes without successful report before notification.
+pub const DEFAULT_AFTER: u64 = 15;
+pub const DEFAULT_RECOVERY: bool = true;
+const CHECK_INTERVAL: u64 = 30;
+/// Rendered with `state` (`down` or `recovered`), `hostname`, `server` and `minutes`.
+pub const DEFAULT_MESSAGE: &str = "{{#if (eq state \"down\")}}probe-client on {{ hostname }} unable to report to {{ server }} for {{ minutes }} minutes{{else}}probe-client on {{ hostname }} reports to {{ server }} again{{/if}}";
+const MESSAGE_TEMPLATE: &str = "message";
+
+/// Time of last report accepted by server.
+pub struct Monitor {
+    last_success: AtomicI64,
+    notified: AtomicBool,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            last_success: AtomicI64::new(chrono::Utc::now().timestamp()),
+            notified: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Monitor {
+    pub fn succeeded(&self) {
+        self.last_success
+            .store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    fn down_for(&self) -> i64 {
+        chrono::Utc::now().timestamp() - self.last_success.load(Ordering::Relaxed)
+    }
+}
+
+/// Watch `monitor` in background if `[fallback]` is configured.
+pub fn spawn(cfg: &Configure, monitor: Arc<Monitor>) -> anyhow::Result<()> {
+    let fallback = match &cfg.fallback {
+        Some(fallback) => fallback,
+        None => return Ok(()),
+    };
+    let notifier = Notifier::new(
+        cfg,
+        fallback.webhook.as_ref(),
+        fallback.command.as_ref(),
+        fallback.smtp.as_ref(),
+    )?;
+    if notifier.is_empty() {
+        log::warn!("No fallback channel configured, ignored");
+        return Ok(());
+    }
+    let mut templates = Templates::default();
+    templates.register(
+        "fallback.message",
+        MESSAGE_TEMPLATE,
+        fallback.message.as_deref().unwrap_or(DEFAULT_MESSAGE),
+    )?;
+    let after = fallback.after.unwrap_or(DEFAULT_AFTER) as i64 * 60;
+    let recovery = fallback.recovery.unwrap_or(DEFAULT_RECOVERY);
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let server = cfg.server.server_address.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            let down_for = monitor.down_for();
+            let notified = monitor.notified.load(Ordering::Relaxed);
+            let state = if !notified && down_for >= after {
+                "down"
+            } else if notified && down_for < after {
+                monitor.notified.store(false, Ordering::Relaxed);
+                if !recovery {
+                    continue;
+                }
+                "recovered"
+            } else {
+                continue;
+            };
+            let context = serde_json::json!({
+                "state": state,
+                "hostname": hostname,
+                "server": server,
+                "minutes": down_for / 60,
+            });
+            let result = match templates.render(MESSAGE_TEMPLATE, &context) {
+                Ok(message) => notifier.send(&message).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => monitor.notified.store(!notified, Ordering::Relaxed),
+                Err(e) => error!("Unable send fallback notification: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
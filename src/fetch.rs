@@ -0,0 +1,241 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `fetch_file` action: send content of allow-listed files (e.g. logs) to server for diagnostics.
+//!
+//! Paths are resolved (following symlinks and `..`) before matching against allow-list globs,
+//! where `*` and `?` do not cross `/`. On Linux the resolved path is read back from the opened
+//! descriptor, so the file checked is the one read even if a link is swapped meanwhile. Large files are truncated to the complete lines in their last `max_size` bytes.
+
+use crate::action::ActionHandler;
+use crate::configparser::config::FetchFileAction;
+use crate::error::Error;
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Value;
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024;
+const REDACTED: &str = "[REDACTED]";
+
+fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], path)
+                || (!path.is_empty() && path[0] != b'/' && glob_match(pattern, &path[1..]))
+        }
+        (Some(b'?'), Some(c)) if *c != b'/' => glob_match(&pattern[1..], &path[1..]),
+        (Some(p), Some(c)) if p == c => glob_match(&pattern[1..], &path[1..]),
+        _ => false,
+    }
+}
+
+/// Open `requested` for reading without blocking on a FIFO or taking a controlling terminal,
+/// along with the path it resolved to.
+#[cfg(target_os = "linux")]
+async fn open(requested: &str) -> std::io::Result<(tokio::fs::File, PathBuf)> {
+    use std::os::unix::io::AsRawFd as _;
+
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY)
+        .open(requested)
+        .await?;
+    let path = tokio::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).await?;
+    Ok((file, path))
+}
+
+/// Without `/proc`, the resolved path is opened refusing a symlink swapped in as last component.
+#[cfg(all(unix, not(target_os = "linux")))]
+async fn open(requested: &str) -> std::io::Result<(tokio::fs::File, PathBuf)> {
+    let path = tokio::fs::canonicalize(requested).await?;
+    let file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOCTTY | libc::O_NOFOLLOW)
+        .open(&path)
+        .await?;
+    Ok((file, path))
+}
+
+#[cfg(not(unix))]
+async fn open(requested: &str) -> std::io::Result<(tokio::fs::File, PathBuf)> {
+    let path = tokio::fs::canonicalize(requested).await?;
+    Ok((tokio::fs::File::open(&path).await?, path))
+}
+
+pub struct FetchFileHandler {
+    allow: Vec<String>,
+    max_size: u64,
+    redact: Vec<Regex>,
+}
+
+impl FetchFileHandler {
+    pub fn new(cfg: &FetchFileAction) -> anyhow::Result<Self> {
+        Ok(Self {
+            allow: cfg.allow.clone(),
+            max_size: cfg.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            redact: cfg
+                .redact
+                .iter()
+                .flatten()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn is_allowed(&self, path: &str) -> bool {
+        self.allow
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), path.as_bytes()))
+    }
+
+    fn redact(&self, content: String) -> String {
+        self.redact.iter().fold(content, |content, pattern| {
+            pattern.replace_all(&content, REDACTED).into_owned()
+        })
+    }
+}
+
+#[async_trait]
+impl ActionHandler for FetchFileHandler {
+    fn name(&self) -> &str {
+        "fetch_file"
+    }
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value> {
+        let requested =
+            params
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or_else(|| Error::MissingParameter {
+                    action: self.name().to_string(),
+                    name: "path",
+                })?;
+        let not_allowed = || Error::NotAllowed {
+            action: self.name().to_string(),
+            list: "file allow-list",
+            value: requested.to_string(),
+        };
+        let (mut file, path) = open(requested).await.map_err(|_| not_allowed())?;
+        let path_str = path.to_string_lossy();
+        if !self.is_allowed(&path_str) {
+            return Err(not_allowed().into());
+        }
+
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(Error::InvalidParameter {
+                action: self.name().to_string(),
+                name: "path",
+                value: format!("{} is not a regular file", path_str),
+            }
+            .into());
+        }
+        let size = metadata.len();
+        let truncated = size > self.max_size;
+        if truncated {
+            file.seek(SeekFrom::End(-(self.max_size as i64))).await?;
+        }
+        let mut buffer = Vec::new();
+        file.take(self.max_size).read_to_end(&mut buffer).await?;
+        if truncated {
+            // Drop partial first line, so redaction patterns are not cut in half
+            let start = buffer.iter().position(|c| *c == b'\n').map_or(0, |i| i + 1);
+            buffer.drain(..start);
+        }
+        let content = self.redact(String::from_utf8_lossy(&buffer).into_owned());
+
+        Ok(serde_json::json!({
+            "path": path_str,
+            "size": size,
+            "truncated": truncated,
+            "content": content,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(pattern.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn glob_does_not_cross_separator() {
+        assert!(matches("/var/log/*.log", "/var/log/syslog.log"));
+        assert!(matches("/var/log/*", "/var/log/"));
+        assert!(!matches("/var/log/*.log", "/var/log/nginx/access.log"));
+        assert!(matches("/var/log/*/*.log", "/var/log/nginx/access.log"));
+        assert!(matches("/var/log/messages.?", "/var/log/messages.1"));
+        assert!(!matches("/var/log/messages.?", "/var/log/messages.10"));
+        assert!(!matches("/var/log?messages", "/var/log/messages"));
+        assert!(!matches("/var/log/*.log", "/var/log/syslog.log.1"));
+        assert!(matches("/etc/hostname", "/etc/hostname"));
+        assert!(!matches("/etc/hostname", "/etc/hostnames"));
+        assert!(matches("*", "hostname"));
+        assert!(!matches("*", "/etc"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_resolved_before_allow_list() {
+        let dir = std::env::temp_dir().join(format!("probe-client-fetch-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("logs")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        std::fs::write(dir.join("logs/app.log"), "first\nsecret=1\n").unwrap();
+        std::fs::write(dir.join("private"), "key").unwrap();
+        std::os::unix::fs::symlink(dir.join("private"), dir.join("logs/escape.log")).unwrap();
+        std::os::unix::fs::symlink(dir.join("logs/app.log"), dir.join("link.log")).unwrap();
+
+        let handler = FetchFileHandler::new(
+            &toml::from_str(&format!(
+                "enabled = true\nallow = [\"{}/logs/*.log\"]\nredact = [\"secret=\\\\d+\"]",
+                dir.display()
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        let fetch = |path: PathBuf| {
+            let handler = &handler;
+            async move {
+                handler
+                    .handle(&serde_json::json!({ "path": path.to_str().unwrap() }))
+                    .await
+            }
+        };
+
+        let result = fetch(dir.join("logs/app.log")).await.unwrap();
+        assert_eq!(result["content"], "first\n[REDACTED]\n");
+        // Link into allowed directory is judged by its target
+        let result = fetch(dir.join("link.log")).await.unwrap();
+        assert_eq!(result["path"], dir.join("logs/app.log").to_str().unwrap());
+        let e = fetch(dir.join("logs/escape.log")).await.unwrap_err();
+        assert_eq!(crate::error::code(&e), crate::error::Code::NotAllowed);
+        let e = fetch(dir.join("logs/missing.log")).await.unwrap_err();
+        assert_eq!(crate::error::code(&e), crate::error::Code::NotAllowed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Firewall collector in `[collector.firewall]`: rule counts and default policy of every base
+//! chain from nftables and iptables on Linux, or state and default actions of every Windows
+//! Firewall profile. `unexpected` is set once firewall is found disabled while it is expected on.
+
+use crate::collector::Collector;
+use crate::configparser::config::Firewall;
+#[cfg(windows)]
+use crate::error::Error;
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "firewall";
+const QUERY_TIMEOUT: u64 = 10;
+pub const DEFAULT_EXPECTED: bool = true;
+
+async fn query(program: &str, args: &[&str]) -> anyhow::Result<String> {
+    let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+    let limits = Limits {
+        timeout: Some(QUERY_TIMEOUT),
+        ..Default::default()
+    };
+    sandbox::run(program, &args, &limits).await
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct Chain {
+    /// Default policy, base chains only
+    policy: Option<String>,
+    rules: u64,
+}
+
+#[cfg(target_os = "linux")]
+fn summarize(chains: BTreeMap<String, Chain>) -> (bool, Value) {
+    let rules = chains.values().map(|chain| chain.rules).sum::<u64>();
+    let blocking = chains.values().any(|chain| {
+        chain
+            .policy
+            .as_deref()
+            .is_some_and(|policy| matches!(policy, "drop" | "reject"))
+    });
+    let chains = chains
+        .into_iter()
+        .map(|(name, chain)| {
+            (
+                name,
+                json!({ "policy": chain.policy, "rules": chain.rules }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>();
+    (
+        rules > 0 || blocking,
+        json!({ "rules": rules, "chains": chains }),
+    )
+}
+
+/// Chains of `nft -j list ruleset`, keyed by `family table chain`.
+#[cfg(target_os = "linux")]
+fn parse_nft(output: &str) -> anyhow::Result<BTreeMap<String, Chain>> {
+    let ruleset: Value = serde_json::from_str(output)?;
+    let key = |object: &Value, name: &str| {
+        format!(
+            "{} {} {}",
+            object["family"].as_str().unwrap_or_default(),
+            object["table"].as_str().unwrap_or_default(),
+            object[name].as_str().unwrap_or_default()
+        )
+    };
+    let mut chains: BTreeMap<String, Chain> = BTreeMap::new();
+    for object in ruleset["nftables"].as_array().into_iter().flatten() {
+        if let Some(chain) = object.get("chain") {
+            chains.entry(key(chain, "name")).or_default().policy = chain["policy"]
+                .as_str()
+                .filter(|_| chain.get("hook").is_some())
+                .map(str::to_string);
+        } else if let Some(rule) = object.get("rule") {
+            chains.entry(key(rule, "chain")).or_default().rules += 1;
+        }
+    }
+    Ok(chains)
+}
+
+/// Chains of `iptables-save`, keyed by `table chain`.
+#[cfg(target_os = "linux")]
+fn parse_iptables_save(output: &str) -> BTreeMap<String, Chain> {
+    let mut chains: BTreeMap<String, Chain> = BTreeMap::new();
+    let mut table = "";
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix('*') {
+            table = name;
+        } else if let Some(declaration) = line.strip_prefix(':') {
+            let mut fields = declaration.split_whitespace();
+            let name = fields.next().unwrap_or_default();
+            // User defined chains have no policy, `-`
+            chains
+                .entry(format!("{} {}", table, name))
+                .or_default()
+                .policy = fields
+                .next()
+                .filter(|policy| *policy != "-")
+                .map(str::to_lowercase);
+        } else if let Some(rule) = line.strip_prefix("-A ") {
+            let name = rule.split_whitespace().next().unwrap_or_default();
+            chains
+                .entry(format!("{} {}", table, name))
+                .or_default()
+                .rules += 1;
+        }
+    }
+    chains
+}
+
+/// Whether any backend filters traffic, and summary of every available backend.
+#[cfg(target_os = "linux")]
+async fn inspect() -> anyhow::Result<(bool, Value)> {
+    let mut enabled = false;
+    let mut result = json!({});
+    let mut last_error = None;
+    let nft = match query("nft", &["-j", "list", "ruleset"]).await {
+        Ok(output) => parse_nft(&output),
+        Err(e) => Err(e),
+    };
+    let backends = [
+        ("nftables", nft),
+        (
+            "iptables",
+            query("iptables-save", &[])
+                .await
+                .map(|o| parse_iptables_save(&o)),
+        ),
+        (
+            "ip6tables",
+            query("ip6tables-save", &[])
+                .await
+                .map(|o| parse_iptables_save(&o)),
+        ),
+    ];
+    for (backend, chains) in backends {
+        result[backend] = match chains {
+            Ok(chains) => {
+                let (filtering, summary) = summarize(chains);
+                enabled |= filtering;
+                summary
+            }
+            Err(e) => {
+                log::debug!("Unable inspect {}: {}", backend, e);
+                last_error = Some(e);
+                Value::Null
+            }
+        };
+    }
+    if ["nftables", "iptables", "ip6tables"]
+        .iter()
+        .all(|backend| result[backend].is_null())
+    {
+        return Err(last_error.unwrap());
+    }
+    Ok((enabled, result))
+}
+
+#[cfg(windows)]
+const PROFILE_SCRIPT: &str = "Get-NetFirewallProfile | ForEach-Object { [pscustomobject]@{ \
+    Name = $_.Name; Enabled = [string]$_.Enabled; \
+    DefaultInboundAction = [string]$_.DefaultInboundAction; \
+    DefaultOutboundAction = [string]$_.DefaultOutboundAction } } | ConvertTo-Json -Compress";
+
+/// Whether every Windows Firewall profile is enabled, and state of each.
+#[cfg(windows)]
+async fn inspect() -> anyhow::Result<(bool, Value)> {
+    let output = query(
+        "powershell.exe",
+        &["-NoProfile", "-NonInteractive", "-Command", PROFILE_SCRIPT],
+    )
+    .await?;
+    let profiles =
+        match serde_json::from_str::<Value>(output.trim()).map_err(|e| Error::Malformed {
+            what: "Get-NetFirewallProfile output".to_string(),
+            message: e.to_string(),
+        })? {
+            Value::Array(profiles) => profiles,
+            // Single profile is not wrapped in array
+            profile => vec![profile],
+        };
+    let mut enabled = !profiles.is_empty();
+    let mut result = json!({ "profiles": {} });
+    for profile in &profiles {
+        let profile_enabled = profile["Enabled"].as_str() == Some("True");
+        enabled &= profile_enabled;
+        result["profiles"][profile["Name"].as_str().unwrap_or("unknown")] = json!({
+            "enabled": profile_enabled,
+            "default_inbound": profile["DefaultInboundAction"],
+            "default_outbound": profile["DefaultOutboundAction"],
+        });
+    }
+    Ok((enabled, result))
+}
+
+pub struct FirewallCollector {
+    expected: bool,
+    /// Whether firewall was enabled at previous collection
+    last: Mutex<Option<bool>>,
+}
+
+impl FirewallCollector {
+    pub fn new(cfg: &Firewall) -> Self {
+        Self {
+            expected: cfg.expected.unwrap_or(DEFAULT_EXPECTED),
+            last: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Collector for FirewallCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let (enabled, mut result) = inspect().await?;
+        let previous = self.last.lock().unwrap().replace(enabled);
+        if self.expected && !enabled && previous != Some(false) {
+            log::warn!("Firewall is disabled, but expected to be enabled");
+        }
+        result["enabled"] = json!(enabled);
+        result["unexpected"] = json!(self.expected && !enabled);
+        Ok(result)
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        ["nftables", "iptables", "ip6tables"]
+            .iter()
+            .flat_map(|backend| {
+                [
+                    (format!("{}.rules", backend), Unit::Count),
+                    (format!("{}.chains.*.rules", backend), Unit::Count),
+                ]
+            })
+            .collect()
+    }
+
+    fn source(&self) -> String {
+        if cfg!(windows) {
+            "Get-NetFirewallProfile".to_string()
+        } else {
+            "nft, iptables-save".to_string()
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Estimate days until each mount is full by linear regression over local usage history.
+
+use crate::configparser::config::Forecast as ForecastConfig;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const DEFAULT_HISTORY_FILE: &str = "disk_history.json";
+pub const DEFAULT_SAMPLE_INTERVAL: i64 = 3600;
+pub const DEFAULT_MIN_SAMPLES: usize = 6;
+const MAX_SAMPLES: usize = 24 * 14;
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+#[derive(Default, Serialize, Deserialize)]
+struct History {
+    mounts: HashMap<String, Vec<(i64, u64)>>,
+}
+
+pub struct Forecast {
+    path: PathBuf,
+    sample_interval: i64,
+    min_samples: usize,
+    history: Mutex<History>,
+}
+
+/// Least squares slope of used bytes per second.
+fn slope(samples: &[(i64, u64)]) -> Option<f64> {
+    let n = samples.len() as f64;
+    let origin = samples.first()?.0;
+    let (sum_x, sum_y) = samples.iter().fold((0.0, 0.0), |(x, y), (t, used)| {
+        (x + (t - origin) as f64, y + *used as f64)
+    });
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (mut numerator, mut denominator) = (0.0, 0.0);
+    for (t, used) in samples {
+        let dx = (t - origin) as f64 - mean_x;
+        numerator += dx * (*used as f64 - mean_y);
+        denominator += dx * dx;
+    }
+    if denominator > 0.0 {
+        Some(numerator / denominator)
+    } else {
+        None
+    }
+}
+
+impl Forecast {
+    pub fn new(cfg: &ForecastConfig) -> Self {
+        let path = match &cfg.history_file {
+            Some(path) => PathBuf::from(path),
+            None => crate::state::path(DEFAULT_HISTORY_FILE),
+        };
+        let history = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            sample_interval: cfg.sample_interval.unwrap_or(DEFAULT_SAMPLE_INTERVAL),
+            min_samples: cfg.min_samples.unwrap_or(DEFAULT_MIN_SAMPLES).max(2),
+            history: Mutex::new(history),
+        }
+    }
+
+    fn record(&self, history: &mut History, mount: &str, now: i64, used: u64) -> bool {
+        let samples = history.mounts.entry(mount.to_string()).or_default();
+        if let Some((last, _)) = samples.last() {
+            if now - last < self.sample_interval {
+                return false;
+            }
+        }
+        samples.push((now, used));
+        if samples.len() > MAX_SAMPLES {
+            samples.remove(0);
+        }
+        true
+    }
+
+    /// Add `days_until_full` to each growing mount in normalized payload.
+    pub fn annotate(&self, payload: &mut Value) {
+        let mounts = match payload.get_mut("mount").and_then(Value::as_array_mut) {
+            Some(mounts) => mounts,
+            None => return,
+        };
+        let now = chrono::Utc::now().timestamp();
+        let mut history = self.history.lock().unwrap();
+        let mut changed = false;
+        for mount in mounts.iter_mut() {
+            let (name, avail, total) = match (
+                mount.get("mount_on").and_then(Value::as_str),
+                mount.get("mount_avail").and_then(Value::as_u64),
+                mount.get("mount_total").and_then(Value::as_u64),
+            ) {
+                (Some(name), Some(avail), Some(total)) if total > 0 => {
+                    (name.to_string(), avail, total)
+                }
+                _ => continue,
+            };
+            changed |= self.record(&mut history, &name, now, total.saturating_sub(avail));
+            let samples = &history.mounts[&name];
+            if samples.len() < self.min_samples {
+                continue;
+            }
+            if let Some(rate) = slope(samples).filter(|rate| *rate > 0.0) {
+                mount["days_until_full"] = serde_json::json!(avail as f64 / rate / SECONDS_PER_DAY);
+            }
+        }
+        if changed {
+            match serde_json::to_vec(&*history) {
+                Ok(bytes) => {
+                    if let Err(e) = crate::state::write(&self.path, bytes) {
+                        warn!(
+                            "Unable save disk history to {}: {:?}",
+                            self.path.display(),
+                            e
+                        );
+                    }
+                }
+                Err(e) => warn!("Unable serialize disk history: {:?}", e),
+            }
+        }
+    }
+}
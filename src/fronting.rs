@@ -0,0 +1,148 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Domain fronting with `server.sni_hostname`: connect to address of server URL while presenting
+//! another name in TLS handshake, and verify certificate against that name.
+//!
+//! Requests to HTTPS server URL are sent to URL with host replaced by `sni_hostname`, through
+//! a client whose resolver maps that name back to host of original URL. One client is built for
+//! each distinct host, so backup servers and alternates keep their own address.
+
+use crate::configparser::config::Configure;
+use crate::error::Error;
+use hyper::client::connect::dns::Name;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Resolve `name` to addresses of `target`, other names as usual.
+struct Resolver {
+    name: String,
+    target: String,
+    inner: Option<Arc<crate::resolver::Resolver>>,
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+        let host = if name.as_str().eq_ignore_ascii_case(&self.name) {
+            self.target.clone()
+        } else {
+            name.as_str().to_string()
+        };
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                let addrs: reqwest::dns::Addrs = Box::new(std::iter::once(SocketAddr::new(ip, 0)));
+                return Ok(addrs);
+            }
+            match inner {
+                Some(inner) => {
+                    let name: Name = host.parse()?;
+                    reqwest::dns::Resolve::resolve(&*inner, name).await
+                }
+                None => {
+                    let addrs: reqwest::dns::Addrs =
+                        Box::new(tokio::net::lookup_host((host, 0)).await?);
+                    Ok(addrs)
+                }
+            }
+        })
+    }
+}
+
+pub struct Fronting {
+    sni_hostname: String,
+    /// Client for each host of server URLs
+    clients: HashMap<String, reqwest::Client>,
+}
+
+impl Fronting {
+    /// `None` if `sni_hostname` is not set. `build` finishes client builder with
+    /// same settings as regular client.
+    pub fn new<'a>(
+        cfg: &Configure,
+        urls: impl Iterator<Item = &'a String>,
+        build: impl Fn(reqwest::ClientBuilder) -> reqwest::Result<reqwest::Client>,
+    ) -> anyhow::Result<Option<Self>> {
+        let sni_hostname = match &cfg.server.sni_hostname {
+            Some(sni_hostname) => sni_hostname.to_ascii_lowercase(),
+            None => return Ok(None),
+        };
+        if cfg.tor.is_some() || cfg.tunnel.is_some() {
+            return Err(Error::Configure {
+                field: "server.sni_hostname",
+                message: "sni_hostname can not be used with [tor] or [tunnel]".to_string(),
+            }
+            .into());
+        }
+        rustls::ServerName::try_from(sni_hostname.as_str()).map_err(|_| Error::Configure {
+            field: "server.sni_hostname",
+            message: format!("Invalid sni_hostname: {}", sni_hostname),
+        })?;
+        let inner = crate::resolver::from_config(cfg)?;
+        let mut clients = HashMap::new();
+        for url in urls {
+            let host = match host(url) {
+                Some(host) => host,
+                None => continue,
+            };
+            if clients.contains_key(&host) {
+                continue;
+            }
+            let resolver = Resolver {
+                name: sni_hostname.clone(),
+                target: host.clone(),
+                inner: inner.clone(),
+            };
+            let builder = crate::session::client_builder(cfg)?.dns_resolver(Arc::new(resolver));
+            clients.insert(host, build(builder)?);
+        }
+        Ok(Some(Self {
+            sni_hostname,
+            clients,
+        }))
+    }
+
+    pub fn sni_hostname(&self) -> &str {
+        &self.sni_hostname
+    }
+
+    /// Client and rewritten URL to send request for `url`, `None` if it is not fronted.
+    pub fn route(&self, url: &str) -> Option<(&reqwest::Client, String)> {
+        let client = self.clients.get(&host(url)?)?;
+        let mut url = reqwest::Url::parse(url).ok()?;
+        url.set_host(Some(&self.sni_hostname)).ok()?;
+        Some((client, url.to_string()))
+    }
+}
+
+/// Host of HTTPS URL, without brackets of IPv6 address.
+fn host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if url.scheme() != "https" {
+        return None;
+    }
+    Some(
+        url.host_str()?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase(),
+    )
+}
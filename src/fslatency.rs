@@ -0,0 +1,174 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Filesystem latency probe in `[[check.fs_latency]]`: timed write, fsync and read of a small
+//! temporary file in each configured directory, to catch dying disks and overloaded SANs that
+//! capacity metrics miss. Page cache is dropped before reading back where supported.
+
+use crate::collector::Collector;
+use crate::configparser::config::FsLatencyCheck;
+use crate::error::Error;
+use crate::normalize::Unit;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use ring::rand::SecureRandom;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const COLLECTOR_NAME: &str = "fs_latency";
+pub const DEFAULT_SIZE: usize = 4096;
+pub const DEFAULT_TIMEOUT: u64 = 10;
+
+#[cfg(unix)]
+fn drop_cache(file: &std::fs::File) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        use std::os::unix::io::AsRawFd;
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+}
+
+#[cfg(not(unix))]
+fn drop_cache(_file: &std::fs::File) {}
+
+struct Timings {
+    write: f64,
+    fsync: f64,
+    read: f64,
+}
+
+fn measure(path: &Path, data: &[u8]) -> anyhow::Result<Timings> {
+    let mut start = Instant::now();
+    let mut lap = || {
+        let elapsed = start.elapsed().as_secs_f64();
+        start = Instant::now();
+        elapsed
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    file.write_all(data)?;
+    let write = lap();
+    file.sync_all()?;
+    let fsync = lap();
+    drop_cache(&file);
+    drop(file);
+    lap();
+    let mut buffer = Vec::with_capacity(data.len());
+    std::fs::File::open(path)?.read_to_end(&mut buffer)?;
+    let read = lap();
+    if buffer != data {
+        return Err(Error::Io {
+            operation: "verify",
+            path: path.display().to_string(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "data read back differs from written",
+            ),
+        }
+        .into());
+    }
+    Ok(Timings { write, fsync, read })
+}
+
+fn probe(directory: &Path, size: usize) -> anyhow::Result<Timings> {
+    let mut data = vec![0u8; size];
+    ring::rand::SystemRandom::new()
+        .fill(&mut data)
+        .map_err(|_| Error::Crypto {
+            message: "Unable generate test data".to_string(),
+        })?;
+    let path = directory.join(format!(
+        ".probe-client-{}-{:02x}{:02x}",
+        std::process::id(),
+        data.first().unwrap_or(&0),
+        data.last().unwrap_or(&0)
+    ));
+    let result = measure(&path, &data);
+    std::fs::remove_file(&path).ok();
+    result
+}
+
+pub struct FsLatencyCollector {
+    checks: Vec<FsLatencyCheck>,
+}
+
+impl FsLatencyCollector {
+    pub fn new(checks: &[FsLatencyCheck]) -> Self {
+        Self {
+            checks: checks.to_vec(),
+        }
+    }
+
+    async fn inspect(check: &FsLatencyCheck) -> anyhow::Result<Value> {
+        let directory = PathBuf::from(&check.path);
+        let size = check.size.unwrap_or(DEFAULT_SIZE);
+        let timeout = check.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        // A hung disk keeps the blocking thread, but heartbeat goes on
+        let timings = tokio::time::timeout(
+            Duration::from_secs(timeout),
+            tokio::task::spawn_blocking(move || probe(&directory, size)),
+        )
+        .await
+        .map_err(|_| Error::Timeout {
+            error: anyhow!("Timeout after {} seconds", timeout),
+        })???;
+        Ok(serde_json::json!({
+            "write": timings.write,
+            "fsync": timings.fsync,
+            "read": timings.read,
+            "total": timings.write + timings.fsync + timings.read,
+            "ok": true,
+        }))
+    }
+}
+
+#[async_trait]
+impl Collector for FsLatencyCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let mut result = serde_json::Map::new();
+        for check in &self.checks {
+            let value = Self::inspect(check)
+                .await
+                .unwrap_or_else(|e| serde_json::json!({ "ok": false, "error": e.to_string() }));
+            result.insert(check.path.clone(), value);
+        }
+        Ok(Value::Object(result))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        ["write", "fsync", "read", "total"]
+            .iter()
+            .map(|field| (format!("*.{}", field), Unit::Seconds))
+            .collect()
+    }
+
+    fn source(&self) -> String {
+        "filesystem latency probe".to_string()
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Hand over session state from running process to its replacement during upgrade,
+//! so the new process continues without reporting gap or duplicate registration.
+//!
+//! Running process listens on `probe_handover.sock` in state directory. A new process started with `--takeover`
+//! connects to it and sends its [`Identity`]. If identification and configure path are the same, the old one
+//! replies with its pending backlog, sequence counter and server state, then exits without sending `shutdown`.
+//! Otherwise the request is refused and the old one keeps running. Only supported on Unix.
+
+use crate::downsample::Sample;
+use crate::error::Error;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Formatter;
+#[cfg(unix)]
+use std::time::Duration;
+
+pub const HANDOVER_SOCKET: &str = "probe_handover.sock";
+/// Running process only replies between heartbeats.
+#[cfg(unix)]
+const REQUEST_TIMEOUT: u64 = 60;
+
+/// Sent by new process, must match running one to take over.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub struct Identity {
+    pub uuid: String,
+    /// Configure file, absolute if possible
+    pub config: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HandoverState {
+    pub version: String,
+    pub seq: u64,
+    pub clock_offset: i64,
+    pub server_version: String,
+    #[serde(default)]
+    pub experiments: Vec<String>,
+    pub backlog: Vec<Sample>,
+}
+
+#[derive(Debug)]
+pub struct HandedOverError;
+
+impl std::error::Error for HandedOverError {}
+
+impl std::fmt::Display for HandedOverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Session handed over to new process")
+    }
+}
+
+#[cfg(unix)]
+pub struct Listener {
+    listener: tokio::net::UnixListener,
+}
+
+#[cfg(not(unix))]
+pub struct Listener;
+
+/// Take over state from running process with same `identity` if any.
+#[cfg(unix)]
+pub async fn request(identity: &Identity) -> Option<HandoverState> {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let mut stream = tokio::net::UnixStream::connect(crate::state::path(HANDOVER_SOCKET))
+        .await
+        .ok()?;
+    info!("Found running process, request handover");
+    let mut request = serde_json::to_vec(identity).unwrap();
+    request.push(b'\n');
+    if let Err(e) = stream.write_all(&request).await {
+        warn!("Unable send handover request: {:?}", e);
+        return None;
+    }
+    let mut buffer = Vec::new();
+    match tokio::time::timeout(
+        Duration::from_secs(REQUEST_TIMEOUT),
+        stream.read_to_end(&mut buffer),
+    )
+    .await
+    {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            warn!("Unable receive handover state: {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            warn!(
+                "Running process did not hand over in {} seconds",
+                REQUEST_TIMEOUT
+            );
+            return None;
+        }
+    }
+    if buffer.is_empty() {
+        warn!("Running process refused handover, identification or configure differs");
+        return None;
+    }
+    match serde_json::from_slice::<HandoverState>(&buffer) {
+        Ok(state) => {
+            info!(
+                "Take over session from running process (version {}, seq {}, {} pending heartbeats)",
+                state.version,
+                state.seq,
+                state.backlog.len()
+            );
+            Some(state)
+        }
+        Err(e) => {
+            warn!("Unable parse handover state: {:?}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn request(_identity: &Identity) -> Option<HandoverState> {
+    None
+}
+
+impl Listener {
+    /// Listen for handover request, replace stale socket left by previous process.
+    /// Socket of another running process is kept, so it can still hand over,
+    /// unless its session was `handed_over` to this one.
+    #[cfg(unix)]
+    pub fn bind(handed_over: bool) -> anyhow::Result<Self> {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let path = crate::state::path(HANDOVER_SOCKET);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if !handed_over && std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            return Err(Error::Io {
+                operation: "bind",
+                path: path.display().to_string(),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    "another process is listening",
+                ),
+            }
+            .into());
+        }
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        // Any local user could otherwise take over the session and make this process exit
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self { listener })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(_handed_over: bool) -> anyhow::Result<Self> {
+        Ok(Self)
+    }
+
+    /// Wait for new process with same `identity` and send `state` to it,
+    /// return `HandedOverError` once sent.
+    #[cfg(unix)]
+    pub async fn serve<F: Fn() -> HandoverState>(
+        &self,
+        identity: &Identity,
+        state: F,
+    ) -> anyhow::Error {
+        use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _};
+
+        loop {
+            let mut stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Unable accept handover request: {:?}", e);
+                    continue;
+                }
+            };
+            let mut line = String::new();
+            let mut reader = tokio::io::BufReader::new(&mut stream);
+            if tokio::time::timeout(
+                Duration::from_secs(REQUEST_TIMEOUT),
+                reader.read_line(&mut line),
+            )
+            .await
+            .is_err()
+            {
+                warn!(
+                    "Handover request not received in {} seconds",
+                    REQUEST_TIMEOUT
+                );
+                continue;
+            }
+            // Probe of `bind` by another process
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Identity>(&line) {
+                Ok(requested) if &requested == identity => {}
+                Ok(requested) => {
+                    warn!(
+                        "Refuse handover to process of {} with configure {}",
+                        requested.uuid, requested.config
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Refuse invalid handover request: {:?}", e);
+                    continue;
+                }
+            }
+            let data = serde_json::to_vec(&state()).unwrap();
+            match stream.write_all(&data).await {
+                Ok(()) => {
+                    stream.shutdown().await.ok();
+                    info!("Session handed over to new process");
+                    return anyhow::Error::new(HandedOverError);
+                }
+                Err(e) => warn!("Unable send handover state: {:?}", e),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn serve<F: Fn() -> HandoverState>(
+        &self,
+        _identity: &Identity,
+        _state: F,
+    ) -> anyhow::Error {
+        std::future::pending().await
+    }
+}
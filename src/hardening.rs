@@ -0,0 +1,553 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Optional self-confinement on Linux, reducing blast radius if a collector or dependency is exploited.
+//!
+//! Landlock limits file access to state directory and configure (read-write), `/proc`, `/sys`,
+//! files needed for name resolution, user lookup and machine identity, system files read by
+//! collectors (read-only), helper programs in system directories (execute), plus files named by
+//! configure. It is applied before dropping privileges, so user and group files stay readable.
+//! A seccomp filter denies syscalls the client never needs, like `mount`, `ptrace` or module loading.
+//!
+//! Should be applied before any other thread is started, Landlock only restricts the calling thread
+//! and threads created afterwards.
+
+use crate::configparser::config::Configure;
+#[cfg(target_os = "linux")]
+use log::{info, warn};
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+/// Needed for name resolution, user and group lookup, machine identity, time zone and child
+/// processes, plus system files read by collectors.
+#[cfg(target_os = "linux")]
+const SYSTEM_READ_PATHS: [&str; 25] = [
+    "/proc",
+    "/sys",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/etc/localtime",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/machine-id",
+    "/etc/selinux/config",
+    "/dev/urandom",
+    "/dev/kmsg",
+    "/boot",
+    "/run/reboot-required",
+    "/run/reboot-required.pkgs",
+    "/run/reboot-needed",
+    "/run/cloud-init/instance-data.json",
+    "/run/log/journal",
+    "/var/lib/dbus/machine-id",
+    "/var/lib/dpkg",
+    "/var/lib/rpm",
+    "/var/lib/pacman",
+    "/var/log",
+];
+/// Dynamic loader and shared libraries, needed to run allowed executables.
+#[cfg(target_os = "linux")]
+const SYSTEM_LIBRARY_PATHS: [&str; 3] = ["/lib", "/lib64", "/usr/lib"];
+/// Helper programs run by collectors and actions (ssh, package managers, firewall and TPM tools).
+#[cfg(target_os = "linux")]
+const SYSTEM_EXECUTE_PATHS: [&str; 6] = [
+    "/bin",
+    "/sbin",
+    "/usr/bin",
+    "/usr/sbin",
+    "/usr/local/bin",
+    "/usr/local/sbin",
+];
+#[cfg(target_os = "linux")]
+const SYSTEM_WRITE_PATHS: [&str; 3] = ["/dev/null", "/dev/tpmrm0", "/run/xtables.lock"];
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::os::unix::ffi::OsStrExt as _;
+    use std::path::Path;
+
+    pub const ACCESS_EXECUTE: u64 = 1 << 0;
+    pub const ACCESS_WRITE_FILE: u64 = 1 << 1;
+    pub const ACCESS_READ_FILE: u64 = 1 << 2;
+    pub const ACCESS_READ_DIR: u64 = 1 << 3;
+    /// Every access right of ABI version 1.
+    pub const ACCESS_ALL: u64 = (1 << 13) - 1;
+    pub const ACCESS_READ: u64 = ACCESS_READ_FILE | ACCESS_READ_DIR;
+    const FILE_ACCESS: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub struct Ruleset {
+        fd: libc::c_int,
+    }
+
+    impl Ruleset {
+        pub fn new() -> std::io::Result<Self> {
+            let attr = RulesetAttr {
+                handled_access_fs: ACCESS_ALL,
+            };
+            // Safety: attr follows the layout of linux/landlock.h
+            let fd = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    &attr,
+                    std::mem::size_of::<RulesetAttr>(),
+                    0,
+                )
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self {
+                fd: fd as libc::c_int,
+            })
+        }
+
+        /// Allow `access` beneath `path`, missing paths are skipped.
+        pub fn allow(&self, path: &Path, access: u64) -> std::io::Result<()> {
+            let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+            // Safety: c_path is a valid C string
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return Ok(());
+            }
+            let access = if path.is_dir() {
+                access
+            } else {
+                access & FILE_ACCESS
+            };
+            let attr = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: fd,
+            };
+            // Safety: attr follows the layout of linux/landlock.h, fd is opened above
+            let result = unsafe {
+                let result = libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    self.fd,
+                    RULE_PATH_BENEATH,
+                    &attr,
+                    0,
+                );
+                libc::close(fd);
+                result
+            };
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Restrict current thread and threads created afterwards.
+        pub fn restrict_self(self) -> std::io::Result<()> {
+            // Safety: plain prctl and landlock calls
+            unsafe {
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || libc::syscall(libc::SYS_landlock_restrict_self, self.fd, 0) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Ruleset {
+        fn drop(&mut self) {
+            // Safety: fd is owned by ruleset
+            unsafe {
+                libc::close(self.fd);
+            }
+        }
+    }
+}
+
+/// Leading components of glob `pattern` without wildcard.
+#[cfg(target_os = "linux")]
+fn glob_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?']))
+        .collect()
+}
+
+/// Files the client reads by configure.
+#[cfg(target_os = "linux")]
+fn configured_reads(cfg: &Configure) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    if let Some(watch) = &cfg.watch {
+        paths.extend(watch.file.iter().flatten().map(|f| PathBuf::from(&f.path)));
+        paths.extend(
+            watch
+                .mac
+                .as_ref()
+                .and_then(|mac| mac.audit_log.as_ref())
+                .map(PathBuf::from),
+        );
+    }
+    if let Some(checks) = cfg.check.as_ref().and_then(|c| c.certificate.as_ref()) {
+        paths.extend(
+            checks
+                .iter()
+                .filter_map(|c| c.file.as_ref().map(PathBuf::from)),
+        );
+    }
+    if let Some(mtls) = &cfg.server.mtls {
+        paths.extend(mtls.certificate.iter().chain(&mtls.key).map(PathBuf::from));
+    }
+    if let Some(talkers) = cfg.collector.as_ref().and_then(|c| c.talkers.as_ref()) {
+        paths.extend(talkers.asn_database.as_ref().map(PathBuf::from));
+    }
+    if let Some(policy) = &cfg.policy {
+        paths.push(PathBuf::from(&policy.module));
+    }
+    if let Some(tunnel) = &cfg.tunnel {
+        paths.extend(
+            tunnel
+                .identity_file
+                .iter()
+                .chain(&tunnel.known_hosts)
+                .map(PathBuf::from),
+        );
+    }
+    if let Some(remote_access) = &cfg.remote_access {
+        paths.extend(
+            remote_access
+                .identity_file
+                .iter()
+                .chain(&remote_access.known_hosts)
+                .map(PathBuf::from),
+        );
+    }
+    if let Some(fetch_file) = cfg.action.as_ref().and_then(|a| a.fetch_file.as_ref()) {
+        paths.extend(fetch_file.allow.iter().map(|pattern| glob_base(pattern)));
+    }
+    paths
+}
+
+/// Files the client writes by configure.
+#[cfg(target_os = "linux")]
+fn configured_writes(cfg: &Configure) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    if let Some(spool) = &cfg.statistics.spool {
+        paths.extend(spool.path.as_ref().map(PathBuf::from));
+    }
+    if let Some(forecast) = &cfg.forecast {
+        paths.extend(forecast.history_file.as_ref().map(PathBuf::from));
+    }
+    if let Some(audit) = &cfg.audit {
+        paths.extend(audit.path.as_ref().map(PathBuf::from));
+    }
+    if let Some(signing) = &cfg.signing {
+        paths.extend(signing.key.as_ref().map(PathBuf::from));
+    }
+    if let Some(checks) = cfg.check.as_ref().and_then(|c| c.fs_latency.as_ref()) {
+        paths.extend(checks.iter().map(|check| PathBuf::from(&check.path)));
+    }
+    paths
+}
+
+/// Programs the client runs by configure, those given by absolute path outside system
+/// directories need their own rule.
+#[cfg(target_os = "linux")]
+fn configured_executes(cfg: &Configure) -> Vec<PathBuf> {
+    let mut commands: Vec<&String> = Vec::new();
+    if let Some(scripts) = cfg.collector.as_ref().and_then(|c| c.script.as_ref()) {
+        commands.extend(scripts.iter().map(|script| &script.command));
+    }
+    if let Some(exec) = cfg.action.as_ref().and_then(|a| a.exec.as_ref()) {
+        commands.extend(exec.command.iter().flatten().map(|c| &c.command));
+    }
+    if let Some(command) = cfg.fallback.as_ref().and_then(|f| f.command.as_ref()) {
+        commands.push(&command.command);
+    }
+    commands.extend(cfg.tunnel.as_ref().and_then(|t| t.ssh_command.as_ref()));
+    commands.extend(
+        cfg.remote_access
+            .as_ref()
+            .and_then(|r| r.ssh_command.as_ref()),
+    );
+    commands
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|command| command.is_absolute())
+        .collect()
+}
+
+/// Landlock rules of `cfg`: access allowed beneath each path.
+#[cfg(target_os = "linux")]
+fn rules(cfg: &Configure, cfg_path: &Path) -> Vec<(PathBuf, u64)> {
+    let hardening = cfg.hardening.as_ref().unwrap();
+    let list = |paths: &Option<Vec<String>>| {
+        paths
+            .iter()
+            .flatten()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+    };
+    let read_write = landlock::ACCESS_READ_FILE | landlock::ACCESS_WRITE_FILE;
+    let execute = landlock::ACCESS_READ | landlock::ACCESS_EXECUTE;
+
+    let mut rules = vec![
+        (crate::state::dir().to_path_buf(), landlock::ACCESS_ALL),
+        (cfg_path.to_path_buf(), read_write),
+    ];
+    rules.extend(
+        SYSTEM_WRITE_PATHS
+            .iter()
+            .map(|path| (PathBuf::from(path), read_write)),
+    );
+    rules.extend(
+        SYSTEM_READ_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .chain(configured_reads(cfg))
+            .chain(list(&hardening.allow_read))
+            .map(|path| (path, landlock::ACCESS_READ)),
+    );
+    rules.extend(
+        SYSTEM_LIBRARY_PATHS
+            .iter()
+            .chain(SYSTEM_EXECUTE_PATHS.iter())
+            .map(PathBuf::from)
+            .chain(configured_executes(cfg))
+            .chain(list(&hardening.allow_execute))
+            .map(|path| (path, execute)),
+    );
+    rules.extend(
+        configured_writes(cfg)
+            .into_iter()
+            .chain(list(&hardening.allow_write))
+            .map(|path| (path, landlock::ACCESS_ALL)),
+    );
+    rules
+}
+
+#[cfg(target_os = "linux")]
+fn confine_files(cfg: &Configure, cfg_path: &Path) -> std::io::Result<()> {
+    if !crate::state::is_read_only() {
+        std::fs::create_dir_all(crate::state::dir())?;
+    }
+    let ruleset = landlock::Ruleset::new()?;
+    for (path, access) in rules(cfg, cfg_path) {
+        ruleset.allow(&path, access)?;
+    }
+    ruleset.restrict_self()
+}
+
+#[cfg(target_os = "linux")]
+const DENIED_SYSCALLS: [libc::c_long; 9] = [
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_kexec_file_load,
+];
+
+/// Syscalls used by the eBPF `tcp` collector to load programs and attach them to tracepoints.
+#[cfg(target_os = "linux")]
+const EBPF_SYSCALLS: [libc::c_long; 2] = [libc::SYS_bpf, libc::SYS_perf_event_open];
+
+/// Syscalls denied to every thread, except those needed by configured collectors.
+#[cfg(target_os = "linux")]
+fn denied_syscalls(cfg: &Configure) -> Vec<libc::c_long> {
+    let ebpf = cfg
+        .collector
+        .as_ref()
+        .is_some_and(|c| c.tcp.as_ref().is_some_and(|t| t.enabled));
+    crate::sandbox::DENIED_SYSCALLS
+        .iter()
+        .chain(DENIED_SYSCALLS.iter())
+        .filter(|syscall| !(ebpf && EBPF_SYSCALLS.contains(syscall)))
+        .copied()
+        .collect()
+}
+
+/// Apply `[hardening]` if enabled, `cfg_path` is the configure location.
+#[cfg(target_os = "linux")]
+pub fn apply(cfg: &Configure, cfg_path: &Path) -> anyhow::Result<()> {
+    if !cfg.hardening.as_ref().is_some_and(|h| h.enabled) {
+        return Ok(());
+    }
+    match confine_files(cfg, cfg_path) {
+        Ok(()) => info!("Landlock file access rules applied"),
+        Err(e)
+            if e.raw_os_error() == Some(libc::ENOSYS)
+                || e.raw_os_error() == Some(libc::EOPNOTSUPP) =>
+        {
+            warn!("Landlock is not supported by kernel, skip file access rules")
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let denied = denied_syscalls(cfg);
+    seccompiler::apply_filter_all_threads(&crate::sandbox::build_seccomp_filter(&denied)?)?;
+    info!("Seccomp filter applied");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(cfg: &Configure, _cfg_path: &Path) -> anyhow::Result<()> {
+    if cfg.hardening.as_ref().is_some_and(|h| h.enabled) {
+        log::warn!("Hardening is only supported on Linux, ignored");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn covers(rules: &[(PathBuf, u64)], path: &str, access: u64) -> bool {
+        rules
+            .iter()
+            .any(|(rule, allowed)| Path::new(path).starts_with(rule) && allowed & access == access)
+    }
+
+    #[test]
+    fn rules_cover_paths_opened_by_features() {
+        crate::state::init_for_test();
+        let cfg: Configure = toml::from_str(
+            r#"
+            [server]
+            server_address = "https://probe.example"
+            token = "token"
+            [server.mtls]
+            certificate = "/etc/probe/cert.pem"
+            key = "/etc/probe/key.pem"
+            [statistics]
+            enabled = true
+            [statistics.spool]
+            path = "/var/spool/probe"
+            [hardening]
+            enabled = true
+            [[collector.script]]
+            name = "custom"
+            command = "/opt/probe/collect"
+            [action.fetch_file]
+            enabled = true
+            allow = ["/var/log/app/*.log"]
+            [[check.fs_latency]]
+            path = "/mnt/data"
+            "#,
+        )
+        .unwrap();
+        let rules = rules(&cfg, Path::new("/etc/probe/probe_client.toml"));
+        let read = landlock::ACCESS_READ_FILE;
+        let read_write = landlock::ACCESS_READ_FILE | landlock::ACCESS_WRITE_FILE;
+        let execute = landlock::ACCESS_EXECUTE;
+
+        // Privilege drop and identity
+        for path in ["/etc/passwd", "/etc/group", "/etc/nsswitch.conf"]
+            .iter()
+            .chain(crate::session::MACHINE_ID_PATHS.iter())
+        {
+            assert!(covers(&rules, path, read), "{} not readable", path);
+        }
+        // Collectors reading system files
+        for path in [
+            crate::reboot::BOOT_DIR,
+            crate::reboot::MODULES_DIR,
+            crate::virt::CLOUD_INIT_DATA_PATH,
+            crate::kmsg::KMSG_PATH,
+            crate::mac::SELINUX_CONFIG,
+            crate::mac::DEFAULT_AUDIT_LOG,
+        ] {
+            assert!(covers(&rules, path, read), "{} not readable", path);
+        }
+        for (flag, packages) in crate::reboot::FLAG_FILES.iter() {
+            let flag = flag.trim_start_matches("/var");
+            assert!(covers(&rules, flag, read), "{} not readable", flag);
+            if let Some(packages) = packages {
+                let packages = packages.trim_start_matches("/var");
+                assert!(covers(&rules, packages, read), "{} not readable", packages);
+            }
+        }
+        // Helper programs, looked up in PATH
+        let helpers = ["ssh", "journalctl", "nft", "iptables-save", "tpm2_sign"]
+            .iter()
+            .copied()
+            .chain(
+                crate::packages::MANAGERS
+                    .iter()
+                    .map(|(_, program, _)| *program),
+            );
+        for helper in helpers {
+            for dir in ["/bin", "/usr/bin", "/usr/sbin"] {
+                let path = format!("{}/{}", dir, helper);
+                assert!(covers(&rules, &path, execute), "{} not executable", path);
+            }
+        }
+        assert!(covers(&rules, crate::reboot::NEEDS_RESTARTING, execute));
+        assert!(covers(&rules, "/dev/tpmrm0", read_write));
+        // Configured paths
+        assert!(covers(&rules, "/etc/probe/probe_client.toml", read_write));
+        assert!(covers(&rules, "/etc/probe/cert.pem", read));
+        assert!(covers(&rules, "/etc/probe/key.pem", read));
+        assert!(covers(&rules, "/var/spool/probe/1.json", read_write));
+        assert!(covers(&rules, "/opt/probe/collect", execute));
+        assert!(covers(&rules, "/var/log/app/error.log", read));
+        assert!(covers(&rules, "/mnt/data/probe", read_write));
+        assert!(!covers(&rules, "/etc/shadow", read));
+        assert!(!covers(&rules, "/opt/other", execute));
+    }
+
+    #[test]
+    fn ebpf_collector_keeps_its_syscalls() {
+        let base = r#"
+            [server]
+            server_address = "https://probe.example"
+            token = "token"
+            [statistics]
+            enabled = true
+            [hardening]
+            enabled = true
+            "#;
+        let cfg: Configure = toml::from_str(base).unwrap();
+        let denied = denied_syscalls(&cfg);
+        assert!(EBPF_SYSCALLS.iter().all(|syscall| denied.contains(syscall)));
+        assert!(denied.contains(&libc::SYS_ptrace));
+
+        let cfg: Configure =
+            toml::from_str(&format!("{}[collector.tcp]\nenabled = true\n", base)).unwrap();
+        let denied = denied_syscalls(&cfg);
+        assert!(EBPF_SYSCALLS
+            .iter()
+            .all(|syscall| !denied.contains(syscall)));
+        assert!(denied.contains(&libc::SYS_ptrace));
+    }
+}
@@ -0,0 +1,81 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `history` subcommand: recent heartbeats kept by dashboard in `probe_dashboard_history.json`
+//! of state directory, oldest first, without a running client.
+//!
+//! With `--output json` it prints `{"points": [{"timestamp", "ok", "cpu", "memory", "load"}]}`:
+//! `timestamp` in unix seconds, `cpu` and `memory` in percent, `load` is load average per CPU.
+
+use chrono::TimeZone as _;
+
+pub const SUBCOMMAND_NAME: &str = "history";
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Show recent heartbeats kept by dashboard")
+        .arg(
+            clap::Arg::with_name("limit")
+                .long("limit")
+                .takes_value(true)
+                .help("Show only last N heartbeats")
+                .validator(|value| {
+                    value
+                        .parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|e| format!("Invalid limit {}: {}", value, e))
+                }),
+        )
+        .arg(crate::output::arg())
+}
+
+pub fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    let history = crate::dashboard::load_history();
+    let limit = matches
+        .value_of("limit")
+        .map_or(history.len(), |limit| limit.parse().unwrap());
+    let points = history
+        .into_iter()
+        .rev()
+        .take(limit)
+        .rev()
+        .collect::<Vec<_>>();
+    if crate::output::of(matches) == crate::output::Format::Json {
+        return crate::output::print_json(&serde_json::json!({ "points": points }));
+    }
+    let value = |value: Option<f64>, precision: usize| {
+        value.map_or_else(|| "-".to_string(), |v| format!("{:.*}", precision, v))
+    };
+    for point in points {
+        let time = chrono::Utc
+            .timestamp_opt(point.timestamp, 0)
+            .single()
+            .map_or_else(|| point.timestamp.to_string(), |time| time.to_rfc3339());
+        println!(
+            "{} {:<6} cpu {:>5}% memory {:>5}% load {:>5}",
+            time,
+            if point.ok { "ok" } else { "failed" },
+            value(point.cpu, 1),
+            value(point.memory, 1),
+            value(point.load, 2)
+        );
+    }
+    Ok(())
+}
@@ -164,6 +164,129 @@ impl From<&systemstat::LoadAverage> for LoadAvg {
     }
 }
 
+/// Connection tracking table usage, exhaustion silently drops new connections on gateways.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize)]
+struct Conntrack {
+    count: u64,
+    max: u64,
+    usage: f64,
+}
+
+#[cfg(target_os = "linux")]
+impl Conntrack {
+    /// `None` if nf_conntrack is not loaded.
+    fn read() -> Option<Self> {
+        let read = |name: &str| -> Option<u64> {
+            std::fs::read_to_string(format!("/proc/sys/net/netfilter/{}", name))
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+        };
+        let count = read("nf_conntrack_count")?;
+        let max = read("nf_conntrack_max")?;
+        Some(Self {
+            count,
+            max,
+            usage: if max > 0 {
+                count as f64 * 100.0 / max as f64
+            } else {
+                0.0
+            },
+        })
+    }
+}
+
+/// Share of time tasks were stalled on a resource, averaged over 10, 60 and 300 seconds,
+/// `total` is cumulative stall time in seconds.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize)]
+struct PressureLine {
+    avg10: f64,
+    avg60: f64,
+    avg300: f64,
+    total: f64,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize)]
+struct PressureResource {
+    /// Some tasks stalled
+    some: PressureLine,
+    /// All non-idle tasks stalled, not reported for CPU by older kernels
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    full: Option<PressureLine>,
+}
+
+/// Pressure Stall Information from `/proc/pressure`, a better saturation signal than load average.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Pressure {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cpu: Option<PressureResource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memory: Option<PressureResource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    io: Option<PressureResource>,
+}
+
+#[cfg(target_os = "linux")]
+impl Pressure {
+    fn parse_line(line: &str) -> Option<(&str, PressureLine)> {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next()?;
+        let values: HashMap<&str, f64> = fields
+            .filter_map(|field| {
+                let (key, value) = field.split_once('=')?;
+                Some((key, value.parse().ok()?))
+            })
+            .collect();
+        Some((
+            kind,
+            PressureLine {
+                avg10: *values.get("avg10")?,
+                avg60: *values.get("avg60")?,
+                avg300: *values.get("avg300")?,
+                total: *values.get("total")? / 1_000_000.0,
+            },
+        ))
+    }
+
+    fn resource(name: &str) -> Option<PressureResource> {
+        let contents = std::fs::read_to_string(format!("/proc/pressure/{}", name)).ok()?;
+        let mut some = None;
+        let mut full = None;
+        for (kind, line) in contents.lines().filter_map(Self::parse_line) {
+            match kind {
+                "some" => some = Some(line),
+                "full" => full = Some(line),
+                _ => {}
+            }
+        }
+        Some(PressureResource { some: some?, full })
+    }
+
+    /// 10 seconds average share of time some tasks stalled on `resource`.
+    pub(crate) fn some_avg10(resource: &str) -> Option<f64> {
+        Self::resource(resource).map(|resource| resource.some.avg10)
+    }
+
+    /// `None` if kernel is built without PSI or it is disabled.
+    fn read() -> Option<Self> {
+        let pressure = Self {
+            cpu: Self::resource("cpu"),
+            memory: Self::resource("memory"),
+            io: Self::resource("io"),
+        };
+        if pressure.cpu.is_none() && pressure.memory.is_none() && pressure.io.is_none() {
+            None
+        } else {
+            Some(pressure)
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CpuLoadInfo {
     user: f32,
@@ -192,7 +315,27 @@ pub struct PostInfo {
     cpu: CpuLoadInfo,
     #[cfg(unix)]
     loadavg: LoadAvg,
+    #[cfg(target_os = "linux")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conntrack: Option<Conntrack>,
+    #[cfg(target_os = "linux")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pressure: Option<Pressure>,
+    #[cfg(target_os = "linux")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    numa: Option<std::collections::BTreeMap<String, crate::numa::NumaNode>>,
+    #[cfg(target_os = "linux")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hugepages: Option<std::collections::BTreeMap<String, crate::numa::Hugepages>>,
     uptime: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    collectors: HashMap<String, serde_json::Value>,
+}
+
+impl PostInfo {
+    pub fn set_collectors(&mut self, collectors: HashMap<String, serde_json::Value>) {
+        self.collectors = collectors;
+    }
 }
 
 impl std::fmt::Display for PostInfo {
@@ -337,6 +480,15 @@ pub async fn get_base_info() -> PostInfo {
         cpu: cpu_load,
         #[cfg(unix)]
         loadavg: load_avg,
+        #[cfg(target_os = "linux")]
+        conntrack: Conntrack::read(),
+        #[cfg(target_os = "linux")]
+        pressure: Pressure::read(),
+        #[cfg(target_os = "linux")]
+        numa: crate::numa::nodes(),
+        #[cfg(target_os = "linux")]
+        hugepages: crate::numa::pools(),
         uptime: uptime.as_secs(),
+        collectors: Default::default(),
     }
 }
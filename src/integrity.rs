@@ -0,0 +1,155 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! File integrity monitoring: hash files in `[[watch.file]]` each heartbeat,
+//! report changes since previous check as events.
+
+use crate::collector::Collector;
+use crate::configparser::config::WatchFile;
+use async_trait::async_trait;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "file_integrity";
+pub const DEFAULT_STATE_FILE: &str = "fim_state.json";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct FileState {
+    hash: String,
+    mtime: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Change {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Serialize)]
+struct Event {
+    path: String,
+    change: Change,
+    old_hash: Option<String>,
+    new_hash: Option<String>,
+    mtime: Option<i64>,
+}
+
+pub struct IntegrityCollector {
+    files: Vec<String>,
+    state_path: PathBuf,
+    /// Last seen state of each file, `None` if file was absent
+    known: Mutex<HashMap<String, Option<FileState>>>,
+}
+
+fn inspect(path: &str) -> std::io::Result<FileState> {
+    let contents = std::fs::read(path)?;
+    let mtime = std::fs::metadata(path)?
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let hash = crate::signing::sha256_hex(&contents);
+    Ok(FileState { hash, mtime })
+}
+
+impl IntegrityCollector {
+    pub fn new(files: &[WatchFile]) -> Self {
+        let state_path = crate::state::path(DEFAULT_STATE_FILE);
+        let known = std::fs::read(&state_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Self {
+            files: files.iter().map(|f| f.path.clone()).collect(),
+            state_path,
+            known: Mutex::new(known),
+        }
+    }
+
+    fn check(&self) -> Vec<Event> {
+        let mut known = self.known.lock().unwrap();
+        let mut events: Vec<Event> = Default::default();
+        for path in &self.files {
+            let current = match inspect(path) {
+                Ok(state) => Some(state),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    warn!("Unable inspect {}: {}", path, e);
+                    continue;
+                }
+            };
+            let previous = match known.insert(path.clone(), current.clone()) {
+                Some(previous) => previous,
+                // First seen, only record baseline
+                None => continue,
+            };
+            let change = match (&previous, &current) {
+                (None, Some(_)) => Change::Created,
+                (Some(old), Some(new)) if old.hash != new.hash => Change::Modified,
+                (Some(_), None) => Change::Deleted,
+                _ => continue,
+            };
+            events.push(Event {
+                path: path.clone(),
+                change,
+                old_hash: previous.map(|s| s.hash),
+                new_hash: current.as_ref().map(|s| s.hash.clone()),
+                mtime: current.map(|s| s.mtime),
+            });
+        }
+        match serde_json::to_vec(&*known) {
+            Ok(bytes) => {
+                if let Err(e) = crate::state::write(&self.state_path, bytes) {
+                    warn!(
+                        "Unable save file integrity state to {}: {:?}",
+                        self.state_path.display(),
+                        e
+                    );
+                }
+            }
+            Err(e) => warn!("Unable serialize file integrity state: {:?}", e),
+        }
+        events
+    }
+}
+
+#[async_trait]
+impl Collector for IntegrityCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        Ok(serde_json::json!({
+            "files": self.files.len(),
+            "events": self.check(),
+        }))
+    }
+
+    fn source(&self) -> String {
+        format!("file integrity: {}", self.files.join(", "))
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Kernel event collector in `[watch.kernel]` (Linux only): scan kernel messages since previous
+//! heartbeat for OOM kills, hardware errors (MCE, EDAC), I/O and filesystem errors and hung
+//! tasks, and report them deduplicated with occurrence counts.
+//!
+//! Messages are read from `/dev/kmsg`. Where it is not readable (e.g. in some containers), the
+//! journald kernel transport is queried with `journalctl -k` instead.
+
+use crate::collector::Collector;
+use crate::configparser::config::WatchKernel;
+use crate::error::Error;
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use chrono::Utc;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "kernel_events";
+pub(crate) const KMSG_PATH: &str = "/dev/kmsg";
+pub const DEFAULT_MAX_EVENTS: usize = 20;
+pub const DEFAULT_ERRORS: bool = false;
+const QUERY_TIMEOUT: u64 = 30;
+/// Lowest syslog priority still reported with `errors = true` (`LOG_ERR`).
+const LOG_ERR: u8 = 3;
+
+const CATEGORIES: [(&str, &str); 5] = [
+    ("oom", r"(?i)out of memory: kill(ed)? process|oom-kill:"),
+    (
+        "hardware",
+        r"(?i)\[hardware error\]|machine check|mce: |EDAC .*\b(CE|UE)\b",
+    ),
+    (
+        "io",
+        r"(?i)i/o error|blk_update_request|critical medium error|medium error|reset controller",
+    ),
+    (
+        "filesystem",
+        r"(?i)(ext4-fs|xfs|btrfs).*(error|corrupt)|remounting filesystem read-only",
+    ),
+    ("hung_task", r"blocked for more than \d+ seconds"),
+];
+
+struct Record {
+    priority: u8,
+    /// Unix timestamp
+    time: i64,
+    message: String,
+}
+
+#[derive(Default)]
+struct Event {
+    category: &'static str,
+    message: String,
+    count: u64,
+    first: i64,
+    last: i64,
+}
+
+enum Source {
+    Kmsg {
+        file: File,
+        /// Wall clock time of `CLOCK_MONOTONIC` zero, kmsg timestamps are relative to it
+        boot: i64,
+    },
+    Journal,
+}
+
+impl Source {
+    fn open() -> Self {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(KMSG_PATH)
+            .and_then(|mut file| file.seek(SeekFrom::End(0)).map(|_| file));
+        match file {
+            Ok(file) => Source::Kmsg {
+                file,
+                boot: Utc::now().timestamp() - monotonic(),
+            },
+            Err(e) => {
+                log::warn!(
+                    "Unable open {}: {}, fall back to journald kernel transport",
+                    KMSG_PATH,
+                    e
+                );
+                Source::Journal
+            }
+        }
+    }
+}
+
+#[allow(clippy::unnecessary_cast)] // time_t is not i64 on every target
+fn monotonic() -> i64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as i64
+}
+
+/// Parse `/dev/kmsg` record: `priority,sequence,timestamp_us,flags[,...];message`,
+/// followed by continuation lines of key-value dictionary which are ignored.
+fn parse_kmsg(record: &str, boot: i64) -> Option<Record> {
+    let (header, message) = record.split_once(';')?;
+    let mut fields = header.split(',');
+    let priority = fields.next()?.parse::<u32>().ok()?;
+    let timestamp = fields.nth(1)?.parse::<i64>().ok()?;
+    Some(Record {
+        priority: (priority & 7) as u8,
+        time: boot + timestamp / 1_000_000,
+        message: message.lines().next().unwrap_or_default().to_string(),
+    })
+}
+
+fn read_kmsg(file: &mut File, boot: i64) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                if let Some(record) = parse_kmsg(&String::from_utf8_lossy(&buffer[..size]), boot) {
+                    records.push(record);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            // Ring buffer overwritten records not read yet, reading continues at next one
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => {
+                log::warn!("Kernel ring buffer overrun, some messages are lost");
+            }
+            Err(source) => {
+                return Err(Error::Io {
+                    operation: "read",
+                    path: KMSG_PATH.to_string(),
+                    source,
+                }
+                .into())
+            }
+        }
+    }
+    Ok(records)
+}
+
+async fn read_journal(since: i64) -> anyhow::Result<Vec<Record>> {
+    let args = vec![
+        "-k".to_string(),
+        "-q".to_string(),
+        "--no-pager".to_string(),
+        "-o".to_string(),
+        "json".to_string(),
+        format!("--since=@{}", since),
+    ];
+    let limits = Limits {
+        timeout: Some(QUERY_TIMEOUT),
+        ..Default::default()
+    };
+    let output = sandbox::run("journalctl", &args, &limits).await?;
+    Ok(output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|entry| {
+            Some(Record {
+                priority: entry["PRIORITY"].as_str()?.parse().ok()?,
+                time: entry["__REALTIME_TIMESTAMP"]
+                    .as_str()?
+                    .parse::<i64>()
+                    .ok()?
+                    / 1_000_000,
+                message: entry["MESSAGE"].as_str()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+pub struct KernelCollector {
+    categories: Vec<(&'static str, Regex)>,
+    numbers: Regex,
+    errors: bool,
+    max_events: usize,
+    source: Mutex<Source>,
+    since: Mutex<i64>,
+}
+
+impl KernelCollector {
+    pub fn new(cfg: &WatchKernel) -> Self {
+        Self {
+            categories: CATEGORIES
+                .iter()
+                .map(|(category, pattern)| (*category, Regex::new(pattern).unwrap()))
+                .collect(),
+            numbers: Regex::new(r"\b(0x)?[0-9a-fA-F]*[0-9][0-9a-fA-F]*\b|[0-9]+").unwrap(),
+            errors: cfg.errors.unwrap_or(DEFAULT_ERRORS),
+            max_events: cfg.max_events.unwrap_or(DEFAULT_MAX_EVENTS),
+            source: Mutex::new(Source::open()),
+            since: Mutex::new(Utc::now().timestamp()),
+        }
+    }
+
+    fn classify(&self, record: &Record) -> Option<&'static str> {
+        self.categories
+            .iter()
+            .find(|(_, pattern)| pattern.is_match(&record.message))
+            .map(|(category, _)| *category)
+            .or_else(|| (self.errors && record.priority <= LOG_ERR).then_some("error"))
+    }
+
+    async fn read(&self, since: i64) -> anyhow::Result<Vec<Record>> {
+        if let Source::Kmsg { file, boot } = &mut *self.source.lock().unwrap() {
+            return read_kmsg(file, *boot);
+        }
+        read_journal(since).await
+    }
+}
+
+#[async_trait]
+impl Collector for KernelCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let since = *self.since.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let records = self.read(since).await?;
+        *self.since.lock().unwrap() = now;
+
+        let mut events: HashMap<(&'static str, String), Event> = HashMap::new();
+        for record in &records {
+            let category = match self.classify(record) {
+                Some(category) => category,
+                None => continue,
+            };
+            let key = self.numbers.replace_all(&record.message, "#").into_owned();
+            let event = events.entry((category, key)).or_insert_with(|| Event {
+                category,
+                message: record.message.clone(),
+                first: record.time,
+                ..Default::default()
+            });
+            event.count += 1;
+            event.last = record.time;
+        }
+
+        let mut summary = serde_json::json!({ "since": since, "total": 0 });
+        for (category, _) in &CATEGORIES {
+            summary[category] = serde_json::json!(0);
+        }
+        if self.errors {
+            summary["error"] = serde_json::json!(0);
+        }
+        let mut events = events.into_values().collect::<Vec<_>>();
+        for event in &events {
+            let count = &mut summary[event.category];
+            *count = serde_json::json!(count.as_u64().unwrap_or(0) + event.count);
+            summary["total"] =
+                serde_json::json!(summary["total"].as_u64().unwrap_or(0) + event.count);
+        }
+        events.sort_by_key(|event| (std::cmp::Reverse(event.count), event.first));
+        summary["events"] = events
+            .iter()
+            .take(self.max_events)
+            .map(|event| {
+                serde_json::json!({
+                    "category": event.category,
+                    "message": event.message,
+                    "count": event.count,
+                    "first": event.first,
+                    "last": event.last,
+                })
+            })
+            .collect();
+        Ok(summary)
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("since".to_string(), Unit::UnixTimestamp),
+            ("total".to_string(), Unit::Count),
+        ]
+    }
+
+    fn source(&self) -> String {
+        match &*self.source.lock().unwrap() {
+            Source::Kmsg { .. } => KMSG_PATH.to_string(),
+            Source::Journal => "journald kernel transport".to_string(),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Agent lifecycle events, so server timeline shows starts and stops explicitly.
+//!
+//! `startup` carries `reason`: `install` (first start), `boot` (host rebooted),
+//! `crash-recovery` (previous run did not exit), `upgrade` (client version changed),
+//! `handover` (took over session from running process of the same version) or `restart`.
+//! `shutdown` carries whether the exit is clean, with the received signal or error.
+//!
+//! On exit, a summary of data left unsent is also written to state directory, and uploaded
+//! as `shutdown_report` on next startup, so data loss windows during outages are quantified.
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Mutex;
+
+pub const LIFECYCLE_FILE: &str = "probe_lifecycle.json";
+pub const REPORT_FILE: &str = "probe_shutdown_report.json";
+
+static SIGNAL: Mutex<Option<&'static str>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    boot_id: String,
+    version: String,
+    running: bool,
+}
+
+fn load() -> Option<State> {
+    serde_json::from_str(&std::fs::read_to_string(crate::state::path(LIFECYCLE_FILE)).ok()?).ok()
+}
+
+fn save(state: &State) {
+    let path = crate::state::path(LIFECYCLE_FILE);
+    if let Err(e) = crate::state::write(&path, serde_json::to_string(state).unwrap()) {
+        warn!("Unable save lifecycle state to {}: {:?}", path.display(), e);
+    }
+}
+
+/// Determine startup reason from previous run and mark current run as running, return `startup` body.
+pub fn startup(boot_id: &str, handover: bool) -> Value {
+    let previous = load();
+    let reason = match &previous {
+        Some(previous) if handover && previous.version == crate::session::CLIENT_VERSION => {
+            "handover"
+        }
+        _ if handover => "upgrade",
+        None => "install",
+        Some(previous) if previous.boot_id != boot_id => "boot",
+        Some(previous) if previous.running => "crash-recovery",
+        Some(previous) if previous.version != crate::session::CLIENT_VERSION => "upgrade",
+        Some(_) => "restart",
+    };
+    save(&State {
+        boot_id: boot_id.to_string(),
+        version: crate::session::CLIENT_VERSION.to_string(),
+        running: true,
+    });
+    serde_json::json!({
+        "reason": reason,
+        "previous_version": previous.as_ref().map(|previous| &previous.version),
+        "previous_clean": previous.as_ref().map(|previous| !previous.running),
+    })
+}
+
+/// Remember signal which requested exit, reported in `shutdown`.
+pub fn set_signal(signal: &'static str) {
+    *SIGNAL.lock().unwrap() = Some(signal);
+}
+
+/// Mark current run as exited, return `shutdown` body.
+pub fn shutdown(boot_id: &str, error: Option<&anyhow::Error>) -> Value {
+    save(&State {
+        boot_id: boot_id.to_string(),
+        version: crate::session::CLIENT_VERSION.to_string(),
+        running: false,
+    });
+    serde_json::json!({
+        "clean": error.is_none(),
+        "signal": *SIGNAL.lock().unwrap(),
+        "error": error.map(|e| e.to_string()),
+        "failure": error.map(crate::error::structured),
+    })
+}
+
+/// Log and save summary of data left unsent on exit, until uploaded on next startup.
+pub fn write_report(report: &Value) {
+    // Spooled heartbeats are not lost, but still wait for upload on next run
+    let unsent = report["unsent"]["heartbeats"].as_u64().unwrap_or_default()
+        + report["unsent"]["spooled"].as_u64().unwrap_or_default();
+    if unsent > 0 || report["shutdown_sent"] == false {
+        warn!("Exit with data unsent: {}", report);
+    } else {
+        info!("Exit without data unsent: {}", report);
+    }
+    let path = crate::state::path(REPORT_FILE);
+    if let Err(e) = crate::state::write(&path, serde_json::to_string(report).unwrap()) {
+        warn!("Unable save shutdown report to {}: {:?}", path.display(), e);
+    }
+}
+
+/// Shutdown report of previous run, if not uploaded yet.
+pub fn load_report() -> Option<Value> {
+    serde_json::from_str(&std::fs::read_to_string(crate::state::path(REPORT_FILE)).ok()?).ok()
+}
+
+/// Forget uploaded shutdown report.
+pub fn remove_report() {
+    let path = crate::state::path(REPORT_FILE);
+    if let Err(e) = std::fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Unable remove {}: {:?}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Mandatory access control collector in `[watch.mac]` (Linux only): report SELinux and AppArmor
+//! mode, loaded AppArmor profiles by mode, and access denials logged since previous heartbeat.
+//!
+//! Denials are read from the audit log. Where it is not readable (e.g. auditd is not running),
+//! the journald audit transport is queried with `journalctl _TRANSPORT=audit` instead.
+
+use crate::collector::Collector;
+use crate::configparser::config::WatchMac;
+use crate::error::Error;
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use chrono::Utc;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "mac";
+pub const DEFAULT_AUDIT_LOG: &str = "/var/log/audit/audit.log";
+pub const DEFAULT_MAX_SUBJECTS: usize = 10;
+const QUERY_TIMEOUT: u64 = 30;
+
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+pub(crate) const SELINUX_CONFIG: &str = "/etc/selinux/config";
+const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+const LSM: &str = "/sys/kernel/security/lsm";
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Value of `KEY=value` line in shell style configuration file, quotes removed.
+fn config_value(content: &str, key: &str) -> Option<String> {
+    content
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().trim_matches('"').to_lowercase())
+}
+
+fn selinux() -> Value {
+    let mode = match read_trimmed(SELINUX_ENFORCE).as_deref() {
+        Some("1") => "enforcing",
+        Some("0") => "permissive",
+        _ => "disabled",
+    };
+    let config = std::fs::read_to_string(SELINUX_CONFIG).ok();
+    let configured = config.as_deref().and_then(|c| config_value(c, "SELINUX"));
+    json!({
+        "mode": mode,
+        "configured": configured,
+        "policy": config.as_deref().and_then(|c| config_value(c, "SELINUXTYPE")),
+        // Mode switched at runtime (`setenforce`) or boot parameters overriding configuration
+        "drift": configured.as_deref().is_some_and(|configured| configured != mode),
+    })
+}
+
+/// Count profiles listed as `name (mode)`, `None` if list is not readable (requires root).
+fn apparmor_profiles() -> Option<BTreeMap<String, u64>> {
+    let content = std::fs::read_to_string(APPARMOR_PROFILES).ok()?;
+    let mut profiles = BTreeMap::new();
+    for line in content.lines() {
+        if let Some((_, mode)) = line.trim_end().rsplit_once(" (") {
+            *profiles
+                .entry(mode.trim_end_matches(')').to_string())
+                .or_insert(0) += 1;
+        }
+    }
+    Some(profiles)
+}
+
+fn apparmor() -> Value {
+    if read_trimmed(APPARMOR_ENABLED).as_deref() != Some("Y") {
+        return json!({ "mode": "disabled", "profiles": null });
+    }
+    let profiles = apparmor_profiles();
+    let mode = match &profiles {
+        None => "unknown",
+        Some(profiles) if profiles.contains_key("enforce") || profiles.contains_key("kill") => {
+            "enforcing"
+        }
+        Some(_) => "permissive",
+    };
+    json!({ "mode": mode, "profiles": profiles })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Framework {
+    SeLinux,
+    AppArmor,
+}
+
+impl Framework {
+    fn name(&self) -> &'static str {
+        match self {
+            Framework::SeLinux => "selinux",
+            Framework::AppArmor => "apparmor",
+        }
+    }
+}
+
+struct Patterns {
+    selinux: Regex,
+    apparmor: Regex,
+}
+
+impl Patterns {
+    fn new() -> Self {
+        Self {
+            selinux: Regex::new(r#"avc:\s+denied\b.*?\bscontext=(\S+)"#).unwrap(),
+            apparmor: Regex::new(r#"apparmor="DENIED".*?\bprofile="([^"]*)""#).unwrap(),
+        }
+    }
+
+    /// Framework and subject (source context or profile) of denial record.
+    fn parse(&self, line: &str) -> Option<(Framework, String)> {
+        if let Some(captures) = self.selinux.captures(line) {
+            return Some((Framework::SeLinux, captures[1].to_string()));
+        }
+        self.apparmor
+            .captures(line)
+            .map(|captures| (Framework::AppArmor, captures[1].to_string()))
+    }
+}
+
+enum Source {
+    AuditLog {
+        path: String,
+        inode: u64,
+        offset: u64,
+    },
+    Journal,
+}
+
+impl Source {
+    fn open(path: &str) -> Self {
+        match File::open(path).and_then(|file| file.metadata()) {
+            Ok(metadata) => Source::AuditLog {
+                path: path.to_string(),
+                inode: metadata.ino(),
+                offset: metadata.len(),
+            },
+            Err(e) => {
+                log::warn!(
+                    "Unable open {}: {}, fall back to journald audit transport",
+                    path,
+                    e
+                );
+                Source::Journal
+            }
+        }
+    }
+}
+
+/// Read lines appended since previous call, restart from beginning if log was rotated.
+fn read_audit_log(path: &str, inode: &mut u64, offset: &mut u64) -> anyhow::Result<String> {
+    let mut file = File::open(path).map_err(|source| Error::Io {
+        operation: "open",
+        path: path.to_string(),
+        source,
+    })?;
+    let metadata = file.metadata()?;
+    if metadata.ino() != *inode || metadata.len() < *offset {
+        *inode = metadata.ino();
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).map_err(|source| Error::Io {
+        operation: "read",
+        path: path.to_string(),
+        source,
+    })?;
+    // Keep incomplete last line for next read
+    let complete = buffer
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    *offset += complete as u64;
+    Ok(String::from_utf8_lossy(&buffer[..complete]).into_owned())
+}
+
+async fn read_journal(since: i64) -> anyhow::Result<String> {
+    let args = vec![
+        "_TRANSPORT=audit".to_string(),
+        "-q".to_string(),
+        "--no-pager".to_string(),
+        "-o".to_string(),
+        "cat".to_string(),
+        format!("--since=@{}", since),
+    ];
+    let limits = Limits {
+        timeout: Some(QUERY_TIMEOUT),
+        ..Default::default()
+    };
+    sandbox::run("journalctl", &args, &limits).await
+}
+
+pub struct MacCollector {
+    patterns: Patterns,
+    max_subjects: usize,
+    source: Mutex<Source>,
+    since: Mutex<i64>,
+}
+
+impl MacCollector {
+    pub fn new(cfg: &WatchMac) -> Self {
+        Self {
+            patterns: Patterns::new(),
+            max_subjects: cfg.max_subjects.unwrap_or(DEFAULT_MAX_SUBJECTS),
+            source: Mutex::new(Source::open(
+                cfg.audit_log.as_deref().unwrap_or(DEFAULT_AUDIT_LOG),
+            )),
+            since: Mutex::new(Utc::now().timestamp()),
+        }
+    }
+
+    async fn read(&self, since: i64) -> anyhow::Result<String> {
+        if let Source::AuditLog {
+            path,
+            inode,
+            offset,
+        } = &mut *self.source.lock().unwrap()
+        {
+            return read_audit_log(path, inode, offset);
+        }
+        read_journal(since).await
+    }
+}
+
+#[async_trait]
+impl Collector for MacCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let since = *self.since.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let records = self.read(since).await?;
+        *self.since.lock().unwrap() = now;
+
+        let mut subjects: HashMap<(Framework, String), u64> = HashMap::new();
+        for line in records.lines() {
+            if let Some(key) = self.patterns.parse(line) {
+                *subjects.entry(key).or_insert(0) += 1;
+            }
+        }
+        let mut denials = json!({ "since": since, "total": 0, "selinux": 0, "apparmor": 0 });
+        for ((framework, _), count) in &subjects {
+            let counter = &mut denials[framework.name()];
+            *counter = json!(counter.as_u64().unwrap_or(0) + count);
+            denials["total"] = json!(denials["total"].as_u64().unwrap_or(0) + count);
+        }
+        let mut subjects = subjects.into_iter().collect::<Vec<_>>();
+        subjects.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        denials["subjects"] = subjects
+            .iter()
+            .take(self.max_subjects)
+            .map(|((framework, subject), count)| {
+                json!({
+                    "framework": framework.name(),
+                    "subject": subject,
+                    "count": count,
+                })
+            })
+            .collect();
+
+        let lsm =
+            read_trimmed(LSM).map(|lsm| lsm.split(',').map(str::to_string).collect::<Vec<_>>());
+        Ok(json!({
+            "lsm": lsm,
+            "selinux": selinux(),
+            "apparmor": apparmor(),
+            "denials": denials,
+        }))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("denials.since".to_string(), Unit::UnixTimestamp),
+            ("denials.total".to_string(), Unit::Count),
+            ("denials.selinux".to_string(), Unit::Count),
+            ("denials.apparmor".to_string(), Unit::Count),
+            ("apparmor.profiles.*".to_string(), Unit::Count),
+        ]
+    }
+
+    fn source(&self) -> String {
+        match &*self.source.lock().unwrap() {
+            Source::AuditLog { path, .. } => path.clone(),
+            Source::Journal => "journald audit transport".to_string(),
+        }
+    }
+}
@@ -17,142 +17,599 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
+mod action;
+mod alert;
+mod allowlist;
+mod annotate;
+mod anomaly;
+mod audit;
+mod blackout;
+mod boot;
+mod certificate;
+mod clock;
+mod cloud;
+mod collector;
+mod compare;
 mod configparser;
+mod configtool;
+mod connectivity;
+mod control;
+mod counters;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod derived;
+#[cfg(any(test, feature = "devtools"))]
+#[cfg_attr(not(feature = "devtools"), allow(dead_code))]
+mod devtools;
+#[cfg(feature = "mdns")]
+mod discovery;
+mod dns;
+mod downsample;
+#[cfg(all(feature = "ebpf", target_os = "linux"))]
+mod ebpf;
+mod environment;
+mod error;
+#[cfg(windows)]
+mod eventlog;
+mod exec;
+mod facts;
+mod fallback;
+mod fetch;
+#[cfg(any(target_os = "linux", windows))]
+mod firewall;
+mod forecast;
+mod fronting;
+mod fslatency;
+mod handover;
+mod hardening;
+#[cfg(feature = "dashboard")]
+mod history;
 mod info;
+mod integrity;
+#[cfg(target_os = "linux")]
+mod kmsg;
+mod lifecycle;
+#[cfg(target_os = "linux")]
+mod mac;
+mod mtls;
+mod nonce;
+mod normalize;
+mod notify;
+#[cfg(target_os = "linux")]
+mod numa;
+mod output;
+mod packages;
+mod peer;
+#[cfg(feature = "plugins")]
+mod plugin;
+#[cfg(feature = "policy")]
+mod policy;
+mod pressure;
+mod privacy;
+mod privilege;
+mod profile;
+mod ratelimit;
+#[cfg(target_os = "linux")]
+mod reboot;
+mod record;
+#[cfg(feature = "relay")]
+mod relay;
+mod reload;
+mod remote_access;
+mod replay;
+mod report;
+mod resolver;
+mod roaming;
+mod sandbox;
+mod scheduling;
 mod session;
+mod signing;
+mod spool;
+mod state;
+mod status;
+#[cfg(feature = "talkers")]
+mod talkers;
+mod template;
+mod tor;
+#[cfg(feature = "tpm")]
+mod tpm;
+mod tunnel;
+mod virt;
+mod wake;
+mod websocket;
 
+use crate::allowlist::AllowList;
+use crate::clock::{overrun, sleep_or_recv, Clock, SystemClock};
+use crate::configparser::config::Configure;
 use crate::session::{ReInitRequest, Session, MAX_RETRY_TIMES};
 use log::{error, info, warn};
+use std::num::NonZeroU32;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncWriteExt as _;
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
-use crate::session::error::TooManyRetriesError;
+use crate::error::Error;
 
 const MAX_TIMEOUT_RETRIES: u32 = 5;
+/// Registrations sent again after clock offset was resynced from server time.
+const MAX_CLOCK_SKEW_RETRIES: u32 = 1;
 
 fn get_timeout_sleep(retry_times: u32) -> u64 {
     5 * 4u64.pow(retry_times) + 10
 }
 
-async fn post_main(session: &Session, rx: Arc<Mutex<mpsc::Receiver<()>>>) -> anyhow::Result<()> {
+async fn post_main(
+    session: &Session,
+    rx: Arc<Mutex<mpsc::Receiver<()>>>,
+    clock: &dyn Clock,
+    network_change: Option<&Notify>,
+    handover: Option<&handover::Listener>,
+    termination: Option<&cloud::TerminationWatch>,
+) -> anyhow::Result<()> {
     let interval = session.get_interval();
     let mut rx = rx.lock().await;
     let mut times = 0;
     let mut retries = 0;
+    let mut maintenance = false;
+    // Pinned outside loop, so action pushed by server resumes after heartbeat instead of dropped
+    let pushed = session.serve_pushed();
+    tokio::pin!(pushed);
     loop {
         if let Err(e) = session.send_heartbeat().await {
+            session.note_failure(&e);
+            if let Some(retry_after) = error::of(&e).and_then(Error::retry_after) {
+                if !std::mem::replace(&mut maintenance, true) {
+                    warn!("{}", e);
+                }
+                if sleep_or_recv(clock, retry_after, &mut rx).await {
+                    break Ok(());
+                }
+                continue;
+            }
             if e.is::<session::ExitProcessRequest>() {
                 warn!("Got exit process request, break loop now");
                 break Err(e);
             }
-            if e.is::<session::error::TimeoutError>() {
+            if e.is::<ReInitRequest>() {
+                warn!("Server requested registration again");
+                break Err(e);
+            }
+            if matches!(error::of(&e), Some(Error::Timeout { .. })) {
                 if retries > MAX_TIMEOUT_RETRIES {
-                    return Err(TooManyRetriesError::new(e));
+                    return Err(Error::TooManyRetries { last: e }.into());
                 };
                 let sleep_time = get_timeout_sleep(retries);
                 warn!(
-                    "Got timeout error in send heartbeat, sleep {} seconds",
+                    "[{}] Got timeout error in send heartbeat, sleep {} seconds",
+                    error::code(&e),
                     sleep_time
                 );
-                if tokio::time::timeout(Duration::from_secs(sleep_time), rx.recv())
-                    .await
-                    .is_ok()
-                {
+                if sleep_or_recv(clock, Duration::from_secs(sleep_time), &mut rx).await {
                     break Ok(());
                 }
                 retries += 1;
                 continue;
             }
-            error!("Got error in send heartbeat: {:?}", e);
-            if tokio::time::timeout(Duration::from_secs(5), rx.recv())
-                .await
-                .is_ok()
-            {
+            error!("[{}] Got error in send heartbeat: {:?}", error::code(&e), e);
+            if sleep_or_recv(clock, Duration::from_secs(5), &mut rx).await {
                 break Ok(());
             }
             if times > MAX_RETRY_TIMES {
-                break Err(TooManyRetriesError::new(e));
+                break Err(Error::TooManyRetries { last: e }.into());
             }
             times += 1;
             continue;
         }
-        if tokio::time::timeout(Duration::from_secs(interval), rx.recv())
-            .await
-            .is_ok()
-        {
-            break Ok(());
+        if std::mem::take(&mut maintenance) {
+            info!("Server maintenance ended, resume heartbeats");
+        }
+        let network_changed = async {
+            match network_change {
+                Some(notify) => notify.notified().await,
+                None => std::future::pending().await,
+            }
+        };
+        let handover_requested = async {
+            match handover {
+                Some(listener) => {
+                    listener
+                        .serve(&session.handover_identity(), || session.handover_state())
+                        .await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let termination_announced = async {
+            match termination {
+                Some(watch) => watch.notified().await,
+                None => std::future::pending().await,
+            }
+        };
+        let sleep_start = clock.wall();
+        tokio::select! {
+            stop = wait_heartbeat(session, clock, Duration::from_secs(interval), &mut rx) => {
+                if stop? {
+                    break Ok(());
+                }
+                // Scheduled heartbeat missed entirely (CPU starvation, suspend), not network loss
+                let stall = overrun(clock, sleep_start, Duration::from_secs(interval));
+                if stall > Duration::from_secs(interval) {
+                    warn!("Woke up {}s late, report stall in next heartbeat", stall.as_secs());
+                    session.note_stall(stall);
+                }
+            }
+            _ = network_changed => {
+                info!("Network changed, send registration again");
+                if let Err(e) = session.register().await {
+                    error!("Got error in re-register: {:?}", e);
+                }
+            }
+            e = handover_requested => break Err(e),
+            _ = &mut pushed => {}
+            _ = reload::requested() => break Err(anyhow::Error::new(reload::ReloadRequest)),
+            notice = termination_announced => {
+                terminate(session, notice, &mut rx).await;
+                break Err(anyhow::Error::new(cloud::TerminatingError));
+            }
         }
         retries = 0;
         times = 0;
     }
 }
 
-async fn retrieve_configure(sever_address: &str) -> anyhow::Result<()> {
+/// Sleep until next heartbeat, sending `ping` every `ping_interval` meanwhile.
+/// Returns whether stop was requested.
+async fn wait_heartbeat(
+    session: &Session,
+    clock: &dyn Clock,
+    interval: Duration,
+    rx: &mut mpsc::Receiver<()>,
+) -> anyhow::Result<bool> {
+    let ping_interval = match session.get_ping_interval() {
+        Some(ping_interval) => ping_interval,
+        None => return Ok(sleep_or_recv(clock, interval, rx).await),
+    };
+    let deadline = clock.now() + interval;
+    loop {
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if remaining <= ping_interval {
+            return Ok(sleep_or_recv(clock, remaining, rx).await);
+        }
+        if sleep_or_recv(clock, ping_interval, rx).await {
+            return Ok(true);
+        }
+        if let Err(e) = session.send_ping().await {
+            if e.is::<session::ExitProcessRequest>() || e.is::<ReInitRequest>() {
+                return Err(e);
+            }
+            // Next heartbeat deals with server errors and maintenance
+            if !matches!(error::of(&e), Some(Error::Maintenance { .. })) {
+                warn!("Unable send ping: {}", e);
+            }
+        }
+    }
+}
+
+/// Announce planned termination and unregister, then stay idle until stopped,
+/// so the client is not restarted to register again while the instance goes away.
+async fn terminate(session: &Session, notice: cloud::Termination, rx: &mut mpsc::Receiver<()>) {
+    warn!("Instance is terminating, unregister now");
+    if let Err(e) = session
+        .send_event(cloud::TERMINATING_EVENT, notice.event())
+        .await
+    {
+        error!("Unable send terminating event: {:?}", e);
+    }
+    let mut unregister = lifecycle::shutdown(session.boot_id(), None);
+    unregister["reason"] = serde_json::json!(cloud::TERMINATING_EVENT);
+    if let Err(e) = session
+        .send_event(cloud::UNREGISTER_EVENT, unregister)
+        .await
+    {
+        error!("Unable send unregister event: {:?}", e);
+    }
+    rx.recv().await;
+}
+
+async fn retrieve_configure(sever_address: &str, cfg: &str) -> anyhow::Result<()> {
     info!("retrieve configure from server");
-    let client = reqwest::ClientBuilder::new()
-        .user_agent(format!("probe_client {}", session::CLIENT_VERSION))
-        .build()?;
+    // Allow-list of current configure also applies to the retrieved one
+    let allow_list = match tokio::fs::read_to_string(cfg).await {
+        Ok(contents) => AllowList::from_config(&toml::from_str(&contents)?)?,
+        Err(_) => None,
+    };
+    let mut builder = reqwest::ClientBuilder::new()
+        .user_agent(format!("probe_client {}", session::CLIENT_VERSION));
+    if let Some(allow_list) = &allow_list {
+        allow_list.check(sever_address)?;
+        builder = builder.redirect(allow_list.redirect_policy());
+    }
+    let client = builder.build()?;
 
-    let r = client.post(sever_address).send().await?;
+    // So server only enables what this build and host support
+    let r = client
+        .post(sever_address)
+        .json(&serde_json::json!({
+            "capabilities": session::capabilities::detect(),
+        }))
+        .send()
+        .await?;
 
     let response = r.text().await?;
 
+    let retrieved: Configure = toml::from_str(&response)?;
+    match allow_list {
+        Some(allow_list) => Some(allow_list),
+        None => AllowList::from_config(&retrieved)?,
+    }
+    .map(|allow_list| allow_list.check_config(&retrieved))
+    .transpose()?;
+
+    if let Some(parent) = std::path::Path::new(cfg).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
     let mut file = tokio::fs::OpenOptions::new()
         .create(true)
-        .open("data/probe_client.toml")
+        .truncate(true)
+        .write(true)
+        .open(cfg)
         .await?;
 
     file.write_all(response.as_bytes()).await?;
     file.sync_all().await?;
+    profile::save(&response)?;
     info!("Write configure completed");
     Ok(())
 }
 
-async fn async_main(mut session: Session, rx: mpsc::Receiver<()>) -> anyhow::Result<bool> {
+/// Take over running process if requested, then listen for handover and on control socket.
+/// Done before dropping privileges, returns whether session was handed over.
+async fn listen(session: &mut Session, takeover: bool) -> (bool, Option<handover::Listener>) {
+    let handed_over = if takeover {
+        handover::request(&session.handover_identity()).await
+    } else {
+        None
+    };
+    let handed_over = match handed_over {
+        Some(state) => {
+            session.restore(state);
+            true
+        }
+        None => false,
+    };
+    if state::is_read_only() {
+        return (handed_over, None);
+    }
+    let handover = match handover::Listener::bind(handed_over) {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            warn!("Unable listen for handover: {:?}", e);
+            None
+        }
+    };
+    if let Err(e) = control::spawn() {
+        warn!("Unable listen on control socket: {:?}", e);
+    }
+    (handed_over, handover)
+}
+
+async fn async_main(
+    mut session: Session,
+    rx: mpsc::Receiver<()>,
+    clock: Arc<dyn Clock>,
+    handed_over: bool,
+    handover: Option<handover::Listener>,
+) -> anyhow::Result<bool> {
+    let startup = lifecycle::startup(session.boot_id(), handed_over);
+    let result = run_session(
+        &mut session,
+        rx,
+        clock.clone(),
+        startup,
+        handed_over,
+        handover,
+    )
+    .await;
+    if matches!(&result, Err(e) if e.is::<handover::HandedOverError>()) {
+        return Ok(false);
+    }
+    // Already unregistered, and stop signal received
+    if matches!(&result, Err(e) if e.is::<cloud::TerminatingError>()) {
+        return Ok(true);
+    }
+    let shutdown = lifecycle::shutdown(session.boot_id(), result.as_ref().err());
+    let shutdown_sent = match session.send_event("shutdown", shutdown).await {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Unable send shutdown event: {:?}", e);
+            false
+        }
+    };
+    let mut report = session.unsent_summary();
+    report["timestamp"] =
+        serde_json::json!(chrono::DateTime::<chrono::Utc>::from(clock.wall()).timestamp());
+    report["clean"] = serde_json::json!(result.is_ok());
+    report["shutdown_sent"] = serde_json::json!(shutdown_sent);
+    lifecycle::write_report(&report);
+    result
+}
+
+/// Every server failed: wait `interval` then start over from the first server,
+/// return true if stopped meanwhile.
+async fn wait_degraded(
+    session: &mut Session,
+    rx: &Mutex<mpsc::Receiver<()>>,
+    clock: &dyn Clock,
+    interval: Duration,
+) -> bool {
+    warn!(
+        "All servers failed, degraded mode: retry in {} seconds",
+        interval.as_secs()
+    );
+    session.set_unreachable();
+    let mut rx = rx.lock().await;
+    if sleep_or_recv(clock, interval, &mut rx).await {
+        return true;
+    }
+    session.reset_server();
+    false
+}
+
+async fn run_session(
+    session: &mut Session,
+    rx: mpsc::Receiver<()>,
+    clock: Arc<dyn Clock>,
+    startup: serde_json::Value,
+    mut handed_over: bool,
+    handover: Option<handover::Listener>,
+) -> anyhow::Result<bool> {
     let arx = Arc::new(Mutex::new(rx));
+    let network_change = if session.is_roaming() {
+        Some(roaming::spawn())
+    } else {
+        None
+    };
+    let termination = match session
+        .get_config()
+        .collector
+        .as_ref()
+        .and_then(|c| c.cloud.as_ref())
+    {
+        Some(cloud) => cloud::spawn(cloud)?,
+        None => None,
+    };
+    if let Some(monitor) = session.fallback_monitor() {
+        fallback::spawn(session.get_config(), monitor)?;
+    }
+    let mut startup = Some(startup);
+    let mut report = lifecycle::load_report();
     let mut return_value = false;
-    while let Some(_) = session.call_next() {
+    let mut degraded = false;
+    let mut reinit = false;
+    // Server requested registration again is served by the same server, without failover
+    while std::mem::take(&mut reinit) || session.call_next().is_some() {
         let mut retries = 0;
-        loop {
+        let mut skew_retries = 0;
+        let mut maintenance = false;
+        let mut registered = Ok(());
+        // Registration is kept from the previous process after handover
+        while !std::mem::take(&mut handed_over) {
             match session.init_connection().await {
                 Ok(()) => break,
-                Err(e) if e.is::<session::error::TimeoutError>() => {
+                Err(e)
+                    if matches!(error::of(&e), Some(Error::ClockSkew { .. }))
+                        && skew_retries < MAX_CLOCK_SKEW_RETRIES =>
+                {
+                    warn!("{}, register again", e);
+                    skew_retries += 1;
+                }
+                Err(e) if error::of(&e).and_then(Error::retry_after).is_some() => {
+                    if !std::mem::replace(&mut maintenance, true) {
+                        warn!("{}", e);
+                    }
+                    let retry_after = error::of(&e).and_then(Error::retry_after).unwrap();
+                    let mut rv = arx.lock().await;
+                    if sleep_or_recv(clock.as_ref(), retry_after, &mut rv).await {
+                        return Ok(return_value);
+                    }
+                }
+                Err(e) if matches!(error::of(&e), Some(Error::Timeout { .. })) => {
                     if retries > MAX_TIMEOUT_RETRIES {
-                        return Err(TooManyRetriesError::new(e));
+                        registered = Err(Error::TooManyRetries { last: e }.into());
+                        break;
                     }
                     let sleep_time = get_timeout_sleep(retries);
                     warn!("Got timeout error, sleep {} seconds", sleep_time);
                     let mut rv = arx.lock().await;
-                    if tokio::time::timeout(Duration::from_secs(sleep_time), rv.recv())
-                        .await
-                        .is_ok()
+                    if sleep_or_recv(clock.as_ref(), Duration::from_secs(sleep_time), &mut rv).await
                     {
                         return Ok(return_value);
                     }
                     retries += 1;
                 }
-                Err(e) => return Err(e),
+                Err(e) => {
+                    registered = Err(e);
+                    break;
+                }
             }
         }
-        match post_main(&session, arx.clone()).await {
+        if let Err(e) = registered {
+            let interval = match session.degraded_interval() {
+                Some(interval) if !e.is::<session::ExitProcessRequest>() => interval,
+                _ => return Err(e),
+            };
+            error!("Unable register: {:?}", e);
+            if session.check_is_last() {
+                degraded = true;
+                if wait_degraded(session, &arx, clock.as_ref(), interval).await {
+                    return Ok(return_value);
+                }
+            }
+            continue;
+        }
+        if std::mem::take(&mut degraded) {
+            info!("Server reachable again, leave degraded mode");
+        }
+        if let Some(startup) = startup.take() {
+            if let Err(e) = session.send_event("startup", startup).await {
+                warn!("Unable send startup event: {:?}", e);
+            }
+        }
+        // Kept for next startup if not uploaded
+        if let Some(report) = report.take() {
+            match session.send_event("shutdown_report", report).await {
+                Ok(()) => lifecycle::remove_report(),
+                Err(e) => warn!("Unable send shutdown report: {:?}", e),
+            }
+        }
+        match post_main(
+            session,
+            arx.clone(),
+            clock.as_ref(),
+            network_change.as_deref(),
+            handover.as_ref(),
+            termination.as_deref(),
+        )
+        .await
+        {
             Ok(()) => {
                 return_value = true;
                 break;
             }
-            Err(e) if e.is::<TooManyRetriesError>() => {
+            Err(e) if matches!(error::of(&e), Some(Error::TooManyRetries { .. })) => {
                 error!("{:?}", e);
                 if session.check_is_last() {
-                    return Err(e);
+                    let interval = match session.degraded_interval() {
+                        Some(interval) => interval,
+                        None => return Err(e),
+                    };
+                    degraded = true;
+                    if wait_degraded(session, &arx, clock.as_ref(), interval).await {
+                        return Ok(return_value);
+                    }
                 }
                 continue;
             }
             Err(e) if e.is::<ReInitRequest>() => {
-                session.init_connection().await?;
+                reinit = true;
+                continue;
+            }
+            // Start over from the first server of reloaded configure
+            Err(e) if e.is::<reload::ReloadRequest>() => {
+                match session.reload().await {
+                    Ok(()) => info!("Register again with reloaded configure"),
+                    Err(e) => {
+                        error!("Unable reload configure, keep current one: {:?}", e);
+                        reinit = true;
+                    }
+                }
                 continue;
             }
+            Err(e) if e.is::<handover::HandedOverError>() || e.is::<cloud::TerminatingError>() => {
+                return Err(e)
+            }
             Err(e) => {
                 error!("Got other error {:?}", e);
                 return Err(e);
@@ -162,15 +619,55 @@ async fn async_main(mut session: Session, rx: mpsc::Receiver<()>) -> anyhow::Res
     Ok(return_value)
 }
 
+#[cfg(unix)]
+async fn wait_terminate() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::terminate())?.recv().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_terminate() -> anyhow::Result<()> {
+    std::future::pending().await
+}
+
 async fn wait_ctrl_c(tx: mpsc::Sender<()>) -> anyhow::Result<()> {
-    tokio::signal::ctrl_c().await?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            lifecycle::set_signal("SIGINT");
+        }
+        result = wait_terminate() => {
+            result?;
+            lifecycle::set_signal("SIGTERM");
+        }
+    }
     tx.send(()).await.ok();
     Ok(())
 }
 
+/// Heartbeat interval of `--interval`, zero would send heartbeats in a busy loop.
+fn parse_interval(value: &str) -> Result<NonZeroU32, String> {
+    value
+        .parse::<NonZeroU32>()
+        .map_err(|e| format!("Invalid interval {}: {}", value, e))
+}
+
 async fn async_switch() -> anyhow::Result<()> {
-    let args = clap::App::new("probe-client")
-        .version(session::CLIENT_VERSION)
+    let app = clap::App::new("probe-client")
+        .setting(clap::AppSettings::DisableVersion)
+        .arg(
+            clap::Arg::with_name("version")
+                .short("V")
+                .long("version")
+                .help("Prints version information"),
+        )
+        .arg(
+            clap::Arg::with_name("verbose")
+                .long("verbose")
+                .help("With --version, also print target, build profile, git commit and features")
+                .requires("version"),
+        )
         .arg(
             clap::Arg::with_name("server_address")
                 .short("r")
@@ -178,22 +675,184 @@ async fn async_switch() -> anyhow::Result<()> {
                 .help("retrieve configure from specify remote server")
                 .takes_value(true),
         )
+        .arg(
+            clap::Arg::with_name("server")
+                .long("server")
+                .help("Send to specify server instead of configured ones, without changing configure")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("interval")
+                .long("interval")
+                .help("Heartbeat interval in seconds instead of configured one, without changing configure")
+                .takes_value(true)
+                .validator(|value| parse_interval(&value).map(|_| ())),
+        )
         .arg(
             clap::Arg::with_name("cfg")
                 .short("c")
                 .help("Specify configure file location")
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            clap::Arg::with_name("state_dir")
+                .long("state-dir")
+                .help("Directory of identity, keys, queue and history (default: data/ if exists, otherwise system or XDG state directory)")
+                .takes_value(true),
+        )
+        .arg(
+            clap::Arg::with_name("takeover")
+                .long("takeover")
+                .help("Take over session of running client with same identification and configure (upgrade)"),
+        )
+        .arg(
+            clap::Arg::with_name("read_only")
+                .long("read-only")
+                .help("Do not write anything to disk (for read-only root filesystem)"),
+        )
+        .arg(
+            clap::Arg::with_name("record")
+                .long("record")
+                .help("Record all server interactions to specify directory")
+                .takes_value(true)
+                .conflicts_with("replay"),
+        )
+        .arg(
+            clap::Arg::with_name("replay")
+                .long("replay")
+                .help("Replay server responses from specify record directory")
+                .takes_value(true),
+        )
+        .subcommand(report::subcommand())
+        .subcommand(control::subcommand())
+        .subcommand(annotate::subcommand())
+        .subcommand(status::subcommand())
+        .subcommand(connectivity::subcommand())
+        .subcommand(configtool::subcommand());
+    #[cfg(feature = "dashboard")]
+    let app = app.subcommand(history::subcommand());
+    #[cfg(feature = "plugins")]
+    let app = app.subcommand(plugin::subcommand());
+    #[cfg(feature = "devtools")]
+    let app = app.subcommand(devtools::mock_server::subcommand()).arg(
+        clap::Arg::with_name("fake_clock")
+            .long("fake-clock")
+            .help("Skip all waits with a simulated clock (for use with mock-server)"),
+    );
+    let args = app.get_matches();
+    if args.is_present("version") {
+        if args.is_present("verbose") {
+            println!("{}", session::capabilities::long_version());
+        } else {
+            println!("probe-client {}", session::CLIENT_VERSION);
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "plugins")]
+    if let Some(matches) = args.subcommand_matches(plugin::SUBCOMMAND_NAME) {
+        return plugin::run(matches);
+    }
+    #[cfg(feature = "devtools")]
+    if let Some(matches) = args.subcommand_matches(devtools::mock_server::SUBCOMMAND_NAME) {
+        return devtools::mock_server::run(matches).await;
+    }
+    state::init(args.value_of("state_dir"));
+    if args.is_present("read_only") {
+        state::set_read_only();
+    }
+    let default_cfg = state::path(state::CONFIG_FILE);
+    let cfg = match args.value_of("cfg") {
+        Some(cfg) => cfg,
+        None => default_cfg.to_str().unwrap(),
+    };
+    let overrides = session::Overrides {
+        server: args.value_of("server").map(str::to_string),
+        interval: args
+            .value_of("interval")
+            .map(parse_interval)
+            .transpose()
+            .map_err(anyhow::Error::msg)?,
+    };
+    if let Some(matches) = args.subcommand_matches(configtool::SUBCOMMAND_NAME) {
+        return configtool::run(matches, cfg, &overrides).await;
+    }
+    if let Some(matches) = args.subcommand_matches(control::SUBCOMMAND_NAME) {
+        return control::run(matches).await;
+    }
+    if let Some(matches) = args.subcommand_matches(annotate::SUBCOMMAND_NAME) {
+        return annotate::run(matches).await;
+    }
+    if let Some(matches) = args.subcommand_matches(status::SUBCOMMAND_NAME) {
+        return status::run(matches).await;
+    }
+    #[cfg(feature = "dashboard")]
+    if let Some(matches) = args.subcommand_matches(history::SUBCOMMAND_NAME) {
+        return history::run(matches);
+    }
+    if let Some(matches) = args.subcommand_matches(connectivity::SUBCOMMAND_NAME) {
+        return connectivity::run(matches, cfg, &overrides).await;
+    }
+    if let Some(matches) = args.subcommand_matches(report::SUBCOMMAND_NAME) {
+        return report::run(cfg, output::of(matches)).await;
+    }
     if let Some(server_addr) = args.value_of("server_address") {
-        return retrieve_configure(server_addr).await;
+        return retrieve_configure(server_addr, cfg).await;
     }
-    info!("Client version: {}", session::CLIENT_VERSION);
+    info!(
+        "Client version: {} ({})",
+        session::CLIENT_VERSION,
+        env!("PROBE_BUILD_COMMIT")
+    );
     let (tx, rx) = mpsc::channel(64);
-    let session = Session::new(args.value_of("cfg").unwrap_or("data/probe_client.toml")).await?;
-    let task = tokio::task::spawn(async_main(session, rx));
+    if let Ok(contents) = std::fs::read_to_string(cfg) {
+        // Before Session::new starts any other thread
+        let (config, _) = profile::apply(&contents)?;
+        if let Some(scheduling) = &config.scheduling {
+            scheduling::apply(scheduling)?;
+        }
+        hardening::apply(&config, std::path::Path::new(cfg))?;
+    }
+    let mut session = Session::new(cfg, &overrides).await?;
+    if let Some(dir) = args.value_of("record") {
+        session.set_interaction(record::Interaction::record(dir).await?);
+    } else if let Some(dir) = args.value_of("replay") {
+        session.set_interaction(record::Interaction::replay(dir));
+    }
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    #[cfg(feature = "devtools")]
+    let clock: Arc<dyn Clock> = if args.is_present("fake_clock") {
+        Arc::new(clock::FakeClock::default())
+    } else {
+        clock
+    };
+    let tunnel_task = tunnel::spawn(session.get_config()).await?;
+    #[cfg(feature = "relay")]
+    let relay_task = relay::spawn(session.get_config())?;
+    #[cfg(feature = "dashboard")]
+    let dashboard_task = dashboard::spawn(session.get_config(), session.board())?;
+    #[cfg(not(feature = "dashboard"))]
+    if session.get_config().dashboard.is_some() {
+        warn!("Built without dashboard feature, dashboard ignored");
+    }
+    let (handed_over, handover) = listen(&mut session, args.is_present("takeover")).await;
+    if let Some(privilege) = &session.get_config().privilege {
+        privilege::drop(privilege, std::path::Path::new(cfg))?;
+    }
+    reload::spawn()?;
+    let task = tokio::task::spawn(async_main(session, rx, clock, handed_over, handover));
     let ctrl_c_task = tokio::task::spawn(wait_ctrl_c(tx));
     let result = task.await??;
+    #[cfg(feature = "relay")]
+    if let Some(relay_task) = relay_task {
+        relay_task.abort();
+    }
+    #[cfg(feature = "dashboard")]
+    if let Some(dashboard_task) = dashboard_task {
+        dashboard_task.abort();
+    }
+    if let Some(tunnel_task) = tunnel_task {
+        tunnel_task.abort();
+    }
     if !result {
         ctrl_c_task.abort();
     }
@@ -221,3 +880,112 @@ fn main() -> anyhow::Result<()> {
         .block_on(async_switch())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_interval_rejected() {
+        assert!(parse_interval("0").is_err());
+        assert_eq!(parse_interval("30").unwrap().get(), 30);
+    }
+
+    #[test]
+    fn timeout_backoff_grows() {
+        let sleeps = (0..=MAX_TIMEOUT_RETRIES)
+            .map(get_timeout_sleep)
+            .collect::<Vec<_>>();
+        assert_eq!(sleeps, [15, 30, 90, 330, 1290, 5130]);
+    }
+
+    /// Session with configure of `server` options, reporting to mock `server`.
+    async fn session(server: &devtools::mock_server::Handle, options: &str) -> Session {
+        let dir = std::env::temp_dir().join(format!("probe-client-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        state::init_for_test();
+        let path = dir.join("probe_client.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "[server]\nserver_address = \"{}\"\ntoken = \"\"\n{}\n[statistics]\nenabled = false\n",
+                server.address, options
+            ),
+        )
+        .unwrap();
+        let mut session = Session::new(&path, &Default::default()).await.unwrap();
+        session.call_next().unwrap();
+        session
+    }
+
+    /// Run heartbeat loop until server asks for registration again, which ends it.
+    async fn run_until_forgotten(session: &Session, clock: &dyn Clock) {
+        let (_tx, rx) = mpsc::channel(1);
+        let e = post_main(session, Arc::new(Mutex::new(rx)), clock, None, None, None)
+            .await
+            .unwrap_err();
+        assert!(e.is::<ReInitRequest>(), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_follow_interval_on_fake_clock() {
+        let server = devtools::mock_server::spawn(&["--forget-after", "2"]);
+        let session = session(&server, "interval = 60").await;
+        let clock = clock::FakeClock::default();
+        let start = clock.now();
+        let real = std::time::Instant::now();
+        run_until_forgotten(&session, &clock).await;
+        assert_eq!(server.heartbeats(), 3);
+        assert_eq!(clock.now() - start, Duration::from_secs(120));
+        assert!(real.elapsed() < Duration::from_secs(10));
+    }
+
+    /// Retry after failure and maintenance waits on clock too.
+    #[tokio::test]
+    async fn failures_back_off_on_fake_clock() {
+        let server = devtools::mock_server::spawn(&[
+            "--fail-times",
+            "1",
+            "--maintenance",
+            "1",
+            "--forget-after",
+            "2",
+        ]);
+        let session = session(&server, "interval = 60").await;
+        let clock = clock::FakeClock::default();
+        let start = clock.now();
+        run_until_forgotten(&session, &clock).await;
+        // Maintenance retry is never sooner than interval
+        assert_eq!(clock.now() - start, Duration::from_secs(5 + 60 + 60));
+    }
+
+    #[tokio::test]
+    async fn maintenance_not_counted_as_failure() {
+        let server = devtools::mock_server::spawn(&[
+            "--fail-times",
+            "1",
+            "--maintenance",
+            "1",
+            "--forget-after",
+            "1",
+        ]);
+        let session = session(&server, "interval = 60").await;
+        let clock = clock::FakeClock::default();
+        run_until_forgotten(&session, &clock).await;
+        let heartbeat = server.last_heartbeat().unwrap();
+        let errors = heartbeat["self_metrics"]["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert_ne!(errors[0]["kind"], "maintenance");
+    }
+
+    #[tokio::test]
+    async fn suspend_reported_as_stall() {
+        let server = devtools::mock_server::spawn(&["--forget-after", "1"]);
+        let session = session(&server, "interval = 60").await;
+        let clock = clock::FakeClock::default();
+        clock.suspend(Duration::from_secs(600));
+        run_until_forgotten(&session, &clock).await;
+        let heartbeat = server.last_heartbeat().unwrap();
+        assert_eq!(heartbeat["stall"]["seconds"], 600);
+    }
+}
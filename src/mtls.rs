@@ -0,0 +1,273 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Client certificate for mutual TLS in `[server.mtls]`: static `certificate` and `key` files,
+//! or enrolled from server with `enroll = true`.
+//!
+//! Enrollment: when the certificate is missing or expires within `renew_before` days, a new
+//! ECDSA P-256 key is generated and its PKCS#10 CSR (subject CN is identification token) is sent
+//! in `csr` of registration, or of a `renew_certificate` event once registered. Server answers
+//! with the signed PEM chain in `certificate`, which is stored along with the key and used from
+//! then on without restart.
+
+use crate::configparser::config::{Configure, Mtls};
+use crate::error::Error;
+use base64::Engine as _;
+use chrono::TimeZone;
+use log::{info, warn};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const CERTIFICATE_FILE: &str = "probe_client_cert.pem";
+pub const KEY_FILE: &str = "probe_client_key.pem";
+pub const DEFAULT_RENEW_BEFORE: u64 = 30;
+pub const DEFAULT_ENROLL: bool = false;
+pub const RENEW_EVENT: &str = "renew_certificate";
+/// Seconds between renewal attempts not answered with a certificate
+const RENEW_RETRY: u64 = 3600;
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// DER TLV of `tag` around `content`.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut output = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        output.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        output.push(0x80 | (bytes.len() - skip) as u8);
+        output.extend_from_slice(&bytes[skip..]);
+    }
+    output.extend_from_slice(content);
+    output
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &items.concat())
+}
+
+/// BIT STRING without unused bits.
+fn bit_string(content: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0u8][..], content].concat())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut output = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        output.push_str(std::str::from_utf8(line).unwrap());
+        output.push('\n');
+    }
+    output.push_str(&format!("-----END {}-----\n", label));
+    output
+}
+
+/// PKCS#10 certification request of `key_pair` with subject `CN=<common_name>`.
+fn csr(key_pair: &EcdsaKeyPair, common_name: &str) -> anyhow::Result<Vec<u8>> {
+    let subject = sequence(&[der(
+        0x31,
+        &sequence(&[
+            der(0x06, OID_COMMON_NAME),
+            der(0x0c, common_name.as_bytes()),
+        ]),
+    )]);
+    let public_key = sequence(&[
+        sequence(&[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)]),
+        bit_string(key_pair.public_key().as_ref()),
+    ]);
+    let info = sequence(&[
+        der(0x02, &[0]),
+        subject,
+        public_key,
+        // No attributes
+        der(0xa0, &[]),
+    ]);
+    let signature = key_pair
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|_| Error::Crypto {
+            message: "Unable sign certificate request".to_string(),
+        })?;
+    Ok(sequence(&[
+        info,
+        sequence(&[der(0x06, OID_ECDSA_SHA256)]),
+        bit_string(signature.as_ref()),
+    ]))
+}
+
+fn paths(mtls: &Mtls) -> (PathBuf, PathBuf) {
+    (
+        mtls.certificate
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| crate::state::path(CERTIFICATE_FILE)),
+        mtls.key
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| crate::state::path(KEY_FILE)),
+    )
+}
+
+fn load(certificate: &Path, key: &Path) -> anyhow::Result<reqwest::Identity> {
+    let mut contents = std::fs::read(key)?;
+    contents.extend(std::fs::read(certificate)?);
+    Ok(reqwest::Identity::from_pem(&contents)?)
+}
+
+/// Client identity of `[server.mtls]`, `None` until enrollment completes.
+pub fn identity(cfg: &Configure) -> anyhow::Result<Option<reqwest::Identity>> {
+    let mtls = match &cfg.server.mtls {
+        Some(mtls) => mtls,
+        None => return Ok(None),
+    };
+    let (certificate, key) = paths(mtls);
+    match load(&certificate, &key) {
+        Ok(identity) => Ok(Some(identity)),
+        Err(e) if mtls.enroll.unwrap_or(DEFAULT_ENROLL) => {
+            if certificate.exists() {
+                warn!(
+                    "Unable load client certificate {}, enroll again: {}",
+                    certificate.display(),
+                    e
+                );
+            }
+            Ok(None)
+        }
+        Err(e) => Err(e.context(format!(
+            "Unable load client certificate {} with key {}",
+            certificate.display(),
+            key.display()
+        ))),
+    }
+}
+
+pub struct Enrollment {
+    certificate: PathBuf,
+    key: PathBuf,
+    /// Seconds before expiry to renew
+    renew_before: i64,
+    /// `notAfter` of current certificate
+    not_after: Mutex<Option<i64>>,
+    /// PKCS#8 of key whose request awaits certificate
+    pending: Mutex<Option<Vec<u8>>>,
+    last_renewal: Mutex<Option<Instant>>,
+}
+
+impl Enrollment {
+    pub fn new(cfg: &Configure) -> anyhow::Result<Option<Self>> {
+        let mtls = match cfg
+            .server
+            .mtls
+            .as_ref()
+            .filter(|mtls| mtls.enroll.unwrap_or(DEFAULT_ENROLL))
+        {
+            Some(mtls) => mtls,
+            None => return Ok(None),
+        };
+        if cfg.server.sni_hostname.is_some() {
+            return Err(Error::Configure {
+                field: "server.mtls.enroll",
+                message: "server.mtls.enroll can not be used with server.sni_hostname".to_string(),
+            }
+            .into());
+        }
+        let (certificate, key) = paths(mtls);
+        let not_after = crate::certificate::read_pem(&certificate.to_string_lossy())
+            .and_then(|der| crate::certificate::not_after(&der))
+            .ok();
+        Ok(Some(Self {
+            certificate,
+            key,
+            renew_before: (mtls.renew_before.unwrap_or(DEFAULT_RENEW_BEFORE) * 86400) as i64,
+            not_after: Mutex::new(not_after),
+            pending: Default::default(),
+            last_renewal: Default::default(),
+        }))
+    }
+
+    /// PEM certificate request with a new key if certificate is missing or due for renewal.
+    /// Renewals outside registration are attempted at most once an hour.
+    pub fn request(&self, common_name: &str, renewal: bool) -> anyhow::Result<Option<String>> {
+        let due = match *self.not_after.lock().unwrap() {
+            Some(not_after) => not_after - chrono::Utc::now().timestamp() < self.renew_before,
+            None => true,
+        };
+        if !due {
+            return Ok(None);
+        }
+        if renewal {
+            let mut last_renewal = self.last_renewal.lock().unwrap();
+            if last_renewal.is_some_and(|last| last.elapsed().as_secs() < RENEW_RETRY) {
+                return Ok(None);
+            }
+            *last_renewal = Some(Instant::now());
+        }
+        let rng = SystemRandom::new();
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).map_err(|_| {
+                Error::Crypto {
+                    message: "Unable generate client key".to_string(),
+                }
+            })?;
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|e| Error::Crypto {
+                    message: format!("Unable load client key: {}", e),
+                })?;
+        let request = pem("CERTIFICATE REQUEST", &csr(&key_pair, common_name)?);
+        *self.pending.lock().unwrap() = Some(pkcs8.as_ref().to_vec());
+        Ok(Some(request))
+    }
+
+    /// Store `certificate` issued for pending request along with its key, return new identity.
+    pub fn complete(&self, certificate: &str) -> anyhow::Result<reqwest::Identity> {
+        let pkcs8 = self
+            .pending
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| Error::Protocol {
+                protocol: "enrollment",
+                message: "Certificate received without pending request".to_string(),
+            })?;
+        let key = pem("PRIVATE KEY", &pkcs8);
+        let identity = reqwest::Identity::from_pem(format!("{}{}", key, certificate).as_bytes())?;
+        crate::state::write_private(&self.key, &key)?;
+        crate::state::write(&self.certificate, certificate)?;
+        let not_after = crate::certificate::read_pem_str(certificate)
+            .and_then(|der| crate::certificate::not_after(&der))?;
+        *self.not_after.lock().unwrap() = Some(not_after);
+        info!(
+            "Client certificate issued, valid until {}",
+            chrono::Utc
+                .timestamp_opt(not_after, 0)
+                .single()
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default()
+        );
+        Ok(identity)
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Strictly increasing nonce which survives restart, so server can reject replayed requests.
+//!
+//! Only a high-water mark is persisted, reserved in blocks to avoid writing on every request.
+
+use log::warn;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub const DEFAULT_NONCE_FILE: &str = "probe_nonce";
+const RESERVE_BLOCK: u64 = 1000;
+
+struct State {
+    next: u64,
+    reserved: u64,
+}
+
+pub struct NonceStore {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+impl NonceStore {
+    pub fn load<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let last = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+        // Nothing survives restart in read-only mode, start from current time to keep increasing
+        let last = if crate::state::is_read_only() {
+            last.max(chrono::Utc::now().timestamp_micros() as u64)
+        } else {
+            last
+        };
+        Self {
+            path,
+            state: Mutex::new(State {
+                next: last,
+                reserved: last,
+            }),
+        }
+    }
+
+    fn persist(&self, reserved: u64) {
+        if let Err(e) = crate::state::write(&self.path, reserved.to_string()) {
+            warn!("Unable persist nonce to {}: {:?}", self.path.display(), e);
+        }
+    }
+
+    pub fn next(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let nonce = state.next;
+        state.next += 1;
+        if state.next > state.reserved {
+            state.reserved = state.next + RESERVE_BLOCK;
+            self.persist(state.reserved);
+        }
+        nonce
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Unit normalization of heartbeat payload.
+//!
+//! Every annotated field is converted to its canonical unit before sending:
+//! sizes to bytes, percentages to 0-100, durations to seconds and timestamps to UTC ISO8601.
+
+use crate::collector::Registry;
+use chrono::TimeZone;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    Bytes,
+    Kibibytes,
+    Mebibytes,
+    Percent,
+    Ratio,
+    Seconds,
+    Milliseconds,
+    UnixTimestamp,
+    Iso8601,
+    Count,
+}
+
+impl Unit {
+    pub fn canonical(self) -> Unit {
+        match self {
+            Unit::Kibibytes | Unit::Mebibytes => Unit::Bytes,
+            Unit::Ratio => Unit::Percent,
+            Unit::Milliseconds => Unit::Seconds,
+            Unit::UnixTimestamp => Unit::Iso8601,
+            unit => unit,
+        }
+    }
+
+    fn convert(self, value: &Value) -> Option<Value> {
+        let scale = |factor: f64| value.as_f64().map(|v| serde_json::json!(v * factor));
+        match self {
+            // Out of range values are kept as reported
+            Unit::Kibibytes => value
+                .as_u64()
+                .and_then(|v| v.checked_mul(1024))
+                .map(Value::from),
+            Unit::Mebibytes => value
+                .as_u64()
+                .and_then(|v| v.checked_mul(1024 * 1024))
+                .map(Value::from),
+            Unit::Ratio => scale(100.0),
+            Unit::Milliseconds => scale(0.001),
+            Unit::UnixTimestamp => value.as_i64().and_then(|ts| {
+                chrono::Utc
+                    .timestamp_opt(ts, 0)
+                    .single()
+                    .map(|t| Value::from(t.to_rfc3339()))
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub struct Schema {
+    fields: Vec<(String, Unit)>,
+}
+
+impl Schema {
+    pub fn new(registry: &Registry) -> Self {
+        let mut fields: Vec<(String, Unit)> = [
+            ("mount.*.mount_avail", Unit::Bytes),
+            ("mount.*.mount_total", Unit::Bytes),
+            ("network_statistics.interfaces.*.rx_bytes", Unit::Bytes),
+            ("network_statistics.interfaces.*.tx_bytes", Unit::Bytes),
+            ("network_statistics.interfaces.*.rx_packets", Unit::Count),
+            ("network_statistics.interfaces.*.tx_packets", Unit::Count),
+            ("network_statistics.interfaces.*.rx_errors", Unit::Count),
+            ("network_statistics.interfaces.*.tx_errors", Unit::Count),
+            ("power.battery_size", Unit::Ratio),
+            ("power.remaining_time", Unit::Seconds),
+            ("memory.used", Unit::Bytes),
+            ("memory.total", Unit::Bytes),
+            ("cpu.user", Unit::Percent),
+            ("cpu.system", Unit::Percent),
+            ("cpu.idle", Unit::Percent),
+            ("conntrack.count", Unit::Count),
+            ("conntrack.max", Unit::Count),
+            ("conntrack.usage", Unit::Percent),
+            ("pressure.*.*.avg10", Unit::Percent),
+            ("pressure.*.*.avg60", Unit::Percent),
+            ("pressure.*.*.avg300", Unit::Percent),
+            ("pressure.*.*.total", Unit::Seconds),
+            ("numa.*.mem_total", Unit::Bytes),
+            ("numa.*.mem_free", Unit::Bytes),
+            ("numa.*.mem_used", Unit::Bytes),
+            ("numa.*.numa_miss", Unit::Count),
+            ("numa.*.numa_foreign", Unit::Count),
+            ("numa.*.hugepages.*.total", Unit::Count),
+            ("numa.*.hugepages.*.free", Unit::Count),
+            ("hugepages.*.total", Unit::Count),
+            ("hugepages.*.free", Unit::Count),
+            ("hugepages.*.reserved", Unit::Count),
+            ("hugepages.*.surplus", Unit::Count),
+            ("uptime", Unit::Seconds),
+        ]
+        .iter()
+        .map(|(path, unit)| (path.to_string(), *unit))
+        .collect();
+        for (name, units) in registry.units() {
+            for (path, unit) in units {
+                fields.push((format!("collectors.{}.{}", name, path), unit));
+            }
+        }
+        Self { fields }
+    }
+
+    pub fn normalize(&self, payload: &mut Value) {
+        for (path, unit) in &self.fields {
+            if unit.canonical() == *unit {
+                continue;
+            }
+            let segments: Vec<&str> = path.split('.').collect();
+            apply(payload, &segments, *unit);
+        }
+    }
+
+    /// Canonical unit of field at dotted `path`, `*` in schema matches any segment.
+    pub fn unit_of(&self, path: &str) -> Option<Unit> {
+        let segments: Vec<&str> = path.split('.').collect();
+        self.fields
+            .iter()
+            .find(|(pattern, _)| {
+                let pattern: Vec<&str> = pattern.split('.').collect();
+                pattern.len() == segments.len()
+                    && pattern
+                        .iter()
+                        .zip(&segments)
+                        .all(|(p, s)| *p == "*" || p == s)
+            })
+            .map(|(_, unit)| unit.canonical())
+    }
+
+    /// Canonical unit of every annotated field, sent along with registration.
+    pub fn annotations(&self) -> BTreeMap<String, Unit> {
+        self.fields
+            .iter()
+            .map(|(path, unit)| (path.clone(), unit.canonical()))
+            .collect()
+    }
+}
+
+fn apply(value: &mut Value, path: &[&str], unit: Unit) {
+    let (segment, rest) = match path.split_first() {
+        Some(x) => x,
+        None => {
+            if let Some(converted) = unit.convert(value) {
+                *value = converted;
+            }
+            return;
+        }
+    };
+    match (value, *segment) {
+        (Value::Object(map), "*") => map.values_mut().for_each(|v| apply(v, rest, unit)),
+        (Value::Array(array), "*") => array.iter_mut().for_each(|v| apply(v, rest, unit)),
+        (Value::Object(map), key) => {
+            if let Some(v) = map.get_mut(key) {
+                apply(v, rest, unit)
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowing_sizes_kept_as_reported() {
+        assert_eq!(
+            Unit::Kibibytes.convert(&Value::from(2)),
+            Some(Value::from(2048))
+        );
+        assert_eq!(Unit::Kibibytes.convert(&Value::from(u64::MAX)), None);
+        assert_eq!(Unit::Mebibytes.convert(&Value::from(u64::MAX / 1024)), None);
+    }
+}
@@ -0,0 +1,227 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Deliver plain text notifications out of band, without going through the probe server.
+//!
+//! Webhook `body` and mail `subject` are templates rendered with `message` and `hostname`.
+
+use crate::allowlist::AllowList;
+use crate::configparser::config::{Configure, NotifyCommand, Smtp, Webhook};
+use crate::error::Error;
+use crate::sandbox::{self, Limits};
+use crate::template::Templates;
+use log::{info, warn};
+use serde_json::Value;
+use std::time::Duration;
+
+const WEBHOOK_TIMEOUT: u64 = 10;
+const WEBHOOK_TEMPLATE: &str = "webhook";
+#[cfg(feature = "smtp")]
+const SUBJECT_TEMPLATE: &str = "subject";
+#[cfg(feature = "smtp")]
+pub const DEFAULT_SUBJECT: &str = "probe-client notification";
+
+pub struct Notifier {
+    client: reqwest::Client,
+    allow_list: Option<AllowList>,
+    webhook: Option<Webhook>,
+    command: Option<NotifyCommand>,
+    smtp: Option<Smtp>,
+    templates: Templates,
+    hostname: String,
+}
+
+impl Notifier {
+    pub fn new(
+        cfg: &Configure,
+        webhook: Option<&Webhook>,
+        command: Option<&NotifyCommand>,
+        smtp: Option<&Smtp>,
+    ) -> anyhow::Result<Self> {
+        let allow_list = AllowList::from_config(cfg)?;
+        let mut builder = reqwest::ClientBuilder::new()
+            .user_agent(format!("probe_client {}", crate::session::CLIENT_VERSION))
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT));
+        if let Some(allow_list) = &allow_list {
+            builder = builder.redirect(allow_list.redirect_policy());
+        }
+        let mut templates = Templates::default();
+        if let Some(body) = webhook.and_then(|webhook| webhook.body.as_ref()) {
+            templates.register("webhook.body", WEBHOOK_TEMPLATE, body)?;
+        }
+        #[cfg(feature = "smtp")]
+        if let Some(subject) = smtp.and_then(|smtp| smtp.subject.as_ref()) {
+            templates.register("smtp.subject", SUBJECT_TEMPLATE, subject)?;
+        }
+        Ok(Self {
+            client: builder.build()?,
+            allow_list,
+            webhook: webhook.cloned(),
+            command: command.cloned(),
+            smtp: smtp.cloned(),
+            templates,
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.webhook.is_none() && self.command.is_none() && self.smtp.is_none()
+    }
+
+    fn context(&self, message: &str) -> Value {
+        serde_json::json!({
+            "message": message,
+            "hostname": self.hostname,
+        })
+    }
+
+    /// Post rendered `body`, or `{"text": ..., "content": ...}` accepted by Slack, Discord
+    /// and Teams incoming webhooks.
+    async fn post_webhook(&self, webhook: &Webhook, message: &str) -> anyhow::Result<()> {
+        if let Some(allow_list) = &self.allow_list {
+            allow_list.check(&webhook.url)?;
+        }
+        let body = if self.templates.has(WEBHOOK_TEMPLATE) {
+            self.templates
+                .render(WEBHOOK_TEMPLATE, &self.context(message))?
+        } else {
+            serde_json::json!({
+                "text": message,
+                "content": message,
+            })
+            .to_string()
+        };
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        for (name, value) in webhook.headers.iter().flatten() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let resp = request.send().await?;
+        if !resp.status().is_success() {
+            return Err(Error::UnexpectedStatus {
+                url: webhook.url.clone(),
+                status: resp.status().as_u16(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Run command with message as last argument.
+    async fn run_command(&self, command: &NotifyCommand, message: &str) -> anyhow::Result<()> {
+        let mut args = command.args.clone().unwrap_or_default();
+        args.push(message.to_string());
+        sandbox::run(
+            &command.command,
+            &args,
+            &Limits {
+                timeout: command.timeout,
+                ..Default::default()
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+
+    #[cfg(feature = "smtp")]
+    async fn send_mail(&self, smtp: &Smtp, message: &str) -> anyhow::Result<()> {
+        use crate::configparser::config::SmtpTls;
+        use lettre::transport::smtp::authentication::Credentials;
+        use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+        let mut builder = Message::builder().from(smtp.from.parse()?).subject(
+            if self.templates.has(SUBJECT_TEMPLATE) {
+                self.templates
+                    .render(SUBJECT_TEMPLATE, &self.context(message))?
+            } else {
+                DEFAULT_SUBJECT.to_string()
+            },
+        );
+        for to in &smtp.to {
+            builder = builder.to(to.parse()?);
+        }
+        let mail = builder.body(message.to_string())?;
+        let mut transport = match smtp.tls.unwrap_or_default() {
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+        };
+        if let Some(port) = smtp.port {
+            transport = transport.port(port);
+        }
+        if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+            transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        transport.build().send(mail).await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "smtp"))]
+    async fn send_mail(&self, _smtp: &Smtp, _message: &str) -> anyhow::Result<()> {
+        Err(Error::Unavailable {
+            feature: "smtp",
+            message: "smtp feature disabled, unable send mail".to_string(),
+        }
+        .into())
+    }
+
+    /// Send through every configured channel, fail only if none succeeded.
+    pub async fn send(&self, message: &str) -> anyhow::Result<()> {
+        let mut delivered = false;
+        let mut last_error = None;
+        if let Some(webhook) = &self.webhook {
+            match self.post_webhook(webhook, message).await {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    warn!("Unable send notification to webhook: {}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let Some(command) = &self.command {
+            match self.run_command(command, message).await {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    warn!("Unable send notification by {}: {}", command.command, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let Some(smtp) = &self.smtp {
+            match self.send_mail(smtp, message).await {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    warn!("Unable send notification mail via {}: {}", smtp.host, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (delivered, last_error) {
+            (false, Some(e)) => Err(e),
+            _ => {
+                info!("Notification sent: {}", message);
+                Ok(())
+            }
+        }
+    }
+}
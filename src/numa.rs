@@ -0,0 +1,150 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! NUMA node memory and hugepage pools on Linux, for database hosts where NUMA imbalance matters.
+//!
+//! Per-node statistics are only reported on hosts with more than one node,
+//! hugepage pools only if any page is allocated.
+
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+
+#[derive(Serialize, Deserialize)]
+pub struct Hugepages {
+    pub total: u64,
+    pub free: u64,
+    /// Promised to mappings but not yet faulted in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserved: Option<u64>,
+    pub surplus: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NumaNode {
+    pub mem_total: u64,
+    pub mem_free: u64,
+    pub mem_used: u64,
+    /// Allocations intended for this node but served elsewhere, and the other way round
+    pub numa_miss: u64,
+    pub numa_foreign: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hugepages: BTreeMap<String, Hugepages>,
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Hugepage pools in `dir` keyed by page size, e.g. `2048kB`.
+fn hugepages(dir: &Path) -> BTreeMap<String, Hugepages> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Default::default(),
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let size = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix("hugepages-")?
+                .to_string();
+            let pool = Hugepages {
+                total: read_number(&path.join("nr_hugepages"))?,
+                free: read_number(&path.join("free_hugepages"))?,
+                reserved: read_number(&path.join("resv_hugepages")),
+                surplus: read_number(&path.join("surplus_hugepages")).unwrap_or(0),
+            };
+            if pool.total == 0 && pool.surplus == 0 {
+                return None;
+            }
+            Some((size, pool))
+        })
+        .collect()
+}
+
+/// Values of `Key: value [kB]` or `key value` lines, sizes in bytes.
+fn read_table(path: &Path) -> BTreeMap<String, u64> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    contents
+        .lines()
+        .filter_map(|line| {
+            // meminfo lines are prefixed with `Node N`
+            let line = match line.strip_prefix("Node ") {
+                Some(rest) => rest.split_once(' ')?.1,
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let key = fields.next()?.trim_end_matches(':').to_string();
+            let value: u64 = fields.next()?.parse().ok()?;
+            let value = match fields.next() {
+                Some("kB") => value * 1024,
+                _ => value,
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+fn node(dir: &Path) -> Option<NumaNode> {
+    let meminfo = read_table(&dir.join("meminfo"));
+    let numastat = read_table(&dir.join("numastat"));
+    let mem_total = *meminfo.get("MemTotal")?;
+    let mem_free = *meminfo.get("MemFree")?;
+    Some(NumaNode {
+        mem_total,
+        mem_free,
+        mem_used: mem_total.saturating_sub(mem_free),
+        numa_miss: numastat.get("numa_miss").copied().unwrap_or(0),
+        numa_foreign: numastat.get("numa_foreign").copied().unwrap_or(0),
+        hugepages: hugepages(&dir.join("hugepages")),
+    })
+}
+
+/// Statistics of every NUMA node keyed by name (`node0`...), `None` on single node hosts.
+pub fn nodes() -> Option<BTreeMap<String, NumaNode>> {
+    let nodes: BTreeMap<String, NumaNode> = std::fs::read_dir(NODE_DIR)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            name.strip_prefix("node")?.parse::<u32>().ok()?;
+            Some((name, node(&entry.path())?))
+        })
+        .collect();
+    if nodes.len() > 1 {
+        Some(nodes)
+    } else {
+        None
+    }
+}
+
+/// System wide hugepage pools keyed by page size, `None` if none is allocated.
+pub fn pools() -> Option<BTreeMap<String, Hugepages>> {
+    let pools = hugepages(Path::new(HUGEPAGES_DIR));
+    if pools.is_empty() {
+        None
+    } else {
+        Some(pools)
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `--output` option shared by subcommands: `text` for operators, `json` for scripts.
+//!
+//! JSON schemas are stable, fields are only added, never renamed or removed.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+pub fn arg<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("output")
+        .long("output")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text")
+        .help("Output format")
+}
+
+pub fn of(matches: &clap::ArgMatches<'_>) -> Format {
+    match matches.value_of("output") {
+        Some("json") => Format::Json,
+        _ => Format::Text,
+    }
+}
+
+/// Print value as pretty JSON followed by newline.
+pub fn print_json(value: &serde_json::Value) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -0,0 +1,229 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Package collector in `[collector.packages]`: count of installed packages and a hash of the
+//! full list per package manager (dpkg, rpm, apk, pacman), collected once a day unless
+//! `[collector.cache]` says otherwise. Server tells drift from the hash, and asks for the full
+//! list with `package_list` action only when it changed.
+
+use crate::action::ActionHandler;
+use crate::collector::Collector;
+use crate::error::Error;
+use crate::normalize::Unit;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+pub const COLLECTOR_NAME: &str = "packages";
+pub const ACTION_NAME: &str = "package_list";
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(86400);
+const QUERY_TIMEOUT: u64 = 120;
+const MAX_OUTPUT: u64 = 16 << 20;
+
+/// Package manager, its query command and how name, version and architecture are laid out.
+pub(crate) const MANAGERS: [(&str, &str, &[&str]); 4] = [
+    (
+        "dpkg",
+        "dpkg-query",
+        &["-W", "-f", "${Package}\t${Version}\t${Architecture}\n"],
+    ),
+    (
+        "rpm",
+        "rpm",
+        &["-qa", "--qf", "%{NAME}\t%{VERSION}-%{RELEASE}\t%{ARCH}\n"],
+    ),
+    ("apk", "apk", &["info", "-v"]),
+    ("pacman", "pacman", &["-Q"]),
+];
+
+struct Package {
+    name: String,
+    version: String,
+    arch: Option<String>,
+}
+
+impl Package {
+    fn parse(manager: &str, line: &str) -> Option<Self> {
+        let (name, version, arch) = match manager {
+            "dpkg" | "rpm" => {
+                let mut fields = line.split('\t');
+                (fields.next()?, fields.next()?, fields.next())
+            }
+            // `name-version-rN`, name may contain `-` itself
+            "apk" => {
+                let mut fields = line.rsplitn(3, '-');
+                let release = fields.next()?;
+                let version = fields.next()?;
+                let name = fields.next()?;
+                return Some(Self {
+                    name: name.to_string(),
+                    version: format!("{}-{}", version, release),
+                    arch: None,
+                });
+            }
+            _ => {
+                let (name, version) = line.split_once(' ')?;
+                (name, version, None)
+            }
+        };
+        Some(Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.filter(|arch| !arch.is_empty()).map(str::to_string),
+        })
+    }
+
+    /// Canonical line hashed for drift detection.
+    fn line(&self) -> String {
+        format!(
+            "{}\t{}\t{}",
+            self.name,
+            self.version,
+            self.arch.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// Installed packages of every package manager found, sorted.
+async fn inventory() -> anyhow::Result<Vec<(&'static str, Vec<Package>)>> {
+    let limits = Limits {
+        timeout: Some(QUERY_TIMEOUT),
+        max_output: Some(MAX_OUTPUT),
+        ..Default::default()
+    };
+    let mut result = Vec::new();
+    for (manager, program, args) in &MANAGERS {
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let output = match sandbox::run(program, &args, &limits).await {
+            Ok(output) => output,
+            Err(e) => {
+                log::debug!("Unable query {} packages: {}", manager, e);
+                continue;
+            }
+        };
+        let mut packages = output
+            .lines()
+            .filter_map(|line| Package::parse(manager, line.trim_end()))
+            .collect::<Vec<_>>();
+        packages.sort_by_cached_key(Package::line);
+        result.push((*manager, packages));
+    }
+    if result.is_empty() {
+        return Err(Error::Unsupported {
+            what: "Package manager".to_string(),
+        }
+        .into());
+    }
+    Ok(result)
+}
+
+fn hash(manager: &str, packages: &[Package]) -> String {
+    let mut list = String::new();
+    for package in packages {
+        list.push_str(manager);
+        list.push('\t');
+        list.push_str(&package.line());
+        list.push('\n');
+    }
+    crate::signing::sha256_hex(list.as_bytes())
+}
+
+pub struct PackageCollector;
+
+#[async_trait]
+impl Collector for PackageCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let inventory = inventory().await?;
+        let mut managers = json!({});
+        for (manager, packages) in &inventory {
+            managers[manager] = json!({
+                "count": packages.len(),
+                "hash": hash(manager, packages),
+            });
+        }
+        let hashes = inventory
+            .iter()
+            .map(|(manager, packages)| hash(manager, packages))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok(json!({
+            "total": inventory.iter().map(|(_, packages)| packages.len()).sum::<usize>(),
+            "hash": crate::signing::sha256_hex(hashes.as_bytes()),
+            "managers": managers,
+        }))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("total".to_string(), Unit::Count),
+            ("managers.*.count".to_string(), Unit::Count),
+        ]
+    }
+
+    fn source(&self) -> String {
+        MANAGERS
+            .iter()
+            .map(|(_, program, _)| *program)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// `package_list` action: full list of installed packages, of one manager if `manager` is given.
+pub struct PackageListHandler;
+
+#[async_trait]
+impl ActionHandler for PackageListHandler {
+    fn name(&self) -> &str {
+        ACTION_NAME
+    }
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value> {
+        let requested = params.get("manager").and_then(Value::as_str);
+        let mut managers = json!({});
+        for (manager, packages) in inventory().await? {
+            if requested.is_some_and(|requested| requested != manager) {
+                continue;
+            }
+            managers[manager] = json!({
+                "hash": hash(manager, &packages),
+                "packages": packages
+                    .iter()
+                    .map(|package| json!({
+                        "name": package.name,
+                        "version": package.version,
+                        "arch": package.arch,
+                    }))
+                    .collect::<Vec<_>>(),
+            });
+        }
+        if let Some(requested) = requested.filter(|_| managers.as_object().unwrap().is_empty()) {
+            return Err(Error::Unsupported {
+                what: format!("Package manager {}", requested),
+            }
+            .into());
+        }
+        Ok(json!({ "managers": managers }))
+    }
+}
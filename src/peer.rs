@@ -0,0 +1,143 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Peer reachability matrix in `[[check.peer]]`: TCP connect round trip to other probe hosts
+//! each interval, so the server can assemble a mesh connectivity matrix across sites.
+//!
+//! A refused connection still proves the host is reachable, so peers need no listener on the
+//! probed port; only timeouts and network errors count as loss.
+
+use crate::collector::Collector;
+use crate::configparser::config::PeerCheck;
+use crate::error::Error;
+use crate::normalize::Unit;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const COLLECTOR_NAME: &str = "peers";
+const DEFAULT_PORT: u16 = 22;
+pub const DEFAULT_COUNT: u32 = 3;
+pub const DEFAULT_TIMEOUT: u64 = 2;
+
+async fn resolve(address: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, DEFAULT_PORT));
+    }
+    let address = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, DEFAULT_PORT)
+    };
+    let found = tokio::net::lookup_host(&address).await?.next();
+    found.ok_or_else(|| Error::Resolve { name: address }.into())
+}
+
+/// Round trip of one connection attempt in seconds, `None` if the peer is unreachable.
+async fn connect(address: SocketAddr, timeout: Duration) -> Option<f64> {
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(address)).await {
+        Ok(Ok(_)) => Some(start.elapsed().as_secs_f64()),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            Some(start.elapsed().as_secs_f64())
+        }
+        _ => None,
+    }
+}
+
+async fn inspect(check: PeerCheck) -> anyhow::Result<Value> {
+    let address = resolve(&check.address).await?;
+    let count = check.count.unwrap_or(DEFAULT_COUNT).max(1);
+    let timeout = Duration::from_secs(check.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let mut latencies = Vec::new();
+    for _ in 0..count {
+        if let Some(latency) = connect(address, timeout).await {
+            latencies.push(latency);
+        }
+    }
+    let received = latencies.len() as u32;
+    let mut result = serde_json::json!({
+        "address": address.to_string(),
+        "sent": count,
+        "received": received,
+        "loss": f64::from(count - received) / f64::from(count) * 100.0,
+        "ok": received > 0,
+    });
+    if received > 0 {
+        result["min"] = serde_json::json!(latencies.iter().cloned().fold(f64::MAX, f64::min));
+        result["max"] = serde_json::json!(latencies.iter().cloned().fold(0.0, f64::max));
+        result["avg"] = serde_json::json!(latencies.iter().sum::<f64>() / f64::from(received));
+    }
+    Ok(result)
+}
+
+pub struct PeerCollector {
+    checks: Vec<PeerCheck>,
+}
+
+impl PeerCollector {
+    pub fn new(checks: &[PeerCheck]) -> Self {
+        Self {
+            checks: checks.to_vec(),
+        }
+    }
+}
+
+#[async_trait]
+impl Collector for PeerCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        // Peers are probed concurrently, an unreachable one costs timeout * count at most
+        let handles = self
+            .checks
+            .iter()
+            .map(|check| {
+                let name = check.name.clone().unwrap_or_else(|| check.address.clone());
+                (name, tokio::spawn(inspect(check.clone())))
+            })
+            .collect::<Vec<_>>();
+        let mut result = serde_json::Map::new();
+        for (name, handle) in handles {
+            let value = match handle.await {
+                Ok(Ok(value)) => value,
+                Ok(Err(e)) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+                Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+            };
+            result.insert(name, value);
+        }
+        Ok(Value::Object(result))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        let mut units = ["min", "avg", "max"]
+            .iter()
+            .map(|field| (format!("*.{}", field), Unit::Seconds))
+            .collect::<Vec<_>>();
+        units.push(("*.loss".to_string(), Unit::Percent));
+        units
+    }
+
+    fn source(&self) -> String {
+        "peer check".to_string()
+    }
+}
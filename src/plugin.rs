@@ -0,0 +1,163 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Collectors loaded from shared libraries.
+//!
+//! A plugin is a cdylib exporting the following C ABI functions:
+//!
+//! ```c
+//! char *probe_plugin_collect(void);         // JSON string, NULL on error
+//! void probe_plugin_free(char *ptr);        // release string from collect
+//! ```
+//!
+//! Plugins are never loaded into client itself: each collection runs client again as hidden
+//! `plugin-host` subcommand in a sandboxed child (see `sandbox`), which loads the library,
+//! prints what it collected and exits. Collector is named after the file stem.
+
+use crate::collector::Collector;
+use crate::error::Error;
+use crate::sandbox::{self, Limits};
+use async_trait::async_trait;
+use libloading::{Library, Symbol};
+use log::{error, info};
+use serde_json::Value;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PLUGIN_DIR: &str = "plugins";
+pub const SUBCOMMAND_NAME: &str = "plugin-host";
+const CPU_SECONDS: u64 = 5;
+const MEMORY_MB: u64 = 512;
+
+type CollectFn = unsafe extern "C" fn() -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+pub struct PluginCollector {
+    name: String,
+    path: PathBuf,
+    limits: Limits,
+}
+
+impl PluginCollector {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| Error::Configure {
+                field: "plugins.directory",
+                message: format!("Invalid plugin file name {}", path.display()),
+            })?;
+        Ok(Self {
+            // Conventional `lib` prefix of shared libraries is not part of the name
+            name: name.strip_prefix("lib").unwrap_or(name).to_string(),
+            path: path.canonicalize()?,
+            limits: Limits {
+                timeout: None,
+                cpu_seconds: Some(CPU_SECONDS),
+                memory_mb: Some(MEMORY_MB),
+                seccomp: cfg!(target_os = "linux"),
+                max_output: None,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl Collector for PluginCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn source(&self) -> String {
+        format!("plugin: {}", self.path.display())
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let program = std::env::current_exe()?;
+        let args = [
+            SUBCOMMAND_NAME.to_string(),
+            self.path.to_string_lossy().to_string(),
+        ];
+        let output = sandbox::run(&program.to_string_lossy(), &args, &self.limits).await?;
+        Ok(serde_json::from_str(output.trim())?)
+    }
+}
+
+pub fn discover<P: AsRef<Path>>(dir: P) -> Vec<PluginCollector> {
+    let dir = dir.as_ref();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Default::default(),
+    };
+    let mut plugins: Vec<PluginCollector> = Default::default();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(std::env::consts::DLL_EXTENSION) {
+            continue;
+        }
+        match PluginCollector::new(&path) {
+            Ok(plugin) => {
+                info!("Found plugin {} at {}", plugin.name, path.display());
+                plugins.push(plugin);
+            }
+            Err(e) => error!("Unable use plugin {}: {:?}", path.display(), e),
+        }
+    }
+    plugins
+}
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .setting(clap::AppSettings::Hidden)
+        .about("Load plugin and print what it collects, run by client in a sandboxed child")
+        .arg(
+            clap::Arg::with_name("path")
+                .help("Plugin library")
+                .required(true),
+        )
+}
+
+pub fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    println!("{}", host(matches.value_of("path").unwrap())?);
+    Ok(())
+}
+
+/// Load plugin in this (child) process and call its collect function.
+fn host(path: &str) -> anyhow::Result<String> {
+    // Safety: only ever called in the sandboxed child, plugin follows the ABI above.
+    unsafe {
+        let library = Library::new(path)?;
+        let collect: Symbol<CollectFn> = library.get(b"probe_plugin_collect\0")?;
+        let free: Symbol<FreeFn> = library.get(b"probe_plugin_free\0")?;
+        let ptr = collect();
+        if ptr.is_null() {
+            return Err(Error::Command {
+                program: "probe_plugin_collect".to_string(),
+                message: "returned null".to_string(),
+            }
+            .into());
+        }
+        let output = CStr::from_ptr(ptr).to_string_lossy().to_string();
+        free(ptr);
+        Ok(output)
+    }
+}
@@ -0,0 +1,108 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Threshold and alert logic supplied as WASM module.
+//!
+//! The module should export:
+//!
+//! * `memory`
+//! * `alloc(len: i32) -> i32`: reserve `len` bytes for the input document
+//! * `evaluate(ptr: i32, len: i32) -> i64`: evaluate the normalized heartbeat payload,
+//!   return `(ptr << 32) | len` of a JSON array of alert messages, or 0 if nothing to alert.
+
+use crate::error::Error;
+use log::info;
+use serde_json::Value;
+use std::path::Path;
+use wasmi::{Config, Engine, Linker, Module, Store};
+
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// Only message of wasmi errors is kept.
+fn failed(e: impl std::fmt::Display) -> Error {
+    Error::Policy {
+        message: e.to_string(),
+    }
+}
+
+pub struct Policy {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+}
+
+impl Policy {
+    pub fn load<P: AsRef<Path>>(path: P, fuel: Option<u64>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|source| Error::Io {
+            operation: "read",
+            path: path.display().to_string(),
+            source,
+        })?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &bytes[..]).map_err(failed)?;
+        info!("Load policy module from {}", path.display());
+        Ok(Self {
+            engine,
+            module,
+            fuel: fuel.unwrap_or(DEFAULT_FUEL),
+        })
+    }
+
+    pub fn evaluate(&self, payload: &Value) -> anyhow::Result<Vec<String>> {
+        let input = serde_json::to_vec(payload)?;
+
+        let mut store = Store::new(&self.engine, ());
+        store.add_fuel(self.fuel).map_err(failed)?;
+        let linker = <Linker<()>>::new(&self.engine);
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(failed)?;
+
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| failed("does not export memory"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(failed)?;
+        let evaluate = instance
+            .get_typed_func::<(i32, i32), i64>(&store, "evaluate")
+            .map_err(failed)?;
+
+        let ptr = alloc.call(&mut store, input.len() as i32).map_err(failed)?;
+        memory
+            .write(&mut store, ptr as usize, &input)
+            .map_err(failed)?;
+        let result = evaluate
+            .call(&mut store, (ptr, input.len() as i32))
+            .map_err(failed)?;
+        if result == 0 {
+            return Ok(Default::default());
+        }
+
+        let (out_ptr, out_len) = ((result >> 32) as u32 as usize, result as u32 as usize);
+        let mut buffer = vec![0u8; out_len];
+        memory.read(&store, out_ptr, &mut buffer).map_err(failed)?;
+        Ok(serde_json::from_slice(&buffer)?)
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Back off under severe host pressure in `[pressure]`: while memory or CPU stall share
+//! (PSI `some avg10`, Linux only) or load average per CPU is above its threshold, heartbeats carry
+//! only a `degraded` tag instead of collected statistics, and are sent with a shorter timeout,
+//! so the agent doesn't worsen an incident.
+
+use crate::configparser::config::Configure;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use systemstat::Platform;
+
+pub const DEFAULT_MEMORY: f64 = 40.0;
+pub const DEFAULT_CPU: f64 = 90.0;
+pub const DEFAULT_LOAD: f64 = 4.0;
+pub const DEFAULT_TIMEOUT: u64 = 3;
+
+pub struct Monitor {
+    memory: f64,
+    cpu: f64,
+    load: f64,
+    timeout: Duration,
+    /// Whether last check was under pressure, to log only transitions
+    active: AtomicBool,
+}
+
+impl Monitor {
+    pub fn new(cfg: &Configure) -> Option<Self> {
+        let pressure = cfg.pressure.as_ref().filter(|pressure| pressure.enabled)?;
+        Some(Self {
+            memory: pressure.memory.unwrap_or(DEFAULT_MEMORY),
+            cpu: pressure.cpu.unwrap_or(DEFAULT_CPU),
+            load: pressure.load.unwrap_or(DEFAULT_LOAD),
+            timeout: Duration::from_secs(pressure.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            active: AtomicBool::new(false),
+        })
+    }
+
+    /// Request timeout while under pressure.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// `degraded` tag of heartbeat if host is under pressure now.
+    pub fn check(&self) -> Option<serde_json::Value> {
+        let mut reasons = Vec::new();
+        let mut tag = serde_json::Map::new();
+        #[cfg(target_os = "linux")]
+        for (resource, threshold) in [("memory", self.memory), ("cpu", self.cpu)] {
+            if let Some(avg10) = crate::info::Pressure::some_avg10(resource) {
+                if avg10 >= threshold {
+                    reasons.push(resource);
+                }
+                tag.insert(resource.to_string(), serde_json::json!(avg10));
+            }
+        }
+        if let Ok(load) = systemstat::System::new().load_average() {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            let load = load.one as f64 / cpus as f64;
+            if load >= self.load {
+                reasons.push("load");
+            }
+            tag.insert("load".to_string(), serde_json::json!(load));
+        }
+        let degraded = !reasons.is_empty();
+        if self.active.swap(degraded, Ordering::Relaxed) != degraded {
+            if degraded {
+                warn!(
+                    "Host under pressure ({}), send minimal heartbeats",
+                    reasons.join(", ")
+                );
+            } else {
+                info!("Host pressure relieved, send full heartbeats again");
+            }
+        }
+        if !degraded {
+            return None;
+        }
+        tag.insert("reasons".to_string(), serde_json::json!(reasons));
+        Some(serde_json::Value::Object(tag))
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Remove infrastructure details from payload before sending, by `statistics.privacy` level.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Privacy {
+    /// Send everything
+    #[default]
+    Full,
+    /// Hash hostname and talkers destinations, strip IP addresses and mount paths. Other
+    /// collector outputs are sent as they are.
+    Redacted,
+    /// Drop hostname, network and mount sections entirely
+    Minimal,
+}
+
+fn hash(salt: &str, value: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA256,
+        format!("{}:{}", salt, value).as_bytes(),
+    );
+    digest.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn remove(value: &mut Value, key: &str) {
+    if let Some(map) = value.as_object_mut() {
+        map.remove(key);
+    }
+}
+
+impl Privacy {
+    /// Redact registration data, hostname is hashed with `salt` (the identification token).
+    pub fn redact_register(self, data: &mut Value, salt: &str) {
+        match self {
+            Privacy::Full => {}
+            Privacy::Redacted => {
+                if let Some(hostname) = data.get("hostname").and_then(Value::as_str) {
+                    data["hostname"] = Value::String(hash(salt, hostname));
+                }
+                if let Some(instance_id) = data
+                    .pointer("/virtualization/instance_id")
+                    .and_then(Value::as_str)
+                {
+                    data["virtualization"]["instance_id"] = Value::String(hash(salt, instance_id));
+                }
+                remove(data, "address");
+            }
+            Privacy::Minimal => {
+                remove(data, "hostname");
+                remove(data, "address");
+                if let Some(virtualization) = data.get_mut("virtualization") {
+                    remove(virtualization, "instance_id");
+                }
+            }
+        }
+    }
+
+    /// Redact heartbeat payload, talkers destinations are hashed with `salt` like hostname.
+    pub fn redact_payload(self, payload: &mut Value, salt: &str) {
+        match self {
+            Privacy::Full => {}
+            Privacy::Redacted => {
+                if let Some(interfaces) = payload
+                    .pointer_mut("/network/interfaces")
+                    .and_then(Value::as_object_mut)
+                {
+                    interfaces
+                        .values_mut()
+                        .for_each(|addresses| *addresses = Value::Array(Vec::new()));
+                }
+                if let Some(mounts) = payload.get_mut("mount").and_then(Value::as_array_mut) {
+                    for mount in mounts {
+                        remove(mount, "mount_from");
+                        remove(mount, "mount_on");
+                    }
+                }
+                if let Some(destinations) = payload
+                    .pointer_mut("/collectors/talkers/destinations")
+                    .and_then(Value::as_array_mut)
+                {
+                    for destination in destinations {
+                        if let Some(address) = destination.get("address").and_then(Value::as_str) {
+                            destination["address"] = Value::String(hash(salt, address));
+                        }
+                    }
+                }
+            }
+            Privacy::Minimal => {
+                for key in ["network", "network_statistics", "mount", "collectors"] {
+                    remove(payload, key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register() -> Value {
+        serde_json::json!({
+            "hostname": "db-01.internal",
+            "address": ["10.0.0.5"],
+            "virtualization": { "instance_id": "i-0123456789" },
+            "os": "Linux",
+        })
+    }
+
+    fn payload() -> Value {
+        serde_json::json!({
+            "cpu": 0.5,
+            "network": { "interfaces": { "eth0": ["10.0.0.5", "fe80::1"] } },
+            "network_statistics": { "eth0": { "rx": 1, "tx": 2 } },
+            "mount": [{ "mount_from": "/dev/sda1", "mount_on": "/srv/secret", "total": 10 }],
+            "collectors": {
+                "custom": { "host": "db-02.internal" },
+            },
+        })
+    }
+
+    #[test]
+    fn full_keeps_everything() {
+        let mut data = register();
+        Privacy::Full.redact_register(&mut data, "salt");
+        assert_eq!(data, register());
+        let mut data = payload();
+        Privacy::Full.redact_payload(&mut data, "salt");
+        assert_eq!(data, payload());
+    }
+
+    #[test]
+    fn redacted_hashes_identity_and_strips_addresses() {
+        let mut data = register();
+        Privacy::Redacted.redact_register(&mut data, "salt");
+        assert_eq!(data["hostname"], hash("salt", "db-01.internal"));
+        assert_ne!(data["hostname"], hash("other", "db-01.internal"));
+        assert_eq!(
+            data["virtualization"]["instance_id"],
+            hash("salt", "i-0123456789")
+        );
+        assert!(data.get("address").is_none());
+        assert_eq!(data["os"], "Linux");
+
+        let mut data = payload();
+        Privacy::Redacted.redact_payload(&mut data, "salt");
+        assert_eq!(data["network"]["interfaces"]["eth0"], serde_json::json!([]));
+        assert_eq!(data["network_statistics"], payload()["network_statistics"]);
+        assert_eq!(data["mount"], serde_json::json!([{ "total": 10 }]));
+        // Collector outputs are not inspected, except talkers
+        assert_eq!(
+            data["collectors"]["custom"],
+            payload()["collectors"]["custom"]
+        );
+        assert_eq!(data["cpu"], 0.5);
+    }
+
+    #[test]
+    fn minimal_drops_identity_and_sections() {
+        let mut data = register();
+        Privacy::Minimal.redact_register(&mut data, "salt");
+        assert_eq!(
+            data,
+            serde_json::json!({ "virtualization": {}, "os": "Linux" })
+        );
+
+        let mut data = payload();
+        Privacy::Minimal.redact_payload(&mut data, "salt");
+        assert_eq!(data, serde_json::json!({ "cpu": 0.5 }));
+    }
+
+    #[test]
+    fn redacted_hashes_talkers_destinations() {
+        let mut payload = serde_json::json!({
+            "collectors": {
+                "talkers": {
+                    "destinations": [{ "address": "10.0.0.9", "bytes": 100 }, { "asn": 64496 }],
+                },
+            },
+        });
+        Privacy::Redacted.redact_payload(&mut payload, "salt");
+        let destinations = &payload["collectors"]["talkers"]["destinations"];
+        assert_eq!(destinations[0]["address"], hash("salt", "10.0.0.9"));
+        assert_eq!(destinations[0]["bytes"], 100);
+        assert_eq!(destinations[1], serde_json::json!({ "asn": 64496 }));
+    }
+}
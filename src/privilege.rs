@@ -0,0 +1,265 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Drop root privileges after startup (configure, keys and listeners are ready),
+//! keeping only listed capabilities on Linux, e.g. `CAP_NET_RAW` for ping checks.
+//!
+//! State directory is shared with the target user, so state can still be persisted, except
+//! trust-bearing files (configure, used approvals and remote access signatures, plugins, control
+//! and handover sockets) which stay owned by root. Directory is sticky, so the user can not replace them either.
+
+use crate::configparser::config::Privilege;
+use crate::error::Error;
+#[cfg(unix)]
+use log::info;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+mod caps {
+    use crate::error::Error;
+
+    const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective: u32,
+        permitted: u32,
+        inheritable: u32,
+    }
+
+    const CAPABILITIES: [(&str, u32); 16] = [
+        ("CAP_CHOWN", 0),
+        ("CAP_DAC_OVERRIDE", 1),
+        ("CAP_DAC_READ_SEARCH", 2),
+        ("CAP_KILL", 5),
+        ("CAP_NET_BIND_SERVICE", 10),
+        ("CAP_NET_BROADCAST", 11),
+        ("CAP_NET_ADMIN", 12),
+        ("CAP_NET_RAW", 13),
+        ("CAP_SYS_RAWIO", 17),
+        ("CAP_SYS_PTRACE", 19),
+        ("CAP_SYS_ADMIN", 21),
+        ("CAP_SYS_NICE", 23),
+        ("CAP_SYS_RESOURCE", 24),
+        ("CAP_SYSLOG", 34),
+        ("CAP_BPF", 39),
+        ("CAP_PERFMON", 38),
+    ];
+
+    pub fn parse(name: &str) -> anyhow::Result<u32> {
+        let name = name.to_ascii_uppercase();
+        CAPABILITIES
+            .iter()
+            .find(|(n, _)| *n == name || n[4..] == name)
+            .map(|(_, cap)| *cap)
+            .ok_or_else(|| {
+                Error::Configure {
+                    field: "privilege.keep_capabilities",
+                    message: format!("Unsupported capability {}", name),
+                }
+                .into()
+            })
+    }
+
+    /// Keep capabilities across setuid, should be called before switching user.
+    pub fn keep_on_setuid() -> anyhow::Result<()> {
+        // Safety: plain prctl call
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// Reduce all capability sets to `caps`, also raised as ambient so they apply to child processes.
+    pub fn restrict(caps: &[u32]) -> anyhow::Result<()> {
+        let mut data = [Data::default(); 2];
+        for cap in caps {
+            let mask = 1u32 << (cap % 32);
+            let slot = &mut data[(cap / 32) as usize];
+            slot.effective |= mask;
+            slot.permitted |= mask;
+            slot.inheritable |= mask;
+        }
+        let mut header = Header {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        // Safety: header and data follow the layout of linux/capability.h (version 3)
+        if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        for cap in caps {
+            // Safety: plain prctl call
+            let raised = unsafe {
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_RAISE,
+                    *cap as libc::c_ulong,
+                    0,
+                    0,
+                )
+            };
+            if raised != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = std::ffi::CString::new(name)?;
+    // Safety: called during startup before other threads look up users
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(Error::Unsupported {
+            what: format!("User {}", name),
+        }
+        .into());
+    }
+    // Safety: checked not null above
+    unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> anyhow::Result<libc::gid_t> {
+    let c_name = std::ffi::CString::new(name)?;
+    // Safety: called during startup before other threads look up groups
+    let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if group.is_null() {
+        return Err(Error::Unsupported {
+            what: format!("Group {}", name),
+        }
+        .into());
+    }
+    // Safety: checked not null above
+    unsafe { Ok((*group).gr_gid) }
+}
+
+/// Whether `name` in state directory decides what the client trusts, so is kept from the target user.
+/// Used stores are written through handles opened before dropping privileges.
+#[cfg(unix)]
+fn is_trusted(name: &str) -> bool {
+    #[cfg(feature = "plugins")]
+    if name == crate::plugin::DEFAULT_PLUGIN_DIR {
+        return true;
+    }
+    [
+        crate::session::authz::USED_FILE,
+        crate::remote_access::USED_FILE,
+        crate::control::CONTROL_SOCKET,
+        crate::handover::HANDOVER_SOCKET,
+        crate::profile::PROFILE_FILE,
+    ]
+    .contains(&name)
+}
+
+/// Share state directory with the target user: directory is owned by root and group of the user
+/// with sticky bit, files directly inside it are given to the user except trusted ones.
+/// Configure at `cfg_path` stays owned by root, only readable by the group for reload.
+#[cfg(unix)]
+fn share_state_dir(uid: libc::uid_t, gid: libc::gid_t, cfg_path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let dir = crate::state::dir();
+    std::fs::create_dir_all(dir)?;
+    std::os::unix::fs::chown(dir, Some(0), Some(gid))?;
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o1770))?;
+    let cfg_path = cfg_path.canonicalize().ok();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.canonicalize().ok() == cfg_path {
+            std::os::unix::fs::chown(&path, Some(0), Some(gid))?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640))?;
+            continue;
+        }
+        let trusted = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_trusted);
+        if !trusted {
+            std::os::unix::fs::chown(&path, Some(uid), Some(gid))?;
+        }
+    }
+    Ok(())
+}
+
+/// Switch to user of `cfg`, `cfg_path` is the configure location.
+#[cfg(unix)]
+pub fn drop(cfg: &Privilege, cfg_path: &Path) -> anyhow::Result<()> {
+    // Safety: geteuid has no side effect
+    if unsafe { libc::geteuid() } != 0 {
+        info!("Not running as root, skip dropping privileges");
+        return Ok(());
+    }
+    let (uid, default_gid) = lookup_user(&cfg.user)?;
+    let gid = match &cfg.group {
+        Some(group) => lookup_group(group)?,
+        None => default_gid,
+    };
+    let keep = cfg.keep_capabilities.clone().unwrap_or_default();
+    #[cfg(target_os = "linux")]
+    let caps = keep
+        .iter()
+        .map(|name| caps::parse(name))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    #[cfg(not(target_os = "linux"))]
+    if !keep.is_empty() {
+        log::warn!("Capabilities are only supported on Linux, ignored");
+    }
+    if !crate::state::is_read_only() {
+        share_state_dir(uid, gid, cfg_path)?;
+    }
+
+    #[cfg(target_os = "linux")]
+    caps::keep_on_setuid()?;
+    // Safety: plain libc calls, order matters: groups and gid must be changed before uid
+    unsafe {
+        if libc::setgroups(1, &gid) != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(Error::Io {
+                operation: "switch to user",
+                path: cfg.user.clone(),
+                source: std::io::Error::last_os_error(),
+            }
+            .into());
+        }
+    }
+    #[cfg(target_os = "linux")]
+    caps::restrict(&caps)?;
+    info!(
+        "Drop privileges to user {} (uid {}, gid {}), keep capabilities {:?}",
+        cfg.user, uid, gid, keep
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop(_cfg: &Privilege, _cfg_path: &Path) -> anyhow::Result<()> {
+    log::warn!("Dropping privileges is not supported on this platform, ignored");
+    Ok(())
+}
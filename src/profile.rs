@@ -0,0 +1,100 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Last configure profile retrieved from server with `--retrieve`, kept in state directory.
+//!
+//! Options it sets are used wherever configure file leaves them unset, so local edits made after
+//! retrieval take precedence. Environment variables and command line overrides apply on top.
+
+use crate::configparser::config::Configure;
+use log::warn;
+
+pub const PROFILE_FILE: &str = "server_profile.toml";
+
+/// Keep retrieved profile for later merges.
+pub fn save(contents: &str) -> std::io::Result<()> {
+    crate::state::write(&crate::state::path(PROFILE_FILE), contents)
+}
+
+fn load() -> Option<toml::Value> {
+    let contents = std::fs::read_to_string(crate::state::path(PROFILE_FILE)).ok()?;
+    match toml::from_str(&contents) {
+        Ok(profile) => Some(profile),
+        Err(e) => {
+            warn!("Ignore invalid server profile {}: {}", PROFILE_FILE, e);
+            None
+        }
+    }
+}
+
+/// Insert options of `profile` missing from `base`, tables are merged key by key, anything else
+/// (including arrays of tables) as a whole. Returns dotted paths of inserted options.
+fn merge(path: &str, base: &mut toml::Value, profile: toml::Value, merged: &mut Vec<String>) {
+    if let (toml::Value::Table(base), toml::Value::Table(profile)) = (base, profile) {
+        for (key, value) in profile {
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            match base.get_mut(&key) {
+                Some(existing) => merge(&path, existing, value, merged),
+                None => {
+                    base.insert(key, value);
+                    merged.push(path);
+                }
+            }
+        }
+    }
+}
+
+/// Configure of file `contents` with last retrieved profile merged in, and paths of options
+/// taken from profile.
+pub fn apply(contents: &str) -> anyhow::Result<(Configure, Vec<String>)> {
+    let mut config: toml::Value = toml::from_str(contents)?;
+    let mut merged = Vec::new();
+    if let Some(profile) = load() {
+        merge("", &mut config, profile, &mut merged);
+    }
+    Ok((config.try_into()?, merged))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_takes_precedence_over_profile() {
+        let mut config: toml::Value = toml::from_str(
+            "[server]\nserver_address = \"https://local\"\ntoken = \"a\"\n[statistics]\nenabled = false\n",
+        )
+        .unwrap();
+        let profile = toml::from_str(
+            "[server]\nserver_address = \"https://profile\"\ninterval = 60\n[dns_cache]\nenabled = true\n",
+        )
+        .unwrap();
+        let mut merged = Vec::new();
+        merge("", &mut config, profile, &mut merged);
+        assert_eq!(merged, ["dns_cache", "server.interval"]);
+        let config: Configure = config.try_into().unwrap();
+        assert_eq!(config.server.server_address, "https://local");
+        assert_eq!(config.server.interval, Some(60));
+        assert_eq!(config.dns_cache.and_then(|cache| cache.enabled), Some(true));
+    }
+}
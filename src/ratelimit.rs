@@ -0,0 +1,109 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Token buckets keyed by event kind, so a flapping condition can't flood a notification
+//! channel: events beyond the burst are counted instead of sent, and reported as one summary
+//! once the bucket refills.
+
+use crate::error::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const DEFAULT_BURST: u32 = 5;
+pub const DEFAULT_PER_MINUTE: f64 = 1.0;
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+    /// Events dropped since last one sent
+    suppressed: u64,
+}
+
+pub struct Limiter {
+    capacity: f64,
+    /// Tokens refilled per second
+    rate: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Limiter {
+    pub fn new(burst: Option<u32>, per_minute: Option<f64>) -> anyhow::Result<Self> {
+        let burst = burst.unwrap_or(DEFAULT_BURST);
+        let per_minute = per_minute.unwrap_or(DEFAULT_PER_MINUTE);
+        if burst == 0 || per_minute.is_nan() || per_minute <= 0.0 {
+            return Err(Error::Configure {
+                field: "alerting.limit",
+                message: "Rate limit requires positive burst and per_minute".to_string(),
+            }
+            .into());
+        }
+        Ok(Self {
+            capacity: burst as f64,
+            rate: per_minute / 60.0,
+            buckets: Default::default(),
+        })
+    }
+
+    fn take(&self, bucket: &mut TokenBucket) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Number of events of `key` suppressed before this one if it may be sent, `None` if
+    /// it is suppressed as well.
+    pub fn admit(&self, key: &str) -> Option<u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.capacity,
+                last: Instant::now(),
+                suppressed: 0,
+            });
+        if self.take(bucket) {
+            Some(std::mem::take(&mut bucket.suppressed))
+        } else {
+            bucket.suppressed += 1;
+            None
+        }
+    }
+
+    /// Keys with suppressed events whose bucket refilled, and their count, spending a token
+    /// for the summary.
+    pub fn flush(&self) -> Vec<(String, u64)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut result = Vec::new();
+        for (key, bucket) in buckets.iter_mut() {
+            if bucket.suppressed > 0 && self.take(bucket) {
+                result.push((key.clone(), std::mem::take(&mut bucket.suppressed)));
+            }
+        }
+        result.sort();
+        result
+    }
+}
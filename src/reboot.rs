@@ -0,0 +1,211 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Reboot collector in `[collector.reboot]` (Linux only): running kernel against the newest one
+//! installed, and whether the distribution asks for a reboot (Debian/Ubuntu `reboot-required`,
+//! SUSE `reboot-needed`, RHEL/Fedora `needs-restarting -r`). A `reboot_required` event is sent
+//! once a reboot becomes required, so server need not diff every heartbeat.
+
+use crate::collector::Collector;
+use crate::sandbox::{self, Limits, Violation};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const COLLECTOR_NAME: &str = "reboot";
+pub const REBOOT_REQUIRED_EVENT: &str = "reboot_required";
+const OSRELEASE: &str = "/proc/sys/kernel/osrelease";
+pub(crate) const BOOT_DIR: &str = "/boot";
+pub(crate) const MODULES_DIR: &str = "/lib/modules";
+/// Flag files written by package managers, with packages asking for reboot if listed
+pub(crate) const FLAG_FILES: [(&str, Option<&str>); 3] = [
+    (
+        "/var/run/reboot-required",
+        Some("/var/run/reboot-required.pkgs"),
+    ),
+    ("/run/reboot-required", Some("/run/reboot-required.pkgs")),
+    ("/run/reboot-needed", None),
+];
+pub(crate) const NEEDS_RESTARTING: &str = "/usr/bin/needs-restarting";
+const QUERY_TIMEOUT: u64 = 60;
+
+/// Compare kernel versions, numeric parts by value (`5.15.0-101` is newer than `5.15.0-91`).
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn chunks(version: &str) -> Vec<(bool, &str)> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for (i, c) in version.char_indices().skip(1) {
+            let previous = version[..i].chars().next_back().unwrap();
+            if previous.is_ascii_digit() != c.is_ascii_digit() {
+                chunks.push(&version[start..i]);
+                start = i;
+            }
+        }
+        chunks.push(&version[start..]);
+        chunks
+            .into_iter()
+            .map(|chunk| (chunk.starts_with(|c: char| c.is_ascii_digit()), chunk))
+            .collect()
+    }
+    for (x, y) in chunks(a).into_iter().zip(chunks(b)) {
+        let ordering = match (x, y) {
+            ((true, x), (true, y)) => x
+                .parse::<u64>()
+                .unwrap_or(0)
+                .cmp(&y.parse::<u64>().unwrap_or(0)),
+            ((_, x), (_, y)) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Kernel versions with image in `/boot` or modules in `/lib/modules`, newest first.
+fn installed_kernels() -> Vec<String> {
+    let mut versions = Vec::new();
+    let names = |dir: &str| -> Vec<String> {
+        std::fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    for name in names(BOOT_DIR) {
+        if let Some(version) = name.strip_prefix("vmlinuz-") {
+            versions.push(version.to_string());
+        }
+    }
+    for name in names(MODULES_DIR) {
+        // Directories left behind by removed kernels have no module index
+        if Path::new(MODULES_DIR)
+            .join(&name)
+            .join("modules.dep")
+            .exists()
+        {
+            versions.push(name);
+        }
+    }
+    // Rescue images (`vmlinuz-0-rescue-<machine id>`) are not kernel versions
+    versions.retain(|version| {
+        version.starts_with(|c: char| c.is_ascii_digit()) && !version.starts_with("0-rescue")
+    });
+    versions.sort_by(|a, b| compare_versions(b, a));
+    versions.dedup();
+    versions
+}
+
+/// Reasons reboot is asked for by distribution tooling, and packages asking for it.
+async fn distribution_flags() -> (Vec<String>, Vec<String>) {
+    let mut reasons = Vec::new();
+    let mut packages = Vec::new();
+    for (flag, list) in &FLAG_FILES {
+        if !Path::new(flag).exists() {
+            continue;
+        }
+        // `/var/run` is usually a link to `/run`
+        let reason = Path::new(flag)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        if !reasons.contains(&reason) {
+            reasons.push(reason);
+        }
+        if let Some(list) = list.and_then(|list| std::fs::read_to_string(list).ok()) {
+            packages.extend(list.lines().map(str::to_string));
+        }
+    }
+    if Path::new(NEEDS_RESTARTING).exists() {
+        let limits = Limits {
+            timeout: Some(QUERY_TIMEOUT),
+            ..Default::default()
+        };
+        // Exit code 1 when reboot is required
+        match sandbox::run(NEEDS_RESTARTING, &["-r".to_string()], &limits).await {
+            Ok(_) => {}
+            Err(e) => match e.downcast_ref::<Violation>() {
+                Some(Violation::ExitCode(1, _)) => reasons.push("needs-restarting".to_string()),
+                _ => log::warn!("Unable run {}: {}", NEEDS_RESTARTING, e),
+            },
+        }
+    }
+    packages.sort();
+    packages.dedup();
+    (reasons, packages)
+}
+
+#[derive(Default)]
+pub struct RebootCollector {
+    /// Whether reboot was required at previous collection
+    required: Mutex<bool>,
+    events: Mutex<Vec<(String, Value)>>,
+}
+
+#[async_trait]
+impl Collector for RebootCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let running = std::fs::read_to_string(OSRELEASE)?.trim().to_string();
+        let installed = installed_kernels();
+        let latest = installed.first().cloned();
+        let drift = latest.as_ref().is_some_and(|latest| {
+            compare_versions(latest, &running) == Ordering::Greater || !installed.contains(&running)
+        });
+        let (mut reasons, packages) = distribution_flags().await;
+        if drift {
+            reasons.insert(0, "kernel".to_string());
+        }
+        let required = !reasons.is_empty();
+        let result = json!({
+            "kernel": {
+                "running": running,
+                "latest": latest,
+                "installed": installed,
+                "drift": drift,
+            },
+            "required": required,
+            "reasons": reasons,
+            "packages": packages,
+        });
+        if required && !std::mem::replace(&mut *self.required.lock().unwrap(), required) {
+            log::warn!("Reboot required: {}", reasons.join(", "));
+            self.events
+                .lock()
+                .unwrap()
+                .push((REBOOT_REQUIRED_EVENT.to_string(), result.clone()));
+        } else {
+            *self.required.lock().unwrap() = required;
+        }
+        Ok(result)
+    }
+
+    fn events(&self) -> Vec<(String, Value)> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
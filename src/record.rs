@@ -0,0 +1,158 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::error::Error;
+use crate::session::ExitProcessRequest;
+use anyhow::anyhow;
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeError {
+    Timeout(String),
+    Other(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Exchange {
+    url: String,
+    request: serde_json::Value,
+    status: Option<u16>,
+    response: Option<String>,
+    error: Option<ExchangeError>,
+}
+
+impl Exchange {
+    pub fn new_response(url: &str, request: &[u8], status: u16, response: String) -> Self {
+        Self {
+            url: url.to_string(),
+            request: serde_json::from_slice(request).unwrap_or_default(),
+            status: Some(status),
+            response: Some(response),
+            error: None,
+        }
+    }
+
+    pub fn new_error(url: &str, request: &[u8], error: &anyhow::Error) -> Self {
+        let error = if matches!(crate::error::of(error), Some(Error::Timeout { .. })) {
+            ExchangeError::Timeout(error.to_string())
+        } else {
+            ExchangeError::Other(error.to_string())
+        };
+        Self {
+            url: url.to_string(),
+            request: serde_json::from_slice(request).unwrap_or_default(),
+            status: None,
+            response: None,
+            error: Some(error),
+        }
+    }
+
+    pub fn into_response(self) -> anyhow::Result<reqwest::Response> {
+        match self.error {
+            Some(ExchangeError::Timeout(e)) => {
+                return Err(Error::Timeout { error: anyhow!(e) }.into())
+            }
+            Some(ExchangeError::Other(message)) => {
+                return Err(Error::Protocol {
+                    protocol: "HTTP",
+                    message,
+                }
+                .into())
+            }
+            None => {}
+        }
+        let resp = http::Response::builder()
+            .status(self.status.unwrap_or(200))
+            .header("Content-Type", "application/json")
+            .body(self.response.unwrap_or_default())?;
+        Ok(reqwest::Response::from(resp))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Record,
+    Replay,
+}
+
+pub struct Interaction {
+    mode: Mode,
+    dir: PathBuf,
+    seq: AtomicUsize,
+}
+
+impl Interaction {
+    pub async fn record<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        info!("Record server interactions to {}", dir.display());
+        Ok(Self {
+            mode: Mode::Record,
+            dir,
+            seq: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn replay<P: AsRef<Path>>(dir: P) -> Self {
+        let dir = dir.as_ref().to_path_buf();
+        info!("Replay server interactions from {}", dir.display());
+        Self {
+            mode: Mode::Replay,
+            dir,
+            seq: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == Mode::Replay
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        self.dir.join(format!("{:06}.json", seq))
+    }
+
+    pub async fn save(&self, exchange: &Exchange) -> anyhow::Result<()> {
+        let path = self.next_path();
+        debug!("Save exchange to {}", path.display());
+        tokio::fs::write(&path, serde_json::to_string_pretty(exchange)?).await?;
+        Ok(())
+    }
+
+    pub async fn next(&self) -> anyhow::Result<Exchange> {
+        let path = self.next_path();
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(anyhow::Error::new(ExitProcessRequest::new(
+                    0,
+                    "All recorded interactions replayed",
+                )))
+            }
+            Err(e) => return Err(anyhow::Error::from(e)),
+        };
+        debug!("Replay exchange from {}", path.display());
+        Ok(serde_json::from_str(&contents)?)
+    }
+}
@@ -0,0 +1,502 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Relay heartbeats of other probes in LAN to remote server.
+//!
+//! Probes behind relay should set `server_address` to the relay listen address,
+//! their requests (including `Authorization` header) are forwarded as-is.
+//!
+//! In aggregate mode, heartbeats are answered locally and uploaded upstream periodically
+//! as a single `aggregate` action, other actions are still forwarded as-is. Relay does not know
+//! tokens of probes: first heartbeat of a probe (and any heartbeat with another `Authorization`)
+//! is forwarded, only once upstream accepted it are following ones answered locally.
+
+use crate::configparser::config::Configure;
+use crate::nonce::NonceStore;
+use crate::session::envelope::RequestEnvelope;
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_ADVERTISE: bool = true;
+pub const DEFAULT_AGGREGATE: bool = false;
+pub const DEFAULT_FLUSH_INTERVAL: u64 = 60;
+pub const DEFAULT_MAX_BATCH: usize = 1000;
+const AGGREGATE_NONCE_FILE: &str = "probe_aggregate_nonce";
+/// Authorization accepted by upstream is checked with it again after this long, so revoked
+/// tokens stop being answered locally.
+const VERIFY_INTERVAL: Duration = Duration::from_secs(600);
+/// Largest request body accepted from probes, larger ones are answered with 413.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+struct Aggregator {
+    uuid: String,
+    token: String,
+    max_batch: usize,
+    pending: Mutex<Vec<Value>>,
+    seq: AtomicU64,
+    nonce: NonceStore,
+    /// `Authorization` upstream accepted heartbeat of probe uuid with, and when.
+    accepted: Mutex<HashMap<String, (HeaderValue, Instant)>>,
+}
+
+struct Relay {
+    client: reqwest::Client,
+    upstreams: Vec<String>,
+    aggregator: Option<Aggregator>,
+}
+
+fn simple_response(status: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+fn json_response(status: StatusCode, body: Bytes) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+/// Read whole `body`, `None` as soon as it grows past `limit`.
+async fn read_limited(mut body: Body, limit: usize) -> hyper::Result<Option<Bytes>> {
+    use hyper::body::HttpBody as _;
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buffer.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(Some(Bytes::from(buffer)))
+}
+
+/// Whether upstream answered with success status and `status` 200 in body.
+fn is_accepted(status: StatusCode, body: &[u8]) -> bool {
+    status.is_success()
+        && serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|v| v.get("status").and_then(Value::as_i64))
+            == Some(200)
+}
+
+impl Aggregator {
+    /// Whether heartbeat of `uuid` can be answered locally, as upstream accepted `authorization`
+    /// of it recently.
+    fn verified(&self, uuid: &str, authorization: Option<&HeaderValue>) -> bool {
+        match (self.accepted.lock().unwrap().get(uuid), authorization) {
+            (Some((accepted, at)), Some(authorization)) => {
+                accepted == authorization && at.elapsed() < VERIFY_INTERVAL
+            }
+            _ => false,
+        }
+    }
+
+    /// Remember answer of upstream to heartbeat of `uuid` forwarded as-is.
+    fn verify(&self, uuid: &str, authorization: Option<&HeaderValue>, accepted: bool) {
+        let mut verified = self.accepted.lock().unwrap();
+        match authorization {
+            Some(authorization) if accepted => {
+                verified.insert(uuid.to_string(), (authorization.clone(), Instant::now()));
+            }
+            _ => {
+                verified.remove(uuid);
+            }
+        }
+    }
+
+    fn push(&self, item: Value) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= self.max_batch {
+            pending.remove(0);
+        }
+        pending.push(item);
+    }
+
+    /// Put back batch failed to upload, before newer heartbeats.
+    fn restore(&self, batch: Vec<Value>) {
+        let mut pending = self.pending.lock().unwrap();
+        let newer = std::mem::replace(&mut *pending, batch);
+        pending.extend(newer);
+        let overflow = pending.len().saturating_sub(self.max_batch);
+        pending.drain(..overflow);
+    }
+}
+
+impl Relay {
+    /// Send body to upstreams in order, return status and body of first reachable one.
+    async fn send_upstream(
+        &self,
+        body: Bytes,
+        authorization: Option<&HeaderValue>,
+        forwarded_for: Option<SocketAddr>,
+    ) -> Option<(StatusCode, Bytes)> {
+        for upstream in &self.upstreams {
+            let mut request = self
+                .client
+                .post(upstream)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(remote) = forwarded_for {
+                request = request.header("X-Forwarded-For", remote.ip().to_string());
+            }
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            match request.send().await {
+                Ok(resp) => {
+                    let status = resp.status();
+                    match resp.bytes().await {
+                        Ok(bytes) => {
+                            debug!("Relay request to {} ({})", upstream, status);
+                            return Some((status, bytes));
+                        }
+                        Err(e) => warn!("Unable read response from {}: {}", upstream, e),
+                    }
+                }
+                Err(e) => warn!("Unable relay request to {}: {}", upstream, e),
+            }
+        }
+        None
+    }
+
+    async fn forward(&self, remote: SocketAddr, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::POST {
+            return simple_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<u64>().ok());
+        if length.is_some_and(|length| length > MAX_REQUEST_SIZE as u64) {
+            warn!("Refuse request of {:?} bytes from {}", length, remote);
+            return simple_response(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let authorization = req.headers().get(AUTHORIZATION).cloned();
+        let body = match read_limited(req.into_body(), MAX_REQUEST_SIZE).await {
+            Ok(Some(body)) => body,
+            Ok(None) => {
+                warn!(
+                    "Refuse request larger than {} bytes from {}",
+                    MAX_REQUEST_SIZE, remote
+                );
+                return simple_response(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            Err(e) => {
+                warn!("Unable read request from {}: {}", remote, e);
+                return simple_response(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        if let Some(aggregator) = &self.aggregator {
+            if let Ok(request) = serde_json::from_slice::<Value>(&body) {
+                if request.get("action").and_then(Value::as_str) == Some("heartbeat") {
+                    let uuid = request
+                        .get("uuid")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string();
+                    if !aggregator.verified(&uuid, authorization.as_ref()) {
+                        let response = self
+                            .send_upstream(body, authorization.as_ref(), Some(remote))
+                            .await;
+                        return match response {
+                            Some((status, bytes)) => {
+                                let accepted = is_accepted(status, &bytes);
+                                aggregator.verify(&uuid, authorization.as_ref(), accepted);
+                                json_response(status, bytes)
+                            }
+                            None => simple_response(StatusCode::BAD_GATEWAY),
+                        };
+                    }
+                    aggregator.push(serde_json::json!({
+                        "authorization": authorization
+                            .as_ref()
+                            .and_then(|v| v.to_str().ok()),
+                        "forwarded_for": remote.ip().to_string(),
+                        "request": request,
+                    }));
+                    let body = serde_json::json!({
+                        "version": crate::session::CLIENT_VERSION,
+                        "status": 200,
+                    });
+                    return json_response(StatusCode::OK, Bytes::from(body.to_string()));
+                }
+            }
+        }
+
+        match self
+            .send_upstream(body, authorization.as_ref(), Some(remote))
+            .await
+        {
+            Some((status, bytes)) => json_response(status, bytes),
+            None => simple_response(StatusCode::BAD_GATEWAY),
+        }
+    }
+
+    /// Upload pending heartbeats as single `aggregate` action.
+    async fn flush(&self, aggregator: &Aggregator) {
+        let batch = std::mem::take(&mut *aggregator.pending.lock().unwrap());
+        if batch.is_empty() {
+            return;
+        }
+        let count = batch.len();
+        let envelope = RequestEnvelope {
+            version: crate::session::CLIENT_VERSION.to_string(),
+            action: "aggregate".to_string(),
+            uuid: aggregator.uuid.clone(),
+            seq: aggregator.seq.fetch_add(1, Ordering::SeqCst),
+            timestamp: chrono::Utc::now().timestamp(),
+            nonce: aggregator.nonce.next(),
+            body: serde_json::json!({ "heartbeats": &batch }),
+            boot_id: None,
+            manifest: None,
+            sent_at: Some(chrono::Utc::now().timestamp_millis()),
+            clock_offset: None,
+            canary: None,
+            experiments: Vec::new(),
+        };
+        let body = match serde_json::to_vec(&envelope) {
+            Ok(body) => Bytes::from(body),
+            Err(e) => {
+                error!("Unable serialize aggregate: {:?}", e);
+                return;
+            }
+        };
+        let authorization = HeaderValue::from_str(&format!("Bearer {}", aggregator.token)).ok();
+        let accepted = match self.send_upstream(body, authorization.as_ref(), None).await {
+            Some((status, bytes)) => is_accepted(status, &bytes),
+            None => false,
+        };
+        if accepted {
+            info!("Uploaded {} aggregated heartbeats", count);
+        } else {
+            warn!("Unable upload aggregated heartbeats, keep them for next time");
+            aggregator.restore(batch);
+        }
+    }
+}
+
+async fn serve(
+    builder: hyper::server::Builder<hyper::server::conn::AddrIncoming>,
+    relay: Arc<Relay>,
+) -> anyhow::Result<()> {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let relay = relay.clone();
+        let remote = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let relay = relay.clone();
+                async move { Ok::<_, Infallible>(relay.forward(remote, req).await) }
+            }))
+        }
+    });
+
+    builder.serve(make_svc).await?;
+    Ok(())
+}
+
+/// Start relay in background if `[relay]` is configured.
+pub fn spawn(cfg: &Configure) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let relay_cfg = match &cfg.relay {
+        Some(relay) => relay,
+        None => return Ok(None),
+    };
+    let listen: SocketAddr = relay_cfg.listen.parse()?;
+    let advertise = relay_cfg.advertise.unwrap_or(DEFAULT_ADVERTISE);
+    let mut upstreams = Vec::new();
+    for server in std::iter::once(&cfg.server.server_address)
+        .chain(cfg.server.backup_servers.iter().flatten())
+    {
+        upstreams.push(server.clone());
+        if let Some(alternates) = cfg.server.alternates.as_ref().and_then(|a| a.get(server)) {
+            upstreams.extend(alternates.iter().cloned());
+        }
+    }
+    let aggregator = if relay_cfg.aggregate.unwrap_or(DEFAULT_AGGREGATE) {
+        Some(Aggregator {
+            uuid: cfg.identification.as_ref().unwrap().token.clone(),
+            token: cfg.server.token.clone(),
+            max_batch: relay_cfg.max_batch.unwrap_or(DEFAULT_MAX_BATCH),
+            pending: Default::default(),
+            seq: AtomicU64::new(0),
+            nonce: NonceStore::load(crate::state::path(AGGREGATE_NONCE_FILE)),
+            accepted: Default::default(),
+        })
+    } else {
+        None
+    };
+    let flush_interval = relay_cfg.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL);
+    // Bind before returning, so listener is ready before privileges are dropped
+    let builder = hyper::Server::try_bind(&listen)?;
+    info!("Relay listening on {}", listen);
+    let relay = Arc::new(Relay {
+        client: crate::session::client_builder(cfg)?.build()?,
+        upstreams,
+        aggregator,
+    });
+    #[cfg(feature = "mdns")]
+    let daemon = if advertise {
+        Some(crate::discovery::advertise(listen.port())?)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "mdns"))]
+    if advertise {
+        debug!("mdns feature disabled, skip advertise relay");
+    }
+    Ok(Some(tokio::spawn(async move {
+        #[cfg(feature = "mdns")]
+        let _daemon = daemon;
+        let flusher = async {
+            let aggregator = match &relay.aggregator {
+                Some(aggregator) => aggregator,
+                None => return std::future::pending().await,
+            };
+            let mut interval = tokio::time::interval(Duration::from_secs(flush_interval));
+            loop {
+                interval.tick().await;
+                relay.flush(aggregator).await;
+            }
+        };
+        tokio::select! {
+            result = serve(builder, relay.clone()) => {
+                if let Err(e) = result {
+                    error!("Got error in relay: {:?}", e);
+                }
+            }
+            _ = flusher => {}
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay() -> Relay {
+        Relay {
+            client: reqwest::Client::new(),
+            upstreams: Vec::new(),
+            aggregator: None,
+        }
+    }
+
+    fn heartbeat(authorization: &str) -> Request<Body> {
+        let body = serde_json::json!({"action": "heartbeat", "uuid": "probe-1"});
+        Request::post("/")
+            .header(AUTHORIZATION, authorization)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn body_is_read_up_to_limit() {
+        let body = read_limited(Body::from(vec![0u8; 16]), 16).await.unwrap();
+        assert_eq!(body.unwrap().len(), 16);
+        let body = read_limited(Body::from(vec![0u8; 17]), 16).await.unwrap();
+        assert!(body.is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_request_is_refused() {
+        let remote: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let request = Request::post("/")
+            .header(CONTENT_LENGTH, MAX_REQUEST_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+        let response = relay().forward(remote, request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Chunked body without length is cut once it passes limit
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..=MAX_REQUEST_SIZE >> 16 {
+                if sender
+                    .send_data(Bytes::from(vec![b' '; 1 << 16]))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        let request = Request::post("/").body(body).unwrap();
+        let response = relay().forward(remote, request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn aggregate_answers_only_verified_heartbeats() {
+        let remote: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let relay = Relay {
+            aggregator: Some(Aggregator {
+                uuid: "relay".to_string(),
+                token: "token".to_string(),
+                max_batch: DEFAULT_MAX_BATCH,
+                pending: Default::default(),
+                seq: AtomicU64::new(0),
+                nonce: NonceStore::load(std::env::temp_dir().join("probe_relay_test_nonce")),
+                accepted: Default::default(),
+            }),
+            ..relay()
+        };
+        let aggregator = relay.aggregator.as_ref().unwrap();
+
+        // Unknown probe is forwarded, no upstream is reachable here
+        let response = relay.forward(remote, heartbeat("Bearer a")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert!(aggregator.pending.lock().unwrap().is_empty());
+
+        aggregator.verify("probe-1", Some(&HeaderValue::from_static("Bearer a")), true);
+        let response = relay.forward(remote, heartbeat("Bearer a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(aggregator.pending.lock().unwrap().len(), 1);
+
+        // Another token of same probe must be checked by upstream
+        let response = relay.forward(remote, heartbeat("Bearer b")).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(aggregator.pending.lock().unwrap().len(), 1);
+
+        aggregator.verify(
+            "probe-1",
+            Some(&HeaderValue::from_static("Bearer a")),
+            false,
+        );
+        assert!(!aggregator.verified("probe-1", Some(&HeaderValue::from_static("Bearer a"))));
+    }
+}
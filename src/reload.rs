@@ -0,0 +1,73 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Configure reload without restarting process, requested by `SIGHUP` or
+//! `probe-client control reload`, e.g. after rotating token or changing server address.
+
+use std::fmt::Formatter;
+use std::sync::OnceLock;
+use tokio::sync::Notify;
+
+static REQUESTED: OnceLock<Notify> = OnceLock::new();
+
+fn notify() -> &'static Notify {
+    REQUESTED.get_or_init(Notify::new)
+}
+
+/// Ask heartbeat loop to reload, kept until served if it is busy.
+pub fn request() {
+    notify().notify_one();
+}
+
+pub async fn requested() {
+    notify().notified().await
+}
+
+/// Request reload on every `SIGHUP`.
+#[cfg(unix)]
+pub fn spawn() -> anyhow::Result<()> {
+    use log::info;
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Got SIGHUP, reload configure");
+            request();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn() -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Returned by heartbeat loop to reload configure and register again.
+#[derive(Debug)]
+pub struct ReloadRequest;
+
+impl std::error::Error for ReloadRequest {}
+
+impl std::fmt::Display for ReloadRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Configure reload requested")
+    }
+}
@@ -0,0 +1,165 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `remote_access` action: open a reverse SSH tunnel for emergency administration of NAT'd devices.
+//!
+//! Request must be signed with key `server_key`, params are
+//! `{"remote_port": 20022, "duration": 600, "expires": <unix timestamp>, "uuid": "...", "signature": "..."}`,
+//! where the Ed25519 signature covers the other params serialized as compact JSON with sorted keys,
+//! and `uuid` is identification of the target probe. Each signature is accepted once, even across restarts.
+//! Only one tunnel can be open at the same time, and it is closed after `duration` seconds.
+
+use crate::action::ActionHandler;
+use crate::configparser::config::{RemoteAccess, Tunnel};
+use crate::error::Error;
+use crate::replay::UsedStore;
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+const ACTION_NAME: &str = "remote_access";
+pub const DEFAULT_FORWARD_PORT: u16 = 22;
+pub const DEFAULT_MAX_DURATION: u64 = 3600;
+pub const USED_FILE: &str = "probe_remote_access_used.json";
+
+fn missing(name: &'static str) -> Error {
+    Error::MissingParameter {
+        action: ACTION_NAME.to_string(),
+        name,
+    }
+}
+
+pub struct RemoteAccessHandler {
+    server_key: String,
+    tunnel: Tunnel,
+    forward_port: u16,
+    max_duration: u64,
+    /// Identification of this probe, requests for other ones are refused
+    uuid: String,
+    used_signatures: UsedStore,
+    active: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl RemoteAccessHandler {
+    pub fn new(cfg: &RemoteAccess, uuid: &str) -> Self {
+        Self {
+            server_key: cfg.server_key.clone(),
+            tunnel: Tunnel {
+                host: cfg.host.clone(),
+                port: cfg.port,
+                user: cfg.user.clone(),
+                identity_file: cfg.identity_file.clone(),
+                known_hosts: cfg.known_hosts.clone(),
+                local_port: None,
+                ssh_command: cfg.ssh_command.clone(),
+            },
+            forward_port: cfg.forward_port.unwrap_or(DEFAULT_FORWARD_PORT),
+            max_duration: cfg.max_duration.unwrap_or(DEFAULT_MAX_DURATION),
+            uuid: uuid.to_string(),
+            used_signatures: UsedStore::load(crate::state::path(USED_FILE)),
+            active: Default::default(),
+        }
+    }
+
+    fn verify(&self, params: &Value) -> anyhow::Result<()> {
+        let mut signed = params.clone();
+        let signature = signed
+            .as_object_mut()
+            .and_then(|params| params.remove("signature"))
+            .and_then(|signature| signature.as_str().map(str::to_string))
+            .ok_or_else(|| missing("signature"))?;
+        crate::signing::verify(&self.server_key, &serde_json::to_vec(&signed)?, &signature)?;
+        let expires = params
+            .get("expires")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| missing("expires"))?;
+        if expires < chrono::Utc::now().timestamp() {
+            return Err(Error::Expired {
+                action: ACTION_NAME.to_string(),
+                expires,
+            }
+            .into());
+        }
+        if params.get("uuid").and_then(Value::as_str) != Some(self.uuid.as_str()) {
+            return Err(Error::WrongTarget {
+                action: ACTION_NAME.to_string(),
+            }
+            .into());
+        }
+        if !self.used_signatures.insert(&signature, expires) {
+            return Err(Error::Replayed {
+                action: ACTION_NAME.to_string(),
+                id: signature,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ActionHandler for RemoteAccessHandler {
+    fn name(&self) -> &str {
+        ACTION_NAME
+    }
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value> {
+        self.verify(params)?;
+        let remote_port = params
+            .get("remote_port")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| missing("remote_port"))?;
+        let duration = params
+            .get("duration")
+            .and_then(Value::as_u64)
+            .unwrap_or(self.max_duration)
+            .min(self.max_duration);
+
+        let mut active = self.active.lock().unwrap();
+        if active.as_ref().is_some_and(|handle| !handle.is_finished()) {
+            return Err(Error::Busy {
+                action: ACTION_NAME.to_string(),
+            }
+            .into());
+        }
+        let forward = format!("{}:127.0.0.1:{}", remote_port, self.forward_port);
+        let mut child = crate::tunnel::build_command(&self.tunnel, ["-R", &forward]).spawn()?;
+        warn!(
+            "Open remote access tunnel to {} (remote port {}) for {} seconds",
+            self.tunnel.host, remote_port, duration
+        );
+        *active = Some(tokio::spawn(async move {
+            match tokio::time::timeout(Duration::from_secs(duration), child.wait()).await {
+                Ok(status) => warn!("Remote access tunnel exited ({:?})", status),
+                Err(_) => {
+                    child.kill().await.ok();
+                    info!("Close remote access tunnel after {} seconds", duration);
+                }
+            }
+        }));
+        Ok(serde_json::json!({
+            "remote_port": remote_port,
+            "duration": duration,
+        }))
+    }
+}
@@ -0,0 +1,113 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! One-time signed requests (remote access, approved actions): every id used is persisted with
+//! its expiry in state directory, so a captured request can not be run again after restart either.
+//! Entries are dropped once expired, since the request is refused by then anyway.
+//!
+//! File is opened on load, before privileges are dropped, and stays owned by root.
+
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Seek as _, Write as _};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct UsedStore {
+    path: PathBuf,
+    /// `None` in read-only mode or if it can not be opened
+    file: Option<Mutex<File>>,
+    /// Expiry by id
+    used: Mutex<BTreeMap<String, i64>>,
+}
+
+impl UsedStore {
+    pub fn load<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let used = std::fs::read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        let file = if crate::state::is_read_only() {
+            None
+        } else {
+            match open(&path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!("Unable open {}: {:?}", path.display(), e);
+                    None
+                }
+            }
+        };
+        Self {
+            path,
+            file,
+            used: Mutex::new(used),
+        }
+    }
+
+    fn persist(&self, contents: &[u8]) -> std::io::Result<()> {
+        let mut file = match &self.file {
+            Some(file) => file.lock().unwrap(),
+            None => return Ok(()),
+        };
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(contents)?;
+        file.sync_data()
+    }
+
+    /// Record `id` as used until `expires`, false if it was already used.
+    pub fn insert(&self, id: &str, expires: i64) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let now = chrono::Utc::now().timestamp();
+        used.retain(|_, expires| *expires >= now);
+        if used.contains_key(id) {
+            return false;
+        }
+        used.insert(id.to_string(), expires);
+        let persisted = serde_json::to_vec(&*used)
+            .map_err(std::io::Error::from)
+            .and_then(|contents| self.persist(&contents));
+        if let Err(e) = persisted {
+            warn!(
+                "Unable persist used ids to {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+        true
+    }
+}
+
+fn open(path: &std::path::Path) -> std::io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
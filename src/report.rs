@@ -0,0 +1,206 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `data-report` subcommand: list every field collected and sent with the active configure,
+//! so the agent can be audited without reading code.
+
+use crate::collector::Registry;
+use crate::configparser::config::Configure;
+use crate::derived::Derived;
+use crate::normalize::Schema;
+use crate::output::Format;
+use crate::privacy::Privacy;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+pub const SUBCOMMAND_NAME: &str = "data-report";
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("List every field collected and sent with current configure (runs collectors once)")
+        .arg(crate::output::arg())
+}
+
+/// Leaf paths of value, array indexes are shown as `*`.
+fn flatten(prefix: &str, value: &Value, output: &mut BTreeSet<String>) {
+    let join = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", prefix, key)
+        }
+    };
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            map.iter().for_each(|(k, v)| flatten(&join(k), v, output))
+        }
+        Value::Array(array) if !array.is_empty() => {
+            array.iter().for_each(|v| flatten(&join("*"), v, output))
+        }
+        _ => {
+            output.insert(prefix.to_string());
+        }
+    }
+}
+
+fn print_section(title: &str, fields: &BTreeSet<String>, schema: &Schema) {
+    println!("{}", title);
+    for field in fields {
+        match schema.unit_of(field) {
+            Some(unit) => println!("  {:<56} {:?}", field, unit),
+            None => println!("  {}", field),
+        }
+    }
+    println!();
+}
+
+fn section_json(fields: &BTreeSet<String>, schema: &Schema) -> Value {
+    fields
+        .iter()
+        .map(|field| serde_json::json!({"field": field, "unit": schema.unit_of(field)}))
+        .collect()
+}
+
+pub async fn run<P: AsRef<Path>>(path: P, format: Format) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    let config: Configure = toml::from_str(&tokio::fs::read_to_string(path).await?)?;
+    let registry = Registry::new(&config);
+    let schema = Schema::new(&registry);
+    let privacy = config.statistics.privacy.unwrap_or_default();
+
+    let mut envelope: BTreeSet<String> = ["version", "action", "uuid", "seq", "timestamp", "nonce"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    if config.signing.is_some() {
+        envelope.extend(
+            [
+                "manifest.algorithm",
+                "manifest.digest",
+                "manifest.signature",
+            ]
+            .iter()
+            .map(|s| s.to_string()),
+        );
+    }
+
+    let mut register = serde_json::json!({
+        "hostname": "",
+        "boot_time": 0,
+        "address": "",
+        "units": {},
+    });
+    if config.signing.is_some() {
+        register["public_key"] = Value::from("");
+        register["public_key_algorithm"] = Value::from("");
+    }
+    if let Some(facts) = crate::facts::load() {
+        register["facts"] = Value::Object(facts);
+    }
+    privacy.redact_register(&mut register, "");
+    let mut register_fields = BTreeSet::new();
+    flatten("", &register, &mut register_fields);
+
+    let heartbeat_fields = if config.statistics.enabled {
+        Some(heartbeat(&config, &registry, &schema, privacy).await?)
+    } else {
+        None
+    };
+
+    match format {
+        Format::Text => {
+            println!("Configure: {}", path.display());
+            println!("Privacy level: {:?}", privacy);
+            println!();
+            print_section("Every request:", &envelope, &schema);
+            print_section(
+                "Registration (body of `register`):",
+                &register_fields,
+                &schema,
+            );
+            match &heartbeat_fields {
+                Some(fields) => print_section("Heartbeat (body of `heartbeat`):", fields, &schema),
+                None => {
+                    println!("Statistics disabled, heartbeats carry no body.");
+                    return Ok(());
+                }
+            }
+            println!("Collectors:");
+            for (name, source) in registry.sources() {
+                println!("  {:<24} {}", name, source);
+            }
+        }
+        Format::Json => {
+            let collectors = registry
+                .sources()
+                .into_iter()
+                .map(|(name, source)| serde_json::json!({"name": name, "source": source}))
+                .collect::<Vec<_>>();
+            crate::output::print_json(&serde_json::json!({
+                "configure": path.display().to_string(),
+                "privacy": privacy,
+                "request": section_json(&envelope, &schema),
+                "register": section_json(&register_fields, &schema),
+                "heartbeat": heartbeat_fields.map(|fields| section_json(&fields, &schema)),
+                "collectors": collectors,
+            }))?
+        }
+    }
+    Ok(())
+}
+
+/// Fields of heartbeat body, from one run of every collector.
+async fn heartbeat(
+    config: &Configure,
+    registry: &Registry,
+    schema: &Schema,
+    privacy: Privacy,
+) -> anyhow::Result<BTreeSet<String>> {
+    let mut info = crate::info::get_base_info().await;
+    info.set_collectors(registry.collect().await);
+    let mut payload = serde_json::to_value(&info)?;
+    schema.normalize(&mut payload);
+    if let Some(definitions) = &config.derived {
+        let derived = Derived::new(definitions)?;
+        payload["derived"] = serde_json::to_value(derived.evaluate(&payload))?;
+    }
+    if config.anomaly.is_some() {
+        payload["anomalies"] =
+            serde_json::json!([{"metric": "", "value": 0, "mean": 0, "stddev": 0, "score": 0}]);
+    }
+    if config.forecast.is_some() {
+        if let Some(mounts) = payload.get_mut("mount").and_then(Value::as_array_mut) {
+            mounts
+                .iter_mut()
+                .for_each(|mount| mount["days_until_full"] = Value::from(0.0));
+        }
+    }
+    if config.policy.is_some() {
+        payload["alerts"] = serde_json::json!([""]);
+    }
+    if config.blackout.is_some() {
+        payload["maintenance"] = serde_json::json!({"window": "", "started": 0, "ends": 0});
+    }
+    privacy.redact_payload(&mut payload, "");
+    let mut fields = BTreeSet::new();
+    flatten("", &payload, &mut fields);
+    Ok(fields)
+}
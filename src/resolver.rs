@@ -0,0 +1,254 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Caching resolver for server connections in `[dns_cache]`, so hosts with slow resolvers
+//! don't pay a lookup per heartbeat.
+//!
+//! A and AAAA records are queried directly (see `dns`, names are used as is without search domains),
+//! answers are kept for their TTL clamped to `min_ttl`..`max_ttl`, names without any address
+//! for `negative_ttl`. If resolver fails, an expired answer is still served for up to `stale` seconds,
+//! so resolver blips are not counted as server failures. `/etc/hosts` takes precedence, and
+//! the system resolver is used when no nameserver is configured.
+//! Entries are dropped by `dns flush` on control socket.
+
+use crate::configparser::config::{Configure, DnsCache};
+use crate::dns::{self, NxDomainError, TYPE_A, TYPE_AAAA};
+use crate::error::Error;
+use anyhow::anyhow;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_ENABLED: bool = false;
+pub const DEFAULT_MIN_TTL: u64 = 5;
+pub const DEFAULT_MAX_TTL: u64 = 3600;
+pub const DEFAULT_NEGATIVE_TTL: u64 = 30;
+pub const DEFAULT_STALE: u64 = 3600;
+/// Answers from hosts file or system resolver come without TTL.
+const UNKNOWN_TTL: u64 = 60;
+/// Wait for resolver before falling back to expired answer, well within connect timeout.
+const STALE_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(unix)]
+const HOSTS_FILE: &str = "/etc/hosts";
+
+static CACHE: OnceLock<Cache> = OnceLock::new();
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl Entry {
+    fn result(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        if self.addrs.is_empty() {
+            Err(Error::Resolve {
+                name: name.to_string(),
+            }
+            .into())
+        } else {
+            Ok(self.addrs.clone())
+        }
+    }
+}
+
+struct Cache {
+    resolver: Option<SocketAddr>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    stale: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[cfg(unix)]
+fn hosts(name: &str) -> Option<Vec<IpAddr>> {
+    let contents = std::fs::read_to_string(HOSTS_FILE).ok()?;
+    let addrs = contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('#').next()?.split_whitespace();
+            let addr = fields.next()?.parse::<IpAddr>().ok()?;
+            fields
+                .any(|host| host.eq_ignore_ascii_case(name))
+                .then_some(addr)
+        })
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        None
+    } else {
+        Some(addrs)
+    }
+}
+
+#[cfg(not(unix))]
+fn hosts(_name: &str) -> Option<Vec<IpAddr>> {
+    None
+}
+
+impl Cache {
+    fn new(cfg: &DnsCache) -> anyhow::Result<Self> {
+        let seconds = |value: Option<u64>, default| Duration::from_secs(value.unwrap_or(default));
+        Ok(Self {
+            resolver: cfg
+                .resolver
+                .as_deref()
+                .map(dns::parse_resolver)
+                .transpose()?,
+            min_ttl: seconds(cfg.min_ttl, DEFAULT_MIN_TTL),
+            max_ttl: seconds(cfg.max_ttl, DEFAULT_MAX_TTL),
+            negative_ttl: seconds(cfg.negative_ttl, DEFAULT_NEGATIVE_TTL),
+            stale: seconds(cfg.stale, DEFAULT_STALE),
+            entries: Default::default(),
+        })
+    }
+
+    async fn system(name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        Ok(tokio::net::lookup_host((name, 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect())
+    }
+
+    /// Resolve without cache, empty result means the name has no address.
+    async fn resolve(&self, name: &str) -> anyhow::Result<(Vec<IpAddr>, Duration)> {
+        let unknown_ttl = Duration::from_secs(UNKNOWN_TTL);
+        if let Some(addrs) = hosts(name) {
+            return Ok((addrs, unknown_ttl));
+        }
+        let resolver = match self.resolver {
+            Some(resolver) => resolver,
+            None => match dns::default_resolver() {
+                Ok(resolver) => resolver,
+                Err(_) => return Ok((Self::system(name).await?, unknown_ttl)),
+            },
+        };
+        let (a, aaaa) = tokio::join!(
+            dns::query(resolver, name, TYPE_A),
+            dns::query(resolver, name, TYPE_AAAA)
+        );
+        let mut answers = Vec::new();
+        let mut error = None;
+        for result in [a, aaaa] {
+            match result {
+                Ok(records) => answers.extend(records),
+                Err(e) if e.is::<NxDomainError>() => {}
+                Err(e) => error = Some(e),
+            }
+        }
+        let ttl = match answers.iter().map(|answer| answer.ttl).min() {
+            Some(ttl) => Duration::from_secs(ttl as u64).clamp(self.min_ttl, self.max_ttl),
+            None => match error {
+                Some(e) => return Err(e),
+                None => return Ok((Vec::new(), self.negative_ttl)),
+            },
+        };
+        let addrs = answers
+            .into_iter()
+            .filter_map(|answer| answer.value.parse().ok())
+            .collect();
+        Ok((addrs, ttl))
+    }
+
+    async fn lookup(&self, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let now = Instant::now();
+        let stale = match self.entries.lock().unwrap().get(&name) {
+            Some(entry) if entry.expires > now => return entry.result(&name),
+            Some(entry) if !entry.addrs.is_empty() && entry.expires + self.stale > now => {
+                Some(entry.addrs.clone())
+            }
+            _ => None,
+        };
+        let result = match stale {
+            Some(_) => tokio::time::timeout(STALE_TIMEOUT, self.resolve(&name))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::Timeout {
+                        error: anyhow!("Timeout resolve {}", name),
+                    }
+                    .into())
+                }),
+            None => self.resolve(&name).await,
+        };
+        let (addrs, ttl) = match result {
+            Ok(result) => result,
+            Err(e) => match stale {
+                Some(addrs) => {
+                    warn!("Unable resolve {}, use expired answer: {:?}", name, e);
+                    return Ok(addrs);
+                }
+                None => return Err(e),
+            },
+        };
+        debug!("Resolved {} to {:?}, cache for {:?}", name, addrs, ttl);
+        let entry = Entry {
+            addrs,
+            expires: now + ttl,
+        };
+        let result = entry.result(&name);
+        self.entries.lock().unwrap().insert(name, entry);
+        result
+    }
+}
+
+/// Resolver to use for client connections, `None` if `[dns_cache]` is not enabled.
+/// Every client shares one cache.
+pub fn from_config(cfg: &Configure) -> anyhow::Result<Option<Arc<Resolver>>> {
+    let dns_cache = match &cfg.dns_cache {
+        Some(dns_cache) if dns_cache.enabled.unwrap_or(DEFAULT_ENABLED) => dns_cache,
+        _ => return Ok(None),
+    };
+    let cache = match CACHE.get() {
+        Some(cache) => cache,
+        None => {
+            CACHE.set(Cache::new(dns_cache)?).ok();
+            CACHE.get().unwrap()
+        }
+    };
+    Ok(Some(Arc::new(Resolver(cache))))
+}
+
+/// Drop all cached entries, return how many were dropped.
+pub fn flush() -> usize {
+    match CACHE.get() {
+        Some(cache) => {
+            let mut entries = cache.entries.lock().unwrap();
+            let count = entries.len();
+            entries.clear();
+            count
+        }
+        None => 0,
+    }
+}
+
+pub struct Resolver(&'static Cache);
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        let cache = self.0;
+        Box::pin(async move {
+            let addrs = cache.lookup(name.as_str()).await?;
+            let addrs: reqwest::dns::Addrs =
+                Box::new(addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addrs)
+        })
+    }
+}
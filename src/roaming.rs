@@ -0,0 +1,155 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Detect default route or primary address change, so laptop/mobile probes can re-register
+//! with the new addressing between heartbeats.
+
+use log::{debug, error, info};
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Documentation addresses, only used to look up the route, no packet is sent.
+const ROUTE_PROBE_V4: &str = "192.0.2.1:9";
+const ROUTE_PROBE_V6: &str = "[2001:db8::1]:9";
+#[cfg(target_os = "linux")]
+const SETTLE_DELAY: Duration = Duration::from_secs(1);
+#[cfg(not(target_os = "linux"))]
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+fn route_source(bind: &str, target: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(target).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Source address of the default route.
+pub fn primary_address() -> Option<IpAddr> {
+    route_source("0.0.0.0:0", ROUTE_PROBE_V4).or_else(|| route_source("[::]:0", ROUTE_PROBE_V6))
+}
+
+struct Watcher {
+    current: Option<IpAddr>,
+    notify: Arc<Notify>,
+}
+
+impl Watcher {
+    fn check(&mut self) {
+        let address = primary_address();
+        if address != self.current {
+            info!(
+                "Primary address changed from {:?} to {:?}",
+                self.current, address
+            );
+            self.current = address;
+            self.notify.notify_one();
+        }
+    }
+}
+
+/// Subscribe to address and route changes from rtnetlink, blocking.
+#[cfg(target_os = "linux")]
+fn watch(mut watcher: Watcher) -> std::io::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = (libc::RTMGRP_LINK
+        | libc::RTMGRP_IPV4_IFADDR
+        | libc::RTMGRP_IPV6_IFADDR
+        | libc::RTMGRP_IPV4_ROUTE
+        | libc::RTMGRP_IPV6_ROUTE) as u32;
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = std::io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    let mut buffer = [0u8; 8192];
+    loop {
+        let size = unsafe {
+            libc::recv(
+                fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if size < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            unsafe { libc::close(fd) };
+            return Err(e);
+        }
+        debug!("Received {} bytes netlink message", size);
+        // Wait for routes to settle, and drop the burst of messages
+        std::thread::sleep(SETTLE_DELAY);
+        while unsafe {
+            libc::recv(
+                fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                libc::MSG_DONTWAIT,
+            )
+        } > 0
+        {}
+        watcher.check();
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch(mut watcher: Watcher) -> std::io::Result<()> {
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        watcher.check();
+    }
+}
+
+/// Start watching network change in background thread, notify returned handle on change.
+pub fn spawn() -> Arc<Notify> {
+    let notify = Arc::new(Notify::new());
+    let watcher = Watcher {
+        current: primary_address(),
+        notify: notify.clone(),
+    };
+    std::thread::spawn(move || {
+        if let Err(e) = watch(watcher) {
+            error!("Unable watch network change: {:?}", e);
+        }
+    });
+    notify
+}
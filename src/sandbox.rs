@@ -0,0 +1,269 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use log::warn;
+use std::fmt::Formatter;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+use tokio::process::Command;
+
+pub const DEFAULT_TIMEOUT: u64 = 10;
+pub const DEFAULT_SECCOMP: bool = false;
+const MAX_OUTPUT_SIZE: u64 = 64 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    pub timeout: Option<u64>,
+    pub cpu_seconds: Option<u64>,
+    pub memory_mb: Option<u64>,
+    pub seccomp: bool,
+    /// Bytes of output kept, `MAX_OUTPUT_SIZE` if not set
+    pub max_output: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum Violation {
+    Timeout(u64),
+    CpuLimit(u64),
+    Signal(i32),
+    ExitCode(i32, String),
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Timeout(secs) => write!(f, "Killed after wall-clock timeout {}s", secs),
+            Violation::CpuLimit(secs) => write!(f, "Killed after CPU time limit {}s", secs),
+            Violation::Signal(sig) => write!(f, "Terminated by signal {}", sig),
+            Violation::ExitCode(code, stderr) => {
+                write!(f, "Exited with code {}: {}", code, stderr.trim())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+#[cfg(target_os = "linux")]
+pub const DENIED_SYSCALLS: [libc::c_long; 13] = [
+    libc::SYS_ptrace,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+];
+
+/// Filter which fails `denied` syscalls with `EPERM` and allows everything else.
+#[cfg(target_os = "linux")]
+pub fn build_seccomp_filter(denied: &[libc::c_long]) -> anyhow::Result<seccompiler::BpfProgram> {
+    use seccompiler::{SeccompAction, SeccompFilter, TargetArch};
+    use std::convert::TryInto;
+
+    let arch: TargetArch =
+        std::env::consts::ARCH
+            .try_into()
+            .map_err(|e| crate::error::Error::Unavailable {
+                feature: "seccomp",
+                message: format!("{:?}", e),
+            })?;
+    let filter = SeccompFilter::new(
+        denied.iter().map(|nr| (*nr, vec![])).collect(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )?;
+    Ok(filter.try_into()?)
+}
+
+#[cfg(unix)]
+fn apply_limits(command: &mut Command, limits: &Limits) -> anyhow::Result<()> {
+    let cpu_seconds = limits.cpu_seconds;
+    let memory = limits.memory_mb.map(|mb| mb * 1024 * 1024);
+    #[cfg(target_os = "linux")]
+    let filter = if limits.seccomp {
+        Some(build_seccomp_filter(&DENIED_SYSCALLS)?)
+    } else {
+        None
+    };
+    #[cfg(not(target_os = "linux"))]
+    if limits.seccomp {
+        warn!("Seccomp is only supported on Linux, ignored");
+    }
+
+    // Safety: only async-signal-safe calls are made between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(secs) = cpu_seconds {
+                let lim = libc::rlimit {
+                    rlim_cur: secs as libc::rlim_t,
+                    rlim_max: secs as libc::rlim_t + 1,
+                };
+                if libc::setrlimit(libc::RLIMIT_CPU, &lim) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(bytes) = memory {
+                let lim = libc::rlimit {
+                    rlim_cur: bytes as libc::rlim_t,
+                    rlim_max: bytes as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_AS, &lim) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(filter) = &filter {
+                seccompiler::apply_filter(filter).map_err(std::io::Error::other)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_limits(_command: &mut Command, limits: &Limits) -> anyhow::Result<()> {
+    if limits.cpu_seconds.is_some() || limits.memory_mb.is_some() || limits.seccomp {
+        warn!("Resource limits are not supported on this platform, ignored");
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_signal(status: &std::process::ExitStatus, limits: &Limits) -> Option<Violation> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().map(|sig| match (sig, limits.cpu_seconds) {
+        (libc::SIGXCPU, Some(secs)) | (libc::SIGKILL, Some(secs)) => Violation::CpuLimit(secs),
+        _ => Violation::Signal(sig),
+    })
+}
+
+#[cfg(not(unix))]
+fn check_signal(_status: &std::process::ExitStatus, _limits: &Limits) -> Option<Violation> {
+    None
+}
+
+/// First `max` bytes of `reader`, the rest is read and dropped so the writer never blocks on a
+/// full pipe.
+async fn read_capped<R: AsyncRead + Unpin>(mut reader: R, max: u64) -> std::io::Result<Vec<u8>> {
+    let mut kept = Vec::new();
+    (&mut reader).take(max).read_to_end(&mut kept).await?;
+    tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    Ok(kept)
+}
+
+/// Run `program` in a constrained child process and return its standard output, cut after
+/// `max_output` bytes.
+pub async fn run(program: &str, args: &[String], limits: &Limits) -> anyhow::Result<String> {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    apply_limits(&mut command, limits)?;
+
+    let mut child = command.spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    let timeout = limits.timeout.unwrap_or(DEFAULT_TIMEOUT);
+    let result = tokio::time::timeout(Duration::from_secs(timeout), async {
+        let (output, error) = tokio::try_join!(
+            read_capped(stdout, limits.max_output.unwrap_or(MAX_OUTPUT_SIZE)),
+            read_capped(stderr, MAX_OUTPUT_SIZE)
+        )?;
+        let status = child.wait().await?;
+        Ok::<_, std::io::Error>((
+            status,
+            String::from_utf8_lossy(&output).into_owned(),
+            String::from_utf8_lossy(&error).into_owned(),
+        ))
+    })
+    .await;
+
+    let (status, output, error) = match result {
+        Ok(r) => r?,
+        Err(_) => {
+            warn!("{} exceed wall-clock timeout, kill it", program);
+            child.kill().await.ok();
+            return Err(anyhow::Error::new(Violation::Timeout(timeout)));
+        }
+    };
+
+    if let Some(violation) = check_signal(&status, limits) {
+        return Err(anyhow::Error::new(violation));
+    }
+    if !status.success() {
+        return Err(anyhow::Error::new(Violation::ExitCode(
+            status.code().unwrap_or(-1),
+            error,
+        )));
+    }
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Vec<String> {
+        vec!["-c".to_string(), script.to_string()]
+    }
+
+    #[tokio::test]
+    async fn output_over_cap_is_truncated() {
+        let limits = Limits {
+            max_output: Some(1000),
+            ..Default::default()
+        };
+        // Far beyond pipe buffer, child must not block until timeout
+        let output = run(
+            "sh",
+            &shell("head -c 1000000 /dev/zero | tr '\\0' a"),
+            &limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output, "a".repeat(1000));
+    }
+
+    #[tokio::test]
+    async fn multibyte_cut_at_cap_is_lossy() {
+        let limits = Limits {
+            max_output: Some(3),
+            ..Default::default()
+        };
+        let output = run("printf", &["\u{e9}\u{e9}".to_string()], &limits)
+            .await
+            .unwrap();
+        assert_eq!(output, "\u{e9}\u{fffd}");
+    }
+}
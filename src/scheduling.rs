@@ -0,0 +1,213 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Lower own scheduling priority at startup in `[scheduling]`, so collectors and the scripts they run
+//! never compete with production workloads: nice level (Unix), I/O scheduling class and
+//! cgroup v2 CPU weight (Linux).
+//!
+//! Nice level and I/O class are per thread on Linux, so they must be applied before any other
+//! thread is started; threads and child processes created afterwards inherit them.
+
+use crate::configparser::config::Scheduling;
+use crate::error::Error;
+#[cfg(unix)]
+use log::{info, warn};
+
+#[cfg(target_os = "linux")]
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: i32 = 1;
+pub const DEFAULT_IO_CLASS: &str = "best-effort";
+pub const DEFAULT_IO_PRIORITY: i32 = 4;
+
+/// Kernel value of I/O scheduling class.
+fn io_class(name: &str) -> anyhow::Result<i32> {
+    match name {
+        "realtime" => Ok(1),
+        "best-effort" => Ok(2),
+        "idle" => Ok(3),
+        _ => Err(Error::Configure {
+            field: "scheduling.io_class",
+            message: format!(
+                "Unsupported io_class {}, expect realtime, best-effort or idle",
+                name
+            ),
+        }
+        .into()),
+    }
+}
+
+/// Validate values, so mistakes are reported even where they can't be applied.
+fn validate(cfg: &Scheduling) -> anyhow::Result<()> {
+    if let Some(nice) = cfg.nice {
+        if !(-20..=19).contains(&nice) {
+            return Err(Error::Configure {
+                field: "scheduling.nice",
+                message: format!("nice {} out of range -20..19", nice),
+            }
+            .into());
+        }
+    }
+    if let Some(class) = &cfg.io_class {
+        io_class(class)?;
+    }
+    if let Some(priority) = cfg.io_priority {
+        if !(0..=7).contains(&priority) {
+            return Err(Error::Configure {
+                field: "scheduling.io_priority",
+                message: format!("io_priority {} out of range 0..7", priority),
+            }
+            .into());
+        }
+    }
+    if let Some(weight) = cfg.cpu_weight {
+        if !(1..=10000).contains(&weight) {
+            return Err(Error::Configure {
+                field: "scheduling.cpu_weight",
+                message: format!("cpu_weight {} out of range 1..10000", weight),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Set nice level of calling thread.
+#[cfg(unix)]
+fn set_nice(nice: i32) -> std::io::Result<()> {
+    // Safety: plain libc call on calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set I/O scheduling class and priority of calling thread.
+#[cfg(target_os = "linux")]
+fn set_io_priority(class: i32, priority: i32) -> std::io::Result<()> {
+    let value = (class << IOPRIO_CLASS_SHIFT) | priority;
+    // Safety: plain syscall on calling thread, no pointer involved
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write `cpu.weight` of own cgroup v2, which is the unit's cgroup when started by systemd.
+#[cfg(target_os = "linux")]
+fn set_cpu_weight(weight: u32) -> anyhow::Result<()> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| Error::Unsupported {
+            what: "cgroup v2 hierarchy".to_string(),
+        })?;
+    if path == "/" {
+        return Err(Error::Unsupported {
+            what: "cgroup other than root".to_string(),
+        }
+        .into());
+    }
+    let file = format!("/sys/fs/cgroup{}/cpu.weight", path.trim_end_matches('/'));
+    std::fs::write(&file, weight.to_string()).map_err(|source| {
+        Error::Io {
+            operation: "write",
+            path: file,
+            source,
+        }
+        .into()
+    })
+}
+
+/// Apply `[scheduling]`. Invalid values are errors, failing to apply a valid one
+/// (e.g. raising priority without CAP_SYS_NICE) is only logged.
+pub fn apply(cfg: &Scheduling) -> anyhow::Result<()> {
+    validate(cfg)?;
+    #[cfg(unix)]
+    if let Some(nice) = cfg.nice {
+        match set_nice(nice) {
+            Ok(()) => info!("Set nice level to {}", nice),
+            Err(e) => warn!("Unable set nice level to {}: {}", nice, e),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if cfg.io_class.is_some() || cfg.io_priority.is_some() {
+        let class = cfg.io_class.as_deref().unwrap_or(DEFAULT_IO_CLASS);
+        // Priority has no meaning in idle class
+        let priority = match class {
+            "idle" => 0,
+            _ => cfg.io_priority.unwrap_or(DEFAULT_IO_PRIORITY),
+        };
+        match set_io_priority(io_class(class)?, priority) {
+            Ok(()) => info!("Set I/O scheduling class to {} ({})", class, priority),
+            Err(e) => warn!("Unable set I/O scheduling class to {}: {}", class, e),
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(weight) = cfg.cpu_weight {
+        match set_cpu_weight(weight) {
+            Ok(()) => info!("Set cgroup CPU weight to {}", weight),
+            Err(e) => warn!("Unable set cgroup CPU weight: {}", e),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if cfg.io_class.is_some() || cfg.io_priority.is_some() || cfg.cpu_weight.is_some() {
+        log::warn!("I/O class and CPU weight are only supported on Linux, ignored");
+    }
+    #[cfg(not(unix))]
+    if cfg.nice.is_some() {
+        log::warn!("Nice level is not supported on this platform, ignored");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate_str(scheduling: &str) -> anyhow::Result<()> {
+        validate(&toml::from_str(scheduling).unwrap())
+    }
+
+    #[test]
+    fn values_validated_at_range_edges() {
+        assert!(validate_str("nice = -20\nio_priority = 0\ncpu_weight = 1").is_ok());
+        assert!(validate_str("nice = 19\nio_priority = 7\ncpu_weight = 10000").is_ok());
+        for invalid in [
+            "nice = 20",
+            "nice = -21",
+            "io_priority = 8",
+            "cpu_weight = 0",
+            "cpu_weight = 10001",
+            "io_class = \"batch\"",
+        ]
+        .iter()
+        {
+            let e = validate_str(invalid).unwrap_err();
+            assert_eq!(
+                crate::error::code(&e),
+                crate::error::Code::Configure,
+                "{}",
+                invalid
+            );
+        }
+        assert_eq!(io_class("idle").unwrap(), 3);
+    }
+}
@@ -17,66 +17,552 @@
  ** You should have received a copy of the GNU Affero General Public License
  ** along with this program. If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::action::{Dispatcher, ServerAction};
+use crate::allowlist::AllowList;
+use crate::anomaly::Detector;
+use crate::audit::{self, AuditLog};
+use crate::blackout::{self, Blackouts};
+use crate::collector::Registry;
 use crate::configparser::config::Configure;
 use crate::configparser::config::*;
-use crate::session::error::TimeoutError;
+use crate::counters::{Counter, Counters};
+use crate::derived::Derived;
+use crate::downsample::{self, Sample};
+use crate::error::{self, Error};
+use crate::forecast::Forecast;
+use crate::fronting::Fronting;
+use crate::handover::HandoverState;
+use crate::nonce::{self, NonceStore};
+use crate::normalize::Schema;
+use crate::pressure::Monitor;
+use crate::privacy::Privacy;
+use crate::record::{Exchange, Interaction};
+use crate::session::authz::Authorizer;
+use crate::session::envelope::RequestEnvelope;
 use crate::session::response::JsonResponse;
+use crate::signing::Signer;
+use crate::spool::{Spool, COLLECTED_AT};
 use anyhow::Result;
-use log::{error, info};
-use reqwest::header::HeaderMap;
-use std::collections::HashMap;
+use log::{error, info, warn};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, HOST};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fmt::{Display, Formatter};
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use systemstat::Platform;
 
 pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const DEFAULT_INTERVAL: u32 = 180;
 pub const MAX_RETRY_TIMES: i32 = 3;
+pub const DEFAULT_DEGRADED: bool = true;
+pub const DEFAULT_CHECK_SERVER_VERSION: bool = false;
+pub const DEFAULT_ROAMING: bool = false;
+pub const DEFAULT_CANARY: bool = false;
+pub const DEFAULT_DEGRADED_INTERVAL: u64 = 600;
+pub const AUTO_ADDRESS: &str = "auto";
+/// Server rejected request timestamp, `server_time` is returned to resync.
+pub const STATUS_CLOCK_SKEW: i64 = 4008;
+/// Heartbeat refused because of sections listed in `rejected`, resend without them.
+pub const STATUS_PAYLOAD_REJECTED: i64 = 4022;
+/// Server does not know client uuid (e.g. lost its state), register again on the same server.
+pub const STATUS_REREGISTER: i64 = 4031;
+/// Server is under maintenance, heartbeats are paused for `retry_after` seconds without failover.
+pub const STATUS_MAINTENANCE: i64 = 5030;
+/// Seconds between heartbeats during maintenance if server gives no `retry_after`.
+const DEFAULT_MAINTENANCE_RETRY: u64 = 300;
+pub const IDENTIFICATION_ENV: &str = "PROBE_CLIENT_UUID";
+const MAX_TRACKED_CONNECTIONS: usize = 1024;
+/// Seconds before TLS details of server are inspected again.
+const SERVER_TLS_REFRESH: u64 = 3600;
+/// Warn when server certificate expires within days.
+const SERVER_TLS_EXPIRY_WARNING: f64 = 14.0;
+pub(crate) const MACHINE_ID_PATHS: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
 
-pub mod error {
-    use std::fmt::Formatter;
+/// Compile-time and runtime capabilities reported in registration and configure retrieval,
+/// so server only enables features this build and host support in its pushed profile.
+pub mod capabilities {
+    use serde_derive::{Deserialize, Serialize};
 
-    #[derive(Debug)]
-    pub struct TooManyRetriesError {
-        e: anyhow::Error,
+    /// Cargo features this binary is built with.
+    const FEATURES: [(&str, bool); 10] = [
+        ("dashboard", cfg!(feature = "dashboard")),
+        ("devtools", cfg!(feature = "devtools")),
+        ("ebpf", cfg!(feature = "ebpf")),
+        ("mdns", cfg!(feature = "mdns")),
+        ("plugins", cfg!(feature = "plugins")),
+        ("policy", cfg!(feature = "policy")),
+        ("relay", cfg!(feature = "relay")),
+        ("smtp", cfg!(feature = "smtp")),
+        ("talkers", cfg!(feature = "talkers")),
+        ("tpm", cfg!(feature = "tpm")),
+    ];
+    const COLLECTORS: [(&str, bool); 16] = [
+        ("script", true),
+        ("file_integrity", true),
+        ("certificates", true),
+        ("dns", true),
+        ("fs_latency", true),
+        ("peers", true),
+        ("cloud", true),
+        ("windows_events", cfg!(windows)),
+        ("kernel_events", cfg!(target_os = "linux")),
+        ("mac", cfg!(target_os = "linux")),
+        ("firewall", cfg!(any(target_os = "linux", windows))),
+        ("reboot", cfg!(target_os = "linux")),
+        ("packages", cfg!(unix)),
+        (
+            "talkers",
+            cfg!(all(feature = "talkers", target_os = "linux")),
+        ),
+        ("tcp", cfg!(all(feature = "ebpf", target_os = "linux"))),
+        ("plugins", cfg!(feature = "plugins")),
+    ];
+    const TRANSPORTS: [(&str, bool); 6] = [
+        ("https", true),
+        ("websocket", true),
+        ("socks5", true),
+        ("tor", true),
+        ("ssh_tunnel", true),
+        ("relay", cfg!(feature = "relay")),
+    ];
+    const ACTIONS: [&str; 5] = [
+        "wake",
+        "exec",
+        "fetch_file",
+        "remote_access",
+        "package_list",
+    ];
+    /// Experimental payload changes canary clients may opt into when advertised by server.
+    pub const EXPERIMENTS: [&str; 1] = [
+        // Leave out null values and empty sections of heartbeat payload
+        "compact_payload",
+    ];
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Platform {
+        pub os: String,
+        pub family: String,
+        pub arch: String,
     }
 
-    impl std::error::Error for TooManyRetriesError {}
+    /// Build facts recorded by `build.rs`.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Build {
+        pub target: String,
+        pub profile: String,
+        pub commit: String,
+    }
 
-    impl std::fmt::Display for TooManyRetriesError {
-        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            write!(f, "Max retry times exceed! last error: {:?}", self.e)
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Runtime {
+        pub root: bool,
+        pub read_only: bool,
+        /// Seccomp filters available for sandboxed commands and hardening.
+        pub seccomp: bool,
+        /// Landlock ABI version supported by kernel, 0 if unavailable.
+        pub landlock: i64,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Capabilities {
+        pub platform: Platform,
+        pub build: Build,
+        pub features: Vec<String>,
+        pub collectors: Vec<String>,
+        pub transports: Vec<String>,
+        pub actions: Vec<String>,
+        pub experiments: Vec<String>,
+        pub runtime: Runtime,
+    }
+
+    fn enabled(flags: &[(&str, bool)]) -> Vec<String> {
+        flags
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn is_root() -> bool {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(unix))]
+    fn is_root() -> bool {
+        false
+    }
+
+    #[cfg(target_os = "linux")]
+    fn landlock_abi() -> i64 {
+        const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<libc::c_void>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        abi.max(0)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn landlock_abi() -> i64 {
+        0
+    }
+
+    pub fn build() -> Build {
+        Build {
+            target: env!("PROBE_BUILD_TARGET").to_string(),
+            profile: env!("PROBE_BUILD_PROFILE").to_string(),
+            commit: env!("PROBE_BUILD_COMMIT").to_string(),
         }
     }
 
-    impl TooManyRetriesError {
-        pub fn new(e: anyhow::Error) -> anyhow::Error {
-            anyhow::Error::new(TooManyRetriesError { e })
+    pub fn features() -> Vec<String> {
+        enabled(&FEATURES)
+    }
+
+    /// Output of `--version --verbose`.
+    pub fn long_version() -> String {
+        let build = build();
+        format!(
+            "probe-client {}\ntarget: {}\nprofile: {}\ncommit: {}\nfeatures: {}",
+            super::CLIENT_VERSION,
+            build.target,
+            build.profile,
+            build.commit,
+            features().join(", ")
+        )
+    }
+
+    pub fn detect() -> Capabilities {
+        Capabilities {
+            platform: Platform {
+                os: std::env::consts::OS.to_string(),
+                family: std::env::consts::FAMILY.to_string(),
+                arch: std::env::consts::ARCH.to_string(),
+            },
+            build: build(),
+            features: features(),
+            collectors: enabled(&COLLECTORS),
+            transports: enabled(&TRANSPORTS),
+            actions: ACTIONS.iter().map(|action| action.to_string()).collect(),
+            experiments: EXPERIMENTS
+                .iter()
+                .map(|experiment| experiment.to_string())
+                .collect(),
+            runtime: Runtime {
+                root: is_root(),
+                read_only: crate::state::is_read_only(),
+                seccomp: cfg!(target_os = "linux"),
+                landlock: landlock_abi(),
+            },
         }
     }
+}
 
-    #[derive(Debug)]
-    pub struct TimeoutError {
-        e: anyhow::Error,
+pub mod envelope {
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    pub struct RequestEnvelope {
+        pub version: String,
+        pub action: String,
+        pub uuid: String,
+        pub seq: u64,
+        #[serde(default)]
+        pub timestamp: i64,
+        #[serde(default)]
+        pub nonce: u64,
+        #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+        pub body: serde_json::Value,
+        /// Changes on every boot of the host, not on restart of client.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub boot_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub manifest: Option<crate::signing::Manifest>,
+        /// Local clock in milliseconds when sent, without any offset applied.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub sent_at: Option<i64>,
+        /// Last measured server minus client clock in milliseconds, from response `Date` header.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub clock_offset: Option<i64>,
+        /// Client is in canary fleet, see `server.canary`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub canary: Option<bool>,
+        /// Experiments advertised by server which client opted into.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub experiments: Vec<String>,
     }
+}
 
-    impl std::error::Error for TimeoutError {}
+/// Two-man rule: dangerous actions run only if approved by at least two distinct operator keys.
+///
+/// Each approval is a base64 Ed25519 signature over `{"action", "expires", "id", "params", "uuid"}`
+/// of the requested action, serialized as compact JSON with sorted keys, where `uuid` is identification
+/// of the target probe. Approved ids are persisted until they expire, so each one runs only once.
+///
+/// Dangerous actions are refused outright without `[authz]`, and `authz.actions` can only add to them.
+pub mod authz {
+    use crate::action::ServerAction;
+    use crate::configparser::config::Authz;
+    use crate::error::Error;
+    use crate::replay::UsedStore;
+    use std::collections::HashSet;
 
-    impl std::fmt::Display for TimeoutError {
-        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-            write!(f, "Timeout: {:?}", self.e)
+    pub const DEFAULT_REQUIRED: usize = 2;
+    /// Actions which always require approvals, whatever `authz.actions` says
+    pub const DANGEROUS_ACTIONS: [&str; 3] = ["exec", "fetch_file", "remote_access"];
+    pub const USED_FILE: &str = "probe_approvals_used.json";
+
+    pub struct Authorizer {
+        operator_keys: Vec<String>,
+        required: usize,
+        actions: Vec<String>,
+        /// Identification of this probe, approvals for other ones are refused
+        uuid: String,
+        used: UsedStore,
+    }
+
+    impl Authorizer {
+        pub fn new(cfg: &Authz, uuid: &str) -> anyhow::Result<Self> {
+            let required = cfg.required.unwrap_or(DEFAULT_REQUIRED);
+            if required < DEFAULT_REQUIRED {
+                return Err(Error::Configure {
+                    field: "authz.required",
+                    message: format!("authz.required should be at least {}", DEFAULT_REQUIRED),
+                }
+                .into());
+            }
+            let operator_keys: HashSet<&String> = cfg.operator_keys.iter().collect();
+            if operator_keys.len() < required {
+                return Err(Error::Configure {
+                    field: "authz.operator_keys",
+                    message: format!(
+                        "authz requires {} approvals but only {} distinct operator keys configured",
+                        required,
+                        operator_keys.len()
+                    ),
+                }
+                .into());
+            }
+            Ok(Self {
+                operator_keys: operator_keys.into_iter().cloned().collect(),
+                required,
+                actions: DANGEROUS_ACTIONS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .chain(cfg.actions.iter().flatten().cloned())
+                    .collect(),
+                uuid: uuid.to_string(),
+                used: UsedStore::load(crate::state::path(USED_FILE)),
+            })
+        }
+
+        /// Check approvals of `request`, return whether approvals were required.
+        fn check(&self, request: &ServerAction) -> anyhow::Result<bool> {
+            if !self.actions.contains(&request.action) {
+                return Ok(false);
+            }
+            let id = request.id.as_ref().ok_or_else(|| Error::MissingParameter {
+                action: request.action.clone(),
+                name: "id",
+            })?;
+            let expires = request.expires.ok_or_else(|| Error::MissingParameter {
+                action: request.action.clone(),
+                name: "expires",
+            })?;
+            if expires < chrono::Utc::now().timestamp() {
+                return Err(Error::Expired {
+                    action: request.action.clone(),
+                    expires,
+                }
+                .into());
+            }
+            let message = serde_json::to_vec(&serde_json::json!({
+                "id": id,
+                "action": request.action,
+                "params": request.params,
+                "expires": expires,
+                "uuid": self.uuid,
+            }))?;
+            let approved =
+                self.operator_keys
+                    .iter()
+                    .filter(|key| {
+                        request.approvals.iter().any(|signature| {
+                            crate::signing::verify(key, &message, signature).is_ok()
+                        })
+                    })
+                    .count();
+            if approved < self.required {
+                return Err(Error::Unapproved {
+                    action: request.action.clone(),
+                    approved,
+                    required: self.required,
+                    configured: true,
+                }
+                .into());
+            }
+            if !self.used.insert(id, expires) {
+                return Err(Error::Replayed {
+                    action: request.action.clone(),
+                    id: id.clone(),
+                }
+                .into());
+            }
+            Ok(true)
         }
     }
 
-    impl TimeoutError {
-        pub fn new(e: anyhow::Error) -> anyhow::Error {
-            anyhow::Error::new(TimeoutError { e })
+    /// Check `request` against `authz` if configured, return whether approvals were required.
+    /// Without it, dangerous actions are refused.
+    pub fn authorize(authz: Option<&Authorizer>, request: &ServerAction) -> anyhow::Result<bool> {
+        match authz {
+            Some(authz) => authz.check(request),
+            None if DANGEROUS_ACTIONS.contains(&request.action.as_str()) => {
+                Err(Error::Unapproved {
+                    action: request.action.clone(),
+                    approved: 0,
+                    required: DEFAULT_REQUIRED,
+                    configured: false,
+                }
+                .into())
+            }
+            None => Ok(false),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use base64::Engine as _;
+        use ring::rand::SystemRandom;
+        use ring::signature::{Ed25519KeyPair, KeyPair as _};
+
+        /// Request with `id` unique to this run, as used ids are persisted.
+        fn request(action: &str, id: &str) -> ServerAction {
+            serde_json::from_value(serde_json::json!({
+                "id": format!("{}-{}", id, uuid::Uuid::new_v4()),
+                "action": action,
+                "params": { "command": "uptime" },
+                "expires": chrono::Utc::now().timestamp() + 60,
+            }))
+            .unwrap()
+        }
+
+        fn operator() -> Ed25519KeyPair {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+        }
+
+        fn public_key(key_pair: &Ed25519KeyPair) -> String {
+            base64::engine::general_purpose::STANDARD.encode(key_pair.public_key().as_ref())
+        }
+
+        /// Approval of `request` for probe `uuid` signed by `key_pair`.
+        fn approve(key_pair: &Ed25519KeyPair, request: &ServerAction, uuid: &str) -> String {
+            let message = serde_json::to_vec(&serde_json::json!({
+                "id": request.id,
+                "action": request.action,
+                "params": request.params,
+                "expires": request.expires,
+                "uuid": uuid,
+            }))
+            .unwrap();
+            base64::engine::general_purpose::STANDARD.encode(key_pair.sign(&message))
+        }
+
+        fn authorizer(operators: &[Ed25519KeyPair], actions: Option<Vec<String>>) -> Authorizer {
+            crate::state::init_for_test();
+            let cfg = Authz {
+                operator_keys: operators.iter().map(public_key).collect(),
+                required: None,
+                actions,
+            };
+            Authorizer::new(&cfg, "uuid").unwrap()
+        }
+
+        fn code(result: anyhow::Result<bool>) -> crate::error::Code {
+            crate::error::code(&result.unwrap_err())
+        }
+
+        #[test]
+        fn exec_refused_without_authz() {
+            assert!(authorize(None, &request("exec", "1")).is_err());
+            assert!(authorize(None, &request("remote_access", "1")).is_err());
+            assert!(!authorize(None, &request("wake", "1")).unwrap());
+        }
+
+        #[test]
+        fn actions_only_add_to_dangerous() {
+            let authz = authorizer(&[operator(), operator()], Some(vec!["wake".to_string()]));
+            for action in ["exec", "wake"].iter() {
+                match authorize(Some(&authz), &request(action, "add")) {
+                    Err(e) => assert!(
+                        matches!(
+                            crate::error::of(&e),
+                            Some(Error::Unapproved { approved: 0, .. })
+                        ),
+                        "{}",
+                        e
+                    ),
+                    Ok(_) => panic!("{} ran without approvals", action),
+                }
+            }
+            assert!(!authorize(Some(&authz), &request("packages", "add")).unwrap());
+        }
+
+        #[test]
+        fn two_distinct_approvals_run_once() {
+            let operators = [operator(), operator()];
+            let authz = authorizer(&operators, None);
+            let mut exec = request("exec", "two-distinct");
+            exec.approvals = operators
+                .iter()
+                .map(|key| approve(key, &exec, "uuid"))
+                .collect();
+            assert!(authorize(Some(&authz), &exec).unwrap());
+            assert_eq!(
+                code(authorize(Some(&authz), &exec)),
+                crate::error::Code::Replayed
+            );
+
+            // Same operator twice is one approval
+            let mut exec = request("exec", "same-operator");
+            let approval = approve(&operators[0], &exec, "uuid");
+            exec.approvals = vec![approval.clone(), approval];
+            assert_eq!(
+                code(authorize(Some(&authz), &exec)),
+                crate::error::Code::Unapproved
+            );
+        }
+
+        #[test]
+        fn approval_for_another_probe_refused() {
+            let operators = [operator(), operator()];
+            let authz = authorizer(&operators, None);
+            let mut exec = request("exec", "another-probe");
+            exec.approvals = operators
+                .iter()
+                .map(|key| approve(key, &exec, "other"))
+                .collect();
+            assert_eq!(
+                code(authorize(Some(&authz), &exec)),
+                crate::error::Code::Unapproved
+            );
         }
     }
 }
 
-mod response {
+pub(crate) mod response {
+    use crate::action::ServerAction;
     use serde_derive::{Deserialize, Serialize};
     use std::fmt::Formatter;
 
@@ -87,6 +573,28 @@ mod response {
         #[deprecated(since = "1.5.0")]
         error_code: Option<i64>,
         message: Option<String>,
+        server_time: Option<i64>,
+        /// Seconds before next request during maintenance.
+        retry_after: Option<u64>,
+        /// Hex SHA-256 of request body as received by server.
+        digest: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        actions: Vec<ServerAction>,
+        /// Dotted paths of payload sections refused by server (schema mismatch).
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        rejected: Vec<String>,
+        /// Experiments offered to canary clients in registration response.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        experiments: Vec<String>,
+        /// PEM client certificate chain issued for `csr` of request.
+        certificate: Option<String>,
+    }
+
+    /// Message server pushes over WebSocket transport outside of any response.
+    #[derive(Deserialize)]
+    pub struct Push {
+        #[serde(default)]
+        pub actions: Vec<ServerAction>,
     }
 
     impl JsonResponse {
@@ -105,6 +613,34 @@ mod response {
         pub fn get_server_version(&self) -> &String {
             &self.version
         }
+
+        pub fn get_server_time(&self) -> Option<i64> {
+            self.server_time
+        }
+
+        pub fn get_retry_after(&self) -> Option<u64> {
+            self.retry_after
+        }
+
+        pub fn get_digest(&self) -> Option<&str> {
+            self.digest.as_deref()
+        }
+
+        pub fn take_actions(&mut self) -> Vec<ServerAction> {
+            std::mem::take(&mut self.actions)
+        }
+
+        pub fn take_rejected(&mut self) -> Vec<String> {
+            std::mem::take(&mut self.rejected)
+        }
+
+        pub fn get_experiments(&self) -> &[String] {
+            &self.experiments
+        }
+
+        pub fn get_certificate(&self) -> Option<&str> {
+            self.certificate.as_deref()
+        }
     }
 
     #[derive(Debug)]
@@ -128,6 +664,12 @@ mod response {
 
     impl std::error::Error for Error {}
 
+    impl Error {
+        pub(crate) fn context(&self) -> serde_json::Value {
+            serde_json::json!({ "status": self.code, "message": self.message })
+        }
+    }
+
     impl From<&JsonResponse> for Error {
         fn from(resp: &JsonResponse) -> Self {
             Error {
@@ -138,8 +680,173 @@ mod response {
     }
 }
 
+/// Only listed dotted sections of heartbeat payload.
+fn select_sections(payload: &serde_json::Value, sections: &[String]) -> serde_json::Value {
+    let mut selected = serde_json::json!({});
+    for section in sections {
+        let value = match payload.pointer(&format!("/{}", section.replace('.', "/"))) {
+            Some(value) => value,
+            None => continue,
+        };
+        let mut target = &mut selected;
+        for key in section.split('.') {
+            if !target.is_object() {
+                *target = serde_json::json!({});
+            }
+            target = target
+                .as_object_mut()
+                .unwrap()
+                .entry(key)
+                .or_insert(serde_json::Value::Null);
+        }
+        *target = value.clone();
+    }
+    selected
+}
+
+/// Only fields of listed dotted sections in backfill summary.
+fn select_summary(body: &mut serde_json::Value, sections: &[String]) {
+    let selected = |key: &str| {
+        sections.iter().any(|section| {
+            key == section
+                || key
+                    .strip_prefix(section.as_str())
+                    .is_some_and(|rest| rest.starts_with('.'))
+        })
+    };
+    for window in body["summary"].as_array_mut().into_iter().flatten() {
+        for statistic in ["min", "max", "avg"] {
+            if let Some(fields) = window[statistic].as_object_mut() {
+                fields.retain(|key, _| selected(key));
+            }
+        }
+    }
+}
+
+/// Remove null values, then objects and arrays left empty.
+fn compact(value: &mut serde_json::Value) {
+    let is_empty = |value: &serde_json::Value| match value {
+        serde_json::Value::Null => true,
+        serde_json::Value::Object(map) => map.is_empty(),
+        serde_json::Value::Array(array) => array.is_empty(),
+        _ => false,
+    };
+    match value {
+        serde_json::Value::Object(map) => {
+            map.values_mut().for_each(compact);
+            map.retain(|_, value| !is_empty(value));
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(compact),
+        _ => {}
+    }
+}
+
+/// Source address of outgoing requests, from `bind_address` or first address of `bind_interface`.
+fn local_address(server: &RemoteServer) -> Result<Option<IpAddr>> {
+    match (&server.bind_address, &server.bind_interface) {
+        (Some(_), Some(_)) => Err(Error::Configure {
+            field: "server.bind_interface",
+            message: "bind_address and bind_interface can not be set together".to_string(),
+        }
+        .into()),
+        (Some(address), None) => Ok(Some(address.parse()?)),
+        (None, Some(interface)) => {
+            let networks = systemstat::System::new().networks()?;
+            let network = networks.get(interface).ok_or_else(|| Error::Configure {
+                field: "server.bind_interface",
+                message: format!("Interface {} not found", interface),
+            })?;
+            let mut addresses: Vec<IpAddr> = network
+                .addrs
+                .iter()
+                .filter_map(|addr| match addr.addr {
+                    systemstat::IpAddr::V4(ip) => Some(IpAddr::V4(ip)),
+                    systemstat::IpAddr::V6(ip) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                })
+                .collect();
+            // Prefer IPv4 address
+            addresses.sort_by_key(|ip| ip.is_ipv6());
+            match addresses.first() {
+                Some(address) => {
+                    info!("Bind to {} ({})", address, interface);
+                    Ok(Some(*address))
+                }
+                None => Err(Error::Configure {
+                    field: "server.bind_interface",
+                    message: format!("Interface {} has no address", interface),
+                }
+                .into()),
+            }
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Authorization and `host_header` sent with every request.
+fn default_headers(cfg: &Configure) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    header_map.append(
+        "Authorization",
+        format!("Bearer {}", &cfg.server.token).parse()?,
+    );
+    if let Some(host) = &cfg.server.host_header {
+        header_map.insert(HOST, host.parse()?);
+    }
+    Ok(header_map)
+}
+
+/// Client builder with timeouts and proxy (SSH tunnel or Tor) applied from configure.
+pub(crate) fn client_builder(cfg: &Configure) -> Result<reqwest::ClientBuilder> {
+    crate::tor::check(cfg)?;
+    let (timeout, connect_timeout) =
+        crate::tor::timeouts(cfg).unwrap_or((Duration::from_secs(10), Duration::from_secs(5)));
+    let mut builder = reqwest::ClientBuilder::new()
+        .timeout(timeout)
+        .connect_timeout(connect_timeout)
+        .local_address(local_address(&cfg.server)?);
+    let proxy = match (crate::tunnel::proxy(cfg)?, crate::tor::proxy(cfg)?) {
+        (Some(_), Some(_)) => {
+            return Err(Error::Configure {
+                field: "tor",
+                message: "[tunnel] and [tor] can not be enabled together".to_string(),
+            }
+            .into())
+        }
+        (tunnel, tor) => tunnel.or(tor),
+    };
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(allow_list) = AllowList::from_config(cfg)? {
+        builder = builder.redirect(allow_list.redirect_policy());
+    }
+    if let Some(pool) = &cfg.server.pool {
+        if let Some(max_idle) = pool.max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = pool.idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(idle_timeout));
+        }
+    }
+    if let Some(resolver) = crate::resolver::from_config(cfg)? {
+        builder = builder.dns_resolver(resolver);
+    }
+    if let Some(identity) = crate::mtls::identity(cfg)? {
+        builder = builder.identity(identity);
+    }
+    Ok(builder)
+}
+
+/// Logical servers in failover order. Each one may have alternate URLs (e.g. CDN name and raw IP)
+/// tried in turn on transport errors before failing over to next logical server.
 pub struct ServerAddress {
     address: Vec<String>,
+    alternates: Vec<Vec<String>>,
+    /// Payload sections each logical server receives, `None` for everything
+    sections: Vec<Option<Vec<String>>>,
+    /// Index in URLs of current logical server which last succeeded
+    preferred: AtomicUsize,
     current_loc: usize,
 }
 
@@ -149,12 +856,59 @@ impl ServerAddress {
         if let Some(servers) = cfg.server.backup_servers.clone() {
             adr.append(&mut servers.clone())
         }
+        let alternates = adr
+            .iter()
+            .map(|address| {
+                cfg.server
+                    .alternates
+                    .as_ref()
+                    .and_then(|alternates| alternates.get(address))
+                    .cloned()
+                    .unwrap_or_default()
+            })
+            .collect();
+        let sections = adr
+            .iter()
+            .map(|address| {
+                cfg.server
+                    .sections
+                    .as_ref()
+                    .and_then(|sections| sections.get(address))
+                    .cloned()
+            })
+            .collect();
         Self {
             address: adr,
+            alternates,
+            sections,
+            preferred: AtomicUsize::new(0),
             current_loc: usize::MAX,
         }
     }
 
+    /// Every URL of current logical server, starting with the one last succeeded.
+    fn urls(&self) -> Vec<(usize, &String)> {
+        let urls = std::iter::once(self.get_unwrap())
+            .chain(self.alternates[self.current_loc].iter())
+            .enumerate()
+            .collect::<Vec<_>>();
+        let preferred = self.preferred.load(Ordering::Relaxed) % urls.len();
+        urls[preferred..]
+            .iter()
+            .chain(urls[..preferred].iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Payload sections current logical server receives, `None` for everything.
+    fn sections(&self) -> Option<&[String]> {
+        self.sections.get(self.current_loc)?.as_deref()
+    }
+
+    fn set_preferred(&self, index: usize) {
+        self.preferred.store(index, Ordering::Relaxed);
+    }
+
     fn get(&self) -> Option<&String> {
         if self.current_loc < self.len() {
             Some(&self.address[self.current_loc])
@@ -168,6 +922,7 @@ impl ServerAddress {
     }
 
     fn next(&mut self) -> Option<&String> {
+        self.set_preferred(0);
         if self.current_loc == usize::MAX {
             self.current_loc = 0;
         } else {
@@ -180,11 +935,24 @@ impl ServerAddress {
         self.current_loc + 1 == self.address.len()
     }
 
+    fn reset(&mut self) {
+        self.set_preferred(0);
+        self.current_loc = usize::MAX;
+    }
+
     fn len(&self) -> usize {
         self.address.len()
     }
 }
 
+/// Digest of request body attached to its response, verified against the one echoed by server.
+#[derive(Clone)]
+struct SentDigest {
+    url: String,
+    length: usize,
+    digest: String,
+}
+
 #[derive(Debug)]
 pub struct ReInitRequest;
 
@@ -221,7 +989,11 @@ impl std::fmt::Display for ExitProcessRequest {
 }
 
 impl ExitProcessRequest {
-    fn new<T: Into<String>>(status_code: i64, message: T) -> Self {
+    pub(crate) fn context(&self) -> serde_json::Value {
+        serde_json::json!({ "status": self.status_code, "message": self.message })
+    }
+
+    pub(crate) fn new<T: Into<String>>(status_code: i64, message: T) -> Self {
         Self {
             status_code,
             message: message.into(),
@@ -239,16 +1011,128 @@ impl From<&JsonResponse> for ExitProcessRequest {
     }
 }
 
+/// Identification without writing configure: `PROBE_CLIENT_UUID`,
+/// otherwise derived from machine ID, otherwise random (changes on every start).
+pub(crate) fn read_only_identification() -> String {
+    if let Ok(token) = std::env::var(IDENTIFICATION_ENV) {
+        return token;
+    }
+    let machine_id = MACHINE_ID_PATHS
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    match machine_id {
+        Some(machine_id) => {
+            // Hash machine ID, which should not be exposed as-is
+            let digest = ring::digest::digest(
+                &ring::digest::SHA256,
+                format!("probe-client:{}", machine_id).as_bytes(),
+            );
+            let mut bytes = [0u8; 16];
+            bytes.copy_from_slice(&digest.as_ref()[..16]);
+            uuid::Builder::from_bytes(bytes)
+                .set_variant(uuid::Variant::RFC4122)
+                .set_version(uuid::Version::Random)
+                .build()
+                .to_string()
+        }
+        None => {
+            let token = uuid::Uuid::new_v4().to_string();
+            warn!(
+                "No {} or machine ID in read-only mode, use temporary identification {}",
+                IDENTIFICATION_ENV, token
+            );
+            token
+        }
+    }
+}
+
 pub struct Session {
     config: Configure,
-    // TODO: client should resettable
-    client: reqwest::Client,
+    /// Configure file, read again on reload
+    path: PathBuf,
+    overrides: Overrides,
+    /// Rebuilt when client certificate is issued
+    client: RwLock<reqwest::Client>,
+    headers: HeaderMap,
+    /// Client certificate enrollment of `[server.mtls]`
+    enrollment: Option<crate::mtls::Enrollment>,
+    /// Clients presenting `sni_hostname`, if set
+    fronting: Option<Fronting>,
+    /// Persistent connection of `transport = "websocket"`
+    websocket: Option<crate::websocket::Transport>,
+    pressure: Option<Monitor>,
+    blackout: Option<Blackouts>,
+    /// Candidate server of A/B comparison
+    mirror: Option<crate::compare::Mirror>,
+    #[cfg(feature = "dashboard")]
+    board: Option<Arc<crate::dashboard::Board>>,
     server_version: String,
+    /// Experiments opted into as canary
+    experiments: Vec<String>,
     server_address: ServerAddress,
+    allow_list: Option<AllowList>,
+    interaction: Option<Interaction>,
+    collectors: Registry,
+    schema: Schema,
+    derived: Option<Derived>,
+    anomaly: Option<Detector>,
+    forecast: Option<Forecast>,
+    backlog: Mutex<Vec<Sample>>,
+    /// Disk-backed replacement of `backlog` once every server failed, if configured
+    spool: Option<Spool>,
+    /// Every server failed in the last round, until a heartbeat gets through again
+    unreachable: AtomicBool,
+    stall: Mutex<Option<Duration>>,
+    /// Failures by code since last successful heartbeat: count and last structured error.
+    failures: Mutex<BTreeMap<error::Code, (u64, serde_json::Value)>>,
+    date_offset: Mutex<Option<i64>>,
+    rejected: Mutex<BTreeSet<String>>,
+    counters: Counters,
+    /// Local and remote address of connections used so far, to tell reused connections from new ones.
+    connections: Mutex<HashSet<(SocketAddr, SocketAddr)>>,
+    /// TLS details of server last inspected, with its URL and time.
+    server_tls: Mutex<Option<(String, std::time::Instant, serde_json::Value)>>,
+    fallback: Option<Arc<crate::fallback::Monitor>>,
+    alerter: Option<crate::alert::Alerter>,
+    seq: AtomicU64,
+    nonce: NonceStore,
+    clock_offset: AtomicI64,
+    signer: Option<Signer>,
+    boot_id: String,
+    actions: Dispatcher,
+    authz: Option<Authorizer>,
+    audit: AuditLog,
+    #[cfg(feature = "policy")]
+    policy: Option<crate::policy::Policy>,
+}
+
+/// Command line values taking precedence over configure and environment for ad-hoc runs,
+/// never written back.
+#[derive(Clone, Default)]
+pub struct Overrides {
+    pub server: Option<String>,
+    pub interval: Option<std::num::NonZeroU32>,
+}
+
+impl Overrides {
+    pub(crate) fn apply(&self, config: &mut Configure) {
+        if let Some(server) = &self.server {
+            warn!("Override server address to {} from command line", server);
+            config.server.server_address = server.clone();
+            // Never fail over from an ad-hoc server to the configured ones
+            config.server.backup_servers = None;
+        }
+        if let Some(interval) = self.interval {
+            warn!("Override interval to {}s from command line", interval);
+            config.server.interval = Some(interval.get());
+        }
+    }
 }
 
 impl Session {
-    pub async fn new<P: AsRef<Path>>(path: P) -> Result<Session> {
+    pub async fn new<P: AsRef<Path>>(path: P, overrides: &Overrides) -> Result<Session> {
         let path = path.as_ref();
         let contents = match tokio::fs::read_to_string(path).await {
             Ok(contents) => contents,
@@ -259,48 +1143,304 @@ impl Session {
         };
         let contents_str = contents.as_str();
 
-        let mut config: Configure = toml::from_str(contents_str)?;
-
-        let mut header_map = HeaderMap::new();
+        let (mut config, _) = crate::profile::apply(contents_str)?;
 
         if config.identification.is_none() {
-            config.identification = Some(Identification {
-                token: uuid::Uuid::new_v4().to_string(),
-            });
-            info!(
-                "Generate new uuid identification token: {}",
-                config.identification.clone().unwrap().token
-            );
-            tokio::fs::write(&path, toml::to_string(&config)?).await?;
+            if crate::state::is_read_only() {
+                config.identification = Some(Identification {
+                    token: read_only_identification(),
+                });
+            } else {
+                config.identification = Some(Identification {
+                    token: uuid::Uuid::new_v4().to_string(),
+                });
+                info!(
+                    "Generate new uuid identification token: {}",
+                    config.identification.clone().unwrap().token
+                );
+                // Without options of server profile, through `Value`, which writes plain values
+                // ahead of tables whatever field order
+                let mut file: Configure = toml::from_str(contents_str)?;
+                file.identification = config.identification.clone();
+                tokio::fs::write(&path, toml::to_string(&toml::Value::try_from(&file)?)?).await?;
+            }
         }
+        crate::environment::apply(&mut config)?;
+        overrides.apply(&mut config);
 
-        header_map.append(
-            "Authorization",
-            format!("Bearer {}", &config.server.token).parse()?,
-        );
-
-        let client = reqwest::ClientBuilder::new()
-            .default_headers(header_map)
-            .redirect(reqwest::redirect::Policy::default())
-            .timeout(Duration::from_secs(10))
-            .connect_timeout(Duration::from_secs(5))
+        let header_map = default_headers(&config)?;
+        let client = client_builder(&config)?
+            .default_headers(header_map.clone())
             .build()?;
+        if config.server.server_address == AUTO_ADDRESS {
+            config.server.server_address = Self::discover_server(&config).await?;
+        }
+        let allow_list = AllowList::from_config(&config)?;
+        if let Some(allow_list) = &allow_list {
+            allow_list.check_config(&config)?;
+        }
         let server_address = ServerAddress::new(&config);
+        let fronting = Fronting::new(
+            &config,
+            server_address
+                .address
+                .iter()
+                .chain(server_address.alternates.iter().flatten()),
+            |builder| builder.default_headers(header_map.clone()).build(),
+        )?;
+        let collectors = Registry::new(&config);
+        let schema = Schema::new(&collectors);
+        let derived = match &config.derived {
+            Some(definitions) => Some(Derived::new(definitions)?),
+            None => None,
+        };
+        let boot_id = crate::boot::boot_id(
+            systemstat::System::new()
+                .boot_time()
+                .map(|boot_time| boot_time.timestamp())
+                .unwrap_or_default(),
+        );
+        let actions = Dispatcher::new(&config)?;
+        let authz = match &config.authz {
+            Some(authz) => Some(Authorizer::new(
+                authz,
+                &config.identification.as_ref().unwrap().token,
+            )?),
+            None => None,
+        };
+        let audit = AuditLog::open(
+            match config.audit.as_ref().and_then(|audit| audit.path.as_ref()) {
+                Some(path) => PathBuf::from(path),
+                None => crate::state::path(audit::DEFAULT_AUDIT_FILE),
+            },
+        );
+        let anomaly = config.anomaly.as_ref().map(Detector::new);
+        let forecast = config.forecast.as_ref().map(Forecast::new);
+        let pressure = Monitor::new(&config);
+        let enrollment = crate::mtls::Enrollment::new(&config)?;
+        let websocket = crate::websocket::Transport::new(&config)?;
+        let blackout = Blackouts::new(&config)?;
+        let spool = Spool::new(&config);
+        let mirror = crate::compare::Mirror::new(&config)?;
+        #[cfg(feature = "dashboard")]
+        let board = crate::dashboard::Board::new(&config);
+        let fallback = config.fallback.as_ref().map(|_| Default::default());
+        let alerter = match &config.alerting {
+            Some(alerting) => Some(crate::alert::Alerter::new(&config, alerting)?),
+            None => None,
+        };
+        let signer = match &config.signing {
+            Some(signing) => Some(Signer::new(signing).await?),
+            None => None,
+        };
+        #[cfg(feature = "policy")]
+        let policy = match &config.policy {
+            Some(policy) => Some(crate::policy::Policy::load(&policy.module, policy.fuel)?),
+            None => None,
+        };
 
         Ok(Session {
             config,
-            client,
+            path: path.to_path_buf(),
+            overrides: overrides.clone(),
+            client: RwLock::new(client),
+            headers: header_map,
+            enrollment,
             server_version: "".to_string(),
+            experiments: Vec::new(),
             server_address,
+            fronting,
+            websocket,
+            pressure,
+            blackout,
+            mirror,
+            #[cfg(feature = "dashboard")]
+            board,
+            allow_list,
+            interaction: None,
+            collectors,
+            schema,
+            derived,
+            anomaly,
+            forecast,
+            backlog: Default::default(),
+            spool,
+            unreachable: Default::default(),
+            stall: Default::default(),
+            failures: Default::default(),
+            date_offset: Default::default(),
+            rejected: Default::default(),
+            counters: Counters::open(),
+            connections: Default::default(),
+            server_tls: Default::default(),
+            fallback,
+            alerter,
+            seq: AtomicU64::new(0),
+            nonce: NonceStore::load(crate::state::path(nonce::DEFAULT_NONCE_FILE)),
+            clock_offset: AtomicI64::new(0),
+            signer,
+            boot_id,
+            actions,
+            authz,
+            audit,
+            #[cfg(feature = "policy")]
+            policy,
         })
     }
 
-    pub async fn post(&self, data: &HashMap<String, String>) -> Result<reqwest::Response> {
-        self.post_data_to_url(self.server_address.get_unwrap(), data)
-            .await
+    /// Read `[server]` of configure file again and reconnect with it, e.g. rotated token
+    /// or changed server addresses. Current one is kept if invalid.
+    /// Other sections keep state built on startup, they take effect on restart.
+    pub async fn reload(&mut self) -> Result<()> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let (mut reloaded, _) = crate::profile::apply(&contents)?;
+        crate::environment::apply(&mut reloaded)?;
+        self.overrides.apply(&mut reloaded);
+        let previous = std::mem::replace(&mut self.config.server, reloaded.server);
+        if let Err(e) = self.reconnect().await {
+            self.config.server = previous;
+            return Err(e);
+        }
+        info!("Reloaded configure from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Rebuild clients and server list from `[server]`, starting over from the first server.
+    async fn reconnect(&mut self) -> Result<()> {
+        let header_map = default_headers(&self.config)?;
+        let client = client_builder(&self.config)?
+            .default_headers(header_map.clone())
+            .build()?;
+        if self.config.server.server_address == AUTO_ADDRESS {
+            self.config.server.server_address = Self::discover_server(&self.config).await?;
+        }
+        let allow_list = AllowList::from_config(&self.config)?;
+        if let Some(allow_list) = &allow_list {
+            allow_list.check_config(&self.config)?;
+        }
+        let server_address = ServerAddress::new(&self.config);
+        let fronting = Fronting::new(
+            &self.config,
+            server_address
+                .address
+                .iter()
+                .chain(server_address.alternates.iter().flatten()),
+            |builder| builder.default_headers(header_map.clone()).build(),
+        )?;
+        let enrollment = crate::mtls::Enrollment::new(&self.config)?;
+        let websocket = crate::websocket::Transport::new(&self.config)?;
+        *self.client.write().unwrap() = client;
+        self.headers = header_map;
+        self.allow_list = allow_list;
+        self.server_address = server_address;
+        self.fronting = fronting;
+        self.enrollment = enrollment;
+        self.websocket = websocket;
+        Ok(())
+    }
+
+    #[cfg(feature = "mdns")]
+    async fn discover_server(config: &Configure) -> Result<String> {
+        let timeout = config
+            .server
+            .discovery_timeout
+            .unwrap_or(crate::discovery::DEFAULT_DISCOVERY_TIMEOUT);
+        crate::discovery::discover(Duration::from_secs(timeout)).await
+    }
+
+    #[cfg(not(feature = "mdns"))]
+    async fn discover_server(_config: &Configure) -> Result<String> {
+        Err(Error::Configure {
+            field: "server.server_address",
+            message: format!(
+                "server_address = \"{}\" requires mdns feature enabled",
+                AUTO_ADDRESS
+            ),
+        }
+        .into())
+    }
+
+    pub fn boot_id(&self) -> &str {
+        &self.boot_id
+    }
+
+    /// Identification and configure path a process taking over must have.
+    pub fn handover_identity(&self) -> crate::handover::Identity {
+        crate::handover::Identity {
+            uuid: self.config.identification.as_ref().unwrap().token.clone(),
+            config: std::fs::canonicalize(&self.path)
+                .unwrap_or_else(|_| self.path.clone())
+                .display()
+                .to_string(),
+        }
+    }
+
+    /// Snapshot of session state for the process taking over.
+    pub fn handover_state(&self) -> HandoverState {
+        HandoverState {
+            version: CLIENT_VERSION.to_string(),
+            seq: self.seq.load(Ordering::SeqCst),
+            clock_offset: self.clock_offset.load(Ordering::Relaxed),
+            server_version: self.server_version.clone(),
+            experiments: self.experiments.clone(),
+            backlog: self.backlog.lock().unwrap().clone(),
+        }
+    }
+
+    /// Continue session handed over by previous process.
+    pub fn restore(&mut self, state: HandoverState) {
+        self.seq.store(state.seq, Ordering::SeqCst);
+        self.clock_offset
+            .store(state.clock_offset, Ordering::Relaxed);
+        self.server_version = state.server_version;
+        self.experiments = state.experiments;
+        *self.backlog.lock().unwrap() = state.backlog;
+    }
+
+    pub fn get_config(&self) -> &Configure {
+        &self.config
+    }
+
+    pub fn set_interaction(&mut self, interaction: Interaction) {
+        self.interaction = Some(interaction);
+    }
+
+    pub async fn post<T: serde::Serialize>(&self, data: &T) -> Result<reqwest::Response> {
+        self.post_with_timeout(data, None).await
+    }
+
+    /// Post to current server, overriding timeout of client if `timeout` is set.
+    async fn post_with_timeout<T: serde::Serialize>(
+        &self,
+        data: &T,
+        timeout: Option<Duration>,
+    ) -> Result<reqwest::Response> {
+        let urls = self.server_address.urls();
+        let mut last_error = None;
+        for (index, url) in &urls {
+            match self.post_data_to_url(url, data, timeout).await {
+                Ok(r) => {
+                    if last_error.is_some() {
+                        info!("Switch to alternate address {}", url);
+                        self.server_address.set_preferred(*index);
+                    }
+                    return Ok(r);
+                }
+                Err(e) => {
+                    if urls.len() > 1 {
+                        warn!("Unable reach {}: {}", url, e);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap())
     }
 
     pub fn call_next(&mut self) -> Option<&String> {
+        if self.server_address.get().is_some() {
+            self.counters.add(Counter::Failovers, 1);
+        }
         self.server_address.next()
     }
 
@@ -308,72 +1448,869 @@ impl Session {
         self.server_address.check_is_last()
     }
 
-    async fn post_data_to_url(
+    pub fn fallback_monitor(&self) -> Option<Arc<crate::fallback::Monitor>> {
+        self.fallback.clone()
+    }
+
+    /// Start over from the first server.
+    pub fn reset_server(&mut self) {
+        self.server_address.reset()
+    }
+
+    /// Wait between rounds over all servers once every server failed, `None` if disabled.
+    pub fn degraded_interval(&self) -> Option<Duration> {
+        if self.config.server.degraded.unwrap_or(DEFAULT_DEGRADED) {
+            Some(Duration::from_secs(
+                self.config
+                    .server
+                    .degraded_interval
+                    .unwrap_or(DEFAULT_DEGRADED_INTERVAL),
+            ))
+        } else {
+            None
+        }
+    }
+
+    async fn post_data_to_url<T: serde::Serialize>(
         &self,
         url: &str,
-        data: &HashMap<String, String>,
+        data: &T,
+        timeout: Option<Duration>,
     ) -> Result<reqwest::Response> {
-        return match self.client.post(url).json(data).send().await {
-            Ok(r) => Ok(r),
-            Err(e) if e.is_timeout() => Err(TimeoutError::new(anyhow::Error::new(e))),
-            Err(e) => Err(anyhow::Error::from(e)),
+        if let Some(interaction) = &self.interaction {
+            if interaction.is_replay() {
+                return interaction.next().await?.into_response();
+            }
+        }
+        if let Some(allow_list) = &self.allow_list {
+            allow_list.check(url)?;
+        }
+        // Serialize directly into request body, without intermediate string
+        let mut buffer: Vec<u8> = Vec::with_capacity(4096);
+        serde_json::to_writer(&mut buffer, data)?;
+        let buffer = bytes::Bytes::from(buffer);
+        let sent = SentDigest {
+            url: url.to_string(),
+            length: buffer.len(),
+            digest: crate::signing::sha256_hex(&buffer),
+        };
+        let (client, target) = match self
+            .fronting
+            .as_ref()
+            .and_then(|fronting| fronting.route(url))
+        {
+            Some((client, url)) => (client.clone(), url),
+            None => (self.client.read().unwrap().clone(), url.to_string()),
         };
+        let mirrored = self
+            .mirror
+            .as_ref()
+            .map(|mirror| mirror.send(buffer.clone()));
+        let sent_at = chrono::Utc::now().timestamp_millis();
+        let result = match &self.websocket {
+            Some(websocket) => websocket
+                .send(&client, &target, buffer.clone(), timeout)
+                .await
+                .inspect(|_| self.counters.add(Counter::BytesSent, buffer.len() as u64)),
+            None => {
+                let mut request = client.post(target).header(CONTENT_TYPE, "application/json");
+                if let Some(timeout) = timeout {
+                    request = request.timeout(timeout);
+                }
+                match request.body(buffer.clone()).send().await {
+                    Ok(r) => {
+                        self.counters.add(Counter::BytesSent, buffer.len() as u64);
+                        self.count_connection(&r);
+                        self.measure_date_offset(&r, sent_at);
+                        Ok(r)
+                    }
+                    Err(e) if e.is_timeout() => Err(Error::Timeout {
+                        error: anyhow::Error::new(e),
+                    }
+                    .into()),
+                    Err(e) => Err(anyhow::Error::from(e)),
+                }
+            }
+        };
+        let result = match &self.interaction {
+            Some(interaction) => {
+                let exchange = match result {
+                    Ok(r) => {
+                        let status = r.status().as_u16();
+                        Exchange::new_response(url, &buffer, status, r.text().await?)
+                    }
+                    Err(ref e) => Exchange::new_error(url, &buffer, e),
+                };
+                interaction.save(&exchange).await?;
+                exchange.into_response()
+            }
+            None => result,
+        };
+        result.map(|mut r| {
+            r.extensions_mut().insert(sent);
+            if let Some(mirrored) = mirrored {
+                r.extensions_mut().insert(mirrored);
+            }
+            r
+        })
     }
 
-    pub async fn send_data(&self, action: &str, body: Option<String>) -> Result<reqwest::Response> {
-        let mut data: HashMap<String, String> = Default::default();
-        for item in [
-            ("version", CLIENT_VERSION),
-            ("action", action),
-            ("uuid", &self.config.identification.as_ref().unwrap().token),
-        ]
-        .iter()
+    /// Negotiated TLS version, cipher and certificate expiry of current server, inspected with a
+    /// separate handshake at most every `SERVER_TLS_REFRESH` seconds. `None` for plain HTTP, and
+    /// when requests go through Tor or tunnel, which a direct handshake would bypass.
+    async fn server_tls(&self) -> Option<serde_json::Value> {
+        if self.config.tor.is_some()
+            || self.config.tunnel.is_some()
+            || self
+                .interaction
+                .as_ref()
+                .is_some_and(Interaction::is_replay)
         {
-            data.insert((*item.0).to_string(), (*item.1).to_string());
+            return None;
         }
-        if body.is_some() {
-            data.insert("body".to_string(), body.unwrap());
+        let url = reqwest::Url::parse(self.server_address.urls().first()?.1).ok()?;
+        if url.scheme() != "https" {
+            return None;
         }
-        self.post(&data).await
+        if let Some((cached, inspected, value)) = &*self.server_tls.lock().unwrap() {
+            if cached == url.as_str()
+                && inspected.elapsed() < Duration::from_secs(SERVER_TLS_REFRESH)
+            {
+                return Some(value.clone());
+            }
+        }
+        let endpoint = format!("{}:{}", url.host_str()?, url.port_or_known_default()?);
+        let handshake = match &self.fronting {
+            Some(fronting) => {
+                crate::certificate::handshake_with_sni(&endpoint, fronting.sni_hostname()).await
+            }
+            None => crate::certificate::handshake(&endpoint).await,
+        };
+        let value = match handshake {
+            Ok(handshake) => {
+                let not_after = crate::certificate::not_after(&handshake.certificate).ok();
+                let days = not_after
+                    .map(|not_after| (not_after - chrono::Utc::now().timestamp()) as f64 / 86400.0);
+                info!(
+                    "Server {} negotiated {} {}, certificate expires in {:.1} days",
+                    endpoint,
+                    handshake.version,
+                    handshake.cipher,
+                    days.unwrap_or(f64::NAN)
+                );
+                if days.is_some_and(|days| days < SERVER_TLS_EXPIRY_WARNING) {
+                    warn!("Certificate of server {} is near expiry", endpoint);
+                }
+                serde_json::json!({
+                    "version": handshake.version,
+                    "cipher": handshake.cipher,
+                    "not_after": not_after,
+                    "days_until_expiry": days,
+                })
+            }
+            Err(e) => {
+                warn!("Unable inspect TLS of server {}: {}", endpoint, e);
+                serde_json::json!({ "error": e.to_string() })
+            }
+        };
+        *self.server_tls.lock().unwrap() =
+            Some((url.to_string(), std::time::Instant::now(), value.clone()));
+        Some(value)
     }
 
-    pub async fn init_connection(&mut self) -> Result<()> {
+    fn count_connection(&self, response: &reqwest::Response) {
+        let info = match response
+            .extensions()
+            .get::<hyper::client::connect::HttpInfo>()
+        {
+            Some(info) => info,
+            None => return,
+        };
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() >= MAX_TRACKED_CONNECTIONS {
+            connections.clear();
+        }
+        if connections.insert((info.local_addr(), info.remote_addr())) {
+            self.counters.add(Counter::ConnectionsNew, 1);
+        } else {
+            self.counters.add(Counter::ConnectionsReused, 1);
+        }
+    }
+
+    /// Compare `Date` header with the midpoint of request round trip. Header has second
+    /// resolution, so server time is taken as the middle of that second.
+    fn measure_date_offset(&self, response: &reqwest::Response, sent_at: i64) {
+        let date = match response
+            .headers()
+            .get(reqwest::header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+        {
+            Some(date) => date.timestamp_millis() + 500,
+            None => return,
+        };
+        let midpoint = (sent_at + chrono::Utc::now().timestamp_millis()) / 2;
+        *self.date_offset.lock().unwrap() = Some(date - midpoint);
+    }
+
+    /// Wrap `body` for sending, fails if a signer is configured but unable sign it: a probe
+    /// with broken key must not look like one with signing disabled.
+    pub async fn envelope(
+        &self,
+        action: &str,
+        mut body: serde_json::Value,
+    ) -> Result<RequestEnvelope> {
+        if let Some(sections) = self.server_address.sections() {
+            match action {
+                "heartbeat" => {
+                    let collected_at = body.get(COLLECTED_AT).cloned();
+                    body = select_sections(&body, sections);
+                    if let Some(collected_at) = collected_at {
+                        body[COLLECTED_AT] = collected_at;
+                    }
+                }
+                "backfill" => select_summary(&mut body, sections),
+                _ => {}
+            }
+        }
+        let timestamp = chrono::Utc::now().timestamp() + self.clock_offset.load(Ordering::Relaxed);
+        let nonce = self.nonce.next();
+        let uuid = self.config.identification.as_ref().unwrap().token.clone();
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let manifest = match &self.signer {
+            Some(signer) => Some(signer.sign(action, &uuid, timestamp, nonce, &body).await?),
+            None => None,
+        };
+        Ok(RequestEnvelope {
+            version: CLIENT_VERSION.to_string(),
+            action: action.to_string(),
+            seq,
+            timestamp,
+            nonce,
+            boot_id: Some(self.boot_id.clone()),
+            manifest,
+            uuid,
+            body,
+            sent_at: Some(chrono::Utc::now().timestamp_millis()),
+            clock_offset: *self.date_offset.lock().unwrap(),
+            canary: self.is_canary().then_some(true),
+            experiments: self.experiments.clone(),
+        })
+    }
+
+    pub async fn send_data(
+        &self,
+        action: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<reqwest::Response> {
+        let envelope = self.envelope(action, body.unwrap_or_default()).await?;
+        self.post(&envelope).await
+    }
+
+    /// Send event which does not expect anything in response other than status.
+    pub async fn send_event(&self, action: &str, body: serde_json::Value) -> Result<()> {
+        let resp = self.send_data(action, Some(body)).await?;
+        self.check_response(resp).await.map(|_| ())
+    }
+
+    /// Keepalive between heartbeats, carries no statistics.
+    pub async fn send_ping(&self) -> Result<()> {
+        self.send_event("ping", serde_json::json!({})).await
+    }
+
+    /// Send registration with current addressing, without reset session state.
+    pub async fn register(&self) -> Result<JsonResponse> {
         let system = systemstat::System::new();
 
         let data = RegisterData {
             boot_time: system.boot_time().unwrap().timestamp(),
             hostname: gethostname::gethostname().to_str().unwrap().to_string(),
+            address: crate::roaming::primary_address().map(|address| address.to_string()),
+            public_key: self.signer.as_ref().map(Signer::public_key),
+            public_key_algorithm: self
+                .signer
+                .as_ref()
+                .map(|signer| signer.algorithm().to_string()),
+            units: self.schema.annotations(),
+            virtualization: crate::virt::detect(),
+            capabilities: capabilities::detect(),
+            facts: crate::facts::load(),
         };
 
-        let resp = self
-            .send_data("register", Some(serde_json::to_string(&data)?))
-            .await?;
-        let rep = self.check_response(resp).await?;
-        if let Some(v) = self.config.server.check_server_version {
-            if v {
-                self.server_version = rep.get_server_version().clone();
+        let mut data = serde_json::to_value(&data)?;
+        self.privacy().redact_register(
+            &mut data,
+            &self.config.identification.as_ref().unwrap().token,
+        );
+        if let Some(enrollment) = &self.enrollment {
+            let token = &self.config.identification.as_ref().unwrap().token;
+            if let Some(csr) = enrollment.request(token, false)? {
+                data["csr"] = serde_json::Value::from(csr);
             }
         }
+        let resp = self.send_data("register", Some(data)).await?;
+        let rep = self.check_response(resp).await?;
+        self.accept_certificate(&rep).await?;
+        Ok(rep)
+    }
+
+    /// Use client certificate issued by server from now on.
+    async fn accept_certificate(&self, rep: &JsonResponse) -> Result<()> {
+        let (enrollment, certificate) = match (&self.enrollment, rep.get_certificate()) {
+            (Some(enrollment), Some(certificate)) => (enrollment, certificate),
+            _ => return Ok(()),
+        };
+        let identity = enrollment.complete(certificate)?;
+        *self.client.write().unwrap() = client_builder(&self.config)?
+            .identity(identity)
+            .default_headers(self.headers.clone())
+            .build()?;
+        if let Some(websocket) = &self.websocket {
+            websocket.reset().await;
+        }
         Ok(())
     }
 
-    pub async fn send_heartbeat(&self) -> Result<()> {
+    /// Request new client certificate once current one is due for renewal.
+    async fn renew_certificate(&self) -> Result<()> {
+        let enrollment = match &self.enrollment {
+            Some(enrollment) => enrollment,
+            None => return Ok(()),
+        };
+        let token = &self.config.identification.as_ref().unwrap().token;
+        let csr = match enrollment.request(token, true)? {
+            Some(csr) => csr,
+            None => return Ok(()),
+        };
+        info!("Client certificate due for renewal, send certificate request");
         let resp = self
             .send_data(
-                "heartbeat",
-                if self.config.statistics.enabled {
-                    Some(crate::info::get_base_info().await.to_string())
-                } else {
-                    None
-                },
+                crate::mtls::RENEW_EVENT,
+                Some(serde_json::json!({ "csr": csr })),
             )
             .await?;
-        self.check_response(resp).await?;
+        let rep = self.check_response(resp).await?;
+        if rep.get_certificate().is_none() {
+            warn!("Server issued no client certificate, retry later");
+        }
+        self.accept_certificate(&rep).await
+    }
+
+    pub async fn init_connection(&mut self) -> Result<()> {
+        let rep = self.register().await?;
+        if self
+            .config
+            .server
+            .check_server_version
+            .unwrap_or(DEFAULT_CHECK_SERVER_VERSION)
+        {
+            self.server_version = rep.get_server_version().clone();
+        }
+        if self.is_canary() {
+            self.opt_into(rep.get_experiments());
+        }
         Ok(())
     }
 
-    async fn check_response(&self, response: reqwest::Response) -> Result<JsonResponse> {
-        let j: JsonResponse = response.json().await?;
+    fn is_canary(&self) -> bool {
+        self.config.server.canary.unwrap_or(DEFAULT_CANARY)
+    }
+
+    /// Take experiments advertised by server which this client supports.
+    fn opt_into(&mut self, advertised: &[String]) {
+        let experiments: Vec<String> = advertised
+            .iter()
+            .filter(|experiment| capabilities::EXPERIMENTS.contains(&experiment.as_str()))
+            .cloned()
+            .collect();
+        if experiments != self.experiments {
+            info!("Canary: opt into experiments {:?}", experiments);
+        }
+        for experiment in advertised {
+            if !experiments.contains(experiment) {
+                warn!("Canary: unsupported experiment {} ignored", experiment);
+            }
+        }
+        self.experiments = experiments;
+    }
+
+    fn has_experiment(&self, experiment: &str) -> bool {
+        self.experiments.iter().any(|e| e == experiment)
+    }
+
+    async fn build_payload(&self) -> Result<serde_json::Value> {
+        let mut info = crate::info::get_base_info().await;
+        info.set_collectors(self.collectors.collect().await);
+        let mut payload = serde_json::to_value(&info)?;
+        self.schema.normalize(&mut payload);
+        if let Some(forecast) = &self.forecast {
+            forecast.annotate(&mut payload);
+        }
+        if let Some(derived) = &self.derived {
+            let values = derived.evaluate(&payload);
+            if !values.is_empty() {
+                payload["derived"] = serde_json::to_value(values)?;
+            }
+        }
+        if let Some(anomaly) = &self.anomaly {
+            let findings = anomaly.evaluate(&payload);
+            if !findings.is_empty() {
+                payload["anomalies"] = serde_json::to_value(findings)?;
+            }
+        }
+        #[cfg(feature = "policy")]
+        if let Some(policy) = &self.policy {
+            match policy.evaluate(&payload) {
+                Ok(alerts) if !alerts.is_empty() => {
+                    payload["alerts"] = serde_json::to_value(alerts)?
+                }
+                Ok(_) => {}
+                Err(e) => error!("Got error in evaluate policy: {:?}", e),
+            }
+        }
+        if let Some(alerter) = &self.alerter {
+            alerter.notify(alerter.evaluate(&payload));
+        }
+        self.privacy().redact_payload(
+            &mut payload,
+            &self.config.identification.as_ref().unwrap().token,
+        );
+        self.drop_rejected(&mut payload);
+        if self.has_experiment("compact_payload") {
+            compact(&mut payload);
+        }
+        Ok(payload)
+    }
+
+    pub async fn send_heartbeat(&self) -> Result<()> {
+        let result = self.post_heartbeat().await;
+        crate::status::record(self.server_address.get().map(String::as_str), &result);
+        #[cfg(feature = "dashboard")]
+        if let Some(board) = &self.board {
+            board.record(&result);
+        }
+        result
+    }
+
+    #[cfg(feature = "dashboard")]
+    pub fn board(&self) -> Option<Arc<crate::dashboard::Board>> {
+        self.board.clone()
+    }
+
+    async fn post_heartbeat(&self) -> Result<()> {
+        let maintenance = match self.blackout.as_ref().and_then(Blackouts::check) {
+            // Silent on purpose, not unable to report
+            Some((blackout::Mode::Suppress, _)) => {
+                if let Some(monitor) = &self.fallback {
+                    monitor.succeeded();
+                }
+                return Ok(());
+            }
+            Some((blackout::Mode::Tag, tag)) => Some(tag),
+            None => None,
+        };
+        let degraded = self.pressure.as_ref().and_then(Monitor::check);
+        let collect = self.config.statistics.enabled && degraded.is_none();
+        let payload = if collect {
+            Some(self.build_payload().await?)
+        } else {
+            None
+        };
+        let mut payload = payload.unwrap_or_default();
+        let timeout = match &degraded {
+            Some(tag) => {
+                payload["degraded"] = tag.clone();
+                self.pressure.as_ref().map(Monitor::timeout)
+            }
+            None => None,
+        };
+        if let Some(tag) = maintenance {
+            payload["maintenance"] = tag;
+        }
+        if let Some(stall) = self.stall.lock().unwrap().take() {
+            payload["stall"] = serde_json::json!({ "seconds": stall.as_secs() });
+        }
+        let annotations = crate::annotate::take();
+        if !annotations.is_empty() {
+            payload[crate::annotate::PAYLOAD_KEY] = serde_json::Value::from(annotations.clone());
+        }
+        payload["self_metrics"] = serde_json::to_value(self.counters.snapshot())?;
+        if let Some(failures) = self.failures_summary() {
+            payload["self_metrics"]["errors"] = failures;
+        }
+        if degraded.is_none() {
+            if let Some(tls) = self.server_tls().await {
+                payload["self_metrics"]["server_tls"] = tls;
+            }
+        }
+        #[cfg(feature = "dashboard")]
+        if let Some(board) = &self.board {
+            board.update(&payload);
+        }
+        // Kept unfiltered, another server may take different sections
+        let unsent = collect.then(|| payload.clone());
+        let delivered = match self.envelope("heartbeat", payload).await {
+            Ok(envelope) => self.deliver_heartbeat(envelope, unsent, timeout).await,
+            Err(e) => Err(e),
+        };
+        let mut rep = match delivered {
+            Ok(rep) => rep,
+            Err(e) => {
+                crate::annotate::restore(annotations);
+                return Err(e);
+            }
+        };
+        self.counters.add(Counter::HeartbeatsSent, 1);
+        self.failures.lock().unwrap().clear();
+        self.unreachable.store(false, Ordering::Relaxed);
+        if let Err(e) = self.renew_certificate().await {
+            warn!("Unable renew client certificate: {:?}", e);
+        }
+        self.run_actions(rep.take_actions()).await;
+        for (action, body) in self.collectors.events() {
+            if let Err(e) = self.send_event(&action, body).await {
+                warn!("Unable send {} event: {:?}", action, e);
+            }
+        }
+        // Backlog waits until pressure is relieved
+        if degraded.is_none() {
+            self.send_backfill().await;
+            self.replay_spool().await;
+        }
+        Ok(())
+    }
+
+    /// Post heartbeat, resending it without sections rejected by server.
+    /// Collected statistics `unsent` are kept for backfill if server is unreachable or in maintenance.
+    async fn deliver_heartbeat(
+        &self,
+        envelope: RequestEnvelope,
+        unsent: Option<serde_json::Value>,
+        timeout: Option<Duration>,
+    ) -> Result<JsonResponse> {
+        // Collection time of the heartbeat, even if it is sent again
+        let timestamp = envelope.timestamp;
+        let mut envelope = envelope;
+        let mut resent = false;
+        loop {
+            let resp = match self.post_with_timeout(&envelope, timeout).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    if let Some(payload) = unsent {
+                        self.keep_unsent(timestamp, payload);
+                    }
+                    return Err(e);
+                }
+            };
+            match self.check_response(resp).await {
+                Err(e)
+                    if !resent && matches!(error::of(&e), Some(Error::PayloadRejected { .. })) =>
+                {
+                    warn!("{}, resend without them", e);
+                    let mut body = envelope.body;
+                    self.drop_rejected(&mut body);
+                    envelope = match self.envelope("heartbeat", body).await {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            if let Some(payload) = unsent {
+                                self.keep_unsent(timestamp, payload);
+                            }
+                            return Err(e);
+                        }
+                    };
+                    resent = true;
+                }
+                // Server did not take the heartbeat, keep it for backfill after maintenance
+                Err(e) if matches!(error::of(&e), Some(Error::Maintenance { .. })) => {
+                    if let Some(payload) = unsent {
+                        self.keep_unsent(timestamp, payload);
+                    }
+                    return Err(e);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Leave out payload sections refused by server from now on.
+    fn reject_sections(&self, sections: Vec<String>) {
+        let mut rejected = self.rejected.lock().unwrap();
+        for section in sections {
+            warn!(
+                "Server rejected payload section {}, drop it from now on",
+                section
+            );
+            rejected.insert(section);
+        }
+    }
+
+    fn drop_rejected(&self, payload: &mut serde_json::Value) {
+        for section in self.rejected.lock().unwrap().iter() {
+            let (parent, key) = match section.rsplit_once('.') {
+                Some((parent, key)) => (
+                    parent
+                        .split('.')
+                        .try_fold(&mut *payload, |value, key| value.get_mut(key)),
+                    key,
+                ),
+                None => (Some(&mut *payload), section.as_str()),
+            };
+            if let Some(map) = parent.and_then(serde_json::Value::as_object_mut) {
+                map.remove(key);
+            }
+        }
+    }
+
+    /// Count failure by its code, reported in next successful heartbeat. Maintenance is not
+    /// a failure, server answered as planned.
+    pub fn note_failure(&self, e: &anyhow::Error) {
+        if matches!(error::of(e), Some(Error::Maintenance { .. })) {
+            return;
+        }
+        let structured = error::structured(e);
+        let mut failures = self.failures.lock().unwrap();
+        let entry = failures
+            .entry(error::code(e))
+            .or_insert((0, serde_json::Value::Null));
+        entry.0 += 1;
+        entry.1 = structured;
+    }
+
+    fn failures_summary(&self) -> Option<serde_json::Value> {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return None;
+        }
+        Some(
+            failures
+                .iter()
+                .map(|(code, (count, last))| {
+                    serde_json::json!({
+                        "code": code.number(),
+                        "kind": code.kind(),
+                        "count": count,
+                        "last": last,
+                    })
+                })
+                .collect(),
+        )
+    }
+
+    /// Report `duration` the client was stalled beyond schedule in next heartbeat.
+    pub fn note_stall(&self, duration: Duration) {
+        *self.stall.lock().unwrap() = Some(duration);
+    }
+
+    /// Run actions requested by server, report each result back.
+    async fn run_actions(&self, actions: Vec<ServerAction>) {
+        for request in actions {
+            let authorized = authz::authorize(self.authz.as_ref(), &request);
+            let (authorization, (action, body)) = match authorized {
+                Ok(approved) => (
+                    if approved { "approved" } else { "not_required" }.to_string(),
+                    self.actions.dispatch(&request).await,
+                ),
+                Err(e) => {
+                    warn!("Refuse server action {}: {}", request.action, e);
+                    (
+                        format!("rejected: {}", e),
+                        Dispatcher::refuse(&request, &e.to_string()),
+                    )
+                }
+            };
+            self.audit.record(&request, &authorization, &body);
+            let result = match self.send_data(&action, Some(body)).await {
+                Ok(resp) => self.check_response(resp).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("Unable report result of action {}: {:?}", request.action, e);
+            }
+        }
+    }
+
+    /// Run actions server pushes over WebSocket transport as they arrive, never returns.
+    pub async fn serve_pushed(&self) {
+        let websocket = match &self.websocket {
+            Some(websocket) => websocket,
+            None => return std::future::pending().await,
+        };
+        loop {
+            let push = websocket.pushed().await;
+            match serde_json::from_value::<response::Push>(push) {
+                Ok(push) => self.run_actions(push.actions).await,
+                Err(e) => warn!("Ignore invalid message pushed by server: {}", e),
+            }
+        }
+    }
+
+    /// Heartbeats still waiting in backlog and counters, for shutdown report.
+    pub fn unsent_summary(&self) -> serde_json::Value {
+        let backlog = self.backlog.lock().unwrap();
+        serde_json::json!({
+            "unsent": {
+                "heartbeats": backlog.len(),
+                "first": backlog.first().map(Sample::timestamp),
+                "last": backlog.last().map(Sample::timestamp),
+                "spooled": self.spool.as_ref().map(Spool::len),
+            },
+            "counters": self.counters.snapshot(),
+        })
+    }
+
+    /// Keep heartbeat server did not take in backlog, or in spool if configured and every server
+    /// failed. Backlog is backfilled to whichever server is reached next during failover.
+    fn keep_unsent(&self, timestamp: i64, mut payload: serde_json::Value) {
+        // Restored for next heartbeat instead, text would be lost by downsampling
+        if let Some(payload) = payload.as_object_mut() {
+            payload.remove(crate::annotate::PAYLOAD_KEY);
+        }
+        if let Some(spool) = self
+            .spool
+            .as_ref()
+            .filter(|_| self.unreachable.load(Ordering::Relaxed))
+        {
+            match spool.push(timestamp, &payload) {
+                Ok(()) => return,
+                Err(e) => warn!("Unable spool heartbeat, keep it in memory: {:?}", e),
+            }
+        }
+        self.push_backlog(Sample::new(payload));
+    }
+
+    /// Every server failed: missed heartbeats go to spool from now on, together with those
+    /// kept in backlog during failover.
+    pub fn set_unreachable(&self) {
+        if self.unreachable.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => return,
+        };
+        let mut backlog = self.backlog.lock().unwrap();
+        while !backlog.is_empty() {
+            let sample = &backlog[0];
+            if let Err(e) = spool.push(sample.timestamp(), sample.payload()) {
+                warn!("Unable spool heartbeat, keep it in memory: {:?}", e);
+                break;
+            }
+            backlog.remove(0);
+        }
+    }
+
+    fn push_backlog(&self, sample: Sample) {
+        let max_size = self
+            .config
+            .statistics
+            .backlog_size
+            .unwrap_or(downsample::DEFAULT_BACKLOG_SIZE);
+        let mut backlog = self.backlog.lock().unwrap();
+        if backlog.len() >= max_size {
+            backlog.remove(0);
+        }
+        backlog.push(sample);
+    }
+
+    /// Upload heartbeats missed during outage as downsampled summary.
+    async fn send_backfill(&self) {
+        let samples = std::mem::take(&mut *self.backlog.lock().unwrap());
+        if samples.is_empty() {
+            return;
+        }
+        let window = self
+            .config
+            .statistics
+            .backfill_window
+            .unwrap_or(downsample::DEFAULT_WINDOW);
+        let body = serde_json::json!({
+            "window": window,
+            "summary": downsample::downsample(&samples, window),
+        });
+        let result = match self.send_data("backfill", Some(body)).await {
+            Ok(resp) => self.check_response(resp).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => info!("Backfilled {} missed heartbeats", samples.len()),
+            Err(e) => {
+                warn!("Unable send backfill, keep it for next time: {:?}", e);
+                let mut backlog = self.backlog.lock().unwrap();
+                let newer = std::mem::replace(&mut *backlog, samples);
+                backlog.extend(newer);
+            }
+        }
+    }
+
+    /// Replay oldest spooled heartbeats in order, stop at first failure to keep the order.
+    async fn replay_spool(&self) {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => return,
+        };
+        let mut replayed = 0;
+        for (path, body) in spool.batch(crate::spool::REPLAY_BATCH) {
+            let mut body = match body {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Drop spooled heartbeat {}: {}", path.display(), e);
+                    spool.remove(&path);
+                    continue;
+                }
+            };
+            let mut payload = body["heartbeat"].take();
+            self.drop_rejected(&mut payload);
+            payload[COLLECTED_AT] = body[COLLECTED_AT].take();
+            let result = match self.send_data("heartbeat", Some(payload)).await {
+                Ok(resp) => self.check_response(resp).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Unable replay spooled heartbeat, keep it for next time: {:?}",
+                    e
+                );
+                break;
+            }
+            spool.remove(&path);
+            replayed += 1;
+        }
+        if replayed > 0 {
+            info!(
+                "Replayed {} spooled heartbeats, {} left",
+                replayed,
+                spool.len()
+            );
+        }
+    }
+
+    async fn check_response(&self, mut response: reqwest::Response) -> Result<JsonResponse> {
+        let sent = response.extensions().get::<SentDigest>().cloned();
+        let mut j: JsonResponse = match response
+            .extensions_mut()
+            .remove::<crate::compare::Pending>()
+        {
+            Some(mirrored) => {
+                let status = response.status().as_u16();
+                let body: serde_json::Value = response.json().await?;
+                crate::compare::compare(mirrored, status, &body).await;
+                serde_json::from_value(body)?
+            }
+            None => response.json().await?,
+        };
+
+        // Servers not echoing digest are not verified
+        if let (Some(sent), Some(received)) = (sent, j.get_digest()) {
+            if !received.eq_ignore_ascii_case(&sent.digest) {
+                return Err(Error::ChecksumMismatch {
+                    url: sent.url,
+                    length: sent.length,
+                    sent: sent.digest,
+                    received: received.to_string(),
+                }
+                .into());
+            }
+        }
 
         if !self.server_version.is_empty() && !self.server_version.eq(self.server_version.as_str())
         {
@@ -387,13 +2324,68 @@ impl Session {
             )));
         }
         match j.get_status_code() {
-            200 => Ok(j),
-            4031 => Err(anyhow::Error::new(ReInitRequest::new())),
+            200 => {
+                self.reject_sections(j.take_rejected());
+                if let Some(monitor) = &self.fallback {
+                    monitor.succeeded();
+                }
+                Ok(j)
+            }
+            STATUS_REREGISTER => Err(anyhow::Error::new(ReInitRequest::new())),
+            // Never heartbeat more often than usual while server is down for maintenance.
+            // Server is reachable, so fallback notification is not due
+            STATUS_MAINTENANCE => {
+                if let Some(monitor) = &self.fallback {
+                    monitor.succeeded();
+                }
+                Err(Error::Maintenance {
+                    retry_after: j
+                        .get_retry_after()
+                        .unwrap_or(DEFAULT_MAINTENANCE_RETRY)
+                        .max(self.get_interval()),
+                    message: j.get_additional_message(),
+                }
+                .into())
+            }
+            STATUS_CLOCK_SKEW => {
+                let mut offset = self.clock_offset.load(Ordering::Relaxed);
+                if let Some(server_time) = j.get_server_time() {
+                    offset = server_time - chrono::Utc::now().timestamp();
+                    self.clock_offset.store(offset, Ordering::Relaxed);
+                    warn!("Resync clock offset to {}s", offset);
+                }
+                Err(Error::ClockSkew { offset }.into())
+            }
+            STATUS_PAYLOAD_REJECTED => {
+                let sections = j.take_rejected();
+                if sections.is_empty() {
+                    return Err(anyhow::Error::new(j.to_error()));
+                }
+                self.reject_sections(sections.clone());
+                Err(Error::PayloadRejected { sections }.into())
+            }
             4002 | 4000 => Err(anyhow::Error::new(ExitProcessRequest::from(&j))),
             _ => Err(anyhow::Error::new(j.to_error())),
         }
     }
 
+    fn privacy(&self) -> Privacy {
+        self.config.statistics.privacy.unwrap_or_default()
+    }
+
+    pub fn is_roaming(&self) -> bool {
+        self.config.server.roaming.unwrap_or(DEFAULT_ROAMING)
+    }
+
+    /// Interval of `ping` between heartbeats, `None` if disabled or not shorter than heartbeat interval.
+    pub fn get_ping_interval(&self) -> Option<Duration> {
+        self.config
+            .server
+            .ping_interval
+            .filter(|ping_interval| *ping_interval > 0 && *ping_interval < self.get_interval())
+            .map(Duration::from_secs)
+    }
+
     pub fn get_interval(&self) -> u64 {
         self.config
             .server
@@ -0,0 +1,253 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Sign each request body, so server can verify payload integrity end-to-end
+//! even if intermediate proxies re-encode the request.
+//!
+//! The digest is SHA-256 of `body` serialized as compact JSON with sorted keys,
+//! the signature is Ed25519 (ECDSA P-256 with key in TPM) over
+//! `<action>.<uuid>.<timestamp>.<nonce>.<hex digest>`, so it can't be replayed for another action
+//! or client. Public key is sent in registration.
+
+use crate::configparser::config::Signing;
+use crate::error::Error;
+use base64::Engine as _;
+use log::info;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_KEY_FILE: &str = "probe_key.pk8";
+pub const DEFAULT_TPM: bool = false;
+pub const ALGORITHM: &str = "sha256+ed25519";
+
+/// Hex SHA-256 of `data`, used for request digests, audit chain and content hashes.
+pub fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub algorithm: String,
+    pub digest: String,
+    pub signature: String,
+}
+
+/// String covered by manifest signature.
+fn signed_message(action: &str, uuid: &str, timestamp: i64, nonce: u64, digest: &str) -> String {
+    format!("{}.{}.{}.{}.{}", action, uuid, timestamp, nonce, digest)
+}
+
+#[cfg(any(test, feature = "devtools"))]
+impl Manifest {
+    /// Check Ed25519 manifest of request `body` as server does, with base64 `public_key` of
+    /// registration.
+    pub fn verify(
+        &self,
+        public_key: &str,
+        action: &str,
+        uuid: &str,
+        timestamp: i64,
+        nonce: u64,
+        body: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        if self.digest != sha256_hex(&serde_json::to_vec(body)?) {
+            return Err(Error::InvalidSignature {
+                reason: "Digest does not match body",
+            }
+            .into());
+        }
+        let message = signed_message(action, uuid, timestamp, nonce, &self.digest);
+        verify(public_key, message.as_bytes(), &self.signature)
+    }
+}
+
+enum Key {
+    Software(Ed25519KeyPair),
+    #[cfg(feature = "tpm")]
+    Tpm(crate::tpm::TpmKey),
+}
+
+pub struct Signer {
+    key: Key,
+}
+
+impl Signer {
+    /// Key of `[signing]`: in TPM if requested and present, otherwise PKCS#8 file.
+    pub async fn new(cfg: &Signing) -> anyhow::Result<Self> {
+        if cfg.tpm.unwrap_or(DEFAULT_TPM) {
+            #[cfg(not(feature = "tpm"))]
+            return Err(Error::Unavailable {
+                feature: "tpm",
+                message: "signing.tpm requires build with --features tpm".to_string(),
+            }
+            .into());
+            #[cfg(feature = "tpm")]
+            {
+                if crate::tpm::available() {
+                    return Ok(Self {
+                        key: Key::Tpm(crate::tpm::TpmKey::load_or_create().await?),
+                    });
+                }
+                log::warn!(
+                    "No TPM 2.0 found at {}, use signing key file instead",
+                    crate::tpm::DEVICE
+                );
+            }
+        }
+        Self::load_or_generate(match &cfg.key {
+            Some(key) => PathBuf::from(key),
+            None => crate::state::path(DEFAULT_KEY_FILE),
+        })
+    }
+
+    /// Load PKCS#8 key from `path`, generate a new one if not exists.
+    pub fn load_or_generate<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let pkcs8 = match std::fs::read(path) {
+            Ok(pkcs8) => pkcs8,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let document =
+                    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| {
+                        Error::Crypto {
+                            message: "Unable generate signing key".to_string(),
+                        }
+                    })?;
+                crate::state::write_private(path, document.as_ref())?;
+                if crate::state::is_read_only() {
+                    info!("Generate temporary signing key in read-only mode");
+                } else {
+                    info!("Generate new signing key to {}", path.display());
+                }
+                document.as_ref().to_vec()
+            }
+            Err(source) => {
+                return Err(Error::Io {
+                    operation: "read",
+                    path: path.display().to_string(),
+                    source,
+                }
+                .into())
+            }
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| Error::Crypto {
+            message: format!("Unable load signing key {}: {}", path.display(), e),
+        })?;
+        Ok(Self {
+            key: Key::Software(key_pair),
+        })
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match &self.key {
+            Key::Software(_) => ALGORITHM,
+            #[cfg(feature = "tpm")]
+            Key::Tpm(_) => crate::tpm::ALGORITHM,
+        }
+    }
+
+    /// Base64 raw Ed25519 public key, or DER SubjectPublicKeyInfo of TPM key.
+    pub fn public_key(&self) -> String {
+        match &self.key {
+            Key::Software(key_pair) => {
+                base64::engine::general_purpose::STANDARD.encode(key_pair.public_key().as_ref())
+            }
+            #[cfg(feature = "tpm")]
+            Key::Tpm(key) => key.public_key().to_string(),
+        }
+    }
+
+    pub async fn sign(
+        &self,
+        action: &str,
+        uuid: &str,
+        timestamp: i64,
+        nonce: u64,
+        body: &serde_json::Value,
+    ) -> anyhow::Result<Manifest> {
+        // serde_json::Map keeps keys sorted, so the output is canonical
+        let canonical = serde_json::to_vec(body)?;
+        let digest = sha256_hex(&canonical);
+        let message = signed_message(action, uuid, timestamp, nonce, &digest);
+        let signature = match &self.key {
+            Key::Software(key_pair) => key_pair.sign(message.as_bytes()).as_ref().to_vec(),
+            #[cfg(feature = "tpm")]
+            Key::Tpm(key) => key.sign(message.as_bytes()).await?,
+        };
+        Ok(Manifest {
+            algorithm: self.algorithm().to_string(),
+            digest,
+            signature: base64::engine::general_purpose::STANDARD.encode(signature),
+        })
+    }
+}
+
+/// Verify base64 Ed25519 `signature` of `message` with base64 `public_key`.
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> anyhow::Result<()> {
+    let engine = base64::engine::general_purpose::STANDARD;
+    let public_key = engine
+        .decode(public_key)
+        .map_err(|_| Error::InvalidSignature {
+            reason: "Public key is not valid base64",
+        })?;
+    let signature = engine
+        .decode(signature)
+        .map_err(|_| Error::InvalidSignature {
+            reason: "Signature is not valid base64",
+        })?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(message, &signature)
+        .map_err(|_| {
+            Error::InvalidSignature {
+                reason: "Signature verification failed",
+            }
+            .into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signature_bound_to_action_and_uuid() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let signer = Signer {
+            key: Key::Software(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()),
+        };
+        let public_key = signer.public_key();
+        let body = serde_json::json!({ "cpu": 1 });
+        let manifest = signer.sign("heartbeat", "uuid", 1, 2, &body).await.unwrap();
+        assert!(manifest
+            .verify(&public_key, "heartbeat", "uuid", 1, 2, &body)
+            .is_ok());
+        assert!(manifest
+            .verify(&public_key, "register", "uuid", 1, 2, &body)
+            .is_err());
+        assert!(manifest
+            .verify(&public_key, "heartbeat", "other", 1, 2, &body)
+            .is_err());
+    }
+}
@@ -0,0 +1,266 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Disk-backed queue of `[statistics.spool]`: once every server failed, heartbeats not taken are
+//! written unfiltered to spool directory, one file each, and replayed in order as `heartbeat` with
+//! `collected_at` once a heartbeat gets through again. Unlike in-memory backlog, spool survives
+//! restarts and is replayed in full instead of downsampled.
+
+use crate::configparser::config::Configure;
+use crate::error::Error;
+use log::warn;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Key of replayed heartbeat holding when it was collected, kept whatever sections server takes
+pub const COLLECTED_AT: &str = "collected_at";
+pub const DEFAULT_SPOOL_DIR: &str = "spool";
+/// MiB
+pub const DEFAULT_MAX_SIZE: u64 = 64;
+pub const DEFAULT_MAX_AGE: u64 = 7 * 86400;
+/// Spooled heartbeats replayed after each delivered one, so a long outage does not hold up the next.
+pub const REPLAY_BATCH: usize = 60;
+const EXTENSION: &str = "json";
+
+struct Entry {
+    path: PathBuf,
+    /// Unix timestamp in milliseconds
+    spooled_at: i64,
+    size: u64,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    max_age: i64,
+    counter: AtomicU64,
+}
+
+impl Spool {
+    pub fn new(cfg: &Configure) -> Option<Self> {
+        let spool = cfg.statistics.spool.as_ref()?;
+        if crate::state::is_read_only() {
+            warn!("Spool is not available in read-only mode, keep missed heartbeats in memory");
+            return None;
+        }
+        Some(Self {
+            dir: match &spool.path {
+                Some(path) => PathBuf::from(path),
+                None => crate::state::path(DEFAULT_SPOOL_DIR),
+            },
+            max_size: spool.max_size.unwrap_or(DEFAULT_MAX_SIZE) << 20,
+            max_age: spool.max_age.unwrap_or(DEFAULT_MAX_AGE) as i64 * 1000,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Spooled files, oldest first.
+    fn entries(&self) -> Vec<Entry> {
+        let mut entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    entry.path().extension().and_then(|e| e.to_str()) == Some(EXTENSION)
+                })
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    let spooled_at = path
+                        .file_stem()?
+                        .to_str()?
+                        .split_once('-')?
+                        .0
+                        .parse()
+                        .ok()?;
+                    Some(Entry {
+                        spooled_at,
+                        size: entry.metadata().ok()?.len(),
+                        path,
+                    })
+                })
+                .collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    /// Drop spooled heartbeats older than `max_age`, then oldest ones beyond `max_size`.
+    fn prune(&self) -> Vec<Entry> {
+        let oldest = chrono::Utc::now().timestamp_millis() - self.max_age;
+        let (expired, mut entries): (Vec<_>, Vec<_>) = self
+            .entries()
+            .into_iter()
+            .partition(|entry| entry.spooled_at < oldest);
+        let mut total = entries.iter().map(|entry| entry.size).sum::<u64>();
+        let mut dropped = expired.len();
+        for entry in &expired {
+            remove(&entry.path);
+        }
+        while total > self.max_size && !entries.is_empty() {
+            let entry = entries.remove(0);
+            total -= entry.size;
+            remove(&entry.path);
+            dropped += 1;
+        }
+        if dropped > 0 {
+            warn!(
+                "Spool is over its limits, drop {} oldest heartbeats",
+                dropped
+            );
+        }
+        entries
+    }
+
+    /// Append heartbeat payload collected at `timestamp` to spool.
+    pub fn push(&self, timestamp: i64, payload: &Value) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let name = format!(
+            "{:013}-{:06}",
+            chrono::Utc::now().timestamp_millis(),
+            self.counter.fetch_add(1, Ordering::Relaxed) % 1_000_000
+        );
+        let body = serde_json::json!({ COLLECTED_AT: timestamp, "heartbeat": payload });
+        // Written under another name first, so a partly written file is never replayed
+        let temporary = self.dir.join(format!("{}.tmp", name));
+        std::fs::write(&temporary, serde_json::to_vec(&body)?)?;
+        std::fs::rename(&temporary, self.dir.join(format!("{}.{}", name, EXTENSION)))?;
+        self.prune();
+        Ok(())
+    }
+
+    /// Up to `limit` oldest spooled heartbeats still within limits, as replay body.
+    pub fn batch(&self, limit: usize) -> Vec<(PathBuf, anyhow::Result<Value>)> {
+        self.prune()
+            .into_iter()
+            .take(limit)
+            .map(|entry| {
+                let body = std::fs::read(&entry.path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|content| {
+                        serde_json::from_slice(&content).map_err(|e| {
+                            Error::Malformed {
+                                what: "spooled heartbeat".to_string(),
+                                message: e.to_string(),
+                            }
+                            .into()
+                        })
+                    });
+                (entry.path, body)
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    pub fn remove(&self, path: &Path) {
+        remove(path)
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Unable remove spooled heartbeat {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spool(max_size: u64) -> Spool {
+        Spool {
+            dir: std::env::temp_dir().join(format!("probe-client-spool-{}", uuid::Uuid::new_v4())),
+            max_size,
+            max_age: DEFAULT_MAX_AGE as i64 * 1000,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn replayed(spool: &Spool) -> Vec<i64> {
+        spool
+            .batch(REPLAY_BATCH)
+            .into_iter()
+            .map(|(_, body)| body.unwrap()[COLLECTED_AT].as_i64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn replayed_in_spool_order() {
+        let spool = spool(DEFAULT_MAX_SIZE << 20);
+        for timestamp in [30, 10, 20].iter() {
+            spool
+                .push(*timestamp, &serde_json::json!({ "cpu": timestamp }))
+                .unwrap();
+        }
+        assert_eq!(replayed(&spool), [30, 10, 20]);
+        let batch = spool.batch(2);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].1.as_ref().unwrap()["heartbeat"]["cpu"], 30);
+        spool.remove(&batch[0].0);
+        assert_eq!(replayed(&spool), [10, 20]);
+        assert_eq!(spool.len(), 2);
+        std::fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_dropped_over_size_cap() {
+        let payload = serde_json::json!({ "data": "x".repeat(100) });
+        let probe = spool(u64::MAX);
+        probe.push(0, &payload).unwrap();
+        let size = probe.entries()[0].size;
+        std::fs::remove_dir_all(&probe.dir).unwrap();
+
+        // Room for exactly two entries
+        let spool = spool(size * 2);
+        for timestamp in 1..=4 {
+            spool.push(timestamp, &payload).unwrap();
+        }
+        assert_eq!(replayed(&spool), [3, 4]);
+        std::fs::remove_dir_all(&spool.dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_and_stray_entries() {
+        let spool = spool(DEFAULT_MAX_SIZE << 20);
+        spool.push(1, &serde_json::json!({})).unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        // Sorted before the pushed one, as it is spooled earlier
+        std::fs::write(
+            spool.dir.join(format!("{:013}-000000.json", now - 1000)),
+            "{truncated",
+        )
+        .unwrap();
+        // Partly written, unknown name, or expired: never replayed
+        std::fs::write(spool.dir.join(format!("{:013}-000001.tmp", now)), "{}").unwrap();
+        std::fs::write(spool.dir.join("notes.json"), "{}").unwrap();
+        std::fs::write(spool.dir.join("0000000000001-000000.json"), "{}").unwrap();
+
+        let batch = spool.batch(REPLAY_BATCH);
+        assert_eq!(batch.len(), 2);
+        let e = batch[0].1.as_ref().unwrap_err();
+        assert_eq!(crate::error::code(e), crate::error::Code::InvalidResponse);
+        assert_eq!(batch[1].1.as_ref().unwrap()[COLLECTED_AT], 1);
+        // Expired entry is removed by pruning
+        assert!(!spool.dir.join("0000000000001-000000.json").exists());
+        std::fs::remove_dir_all(&spool.dir).unwrap();
+    }
+}
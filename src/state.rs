@@ -0,0 +1,164 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Directory of files written by client: identity, keys, nonce, queue and history.
+//!
+//! Resolved in order: `--state-dir`, `data/` in working directory (layout of previous versions),
+//! `/var/lib/probe-client` when running as root on Linux, `$XDG_STATE_HOME/probe-client`
+//! (default `~/.local/state/probe-client`), otherwise `data/`.
+//!
+//! In read-only mode (`--read-only`), nothing is written: state is kept in memory only.
+
+use log::debug;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+pub const LEGACY_STATE_DIR: &str = "data";
+pub const CONFIG_FILE: &str = "probe_client.toml";
+#[cfg(target_os = "linux")]
+const SYSTEM_STATE_DIR: &str = "/var/lib/probe-client";
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+fn system_dir() -> Option<PathBuf> {
+    // Safety: geteuid has no side effect
+    if unsafe { libc::geteuid() } == 0 {
+        Some(PathBuf::from(SYSTEM_STATE_DIR))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_dir() -> Option<PathBuf> {
+    None
+}
+
+fn xdg_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")),
+    }
+    .map(|dir| dir.join("probe-client"))
+}
+
+fn default_dir() -> PathBuf {
+    if Path::new(LEGACY_STATE_DIR).is_dir() {
+        return PathBuf::from(LEGACY_STATE_DIR);
+    }
+    system_dir()
+        .or_else(xdg_dir)
+        .unwrap_or_else(|| PathBuf::from(LEGACY_STATE_DIR))
+}
+
+/// Set state directory from `--state-dir`, should be called before any state is accessed.
+pub fn init(dir: Option<&str>) {
+    let dir = dir.map(PathBuf::from).unwrap_or_else(default_dir);
+    debug!("Use state directory {}", dir.display());
+    STATE_DIR.set(dir).ok();
+}
+
+/// Fresh state directory shared by all tests of this process, as it can only be set once.
+///
+/// Panics if state directory was already resolved elsewhere, so tests never write into the
+/// real one.
+#[cfg(test)]
+pub fn init_for_test() {
+    static TEST_DIR: OnceLock<PathBuf> = OnceLock::new();
+    let test_dir = TEST_DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("probe-client-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    });
+    if STATE_DIR.set(test_dir.clone()).is_err() {
+        let dir = dir();
+        assert!(
+            dir == test_dir,
+            "state directory {} was set before init_for_test",
+            dir.display()
+        );
+    }
+}
+
+#[cfg(not(test))]
+pub fn dir() -> &'static Path {
+    STATE_DIR.get_or_init(default_dir)
+}
+
+/// Tests must not fall back to the real state directory.
+#[cfg(test)]
+pub fn dir() -> &'static Path {
+    STATE_DIR
+        .get()
+        .expect("state::init_for_test() not called before accessing state")
+}
+
+/// Location of `name` in state directory.
+pub fn path(name: &str) -> PathBuf {
+    dir().join(name)
+}
+
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Write `contents` to `path`, creating parent directory. Does nothing in read-only mode.
+pub fn write<C: AsRef<[u8]>>(path: &Path, contents: C) -> std::io::Result<()> {
+    if is_read_only() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)
+}
+
+/// Like [`write`], but file is only accessible by owner on Unix, for private keys.
+/// Permissions are set before `contents` is written, also when file already exists.
+pub fn write_private<C: AsRef<[u8]>>(path: &Path, contents: C) -> std::io::Result<()> {
+    use std::io::Write as _;
+    if is_read_only() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_ref())
+}
@@ -0,0 +1,123 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `status` subcommand: state of running client through control socket, e.g. whether its last
+//! heartbeat got through and which server it talks to.
+//!
+//! Control command `status` answers one line of JSON, `{"version", "pid", "server",
+//! "last_success", "last_failure", "failures", "error"}`: timestamps are unix seconds, `failures`
+//! counts heartbeats failed in a row and `error` is the last failure (see Error codes).
+
+use chrono::TimeZone as _;
+use serde_json::Value;
+use std::sync::Mutex;
+
+pub const SUBCOMMAND_NAME: &str = "status";
+pub const CONTROL_COMMAND: &str = "status";
+
+struct State {
+    server: Option<String>,
+    last_success: Option<i64>,
+    last_failure: Option<i64>,
+    failures: u64,
+    error: Option<Value>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    server: None,
+    last_success: None,
+    last_failure: None,
+    failures: 0,
+    error: None,
+});
+
+pub fn subcommand<'a, 'b>() -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Show state of running client, e.g. whether its last heartbeat got through")
+        .arg(crate::output::arg())
+}
+
+/// Note result of heartbeat sent to `server`.
+pub fn record(server: Option<&str>, result: &anyhow::Result<()>) {
+    let mut state = STATE.lock().unwrap();
+    let now = chrono::Utc::now().timestamp();
+    state.server = server.map(str::to_string);
+    match result {
+        Ok(()) => {
+            state.last_success = Some(now);
+            state.failures = 0;
+        }
+        Err(e) => {
+            state.last_failure = Some(now);
+            state.failures += 1;
+            state.error = Some(crate::error::structured(e));
+        }
+    }
+}
+
+/// Reply of control command.
+pub fn reply() -> String {
+    let state = STATE.lock().unwrap();
+    serde_json::json!({
+        "version": crate::session::CLIENT_VERSION,
+        "pid": std::process::id(),
+        "server": state.server,
+        "last_success": state.last_success,
+        "last_failure": state.last_failure,
+        "failures": state.failures,
+        "error": state.error,
+    })
+    .to_string()
+}
+
+pub async fn run(matches: &clap::ArgMatches<'_>) -> anyhow::Result<()> {
+    let reply = crate::control::request(CONTROL_COMMAND).await?;
+    let status: Value = serde_json::from_str(&reply)?;
+    if crate::output::of(matches) == crate::output::Format::Json {
+        return crate::output::print_json(&status);
+    }
+    let time = |key: &str| match status[key].as_i64() {
+        Some(timestamp) => chrono::Utc
+            .timestamp_opt(timestamp, 0)
+            .single()
+            .map_or_else(|| timestamp.to_string(), |time| time.to_rfc3339()),
+        None => "never".to_string(),
+    };
+    println!(
+        "version: {}",
+        status["version"].as_str().unwrap_or_default()
+    );
+    println!("pid: {}", status["pid"]);
+    println!(
+        "server: {}",
+        status["server"].as_str().unwrap_or("not registered")
+    );
+    println!("last success: {}", time("last_success"));
+    println!("last failure: {}", time("last_failure"));
+    println!("failures in a row: {}", status["failures"]);
+    if let Some(error) = status["error"].as_object() {
+        println!(
+            "error: [E{:03}] {}",
+            error["code"].as_u64().unwrap_or_default(),
+            error["message"].as_str().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
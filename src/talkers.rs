@@ -0,0 +1,355 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Top talkers in `[collector.talkers]`, coarse traffic visibility without a flow exporter.
+//!
+//! Outgoing packets are sampled on a packet socket for `window` seconds each heartbeat,
+//! bytes are summed by destination (AS number with `asn_database`, otherwise address)
+//! and by destination port. Loopback traffic is ignored. Linux only, requires `CAP_NET_RAW`.
+//!
+//! `asn_database` is a TSV file in ip2asn format: `range_start range_end AS_number country description`.
+
+use crate::collector::Collector;
+use crate::configparser::config::Talkers;
+use crate::error::Error;
+use crate::normalize::Unit;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+pub const COLLECTOR_NAME: &str = "talkers";
+pub const DEFAULT_WINDOW: u64 = 2;
+pub const DEFAULT_TOP: usize = 10;
+const PACKET_OUTGOING: u8 = 4;
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+/// Only headers are needed, real length is returned with `MSG_TRUNC`.
+const SNAP_LENGTH: usize = 128;
+
+struct AsnRange {
+    start: u128,
+    end: u128,
+    asn: u32,
+    name: String,
+}
+
+struct AsnDatabase {
+    ranges: Vec<AsnRange>,
+}
+
+fn ip_key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl AsnDatabase {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|source| Error::Io {
+            operation: "read ASN database",
+            path: path.to_string(),
+            source,
+        })?;
+        let mut ranges = contents
+            .lines()
+            .filter_map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+                let asn = fields.get(2)?.parse::<u32>().ok()?;
+                // AS 0 marks ranges not routed
+                if asn == 0 {
+                    return None;
+                }
+                Some(AsnRange {
+                    start: ip_key(fields.first()?.parse().ok()?),
+                    end: ip_key(fields.get(1)?.parse().ok()?),
+                    asn,
+                    name: fields.get(4).unwrap_or(&"").to_string(),
+                })
+            })
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|range| range.start);
+        Ok(Self { ranges })
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<&AsnRange> {
+        let key = ip_key(ip);
+        let index = self.ranges.partition_point(|range| range.start <= key);
+        let range = self.ranges.get(index.checked_sub(1)?)?;
+        if key <= range.end {
+            Some(range)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sample {
+    bytes: u64,
+    destinations: HashMap<IpAddr, u64>,
+    ports: HashMap<(&'static str, u16), u64>,
+}
+
+impl Sample {
+    /// Account packet starting at network header, `length` is its size on wire.
+    fn add(&mut self, protocol: u16, data: &[u8], length: u64) {
+        let (destination, transport, header) = match protocol {
+            ETH_P_IP if data.len() >= 20 => {
+                let header = (data[0] & 0x0f) as usize * 4;
+                // Only first fragment carries ports
+                let fragment = u16::from_be_bytes([data[6], data[7]]) & 0x1fff;
+                let destination = IpAddr::V4(Ipv4Addr::new(data[16], data[17], data[18], data[19]));
+                let header = if fragment == 0 { Some(header) } else { None };
+                (destination, data[9], header)
+            }
+            ETH_P_IPV6 if data.len() >= 40 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&data[24..40]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), data[6], Some(40))
+            }
+            _ => return,
+        };
+        if destination.is_loopback() {
+            return;
+        }
+        self.bytes += length;
+        *self.destinations.entry(destination).or_default() += length;
+        let name = match transport {
+            6 => "tcp",
+            17 => "udp",
+            _ => return,
+        };
+        if let Some(port) = header.and_then(|header| data.get(header + 2..header + 4)) {
+            *self
+                .ports
+                .entry((name, u16::from_be_bytes([port[0], port[1]])))
+                .or_default() += length;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn capture(interface: Option<&str>, window: Duration) -> anyhow::Result<Sample> {
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_DGRAM,
+            ETH_P_ALL.to_be() as libc::c_int,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::Io {
+            operation: "open",
+            path: "packet socket (requires CAP_NET_RAW)".to_string(),
+            source: std::io::Error::last_os_error(),
+        }
+        .into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    if let Some(interface) = interface {
+        let name = std::ffi::CString::new(interface)?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index == 0 {
+            return Err(Error::Unsupported {
+                what: format!("Interface {}", interface),
+            }
+            .into());
+        }
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as u16;
+        address.sll_protocol = ETH_P_ALL.to_be();
+        address.sll_ifindex = index as i32;
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 200_000,
+    };
+    unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        );
+    }
+    let mut sample = Sample::default();
+    let mut buffer = [0u8; SNAP_LENGTH];
+    let deadline = Instant::now() + window;
+    while Instant::now() < deadline {
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut address_length = std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let length = unsafe {
+            libc::recvfrom(
+                socket.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                libc::MSG_TRUNC,
+                &mut address as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut address_length,
+            )
+        };
+        if length < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.kind() {
+                std::io::ErrorKind::WouldBlock
+                | std::io::ErrorKind::TimedOut
+                | std::io::ErrorKind::Interrupted => continue,
+                _ => return Err(e.into()),
+            }
+        }
+        if address.sll_pkttype != PACKET_OUTGOING {
+            continue;
+        }
+        let captured = (length as usize).min(buffer.len());
+        sample.add(
+            u16::from_be(address.sll_protocol),
+            &buffer[..captured],
+            length as u64,
+        );
+    }
+    Ok(sample)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn capture(_interface: Option<&str>, _window: Duration) -> anyhow::Result<Sample> {
+    Err(Error::Unavailable {
+        feature: "talkers",
+        message: "Top talkers sampling is only supported on Linux".to_string(),
+    }
+    .into())
+}
+
+fn top<K, F: Fn(&K) -> Value>(counts: HashMap<K, u64>, limit: usize, describe: F) -> Vec<Value> {
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+    counts
+        .iter()
+        .take(limit)
+        .map(|(key, bytes)| {
+            let mut value = describe(key);
+            value["bytes"] = Value::from(*bytes);
+            value
+        })
+        .collect()
+}
+
+pub struct TalkersCollector {
+    interface: Option<String>,
+    window: Duration,
+    top: usize,
+    asn: Option<AsnDatabase>,
+}
+
+impl TalkersCollector {
+    pub fn new(cfg: &Talkers) -> anyhow::Result<Self> {
+        Ok(Self {
+            interface: cfg.interface.clone(),
+            window: Duration::from_secs(cfg.window.unwrap_or(DEFAULT_WINDOW)),
+            top: cfg.top.unwrap_or(DEFAULT_TOP),
+            asn: cfg
+                .asn_database
+                .as_deref()
+                .map(AsnDatabase::load)
+                .transpose()?,
+        })
+    }
+
+    fn destinations(&self, destinations: HashMap<IpAddr, u64>) -> Vec<Value> {
+        let asn = match &self.asn {
+            Some(asn) => asn,
+            None => {
+                return top(
+                    destinations,
+                    self.top,
+                    |address| serde_json::json!({ "address": address.to_string() }),
+                )
+            }
+        };
+        let mut by_asn: HashMap<u32, u64> = HashMap::new();
+        let mut names: HashMap<u32, &str> = HashMap::new();
+        for (address, bytes) in destinations {
+            let number = match asn.lookup(address) {
+                Some(range) => {
+                    names.insert(range.asn, &range.name);
+                    range.asn
+                }
+                None => 0,
+            };
+            *by_asn.entry(number).or_default() += bytes;
+        }
+        top(by_asn, self.top, |number| {
+            serde_json::json!({
+                "asn": number,
+                "name": names.get(number).copied().unwrap_or("unknown"),
+            })
+        })
+    }
+}
+
+#[async_trait]
+impl Collector for TalkersCollector {
+    fn name(&self) -> &str {
+        COLLECTOR_NAME
+    }
+
+    async fn collect(&self) -> anyhow::Result<Value> {
+        let interface = self.interface.clone();
+        let window = self.window;
+        let sample =
+            tokio::task::spawn_blocking(move || capture(interface.as_deref(), window)).await??;
+        Ok(serde_json::json!({
+            "window": window.as_secs(),
+            "bytes": sample.bytes,
+            "destinations": self.destinations(sample.destinations),
+            "ports": top(sample.ports, self.top, |(protocol, port)| {
+                serde_json::json!({ "protocol": protocol, "port": port })
+            }),
+        }))
+    }
+
+    fn units(&self) -> Vec<(String, Unit)> {
+        vec![
+            ("window".to_string(), Unit::Seconds),
+            ("bytes".to_string(), Unit::Bytes),
+            ("destinations.*.bytes".to_string(), Unit::Bytes),
+            ("ports.*.bytes".to_string(), Unit::Bytes),
+        ]
+    }
+
+    fn source(&self) -> String {
+        "packet socket sampling".to_string()
+    }
+}
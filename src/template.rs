@@ -0,0 +1,83 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Handlebars templates of alert and notification messages.
+//!
+//! HTML escaping is disabled, `{{json value}}` renders value as JSON (for webhook bodies).
+
+use crate::error::Error;
+use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+use serde_json::Value;
+
+fn json_helper(
+    helper: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = helper
+        .param(0)
+        .map(|param| param.value().to_string())
+        .unwrap_or_else(|| "null".to_string());
+    out.write(&value)?;
+    Ok(())
+}
+
+pub struct Templates {
+    registry: Handlebars<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        let mut registry = Handlebars::new();
+        registry.register_escape_fn(handlebars::no_escape);
+        registry.register_helper("json", Box::new(json_helper));
+        Self { registry }
+    }
+}
+
+impl Templates {
+    /// Compile `template` under `name`, so syntax errors are reported at startup against `field`.
+    pub fn register(
+        &mut self,
+        field: &'static str,
+        name: &str,
+        template: &str,
+    ) -> anyhow::Result<()> {
+        self.registry
+            .register_template_string(name, template)
+            .map_err(|e| {
+                Error::Configure {
+                    field,
+                    message: format!("Invalid template {}: {}", name, e),
+                }
+                .into()
+            })
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.registry.has_template(name)
+    }
+
+    pub fn render(&self, name: &str, context: &Value) -> anyhow::Result<String> {
+        Ok(self.registry.render(name, context)?)
+    }
+}
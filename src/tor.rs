@@ -0,0 +1,80 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Route reports through local Tor SOCKS proxy, so `.onion` server addresses can be used.
+
+use crate::configparser::config::Configure;
+use crate::error::Error;
+use std::time::Duration;
+
+pub const DEFAULT_SOCKS: &str = "127.0.0.1:9050";
+/// Tor circuits add several seconds of latency, so wait longer than direct connections.
+pub const DEFAULT_TIMEOUT: u64 = 60;
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+
+pub fn proxy(cfg: &Configure) -> anyhow::Result<Option<reqwest::Proxy>> {
+    match &cfg.tor {
+        Some(tor) => Ok(Some(reqwest::Proxy::all(format!(
+            "socks5h://{}",
+            tor.socks.as_deref().unwrap_or(DEFAULT_SOCKS)
+        ))?)),
+        None => Ok(None),
+    }
+}
+
+/// Return (request timeout, connect timeout) tuned for Tor if enabled.
+pub fn timeouts(cfg: &Configure) -> Option<(Duration, Duration)> {
+    cfg.tor.as_ref().map(|tor| {
+        (
+            Duration::from_secs(tor.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            Duration::from_secs(tor.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT)),
+        )
+    })
+}
+
+fn is_onion(address: &str) -> bool {
+    reqwest::Url::parse(address)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.ends_with(".onion")))
+        .unwrap_or(false)
+}
+
+/// `.onion` address can only be reached through Tor.
+pub fn check(cfg: &Configure) -> anyhow::Result<()> {
+    if cfg.tor.is_some() {
+        return Ok(());
+    }
+    let mut addresses = vec![&cfg.server.server_address];
+    addresses.extend(cfg.server.backup_servers.iter().flatten());
+    addresses.extend(
+        cfg.server
+            .alternates
+            .iter()
+            .flat_map(|a| a.values().flatten()),
+    );
+    match addresses.into_iter().find(|address| is_onion(address)) {
+        Some(address) => Err(Error::Configure {
+            field: "tor",
+            message: format!("{} is onion address, but [tor] is not configured", address),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
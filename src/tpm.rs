@@ -0,0 +1,261 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Signing key kept in TPM 2.0 (feature `tpm`), driven through `tpm2-tools`.
+//!
+//! The key is an ECDSA P-256 key created under the owner hierarchy storage primary key with
+//! `fixedtpm` and `fixedparent`, so its private part never leaves the TPM: the state directory
+//! only holds the key wrapped by this TPM (`probe_tpm_key.pub` / `probe_tpm_key.priv`), which is
+//! useless once copied to another machine.
+
+use crate::error::Error;
+use base64::Engine as _;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+
+pub const DEVICE: &str = "/dev/tpmrm0";
+pub const PUBLIC_FILE: &str = "probe_tpm_key.pub";
+pub const PRIVATE_FILE: &str = "probe_tpm_key.priv";
+const PRIMARY_CONTEXT: &str = "probe_tpm_primary.ctx";
+const KEY_CONTEXT: &str = "probe_tpm_key.ctx";
+const PUBLIC_DER: &str = "probe_tpm_key.der";
+pub const ALGORITHM: &str = "sha256+ecdsa-p256";
+
+/// Whether TPM 2.0 resource manager device is present.
+pub fn available() -> bool {
+    Path::new(DEVICE).exists()
+}
+
+fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env("TPM2TOOLS_TCTI", format!("device:{}", DEVICE));
+    command
+}
+
+async fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let output = command(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| Error::Command {
+            program: program.to_string(),
+            message: format!("{} (is tpm2-tools installed?)", e),
+        })?;
+    if !output.status.success() {
+        return Err(Error::Command {
+            program: program.to_string(),
+            message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn path(name: &str) -> String {
+    crate::state::path(name).to_string_lossy().to_string()
+}
+
+/// Storage primary key, derived from owner seed so it is the same every time.
+async fn create_primary() -> anyhow::Result<String> {
+    let primary = path(PRIMARY_CONTEXT);
+    run(
+        "tpm2_createprimary",
+        &["-C", "o", "-g", "sha256", "-G", "ecc256", "-c", &primary],
+    )
+    .await?;
+    Ok(primary)
+}
+
+/// DER encoded integer of big-endian unsigned `bytes`.
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bytes.len() - 1);
+    let bytes = &bytes[start..];
+    let mut integer = vec![0x02];
+    if bytes[0] & 0x80 != 0 {
+        integer.extend([bytes.len() as u8 + 1, 0]);
+    } else {
+        integer.push(bytes.len() as u8);
+    }
+    integer.extend_from_slice(bytes);
+    integer
+}
+
+/// ECDSA signature written by `tpm2_sign -f plain` as DER `SEQUENCE { r, s }`. tpm2-tools 4 and
+/// later already write DER for ECDSA, earlier ones write raw `r || s`, which is encoded here.
+fn der_signature(signature: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match signature.len() {
+        64 => {
+            let (r, s) = signature.split_at(32);
+            let mut body = der_integer(r);
+            body.extend(der_integer(s));
+            let mut der = vec![0x30, body.len() as u8];
+            der.extend(body);
+            Ok(der)
+        }
+        length if length > 2 && signature[0] == 0x30 && signature[1] as usize == length - 2 => {
+            Ok(signature)
+        }
+        length => Err(Error::Malformed {
+            what: "tpm2_sign output".to_string(),
+            message: format!("{} bytes is neither DER nor raw P-256 signature", length),
+        }
+        .into()),
+    }
+}
+
+pub struct TpmKey {
+    context: PathBuf,
+    /// Base64 DER SubjectPublicKeyInfo
+    public_key: String,
+}
+
+impl TpmKey {
+    /// Load key wrapped in state directory into TPM, create it first if not exists.
+    pub async fn load_or_create() -> anyhow::Result<Self> {
+        if crate::state::is_read_only() {
+            return Err(Error::Configure {
+                field: "signing.tpm",
+                message: "TPM signing key requires writable state directory".to_string(),
+            }
+            .into());
+        }
+        std::fs::create_dir_all(crate::state::dir())?;
+        let primary = create_primary().await?;
+        let (public, private) = (path(PUBLIC_FILE), path(PRIVATE_FILE));
+        if !Path::new(&public).exists() || !Path::new(&private).exists() {
+            run(
+                "tpm2_create",
+                &[
+                    "-C",
+                    &primary,
+                    "-G",
+                    "ecc256:ecdsa-sha256",
+                    "-a",
+                    "fixedtpm|fixedparent|sensitivedataorigin|userwithauth|sign",
+                    "-u",
+                    &public,
+                    "-r",
+                    &private,
+                ],
+            )
+            .await?;
+            info!("Create signing key in TPM");
+        }
+        let context = path(KEY_CONTEXT);
+        Self::load(&primary, &public, &private, &context).await?;
+        let der = path(PUBLIC_DER);
+        run(
+            "tpm2_readpublic",
+            &["-c", &context, "-f", "der", "-o", &der],
+        )
+        .await?;
+        Ok(Self {
+            context: PathBuf::from(context),
+            public_key: base64::engine::general_purpose::STANDARD.encode(std::fs::read(&der)?),
+        })
+    }
+
+    async fn load(primary: &str, public: &str, private: &str, context: &str) -> anyhow::Result<()> {
+        run(
+            "tpm2_load",
+            &["-C", primary, "-u", public, "-r", private, "-c", context],
+        )
+        .await
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    async fn try_sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut child = command("tpm2_sign")
+            .arg("-c")
+            .arg(&self.context)
+            .args([
+                "-g",
+                "sha256",
+                "-s",
+                "ecdsa",
+                "-f",
+                "plain",
+                "-o",
+                "/dev/stdout",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(message).await?;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(Error::Command {
+                program: "tpm2_sign".to_string(),
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }
+            .into());
+        }
+        der_signature(output.stdout)
+    }
+
+    /// DER ECDSA signature of SHA-256 of `message`. Saved key context is invalidated by TPM
+    /// reset, in which case key is loaded again once.
+    pub async fn sign(&self, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self.try_sign(message).await {
+            Ok(signature) => Ok(signature),
+            Err(e) => {
+                warn!("{}, load TPM key again", e);
+                Self::load(
+                    &create_primary().await?,
+                    &path(PUBLIC_FILE),
+                    &path(PRIVATE_FILE),
+                    &self.context.to_string_lossy(),
+                )
+                .await?;
+                self.try_sign(message).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_signature_is_encoded_as_der() {
+        let mut raw = vec![0u8; 64];
+        raw[1] = 0x7f;
+        raw[32] = 0x80;
+        let der = der_signature(raw).unwrap();
+        // r loses its leading zero, s gets one as its high bit is set
+        assert_eq!(&der[..5], &[0x30, 0x44, 0x02, 0x1f, 0x7f]);
+        assert_eq!(&der[35..39], &[0x02, 0x21, 0x00, 0x80]);
+        assert_eq!(der.len(), 2 + 0x44);
+        assert_eq!(der_signature(der.clone()).unwrap(), der);
+        assert!(der_signature(vec![0x30, 0x10, 0x02]).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Send heartbeats through SSH tunnel to jump host, for environments where only SSH egress is permitted.
+//!
+//! The client runs `ssh -N -D` as a local SOCKS proxy, and restarts it whenever it exits.
+
+use crate::configparser::config::{Configure, Tunnel};
+use log::{error, info, warn};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_PORT: u16 = 22;
+pub const DEFAULT_SSH_COMMAND: &str = "ssh";
+pub const DEFAULT_LOCAL_PORT: u16 = 18890;
+const READY_TIMEOUT: u64 = 15;
+const MAX_RESTART_DELAY: u64 = 300;
+
+fn local_port(tunnel: &Tunnel) -> u16 {
+    tunnel.local_port.unwrap_or(DEFAULT_LOCAL_PORT)
+}
+
+/// SOCKS proxy for outgoing requests if `[tunnel]` is configured.
+pub fn proxy(cfg: &Configure) -> anyhow::Result<Option<reqwest::Proxy>> {
+    match &cfg.tunnel {
+        Some(tunnel) => Ok(Some(reqwest::Proxy::all(format!(
+            "socks5h://127.0.0.1:{}",
+            local_port(tunnel)
+        ))?)),
+        None => Ok(None),
+    }
+}
+
+/// Build `ssh -N` command to `tunnel.host` with port forwarding arguments `forward`.
+pub fn build_command(tunnel: &Tunnel, forward: [&str; 2]) -> Command {
+    let mut command = Command::new(tunnel.ssh_command.as_deref().unwrap_or(DEFAULT_SSH_COMMAND));
+    command
+        .arg("-N")
+        .args(forward)
+        .args(["-p", &tunnel.port.unwrap_or(DEFAULT_PORT).to_string()])
+        .args(["-o", "BatchMode=yes"])
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .args(["-o", "ServerAliveInterval=30"])
+        .args(["-o", "ServerAliveCountMax=3"]);
+    if let Some(identity_file) = &tunnel.identity_file {
+        command.args(["-i", identity_file]);
+    }
+    if let Some(known_hosts) = &tunnel.known_hosts {
+        command.args(["-o", &format!("UserKnownHostsFile={}", known_hosts)]);
+    }
+    match &tunnel.user {
+        Some(user) => command.arg(format!("{}@{}", user, tunnel.host)),
+        None => command.arg(&tunnel.host),
+    };
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+    command
+}
+
+async fn wait_ready(port: u16) -> bool {
+    for _ in 0..READY_TIMEOUT * 2 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    false
+}
+
+async fn supervise(tunnel: Tunnel) {
+    let mut delay = 1;
+    loop {
+        info!("Start SSH tunnel to {}", tunnel.host);
+        let start = std::time::Instant::now();
+        let forward = format!("127.0.0.1:{}", local_port(&tunnel));
+        match build_command(&tunnel, ["-D", &forward]).spawn() {
+            Ok(mut child) => match child.wait().await {
+                Ok(status) => warn!("SSH tunnel exited ({})", status),
+                Err(e) => error!("Got error while wait SSH tunnel: {:?}", e),
+            },
+            Err(e) => error!("Unable start SSH tunnel: {:?}", e),
+        }
+        if start.elapsed() > Duration::from_secs(MAX_RESTART_DELAY) {
+            delay = 1;
+        }
+        warn!("Reconnect SSH tunnel in {} seconds", delay);
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Start SSH tunnel in background if `[tunnel]` is configured, wait until the proxy port is ready.
+pub async fn spawn(cfg: &Configure) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let tunnel = match &cfg.tunnel {
+        Some(tunnel) => tunnel.clone(),
+        None => return Ok(None),
+    };
+    let port = local_port(&tunnel);
+    let handle = tokio::spawn(supervise(tunnel));
+    if !wait_ready(port).await {
+        warn!("SSH tunnel is not ready after {} seconds", READY_TIMEOUT);
+    }
+    Ok(Some(handle))
+}
@@ -0,0 +1,188 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Detect virtualization and cloud placement of host, reported once in registration
+//! so server can group cloud instances automatically.
+//!
+//! Hypervisor is taken from CPUID hypervisor leaf, then DMI strings. Cloud instance ID,
+//! region and zone are read from cloud-init instance data when available.
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+const DMI_PATH: &str = "/sys/class/dmi/id";
+const XEN_TYPE_PATH: &str = "/sys/hypervisor/type";
+pub(crate) const CLOUD_INIT_DATA_PATH: &str = "/run/cloud-init/instance-data.json";
+/// Chassis asset tag set on all Azure virtual machines.
+const AZURE_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+
+/// CPUID vendor signatures of hypervisor leaf `0x40000000`.
+const CPUID_SIGNATURES: [(&[u8; 12], &str); 8] = [
+    (b"KVMKVMKVM\0\0\0", "kvm"),
+    (b"Microsoft Hv", "hyperv"),
+    (b"VMwareVMware", "vmware"),
+    (b"XenVMMXenVMM", "xen"),
+    (b"TCGTCGTCGTCG", "qemu"),
+    (b"VBoxVBoxVBox", "virtualbox"),
+    (b"bhyve bhyve ", "bhyve"),
+    (b" lrpepyh  vr", "parallels"),
+];
+
+/// Substrings of DMI `sys_vendor`/`product_name`/`bios_vendor`, checked in order.
+const DMI_SIGNATURES: [(&str, &str); 10] = [
+    ("Microsoft Corporation Virtual Machine", "hyperv"),
+    ("KVM", "kvm"),
+    ("QEMU", "qemu"),
+    ("Amazon EC2", "kvm"),
+    ("Google Compute Engine", "kvm"),
+    ("VMware", "vmware"),
+    ("VirtualBox", "virtualbox"),
+    ("innotek GmbH", "virtualbox"),
+    ("Xen", "xen"),
+    ("Parallels", "parallels"),
+];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VirtInfo {
+    pub hypervisor: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cloud: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[allow(unused_unsafe)]
+fn cpuid_hypervisor() -> Option<Option<&'static str>> {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::__cpuid;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::__cpuid;
+
+    // Safety: CPUID is available on every x86 CPU able to run this binary.
+    let features = unsafe { __cpuid(1) };
+    if features.ecx & (1 << 31) == 0 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(0x4000_0000) };
+    let mut signature = [0u8; 12];
+    signature[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    signature[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+    Some(
+        CPUID_SIGNATURES
+            .iter()
+            .find(|(sig, _)| **sig == signature)
+            .map(|(_, name)| *name),
+    )
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn cpuid_hypervisor() -> Option<Option<&'static str>> {
+    None
+}
+
+fn read_trimmed(path: &str) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn dmi(name: &str) -> Option<String> {
+    read_trimmed(&format!("{}/{}", DMI_PATH, name))
+}
+
+fn dmi_hypervisor() -> Option<&'static str> {
+    let strings = ["sys_vendor", "product_name", "bios_vendor"]
+        .iter()
+        .filter_map(|name| dmi(name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    DMI_SIGNATURES
+        .iter()
+        .find(|(pattern, _)| strings.contains(pattern))
+        .map(|(_, name)| *name)
+}
+
+fn dmi_cloud() -> Option<&'static str> {
+    let vendor = dmi("sys_vendor").unwrap_or_default();
+    let product = dmi("product_name").unwrap_or_default();
+    if vendor.contains("Amazon EC2")
+        || dmi("product_uuid").is_some_and(|uuid| uuid.to_lowercase().starts_with("ec2"))
+    {
+        Some("aws")
+    } else if product.contains("Google Compute Engine") {
+        Some("gcp")
+    } else if dmi("chassis_asset_tag").as_deref() == Some(AZURE_ASSET_TAG) {
+        Some("azure")
+    } else {
+        None
+    }
+}
+
+/// Read `v1` section of cloud-init instance data.
+fn apply_cloud_init(info: &mut VirtInfo) {
+    let data: Value = match std::fs::read(CLOUD_INIT_DATA_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+    {
+        Some(data) => data,
+        None => return,
+    };
+    let field = |name: &str| {
+        data.pointer(&format!("/v1/{}", name))
+            .and_then(Value::as_str)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+    if let Some(cloud) = field("cloud_name").filter(|name| name != "none") {
+        info.cloud = Some(match cloud.as_str() {
+            "aws" | "ec2" => "aws".to_string(),
+            "gce" => "gcp".to_string(),
+            _ => cloud,
+        });
+    }
+    info.instance_id = field("instance_id");
+    info.region = field("region");
+    info.zone = field("availability_zone");
+}
+
+/// Return `None` on bare metal or if nothing indicates a hypervisor.
+pub fn detect() -> Option<VirtInfo> {
+    let cpuid = cpuid_hypervisor();
+    let hypervisor = match (cpuid, dmi_hypervisor()) {
+        (Some(Some(name)), _) => name.to_string(),
+        (_, Some(name)) => name.to_string(),
+        (Some(None), None) => "unknown".to_string(),
+        (None, None) => read_trimmed(XEN_TYPE_PATH)?,
+    };
+    let mut info = VirtInfo {
+        hypervisor,
+        cloud: dmi_cloud().map(str::to_string),
+        ..Default::default()
+    };
+    apply_cloud_init(&mut info);
+    Some(info)
+}
@@ -0,0 +1,125 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! `wake` action: send Wake-on-LAN magic packets to machines in LAN on behalf of server.
+
+use crate::action::ActionHandler;
+use crate::configparser::config::WakeAction;
+use crate::error::Error;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::net::SocketAddr;
+
+pub const DEFAULT_BROADCAST: &str = "255.255.255.255:9";
+
+fn invalid_mac(mac: &str) -> Error {
+    Error::InvalidParameter {
+        action: "wake".to_string(),
+        name: "mac",
+        value: mac.to_string(),
+    }
+}
+
+fn parse_mac(mac: &str) -> anyhow::Result<[u8; 6]> {
+    let octets: Vec<u8> = mac
+        .split([':', '-'])
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid_mac(mac))?;
+    let mut result = [0u8; 6];
+    if octets.len() != result.len() {
+        return Err(invalid_mac(mac).into());
+    }
+    result.copy_from_slice(&octets);
+    Ok(result)
+}
+
+fn magic_packet(mac: &[u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xffu8; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    packet
+}
+
+pub struct WakeHandler {
+    broadcast: SocketAddr,
+    allowed: Option<Vec<[u8; 6]>>,
+}
+
+impl WakeHandler {
+    pub fn new(cfg: &WakeAction) -> anyhow::Result<Self> {
+        Ok(Self {
+            broadcast: cfg
+                .broadcast
+                .as_deref()
+                .unwrap_or(DEFAULT_BROADCAST)
+                .parse()?,
+            allowed: match &cfg.allowed_macs {
+                Some(macs) => Some(
+                    macs.iter()
+                        .map(|mac| parse_mac(mac))
+                        .collect::<anyhow::Result<_>>()?,
+                ),
+                None => None,
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl ActionHandler for WakeHandler {
+    fn name(&self) -> &str {
+        "wake"
+    }
+
+    async fn handle(&self, params: &Value) -> anyhow::Result<Value> {
+        let macs: Vec<String> =
+            serde_json::from_value(params.get("macs").cloned().ok_or_else(|| {
+                Error::MissingParameter {
+                    action: self.name().to_string(),
+                    name: "macs",
+                }
+            })?)?;
+        let mut targets: Vec<[u8; 6]> = Default::default();
+        for mac in &macs {
+            let octets = parse_mac(mac)?;
+            if let Some(allowed) = &self.allowed {
+                if !allowed.contains(&octets) {
+                    return Err(Error::NotAllowed {
+                        action: self.name().to_string(),
+                        list: "allowed_macs",
+                        value: mac.to_string(),
+                    }
+                    .into());
+                }
+            }
+            targets.push(octets);
+        }
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.set_broadcast(true)?;
+        for octets in &targets {
+            socket
+                .send_to(&magic_packet(octets), self.broadcast)
+                .await?;
+        }
+        Ok(serde_json::json!({ "sent": macs }))
+    }
+}
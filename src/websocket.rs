@@ -0,0 +1,587 @@
+/*
+ ** Copyright (C) 2021 KunoiSayami
+ **
+ ** This file is part of probe-client and is released under
+ ** the AGPL v3 License: https://www.gnu.org/licenses/agpl-3.0.txt
+ **
+ ** This program is free software: you can redistribute it and/or modify
+ ** it under the terms of the GNU Affero General Public License as published by
+ ** the Free Software Foundation, either version 3 of the License, or
+ ** any later version.
+ **
+ ** This program is distributed in the hope that it will be useful,
+ ** but WITHOUT ANY WARRANTY; without even the implied warranty of
+ ** MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ ** GNU Affero General Public License for more details.
+ **
+ ** You should have received a copy of the GNU Affero General Public License
+ ** along with this program. If not, see <https://www.gnu.org/licenses/>.
+ */
+//! WebSocket transport of `[server] transport = "websocket"`: requests go over one persistent
+//! connection to server instead of a POST each, and server may push actions at any time.
+//!
+//! The connection is upgraded from an HTTP request to server URL with the same client as POST
+//! requests, so proxy, Tor, client certificate and `sni_hostname` settings apply unchanged.
+//! Every request is sent as a text message with the same body as POST would have. Server replies
+//! to each in order, with the same body as POST response. Messages of form `{"push": {...}}` are
+//! not replies, but pushed by server on its own.
+
+use crate::error::Error;
+use anyhow::anyhow;
+use bytes::{Buf, BytesMut};
+use reqwest::header::{CONNECTION, CONTENT_TYPE, UPGRADE};
+use ring::rand::SecureRandom;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+pub const TRANSPORT_NAME: &str = "websocket";
+pub const DEFAULT_TRANSPORT: &str = "https";
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message accepted from peer.
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+const DEFAULT_TIMEOUT: u64 = 10;
+/// Requests waiting for connection to write them.
+const QUEUE_SIZE: usize = 16;
+const PUSH_QUEUE_SIZE: usize = 64;
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+fn violation(message: String) -> Error {
+    Error::Protocol {
+        protocol: TRANSPORT_NAME,
+        message,
+    }
+}
+
+/// `Sec-WebSocket-Accept` expected in response to `Sec-WebSocket-Key` of handshake.
+pub fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, GUID).as_bytes(),
+    );
+    base64::Engine::encode(&base64::engine::general_purpose::STANDARD, digest)
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Take one complete frame from front of `buffer`, `None` if more bytes are needed.
+fn decode(buffer: &mut BytesMut) -> anyhow::Result<Option<Frame>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let masked = buffer[1] & 0x80 != 0;
+    let (length, mut offset) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => {
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(length), 10)
+        }
+        126 | 127 => return Ok(None),
+        length => (length as u64, 2),
+    };
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err(violation(format!("WebSocket frame of {} bytes is too large", length)).into());
+    }
+    let mask = if masked {
+        if buffer.len() < offset + 4 {
+            return Ok(None);
+        }
+        offset += 4;
+        Some([
+            buffer[offset - 4],
+            buffer[offset - 3],
+            buffer[offset - 2],
+            buffer[offset - 1],
+        ])
+    } else {
+        None
+    };
+    if buffer.len() < offset + length as usize {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    buffer.advance(offset);
+    let mut payload = buffer.split_to(length as usize).to_vec();
+    if let Some(mask) = mask {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+fn encode(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length if length < 126 => frame.push(mask_bit | length as u8),
+        length if length <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+pub enum Message {
+    /// Text or binary message
+    Data(Vec<u8>),
+    Ping(Vec<u8>),
+    Close,
+}
+
+/// Message framing over upgraded connection. Client side masks every frame it sends.
+pub struct Stream<S> {
+    io: S,
+    buffer: BytesMut,
+    /// Opcode and payload of fragmented message received so far
+    fragments: Option<(u8, Vec<u8>)>,
+    client: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    pub fn new(io: S, client: bool) -> Self {
+        Self {
+            io,
+            buffer: BytesMut::with_capacity(8192),
+            fragments: None,
+            client,
+        }
+    }
+
+    pub async fn send(&mut self, opcode: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let mask = if self.client {
+            let mut mask = [0u8; 4];
+            ring::rand::SystemRandom::new()
+                .fill(&mut mask)
+                .map_err(|_| Error::Crypto {
+                    message: "Unable generate WebSocket mask".to_string(),
+                })?;
+            Some(mask)
+        } else {
+            None
+        };
+        self.io.write_all(&encode(opcode, payload, mask)).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        self.send(OPCODE_CLOSE, &1000u16.to_be_bytes()).await
+    }
+
+    /// Next message from peer, pong is left to caller. Cancel safe.
+    pub async fn receive(&mut self) -> anyhow::Result<Message> {
+        loop {
+            while let Some(frame) = decode(&mut self.buffer)? {
+                // Control frames can't be fragmented and carry at most 125 bytes
+                if frame.opcode & 0x8 != 0 && (!frame.fin || frame.payload.len() > 125) {
+                    return Err(violation(format!(
+                        "WebSocket control frame {:#x} is fragmented or too large",
+                        frame.opcode
+                    ))
+                    .into());
+                }
+                match frame.opcode {
+                    OPCODE_CLOSE => return Ok(Message::Close),
+                    OPCODE_PING => return Ok(Message::Ping(frame.payload)),
+                    OPCODE_PONG => continue,
+                    OPCODE_CONTINUATION => {
+                        let (_, payload) = self.fragments.as_mut().ok_or_else(|| {
+                            violation("WebSocket continuation frame without message".to_string())
+                        })?;
+                        payload.extend_from_slice(&frame.payload);
+                        if payload.len() > MAX_MESSAGE_SIZE {
+                            return Err(
+                                violation("WebSocket message is too large".to_string()).into()
+                            );
+                        }
+                    }
+                    OPCODE_TEXT | OPCODE_BINARY => {
+                        if self.fragments.is_some() {
+                            return Err(violation(
+                                "WebSocket message started before previous one ended".to_string(),
+                            )
+                            .into());
+                        }
+                        self.fragments = Some((frame.opcode, frame.payload));
+                    }
+                    opcode => {
+                        return Err(
+                            violation(format!("Unknown WebSocket opcode {:#x}", opcode)).into()
+                        )
+                    }
+                }
+                if frame.fin {
+                    if let Some((_, payload)) = self.fragments.take() {
+                        return Ok(Message::Data(payload));
+                    }
+                }
+            }
+            if self.io.read_buf(&mut self.buffer).await? == 0 {
+                return Err(violation("WebSocket connection closed by peer".to_string()).into());
+            }
+        }
+    }
+}
+
+/// Open WebSocket connection to `url` over HTTP/1.1 upgrade.
+async fn connect(client: &reqwest::Client, url: &str) -> anyhow::Result<Stream<reqwest::Upgraded>> {
+    let mut key = [0u8; 16];
+    ring::rand::SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| Error::Crypto {
+            message: "Unable generate WebSocket key".to_string(),
+        })?;
+    let key = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, key);
+    let response = client
+        .get(url)
+        .header(CONNECTION, "Upgrade")
+        .header(UPGRADE, "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", &key)
+        .send()
+        .await?;
+    if response.status() != reqwest::StatusCode::SWITCHING_PROTOCOLS {
+        return Err(violation(format!(
+            "Server {} refused WebSocket upgrade with status {}",
+            url,
+            response.status()
+        ))
+        .into());
+    }
+    let accepted = response
+        .headers()
+        .get("Sec-WebSocket-Accept")
+        .and_then(|accept| accept.to_str().ok());
+    if accepted != Some(accept_key(&key).as_str()) {
+        return Err(violation(format!("Server {} sent invalid Sec-WebSocket-Accept", url)).into());
+    }
+    Ok(Stream::new(response.upgrade().await?, true))
+}
+
+struct Pending {
+    body: bytes::Bytes,
+    reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+}
+
+/// Write queued requests and route messages of server until either side closes connection.
+async fn serve(
+    url: String,
+    mut stream: Stream<reqwest::Upgraded>,
+    mut requests: mpsc::Receiver<Pending>,
+    pushed: mpsc::Sender<Value>,
+) {
+    let mut waiting: VecDeque<oneshot::Sender<anyhow::Result<Vec<u8>>>> = VecDeque::new();
+    let result: anyhow::Result<()> = async {
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => {
+                        stream.send(OPCODE_TEXT, &request.body).await?;
+                        waiting.push_back(request.reply);
+                    }
+                    // Transport switched to another server or is dropped
+                    None => return stream.close().await,
+                },
+                message = stream.receive() => match message? {
+                    Message::Data(data) => {
+                        if let Some(push) = serde_json::from_slice::<Value>(&data)
+                            .ok()
+                            .and_then(|mut value| value.get_mut("push").map(Value::take))
+                        {
+                            if pushed.try_send(push).is_err() {
+                                log::warn!("Too many messages pushed by {}, drop one", url);
+                            }
+                            continue;
+                        }
+                        match waiting.pop_front() {
+                            Some(reply) => {
+                                reply.send(Ok(data)).ok();
+                            }
+                            None => log::warn!("Ignore unexpected reply from {}", url),
+                        }
+                    }
+                    Message::Ping(payload) => stream.send(OPCODE_PONG, &payload).await?,
+                    Message::Close => {
+                        return Err(violation("WebSocket connection closed by server".to_string()).into())
+                    }
+                },
+            }
+        }
+    }
+    .await;
+    if let Err(e) = result {
+        log::warn!("WebSocket connection to {} ended: {}", url, e);
+        for reply in waiting {
+            reply
+                .send(Err(
+                    violation(format!("WebSocket connection ended: {}", e)).into()
+                ))
+                .ok();
+        }
+    }
+}
+
+/// Persistent connection to current server, reopened on demand after it is lost.
+pub struct Transport {
+    timeout: Duration,
+    connection: tokio::sync::Mutex<Option<(String, mpsc::Sender<Pending>)>>,
+    pushed_sender: mpsc::Sender<Value>,
+    pushed: tokio::sync::Mutex<mpsc::Receiver<Value>>,
+}
+
+impl Transport {
+    pub fn new(cfg: &crate::configparser::config::Configure) -> anyhow::Result<Option<Self>> {
+        match cfg.server.transport.as_deref() {
+            None | Some(DEFAULT_TRANSPORT) => return Ok(None),
+            Some(TRANSPORT_NAME) => {}
+            Some(transport) => {
+                return Err(Error::Configure {
+                    field: "server.transport",
+                    message: format!("Unknown transport {}", transport),
+                }
+                .into())
+            }
+        }
+        let (pushed_sender, pushed) = mpsc::channel(PUSH_QUEUE_SIZE);
+        Ok(Some(Self {
+            timeout: crate::tor::timeouts(cfg)
+                .map(|(timeout, _)| timeout)
+                .unwrap_or(Duration::from_secs(DEFAULT_TIMEOUT)),
+            connection: Default::default(),
+            pushed_sender,
+            pushed: tokio::sync::Mutex::new(pushed),
+        }))
+    }
+
+    /// Send `body` to `url` and wait its reply, as response of a POST request with that body.
+    pub async fn send(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        body: bytes::Bytes,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let timeout = timeout.unwrap_or(self.timeout);
+        let (reply, received) = oneshot::channel();
+        {
+            let mut connection = self.connection.lock().await;
+            let requests = match &*connection {
+                Some((current, requests)) if current == url && !requests.is_closed() => {
+                    requests.clone()
+                }
+                _ => {
+                    *connection = None;
+                    let stream = tokio::time::timeout(timeout, connect(client, url))
+                        .await
+                        .map_err(|_| Error::Timeout {
+                            error: anyhow!("Timeout open WebSocket to {}", url),
+                        })??;
+                    log::info!("WebSocket connection to {} opened", url);
+                    let (requests, receiver) = mpsc::channel(QUEUE_SIZE);
+                    tokio::spawn(serve(
+                        url.to_string(),
+                        stream,
+                        receiver,
+                        self.pushed_sender.clone(),
+                    ));
+                    *connection = Some((url.to_string(), requests.clone()));
+                    requests
+                }
+            };
+            requests
+                .send(Pending { body, reply })
+                .await
+                .map_err(|_| violation(format!("WebSocket connection to {} closed", url)))?;
+        }
+        let body = match tokio::time::timeout(timeout, received).await {
+            Ok(Ok(body)) => body?,
+            Ok(Err(_)) => {
+                return Err(violation(format!("WebSocket connection to {} closed", url)).into())
+            }
+            Err(_) => {
+                // Later replies would be out of order, start over with a new connection
+                self.reset().await;
+                return Err(Error::Timeout {
+                    error: anyhow!("Timeout wait reply over WebSocket from {}", url),
+                }
+                .into());
+            }
+        };
+        let response = http::Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)?;
+        Ok(reqwest::Response::from(response))
+    }
+
+    /// Close current connection, e.g. once client certificate changed.
+    pub async fn reset(&self) {
+        self.connection.lock().await.take();
+    }
+
+    /// Next message pushed by server.
+    pub async fn pushed(&self) -> Value {
+        match self.pushed.lock().await.recv().await {
+            Some(push) => push,
+            // Sender is owned by self
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Frame {
+        let mut buffer = BytesMut::from(&encode(opcode, payload, mask)[..]);
+        let frame = decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty());
+        frame
+    }
+
+    #[test]
+    fn accept_key_of_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn frames_round_trip_with_extended_lengths() {
+        for length in [0, 125, 126, u16::MAX as usize, u16::MAX as usize + 1].iter() {
+            let payload = (0..*length).map(|i| i as u8).collect::<Vec<_>>();
+            for mask in [None, Some([1, 2, 3, 4])].iter() {
+                let encoded = encode(OPCODE_BINARY, &payload, *mask);
+                let header = match length {
+                    0..=125 => 2,
+                    126..=65535 => 4,
+                    _ => 10,
+                } + if mask.is_some() { 4 } else { 0 };
+                assert_eq!(encoded.len(), header + length);
+                if let (Some(_), true) = (mask, *length > 0) {
+                    assert_ne!(&encoded[header..], &payload[..]);
+                }
+                let frame = round_trip(OPCODE_BINARY, &payload, *mask);
+                assert!(frame.fin);
+                assert_eq!(frame.opcode, OPCODE_BINARY);
+                assert_eq!(frame.payload, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_frame_waits_for_more() {
+        let encoded = encode(OPCODE_TEXT, &[b'a'; 300], Some([9, 8, 7, 6]));
+        for cut in [1, 3, 7, encoded.len() - 1].iter() {
+            let mut buffer = BytesMut::from(&encoded[..*cut]);
+            assert!(decode(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), *cut);
+        }
+    }
+
+    #[test]
+    fn oversized_frame_refused() {
+        let mut header = vec![0x82, 127];
+        header.extend_from_slice(&(MAX_MESSAGE_SIZE as u64 + 1).to_be_bytes());
+        let e = decode(&mut BytesMut::from(&header[..])).err().unwrap();
+        assert_eq!(crate::error::code(&e), crate::error::Code::Protocol);
+    }
+
+    /// Messages received from raw `frames` written by peer.
+    async fn receive(frames: &[Vec<u8>]) -> Vec<anyhow::Result<Message>> {
+        let (client, mut server) = tokio::io::duplex(1 << 20);
+        for frame in frames {
+            server.write_all(frame).await.unwrap();
+        }
+        drop(server);
+        let mut stream = Stream::new(client, true);
+        let mut messages = Vec::new();
+        loop {
+            let message = stream.receive().await;
+            let done = message.is_err();
+            messages.push(message);
+            if done {
+                return messages;
+            }
+        }
+    }
+
+    /// Frame with `fin` flag and opcode as given, payload unmasked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode(opcode, payload, None);
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        frame
+    }
+
+    #[tokio::test]
+    async fn control_frames_between_fragments() {
+        let messages = receive(&[
+            frame(false, OPCODE_TEXT, b"hel"),
+            frame(true, OPCODE_PING, b"ping"),
+            frame(true, OPCODE_PONG, b""),
+            frame(true, OPCODE_CONTINUATION, b"lo"),
+            frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes()),
+        ])
+        .await;
+        assert!(matches!(&messages[0], Ok(Message::Ping(payload)) if payload == b"ping"));
+        assert!(matches!(&messages[1], Ok(Message::Data(payload)) if payload == b"hello"));
+        assert!(matches!(&messages[2], Ok(Message::Close)));
+        assert!(messages[3].is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_refused() {
+        let malformed = [
+            vec![frame(true, OPCODE_CONTINUATION, b"orphan")],
+            vec![frame(false, OPCODE_PING, b"")],
+            vec![frame(true, OPCODE_PING, &[0; 126])],
+            vec![
+                frame(false, OPCODE_TEXT, b"a"),
+                frame(true, OPCODE_TEXT, b"b"),
+            ],
+            vec![frame(true, 0x3, b"")],
+        ];
+        for frames in malformed.iter() {
+            let messages = receive(frames).await;
+            assert_eq!(messages.len(), 1);
+            let e = messages[0].as_ref().err().unwrap();
+            assert_eq!(crate::error::code(e), crate::error::Code::Protocol, "{}", e);
+        }
+    }
+}